@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use payday_core::{PaydayError, PaydayResult};
+
+use crate::on_chain_api::{FeeEstimator, FeeRate};
+
+/// A minimal HTTP client, so fee estimators do not depend on a specific HTTP
+/// library and can be tested without a server.
+#[async_trait]
+pub trait HttpGetApi: Send + Sync {
+    /// Returns the body of a successful GET request to url.
+    async fn get(&self, url: &str) -> PaydayResult<String>;
+}
+
+/// Fee estimates of an Esplora API like mempool.space or blockstream.info.
+/// Useful on regtest and signet where node estimates are often unusable.
+pub struct EsploraFeeEstimator {
+    base_url: String,
+    client: Box<dyn HttpGetApi>,
+}
+
+impl EsploraFeeEstimator {
+    /// Creates an estimator for the API at base_url, e.g.
+    /// https://mempool.space/api.
+    pub fn new(base_url: &str, client: Box<dyn HttpGetApi>) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            client,
+        }
+    }
+}
+
+#[async_trait]
+impl FeeEstimator for EsploraFeeEstimator {
+    async fn sats_per_vbyte(&self, target_conf: u16) -> PaydayResult<FeeRate> {
+        let body = self
+            .client
+            .get(&format!("{}/fee-estimates", self.base_url))
+            .await?;
+        let estimates: HashMap<String, f64> = serde_json::from_str(&body)
+            .map_err(|e| PaydayError::NodeApiError(format!("invalid fee estimates: {}", e)))?;
+        select_fee_rate(&estimates, target_conf)
+    }
+}
+
+/// Selects the estimate of the target. Esplora only returns some targets, so a
+/// missing target falls back to the nearest available one, preferring the
+/// faster target on a tie. Rates are rounded up to whole sats per vbyte.
+fn select_fee_rate(estimates: &HashMap<String, f64>, target_conf: u16) -> PaydayResult<FeeRate> {
+    estimates
+        .iter()
+        .filter_map(|(target, rate)| Some((target.parse::<u16>().ok()?, *rate)))
+        .filter(|(_, rate)| rate.is_finite() && *rate >= 0.0)
+        .min_by_key(|(target, _)| (target.abs_diff(target_conf), *target))
+        .map(|(_, rate)| FeeRate::from_sat_per_vb((rate.ceil() as u64).max(1)))
+        .ok_or(PaydayError::NodeApiError(
+            "no fee estimates available".to_string(),
+        ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::sync::Mutex;
+
+    use super::*;
+
+    struct MockHttp {
+        body: String,
+        requested: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl HttpGetApi for MockHttp {
+        async fn get(&self, url: &str) -> PaydayResult<String> {
+            self.requested.lock().await.push(url.to_string());
+            Ok(self.body.to_string())
+        }
+    }
+
+    fn mock_estimator(body: &str) -> (EsploraFeeEstimator, Arc<Mutex<Vec<String>>>) {
+        let requested = Arc::new(Mutex::new(Vec::new()));
+        let client = MockHttp {
+            body: body.to_string(),
+            requested: requested.clone(),
+        };
+        (
+            EsploraFeeEstimator::new("https://mempool.space/api/", Box::new(client)),
+            requested,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_esplora_fee_estimates() {
+        let (estimator, requested) =
+            mock_estimator(r#"{"1": 25.3, "2": 20.0, "6": 12.1, "144": 1.027, "1008": 0.5}"#);
+        let rate = |target: u16| {
+            let estimator = &estimator;
+            async move { estimator.sats_per_vbyte(target).await.unwrap() }
+        };
+
+        assert_eq!(rate(1).await, FeeRate::from_sat_per_vb(26));
+        assert_eq!(rate(2).await, FeeRate::from_sat_per_vb(20));
+        assert_eq!(rate(144).await, FeeRate::from_sat_per_vb(2));
+        assert_eq!(
+            requested.lock().await[0],
+            "https://mempool.space/api/fee-estimates"
+        );
+
+        // missing targets use the nearest bucket, the faster one on a tie
+        assert_eq!(rate(5).await, FeeRate::from_sat_per_vb(13));
+        assert_eq!(rate(4).await, FeeRate::from_sat_per_vb(20));
+        assert_eq!(rate(500).await, FeeRate::from_sat_per_vb(2));
+        assert_eq!(rate(2000).await, FeeRate::from_sat_per_vb(1));
+    }
+
+    #[tokio::test]
+    async fn test_esplora_no_fee_estimates() {
+        let (estimator, _) = mock_estimator("{}");
+        assert!(estimator.sats_per_vbyte(6).await.is_err());
+        let (estimator, _) = mock_estimator("not json");
+        assert!(estimator.sats_per_vbyte(6).await.is_err());
+    }
+}
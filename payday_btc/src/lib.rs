@@ -1,14 +1,429 @@
+pub mod fees;
+pub mod lightning_aggregate;
+pub mod lightning_api;
 pub mod on_chain_aggregate;
 pub mod on_chain_api;
+pub mod on_chain_expiry;
+pub mod on_chain_invoice;
 pub mod on_chain_processor;
+pub mod on_chain_service;
+pub mod on_chain_streams;
+pub mod on_chain_view;
+pub mod on_chain_webhook;
 
 use std::str::FromStr;
 
-use bitcoin::{Address, Network};
-use payday_core::PaydayResult;
+use bitcoin::{Address, Denomination, Network};
+use payday_core::{
+    payment::{amount::Amount, currency::Currency},
+    PaydayError, PaydayResult,
+};
+use serde::{Deserialize, Serialize};
 
 /// Given a Bitcoin address string and a network, parses and validates the address.
 /// Returns a checked address result.
 pub fn to_address(addr: &str, network: Network) -> PaydayResult<Address> {
     Ok(Address::from_str(addr)?.require_network(network)?)
 }
+
+/// Parses a network name as used in configs and reported by nodes. Accepts the
+/// common aliases of each network, case insensitive.
+pub fn parse_network(network: &str) -> PaydayResult<Network> {
+    match network.trim().to_lowercase().as_str() {
+        "bitcoin" | "mainnet" | "main" => Ok(Network::Bitcoin),
+        "testnet" | "testnet3" | "test" => Ok(Network::Testnet),
+        "signet" => Ok(Network::Signet),
+        "regtest" => Ok(Network::Regtest),
+        _ => Err(PaydayError::InvalidBitcoinNetwork(network.to_string())),
+    }
+}
+
+/// Returns the number of confirmations an on-chain payment needs to be considered
+/// confirmed. An explicit value takes precedence over the network default.
+pub fn required_confirmations(network: Network, explicit: Option<u64>) -> u64 {
+    explicit.unwrap_or(match network {
+        Network::Bitcoin => 3,
+        _ => 1,
+    })
+}
+
+/// Invoices of at least min_amount need the given number of confirmations.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConfirmationTier {
+    pub min_amount: Amount,
+    pub confirmations: u64,
+}
+
+/// Required confirmations by invoice amount, so high value payments can wait for
+/// more confirmations than low value ones.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConfirmationTiers(Vec<ConfirmationTier>);
+
+impl ConfirmationTiers {
+    pub fn new(mut tiers: Vec<ConfirmationTier>) -> Self {
+        tiers.sort_by_key(|t| t.min_amount.amount);
+        Self(tiers)
+    }
+
+    /// Returns the confirmations of the highest tier the amount reaches or None
+    /// if the amount is below all tiers.
+    pub fn resolve(&self, amount: &Amount) -> Option<u64> {
+        self.0
+            .iter()
+            .rev()
+            .find(|t| {
+                t.min_amount.currency == amount.currency && amount.amount >= t.min_amount.amount
+            })
+            .map(|t| t.confirmations)
+    }
+}
+
+/// Builds a BIP21 URI that wallets can pay on-chain or, if a BOLT11 invoice is
+/// given, over lightning. A zero amount is left out, so the payer chooses it.
+pub fn to_bip21(
+    address: &Address,
+    amount: Amount,
+    lightning: Option<&str>,
+    label: Option<&str>,
+) -> PaydayResult<String> {
+    bip21_uri(address, amount, lightning, label, false)
+}
+
+/// Like to_bip21 but with an uppercase lightning invoice, which encodes into
+/// smaller QR codes.
+pub fn to_bip21_qr(
+    address: &Address,
+    amount: Amount,
+    lightning: Option<&str>,
+    label: Option<&str>,
+) -> PaydayResult<String> {
+    bip21_uri(address, amount, lightning, label, true)
+}
+
+fn bip21_uri(
+    address: &Address,
+    amount: Amount,
+    lightning: Option<&str>,
+    label: Option<&str>,
+    uppercase_lightning: bool,
+) -> PaydayResult<String> {
+    if amount.currency != Currency::Btc {
+        return Err(PaydayError::InvalidBitcoinAmount(amount.to_string()));
+    }
+    let mut params = Vec::new();
+    if amount.amount > 0 {
+        let btc = bitcoin::Amount::from_sat(amount.amount).to_string_in(Denomination::Bitcoin);
+        params.push(format!("amount={}", btc));
+    }
+    if let Some(lightning) = lightning {
+        let lightning = match uppercase_lightning {
+            true => lightning.to_uppercase(),
+            false => lightning.to_string(),
+        };
+        params.push(format!("lightning={}", percent_encode(&lightning)));
+    }
+    if let Some(label) = label {
+        params.push(format!("label={}", percent_encode(label)));
+    }
+    match params.is_empty() {
+        true => Ok(format!("bitcoin:{}", address)),
+        false => Ok(format!("bitcoin:{}?{}", address, params.join("&"))),
+    }
+}
+
+/// A payment request parsed from a BIP21 URI. The address is optional for
+/// lightning only requests, the lightning invoice is checked for the network
+/// but not decoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParsedBip21 {
+    pub address: Option<Address>,
+    pub amount: Option<Amount>,
+    pub lightning: Option<String>,
+    pub label: Option<String>,
+    pub message: Option<String>,
+}
+
+/// Parses a BIP21 URI as pasted or scanned by a user. The address has to be
+/// valid for the network, an embedded BOLT11 invoice has to be for the same
+/// network. Unknown required (req-) parameters are rejected as of BIP21.
+pub fn parse_bip21(uri: &str, network: Network) -> PaydayResult<ParsedBip21> {
+    let invalid = |m: &str| PaydayError::InvalidPaymentUri(format!("{}: {}", m, uri));
+    let uri = uri.trim();
+    let rest = match uri.split_once(':') {
+        Some((scheme, rest)) if scheme.eq_ignore_ascii_case("bitcoin") => rest,
+        _ => return Err(invalid("not a bitcoin uri")),
+    };
+    let (address, query) = rest.split_once('?').unwrap_or((rest, ""));
+
+    let mut parsed = ParsedBip21 {
+        address: None,
+        amount: None,
+        lightning: None,
+        label: None,
+        message: None,
+    };
+    if !address.is_empty() {
+        parsed.address = Some(to_address(address, network)?);
+    }
+    for param in query.split('&').filter(|p| !p.is_empty()) {
+        let (key, value) = param
+            .split_once('=')
+            .ok_or_else(|| invalid("parameter without value"))?;
+        let value = percent_decode(value).ok_or_else(|| invalid("invalid percent encoding"))?;
+        match key.to_lowercase().as_str() {
+            "amount" => {
+                let sats = bitcoin::Amount::from_str_in(&value, Denomination::Bitcoin)?;
+                if sats > bitcoin::Amount::MAX_MONEY {
+                    return Err(PaydayError::InvalidBitcoinAmount(format!(
+                        "{} exceeds the bitcoin supply",
+                        value
+                    )));
+                }
+                parsed.amount = Some(Amount::new(Currency::Btc, sats.to_sat()));
+            }
+            "lightning" => {
+                if bolt11_network(&value) != Some(network) {
+                    return Err(invalid("lightning invoice is not for the network"));
+                }
+                parsed.lightning = Some(value);
+            }
+            "label" => parsed.label = Some(value),
+            "message" => parsed.message = Some(value),
+            key if key.starts_with("req-") => {
+                return Err(invalid("unsupported required parameter"))
+            }
+            _ => {}
+        }
+    }
+    if parsed.address.is_none() && parsed.lightning.is_none() {
+        return Err(invalid("neither address nor lightning invoice"));
+    }
+    Ok(parsed)
+}
+
+/// The network of a BOLT11 invoice by its human readable prefix.
+fn bolt11_network(invoice: &str) -> Option<Network> {
+    let invoice = invoice.to_lowercase();
+    let (hrp, _) = invoice.strip_prefix("ln")?.split_once('1')?;
+    if hrp.starts_with("bcrt") {
+        Some(Network::Regtest)
+    } else if hrp.starts_with("bc") {
+        Some(Network::Bitcoin)
+    } else if hrp.starts_with("tbs") {
+        Some(Network::Signet)
+    } else if hrp.starts_with("tb") {
+        Some(Network::Testnet)
+    } else {
+        None
+    }
+}
+
+/// Percent encodes all but the unreserved characters of RFC 3986.
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Decodes percent encoded bytes, None if an escape is invalid or the result is
+/// not UTF-8.
+fn percent_decode(value: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut iter = value.bytes();
+    while let Some(b) = iter.next() {
+        match b {
+            b'%' => {
+                let hex = [iter.next()?, iter.next()?];
+                bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            _ => bytes.push(b),
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_network() {
+        for alias in ["bitcoin", "mainnet", "main", "Mainnet"] {
+            assert_eq!(parse_network(alias).unwrap(), Network::Bitcoin);
+        }
+        for alias in ["testnet", "testnet3", "test"] {
+            assert_eq!(parse_network(alias).unwrap(), Network::Testnet);
+        }
+        assert_eq!(parse_network("signet").unwrap(), Network::Signet);
+        assert_eq!(parse_network(" regtest ").unwrap(), Network::Regtest);
+
+        match parse_network("liquid") {
+            Err(PaydayError::InvalidBitcoinNetwork(value)) => assert_eq!(value, "liquid"),
+            r => panic!("expected invalid network error, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_required_confirmations_network_default() {
+        assert_eq!(required_confirmations(Network::Bitcoin, None), 3);
+        assert_eq!(required_confirmations(Network::Testnet, None), 1);
+        assert_eq!(required_confirmations(Network::Signet, None), 1);
+        assert_eq!(required_confirmations(Network::Regtest, None), 1);
+    }
+
+    #[test]
+    fn test_confirmation_tiers() {
+        let tier = |sats: u64, confirmations: u64| ConfirmationTier {
+            min_amount: Amount::new(Currency::Btc, sats),
+            confirmations,
+        };
+        let tiers =
+            ConfirmationTiers::new(vec![tier(100_000_000, 6), tier(0, 1), tier(10_000_000, 3)]);
+        let resolve = |sats: u64| tiers.resolve(&Amount::new(Currency::Btc, sats));
+        assert_eq!(resolve(100_000), Some(1));
+        assert_eq!(resolve(10_000_000), Some(3));
+        assert_eq!(resolve(100_000_000), Some(6));
+        assert_eq!(resolve(500_000_000), Some(6));
+        assert_eq!(
+            ConfirmationTiers::default().resolve(&Amount::default()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_required_confirmations_override() {
+        assert_eq!(required_confirmations(Network::Bitcoin, Some(6)), 6);
+        assert_eq!(required_confirmations(Network::Regtest, Some(2)), 2);
+    }
+
+    #[test]
+    fn test_bip21() {
+        let address = to_address(
+            "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx",
+            Network::Testnet,
+        )
+        .unwrap();
+        let btc = |sats: u64| Amount::new(Currency::Btc, sats);
+
+        assert_eq!(
+            to_bip21(&address, btc(100_000), None, None).unwrap(),
+            "bitcoin:tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx?amount=0.001"
+        );
+        assert_eq!(
+            to_bip21(&address, btc(0), None, None).unwrap(),
+            "bitcoin:tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx"
+        );
+        assert_eq!(
+            to_bip21(
+                &address,
+                btc(123_456_789),
+                Some("lntb1abc"),
+                Some("Order #1 & co/ü")
+            )
+            .unwrap(),
+            "bitcoin:tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx?amount=1.23456789\
+            &lightning=lntb1abc&label=Order%20%231%20%26%20co%2F%C3%BC"
+        );
+        assert_eq!(
+            to_bip21_qr(&address, btc(100_000_000), Some("lntb1abc"), None).unwrap(),
+            "bitcoin:tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx?amount=1&lightning=LNTB1ABC"
+        );
+        assert!(to_bip21(&address, Amount::new(Currency::Usd, 1_000), None, None).is_err());
+    }
+
+    #[test]
+    fn test_parse_bip21() {
+        let address = "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx";
+        let parsed = parse_bip21(
+            &format!(
+                "BITCOIN:{}?amount=1.23456789&lightning=LNTB1ABC&label=Order%20%231%20%26%20co%2F%C3%BC&message=thanks&foo=bar",
+                address
+            ),
+            Network::Testnet,
+        )
+        .unwrap();
+        assert_eq!(
+            parsed,
+            ParsedBip21 {
+                address: Some(to_address(address, Network::Testnet).unwrap()),
+                amount: Some(Amount::new(Currency::Btc, 123_456_789)),
+                lightning: Some("LNTB1ABC".to_string()),
+                label: Some("Order #1 & co/ü".to_string()),
+                message: Some("thanks".to_string()),
+            }
+        );
+
+        // round trip of the builder
+        let uri = to_bip21(
+            &to_address(address, Network::Testnet).unwrap(),
+            Amount::new(Currency::Btc, 100_000),
+            None,
+            Some("a b"),
+        )
+        .unwrap();
+        let parsed = parse_bip21(&uri, Network::Testnet).unwrap();
+        assert_eq!(parsed.amount, Some(Amount::new(Currency::Btc, 100_000)));
+        assert_eq!(parsed.label.as_deref(), Some("a b"));
+    }
+
+    #[test]
+    fn test_parse_bip21_lightning_only() {
+        let parsed = parse_bip21("bitcoin:?lightning=lnbcrt10u1abc", Network::Regtest).unwrap();
+        assert_eq!(parsed.address, None);
+        assert_eq!(parsed.lightning.as_deref(), Some("lnbcrt10u1abc"));
+        assert!(parse_bip21("bitcoin:?label=nothing", Network::Regtest).is_err());
+    }
+
+    #[test]
+    fn test_parse_bip21_invalid() {
+        let address = "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx";
+        let invalid = [
+            address.to_string(),
+            format!("lightning:{}", address),
+            format!("bitcoin//{}", address),
+            format!("bitcoin:{}?amount", address),
+            format!("bitcoin:{}?label=%zz", address),
+            format!("bitcoin:{}?req-somethingnew=1", address),
+        ];
+        for uri in invalid {
+            assert!(
+                matches!(
+                    parse_bip21(&uri, Network::Testnet),
+                    Err(PaydayError::InvalidPaymentUri(_))
+                ),
+                "{}",
+                uri
+            );
+        }
+
+        // address or lightning invoice of another network
+        assert!(parse_bip21(&format!("bitcoin:{}", address), Network::Bitcoin).is_err());
+        assert!(parse_bip21(
+            &format!("bitcoin:{}?lightning=lnbc10u1abc", address),
+            Network::Testnet
+        )
+        .is_err());
+        assert!(parse_bip21("bitcoin:?lightning=lntbs10u1abc", Network::Testnet).is_err());
+
+        // amounts above the supply and of sub satoshi precision
+        for amount in ["21000001", "184467440737.09551616", "0.000000001", "-1"] {
+            assert!(
+                matches!(
+                    parse_bip21(
+                        &format!("bitcoin:{}?amount={}", address, amount),
+                        Network::Testnet
+                    ),
+                    Err(PaydayError::InvalidBitcoinAmount(_))
+                ),
+                "{}",
+                amount
+            );
+        }
+    }
+}
@@ -0,0 +1,477 @@
+use async_trait::async_trait;
+use cqrs_es::{Aggregate, DomainEvent};
+use payday_core::date::{now, DateTime};
+use payday_core::payment::amount::Amount;
+use payday_core::payment::currency::Currency;
+use payday_core::payment::invoice::{InvoiceError, InvoiceId};
+use serde::{Deserialize, Serialize};
+
+use crate::lightning_api::LightningTransactionEvent;
+
+/// How a settled payment above the invoice amount is handled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OverpaymentPolicy {
+    /// The invoice is paid, the overpayment is only marked.
+    #[default]
+    Accept,
+    /// The invoice is not finalized and is flagged for manual review.
+    FlagForReview,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BtcLightningInvoice {
+    pub invoice_id: InvoiceId,
+    pub amount: Amount,
+    pub received_amount: Amount,
+    pub overpayment_policy: OverpaymentPolicy,
+    pub settle_index: Option<u64>,
+    /// A payment is held by the node but not settled yet, e.g. for hold
+    /// invoices that wait for manual settlement.
+    pub accepted: bool,
+    pub overpayment: bool,
+    /// An overpayment waits for manual review.
+    pub review_required: bool,
+    pub paid: bool,
+    pub paid_at: Option<DateTime>,
+    pub canceled: bool,
+    /// After this time the node rejects payments, None if unknown.
+    pub expires_at: Option<DateTime>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub enum LightningInvoiceCommand {
+    CreateInvoice {
+        invoice_id: InvoiceId,
+        amount: Amount,
+        overpayment_policy: OverpaymentPolicy,
+        /// The expiry requested for the invoice.
+        #[serde(default)]
+        expires_at: Option<DateTime>,
+        /// The expiry of the invoice created on the node.
+        #[serde(default)]
+        node_expires_at: Option<DateTime>,
+    },
+    SetAccepted {
+        amount: Amount,
+    },
+    SetSettled {
+        amount: Amount,
+        settle_index: u64,
+        settled_at: Option<DateTime>,
+    },
+    Cancel,
+}
+
+/// A command for a lightning invoice aggregate. Lightning invoices are keyed by
+/// their hex encoded r_hash, the only stable id present in all node events.
+#[derive(Debug)]
+pub struct LightningCommand {
+    pub id: String,
+    pub command: LightningInvoiceCommand,
+}
+
+impl From<LightningTransactionEvent> for LightningCommand {
+    fn from(value: LightningTransactionEvent) -> Self {
+        let (aggregate_id, command) = match value {
+            LightningTransactionEvent::Settled(tx) => (
+                tx.r_hash,
+                LightningInvoiceCommand::SetSettled {
+                    amount: Amount::new(Currency::Btc, tx.amount.to_sat()),
+                    settle_index: tx.settle_index,
+                    settled_at: tx.settled_at,
+                },
+            ),
+            LightningTransactionEvent::Accepted(tx) => (
+                tx.r_hash,
+                LightningInvoiceCommand::SetAccepted {
+                    amount: Amount::new(Currency::Btc, tx.amount.to_sat()),
+                },
+            ),
+            LightningTransactionEvent::Canceled(tx) => (tx.r_hash, LightningInvoiceCommand::Cancel),
+        };
+        LightningCommand {
+            id: aggregate_id,
+            command,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum LightningInvoiceEvent {
+    InvoiceCreated {
+        invoice_id: InvoiceId,
+        amount: Amount,
+        #[serde(default)]
+        overpayment_policy: OverpaymentPolicy,
+        #[serde(default)]
+        expires_at: Option<DateTime>,
+    },
+    PaymentAccepted {
+        received_amount: Amount,
+    },
+    PaymentSettled {
+        received_amount: Amount,
+        overpayment: bool,
+        settle_index: u64,
+        paid_at: DateTime,
+    },
+    InvoiceOverpaid {
+        received_amount: Amount,
+        settle_index: u64,
+    },
+    InvoiceCanceled,
+}
+
+impl DomainEvent for LightningInvoiceEvent {
+    fn event_type(&self) -> String {
+        let event_type = match self {
+            LightningInvoiceEvent::InvoiceCreated { .. } => "LightningInvoiceCreated",
+            LightningInvoiceEvent::PaymentAccepted { .. } => "LightningPaymentAccepted",
+            LightningInvoiceEvent::PaymentSettled { .. } => "LightningPaymentSettled",
+            LightningInvoiceEvent::InvoiceOverpaid { .. } => "LightningInvoiceOverpaid",
+            LightningInvoiceEvent::InvoiceCanceled => "LightningInvoiceCanceled",
+        };
+        event_type.to_string()
+    }
+
+    fn event_version(&self) -> String {
+        "1.0.0".to_string()
+    }
+}
+
+#[async_trait]
+impl Aggregate for BtcLightningInvoice {
+    type Command = LightningInvoiceCommand;
+    type Event = LightningInvoiceEvent;
+    type Error = InvoiceError;
+    type Services = ();
+
+    fn aggregate_type() -> String {
+        "BtcLightningInvoice".to_string()
+    }
+
+    async fn handle(
+        &self,
+        command: Self::Command,
+        _service: &Self::Services,
+    ) -> Result<Vec<Self::Event>, Self::Error> {
+        match command {
+            LightningInvoiceCommand::CreateInvoice {
+                invoice_id,
+                amount,
+                overpayment_policy,
+                expires_at,
+                node_expires_at,
+            } => {
+                if amount.currency != Currency::Btc {
+                    return Err(InvoiceError::InvalidCurrency(
+                        amount.currency.to_string(),
+                        Currency::Btc.to_string(),
+                    ));
+                }
+                Ok(vec![LightningInvoiceEvent::InvoiceCreated {
+                    invoice_id,
+                    amount,
+                    overpayment_policy,
+                    expires_at: stricter_expiry(expires_at, node_expires_at),
+                }])
+            }
+            LightningInvoiceCommand::SetAccepted { amount } => {
+                if self.paid
+                    || self.canceled
+                    || self.review_required
+                    || (self.accepted && self.received_amount == amount)
+                {
+                    return Ok(vec![]);
+                }
+                Ok(vec![LightningInvoiceEvent::PaymentAccepted {
+                    received_amount: amount,
+                }])
+            }
+            LightningInvoiceCommand::SetSettled {
+                amount,
+                settle_index,
+                settled_at,
+            } => {
+                if self.paid || self.canceled || self.review_required {
+                    return Ok(vec![]);
+                }
+                let overpayment = amount.gt_checked(&self.amount)?;
+                if overpayment && self.overpayment_policy == OverpaymentPolicy::FlagForReview {
+                    return Ok(vec![LightningInvoiceEvent::InvoiceOverpaid {
+                        received_amount: amount,
+                        settle_index,
+                    }]);
+                }
+                Ok(vec![LightningInvoiceEvent::PaymentSettled {
+                    received_amount: amount,
+                    overpayment,
+                    settle_index,
+                    paid_at: settled_at.unwrap_or_else(now),
+                }])
+            }
+            LightningInvoiceCommand::Cancel => {
+                if self.paid {
+                    return Err(InvoiceError::AlreadyPaid(self.invoice_id.to_owned()));
+                }
+                if self.canceled {
+                    return Ok(vec![]);
+                }
+                Ok(vec![LightningInvoiceEvent::InvoiceCanceled])
+            }
+        }
+    }
+
+    fn apply(&mut self, event: Self::Event) {
+        match event {
+            LightningInvoiceEvent::InvoiceCreated {
+                invoice_id,
+                amount,
+                overpayment_policy,
+                expires_at,
+            } => {
+                self.invoice_id = invoice_id;
+                self.amount = amount;
+                self.overpayment_policy = overpayment_policy;
+                self.expires_at = expires_at;
+            }
+            LightningInvoiceEvent::PaymentAccepted { received_amount } => {
+                self.received_amount = received_amount;
+                self.accepted = true;
+            }
+            LightningInvoiceEvent::PaymentSettled {
+                received_amount,
+                overpayment,
+                settle_index,
+                paid_at,
+            } => {
+                self.received_amount = received_amount;
+                self.overpayment = overpayment;
+                self.settle_index = Some(settle_index);
+                self.paid = true;
+                self.paid_at = Some(paid_at);
+            }
+            LightningInvoiceEvent::InvoiceOverpaid {
+                received_amount,
+                settle_index,
+            } => {
+                self.received_amount = received_amount;
+                self.overpayment = true;
+                self.settle_index = Some(settle_index);
+                self.review_required = true;
+            }
+            LightningInvoiceEvent::InvoiceCanceled => {
+                self.canceled = true;
+            }
+        }
+    }
+}
+
+/// The earlier of both expiries, so the invoice is never considered payable
+/// after the node rejects payments.
+fn stricter_expiry(requested: Option<DateTime>, node: Option<DateTime>) -> Option<DateTime> {
+    match (requested, node) {
+        (Some(requested), Some(node)) => Some(requested.min(node)),
+        (requested, node) => requested.or(node),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use cqrs_es::test::TestFramework;
+    use payday_core::date::from_timestamp;
+
+    use super::*;
+    use crate::lightning_api::LightningTransaction;
+
+    type LightningInvoiceTestFramework = TestFramework<BtcLightningInvoice>;
+
+    #[test]
+    fn test_settled_event_to_command() {
+        let command: LightningCommand = LightningTransactionEvent::Settled(LightningTransaction {
+            node_id: "lnd1".to_string(),
+            r_hash: "abcd".to_string(),
+            invoice: "lntbs1".to_string(),
+            amount: bitcoin::Amount::from_sat(1_000),
+            settle_index: 7,
+            settled_at: Some(from_timestamp(1_700_000_000)),
+            custom_records: HashMap::new(),
+        })
+        .into();
+        assert_eq!(command.id, "abcd");
+        assert_eq!(
+            command.command,
+            LightningInvoiceCommand::SetSettled {
+                amount: Amount::new(Currency::Btc, 1_000),
+                settle_index: 7,
+                settled_at: Some(from_timestamp(1_700_000_000)),
+            }
+        );
+    }
+
+    #[test]
+    fn test_overpayment_accepted() {
+        LightningInvoiceTestFramework::with(())
+            .given(vec![mock_created_event(OverpaymentPolicy::Accept)])
+            .when(settle_command(1_100))
+            .then_expect_events(vec![LightningInvoiceEvent::PaymentSettled {
+                received_amount: amount_fn(1_100),
+                overpayment: true,
+                settle_index: 7,
+                paid_at: from_timestamp(1_700_000_000),
+            }]);
+    }
+
+    #[test]
+    fn test_overpayment_flagged_for_review() {
+        LightningInvoiceTestFramework::with(())
+            .given(vec![mock_created_event(OverpaymentPolicy::FlagForReview)])
+            .when(settle_command(1_100))
+            .then_expect_events(vec![LightningInvoiceEvent::InvoiceOverpaid {
+                received_amount: amount_fn(1_100),
+                settle_index: 7,
+            }]);
+
+        let mut invoice = BtcLightningInvoice::default();
+        invoice.apply(mock_created_event(OverpaymentPolicy::FlagForReview));
+        invoice.apply(LightningInvoiceEvent::InvoiceOverpaid {
+            received_amount: amount_fn(1_100),
+            settle_index: 7,
+        });
+        assert!(invoice.review_required);
+        assert!(!invoice.paid);
+    }
+
+    #[test]
+    fn test_exact_payment_with_review_policy_is_paid() {
+        LightningInvoiceTestFramework::with(())
+            .given(vec![mock_created_event(OverpaymentPolicy::FlagForReview)])
+            .when(settle_command(1_000))
+            .then_expect_events(vec![LightningInvoiceEvent::PaymentSettled {
+                received_amount: amount_fn(1_000),
+                overpayment: false,
+                settle_index: 7,
+                paid_at: from_timestamp(1_700_000_000),
+            }]);
+    }
+
+    #[test]
+    fn test_hold_invoice_accept_then_settle() {
+        LightningInvoiceTestFramework::with(())
+            .given(vec![mock_created_event(OverpaymentPolicy::Accept)])
+            .when(LightningInvoiceCommand::SetAccepted {
+                amount: amount_fn(1_000),
+            })
+            .then_expect_events(vec![LightningInvoiceEvent::PaymentAccepted {
+                received_amount: amount_fn(1_000),
+            }]);
+
+        let accepted = LightningInvoiceEvent::PaymentAccepted {
+            received_amount: amount_fn(1_000),
+        };
+        LightningInvoiceTestFramework::with(())
+            .given(vec![
+                mock_created_event(OverpaymentPolicy::Accept),
+                accepted.clone(),
+            ])
+            .when(settle_command(1_000))
+            .then_expect_events(vec![LightningInvoiceEvent::PaymentSettled {
+                received_amount: amount_fn(1_000),
+                overpayment: false,
+                settle_index: 7,
+                paid_at: from_timestamp(1_700_000_000),
+            }]);
+
+        let mut invoice = BtcLightningInvoice::default();
+        invoice.apply(mock_created_event(OverpaymentPolicy::Accept));
+        assert!(!invoice.accepted);
+        invoice.apply(accepted);
+        assert!(invoice.accepted);
+        assert!(!invoice.paid);
+    }
+
+    #[test]
+    fn test_hold_invoice_accept_then_cancel() {
+        let accepted = LightningInvoiceEvent::PaymentAccepted {
+            received_amount: amount_fn(1_000),
+        };
+        LightningInvoiceTestFramework::with(())
+            .given(vec![
+                mock_created_event(OverpaymentPolicy::Accept),
+                accepted.clone(),
+            ])
+            .when(LightningInvoiceCommand::Cancel)
+            .then_expect_events(vec![LightningInvoiceEvent::InvoiceCanceled]);
+
+        LightningInvoiceTestFramework::with(())
+            .given(vec![
+                mock_created_event(OverpaymentPolicy::Accept),
+                accepted,
+                LightningInvoiceEvent::InvoiceCanceled,
+            ])
+            .when(settle_command(1_000))
+            .then_expect_events(vec![]);
+    }
+
+    #[test]
+    fn test_mismatched_expiry_resolves_to_stricter() {
+        let create = |expires_at: Option<i64>, node_expires_at: Option<i64>| {
+            LightningInvoiceCommand::CreateInvoice {
+                invoice_id: "123".into(),
+                amount: amount_fn(1_000),
+                overpayment_policy: OverpaymentPolicy::Accept,
+                expires_at: expires_at.map(from_timestamp),
+                node_expires_at: node_expires_at.map(from_timestamp),
+            }
+        };
+        let created = |expires_at: Option<i64>| LightningInvoiceEvent::InvoiceCreated {
+            invoice_id: "123".into(),
+            amount: amount_fn(1_000),
+            overpayment_policy: OverpaymentPolicy::Accept,
+            expires_at: expires_at.map(from_timestamp),
+        };
+
+        for (requested, node, expected) in [
+            (
+                Some(1_700_007_200),
+                Some(1_700_003_600),
+                Some(1_700_003_600),
+            ),
+            (
+                Some(1_700_000_600),
+                Some(1_700_003_600),
+                Some(1_700_000_600),
+            ),
+            (None, Some(1_700_003_600), Some(1_700_003_600)),
+            (None, None, None),
+        ] {
+            LightningInvoiceTestFramework::with(())
+                .given_no_previous_events()
+                .when(create(requested, node))
+                .then_expect_events(vec![created(expected)]);
+        }
+    }
+
+    fn amount_fn(amount: u64) -> Amount {
+        Amount::new(Currency::Btc, amount)
+    }
+
+    fn settle_command(amount: u64) -> LightningInvoiceCommand {
+        LightningInvoiceCommand::SetSettled {
+            amount: amount_fn(amount),
+            settle_index: 7,
+            settled_at: Some(from_timestamp(1_700_000_000)),
+        }
+    }
+
+    fn mock_created_event(overpayment_policy: OverpaymentPolicy) -> LightningInvoiceEvent {
+        LightningInvoiceEvent::InvoiceCreated {
+            invoice_id: "123".into(),
+            amount: amount_fn(1_000),
+            overpayment_policy,
+            expires_at: None,
+        }
+    }
+}
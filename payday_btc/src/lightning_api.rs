@@ -0,0 +1,633 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use bitcoin::{
+    hashes::{
+        hmac::{Hmac, HmacEngine},
+        sha256, Hash, HashEngine,
+    },
+    secp256k1::PublicKey,
+    Amount,
+};
+use payday_core::{
+    date::DateTime,
+    payment::{
+        amount::Amount as PaydayAmount,
+        currency::Currency,
+        invoice::{InvoiceId, LnInvoice},
+        offer::Offer,
+    },
+    persistence::payment_log::{OutgoingPaymentStatus, PaymentLogApi, PaymentRecord},
+    PaydayError, PaydayResult,
+};
+use rand::{rngs::OsRng, RngCore};
+
+#[derive(Debug, Clone)]
+pub enum LightningTransactionEvent {
+    Settled(LightningTransaction),
+    Accepted(LightningTransaction),
+    Canceled(LightningTransaction),
+}
+
+impl LightningTransactionEvent {
+    /// The id of the node this event originates from.
+    pub fn node_id(&self) -> String {
+        self.transaction().node_id.to_owned()
+    }
+
+    pub fn settle_index(&self) -> u64 {
+        self.transaction().settle_index
+    }
+
+    fn transaction(&self) -> &LightningTransaction {
+        match self {
+            LightningTransactionEvent::Settled(tx) => tx,
+            LightningTransactionEvent::Accepted(tx) => tx,
+            LightningTransactionEvent::Canceled(tx) => tx,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LightningTransaction {
+    pub node_id: String,
+    /// Hex encoded payment hash, the aggregate id of lightning invoices.
+    pub r_hash: String,
+    pub invoice: String,
+    pub amount: Amount,
+    pub settle_index: u64,
+    /// Time the invoice was settled, None unless settled.
+    pub settled_at: Option<DateTime>,
+    /// Custom TLV records sent along with the payment, e.g. by keysend senders.
+    pub custom_records: HashMap<u64, Vec<u8>>,
+}
+
+/// TLV record type of the message attached to keysend payments.
+pub const KEYSEND_MESSAGE_RECORD: u64 = 34349334;
+
+impl LightningTransaction {
+    /// The keysend message sent with the payment if present and valid UTF-8.
+    pub fn keysend_message(&self) -> Option<String> {
+        self.custom_records
+            .get(&KEYSEND_MESSAGE_RECORD)
+            .and_then(|message| String::from_utf8(message.to_owned()).ok())
+    }
+}
+
+/// A request to create a BOLT11 invoice.
+#[derive(Debug, Clone)]
+pub struct LightningInvoiceRequest {
+    pub amount: Amount,
+    pub memo: Option<String>,
+    /// Expiry of the invoice in seconds.
+    pub ttl: Option<i64>,
+    /// Adds a new on-chain address of the node as fallback address, so the
+    /// invoice can also be paid on-chain.
+    pub fallback_address: bool,
+}
+
+impl LightningInvoiceRequest {
+    pub fn new(amount: Amount) -> Self {
+        Self {
+            amount,
+            memo: None,
+            ttl: None,
+            fallback_address: false,
+        }
+    }
+
+    pub fn memo(mut self, memo: &str) -> Self {
+        self.memo = Some(memo.to_string());
+        self
+    }
+
+    pub fn ttl(mut self, ttl: i64) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    pub fn fallback_address(mut self, fallback_address: bool) -> Self {
+        self.fallback_address = fallback_address;
+        self
+    }
+}
+
+/// A request to pay a BOLT11 invoice.
+#[derive(Debug, Clone)]
+pub struct LightningPaymentRequest {
+    pub invoice: String,
+    pub amount: Option<Amount>,
+    pub fee_limit: Option<Amount>,
+    pub timeout_seconds: i32,
+    /// Only use these channels for the first hop of the payment.
+    pub outgoing_chan_ids: Vec<u64>,
+    /// Only route the payment through this node as the last hop.
+    pub last_hop_pubkey: Option<PublicKey>,
+    /// Internal reference the payment is logged with, e.g. an order id.
+    pub reference: Option<String>,
+}
+
+impl LightningPaymentRequest {
+    pub fn new(invoice: &str) -> Self {
+        Self {
+            invoice: invoice.to_string(),
+            amount: None,
+            fee_limit: None,
+            timeout_seconds: 60,
+            outgoing_chan_ids: Vec::new(),
+            last_hop_pubkey: None,
+            reference: None,
+        }
+    }
+
+    /// Sets the amount to pay for zero amount invoices.
+    pub fn amount(mut self, amount: Amount) -> Self {
+        self.amount = Some(amount);
+        self
+    }
+
+    pub fn fee_limit(mut self, fee_limit: Amount) -> Self {
+        self.fee_limit = Some(fee_limit);
+        self
+    }
+
+    pub fn timeout_seconds(mut self, timeout_seconds: i32) -> Self {
+        self.timeout_seconds = timeout_seconds;
+        self
+    }
+
+    pub fn outgoing_chan_ids(mut self, outgoing_chan_ids: Vec<u64>) -> Self {
+        self.outgoing_chan_ids = outgoing_chan_ids;
+        self
+    }
+
+    pub fn last_hop_pubkey(mut self, last_hop_pubkey: PublicKey) -> Self {
+        self.last_hop_pubkey = Some(last_hop_pubkey);
+        self
+    }
+
+    pub fn reference(mut self, reference: &str) -> Self {
+        self.reference = Some(reference.to_string());
+        self
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LightningPaymentResult {
+    pub payment_hash: String,
+    pub payment_preimage: String,
+    pub amount: Amount,
+    pub fee: Amount,
+}
+
+#[async_trait]
+pub trait LightningInvoiceApi: Send + Sync {
+    /// Creates a hold invoice for a payment hash. The preimage is only known to
+    /// the caller, so an accepted payment is not settled before the caller
+    /// settles or cancels the invoice, e.g. when an escrow is released.
+    async fn create_hold_invoice(
+        &self,
+        amount: Amount,
+        payment_hash: [u8; 32],
+        memo: Option<String>,
+        ttl: Option<i64>,
+    ) -> PaydayResult<LnInvoice>;
+
+    /// Settles an accepted hold invoice by revealing its preimage.
+    async fn settle_hold_invoice(&self, preimage: [u8; 32]) -> PaydayResult<()>;
+
+    /// Cancels a hold invoice. An accepted payment is returned to the sender.
+    async fn cancel_hold_invoice(&self, payment_hash: [u8; 32]) -> PaydayResult<()>;
+}
+
+#[async_trait]
+pub trait LightningInvoiceCreateApi: Send + Sync {
+    /// Returns the invoice with the given payment hash if the node knows it.
+    async fn lookup_invoice(&self, payment_hash: [u8; 32]) -> PaydayResult<Option<LnInvoice>>;
+
+    /// Adds an invoice that is settled by revealing the given preimage.
+    async fn add_invoice(
+        &self,
+        request: LightningInvoiceRequest,
+        preimage: [u8; 32],
+    ) -> PaydayResult<LnInvoice>;
+}
+
+/// Creates the invoice for an invoice id. The preimage is derived from the
+/// invoice id, so a retried request finds the invoice already created on the
+/// node, also after a restart, and returns it instead of creating a second one.
+pub async fn create_invoice_for_id(
+    node: &dyn LightningInvoiceCreateApi,
+    secret: &[u8; 32],
+    invoice_id: &InvoiceId,
+    request: LightningInvoiceRequest,
+) -> PaydayResult<LnInvoice> {
+    let preimage = derive_preimage(secret, invoice_id);
+    let payment_hash = payment_hash(&preimage);
+    if let Some(invoice) = node.lookup_invoice(payment_hash).await? {
+        return Ok(invoice);
+    }
+    match node.add_invoice(request, preimage).await {
+        Ok(invoice) => Ok(invoice),
+        // a concurrent request for the same id may have created it meanwhile
+        Err(e) => node.lookup_invoice(payment_hash).await?.ok_or(e),
+    }
+}
+
+#[async_trait]
+pub trait LightningPaymentStatusApi: Send + Sync {
+    /// Returns the current status of an outgoing payment. Payments the node
+    /// does not know are reported as unknown.
+    async fn payment_status(&self, payment_hash: &str) -> PaydayResult<OutgoingPaymentStatus>;
+}
+
+#[async_trait]
+pub trait LightningPaymentApi: Send + Sync {
+    /// Returns the hex encoded payment hash and the amount of a BOLT11 invoice.
+    /// The amount is zero for zero amount invoices.
+    async fn decode_invoice(&self, invoice: &str) -> PaydayResult<(String, Amount)>;
+
+    /// Sends the payment and waits for it to complete.
+    async fn send_payment(
+        &self,
+        request: LightningPaymentRequest,
+    ) -> PaydayResult<LightningPaymentResult>;
+}
+
+#[async_trait]
+pub trait LightningOfferApi: Send + Sync {
+    /// Pays a BOLT12 offer and waits for the payment to complete. The amount
+    /// is required for offers that do not specify one.
+    async fn pay_offer(
+        &self,
+        offer: &Offer,
+        amount: Option<PaydayAmount>,
+    ) -> PaydayResult<LightningPaymentResult>;
+}
+
+/// Pays a BOLT11 invoice and logs the payment with the reference of the
+/// request. The payment is logged in flight before it is sent, so it can be
+/// reconciled if the service stops before the result is known. A payment that
+/// failed on the node is logged as failed, other errors leave it in flight for
+/// reconcile_payments.
+pub async fn pay_invoice(
+    payment_log: &dyn PaymentLogApi,
+    node: &dyn LightningPaymentApi,
+    request: LightningPaymentRequest,
+) -> PaydayResult<LightningPaymentResult> {
+    let (payment_hash, invoice_amount) = node.decode_invoice(&request.invoice).await?;
+    let amount = request.amount.unwrap_or(invoice_amount);
+    payment_log
+        .record(PaymentRecord {
+            payment_id: payment_hash.to_owned(),
+            reference: request.reference.to_owned(),
+            amount: PaydayAmount::new(Currency::Btc, amount.to_sat()),
+            status: OutgoingPaymentStatus::InFlight,
+        })
+        .await?;
+
+    match node.send_payment(request).await {
+        Ok(result) => {
+            payment_log
+                .set_status(&payment_hash, OutgoingPaymentStatus::Succeeded)
+                .await?;
+            Ok(result)
+        }
+        Err(e @ PaydayError::LightningPaymentFailed(_)) => {
+            payment_log
+                .set_status(&payment_hash, OutgoingPaymentStatus::Failed)
+                .await?;
+            Err(e)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Resolves payments that were in flight when the service stopped by asking the
+/// node for their final status. Payments that are still in flight or unknown to
+/// the node are left as they are, so they are checked again by the next run.
+/// Every status is stored as soon as it is known, so an interrupted
+/// reconciliation can simply be run again. Returns the number of resolved
+/// payments.
+pub async fn reconcile_payments(
+    payment_log: &dyn PaymentLogApi,
+    node: &dyn LightningPaymentStatusApi,
+) -> PaydayResult<usize> {
+    let mut resolved = 0;
+    for payment_hash in payment_log.in_flight().await? {
+        match node.payment_status(&payment_hash).await? {
+            OutgoingPaymentStatus::InFlight => {}
+            OutgoingPaymentStatus::Unknown => {
+                println!("Outgoing payment {} is unknown to the node", payment_hash);
+            }
+            status => {
+                payment_log.set_status(&payment_hash, status).await?;
+                resolved += 1;
+            }
+        }
+    }
+    Ok(resolved)
+}
+
+/// Source of preimages for invoices created by payday.
+pub trait PreimageSource: Send + Sync {
+    fn generate(&self) -> [u8; 32];
+}
+
+/// Generates random preimages from the operating system RNG.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OsPreimageSource;
+
+impl PreimageSource for OsPreimageSource {
+    fn generate(&self) -> [u8; 32] {
+        let mut preimage = [0u8; 32];
+        OsRng.fill_bytes(&mut preimage);
+        preimage
+    }
+}
+
+/// Always returns the same preimage. Only useful for deterministic tests.
+#[derive(Debug, Clone, Copy)]
+pub struct FixedPreimageSource([u8; 32]);
+
+impl FixedPreimageSource {
+    pub fn new(preimage: [u8; 32]) -> Self {
+        Self(preimage)
+    }
+}
+
+impl PreimageSource for FixedPreimageSource {
+    fn generate(&self) -> [u8; 32] {
+        self.0
+    }
+}
+
+/// Derives the preimage of the invoice for an invoice id. The secret keeps the
+/// preimage unknown to anyone who only knows the invoice id.
+pub fn derive_preimage(secret: &[u8; 32], invoice_id: &InvoiceId) -> [u8; 32] {
+    let mut engine = HmacEngine::<sha256::Hash>::new(secret);
+    engine.input(invoice_id.as_str().as_bytes());
+    Hmac::<sha256::Hash>::from_engine(engine).to_byte_array()
+}
+
+/// The payment hash for the given preimage.
+pub fn payment_hash(preimage: &[u8; 32]) -> [u8; 32] {
+    sha256::Hash::hash(preimage).to_byte_array()
+}
+
+#[cfg(test)]
+mod tests {
+    use payday_core::error::PaymentFailureReason;
+    use payday_core::persistence::payment_log::MemPaymentLog;
+    use tokio::sync::Mutex;
+
+    use super::*;
+
+    #[test]
+    fn test_settled_event_node_id() {
+        let event = LightningTransactionEvent::Settled(LightningTransaction {
+            node_id: "lnd1".to_string(),
+            r_hash: "r_hash".to_string(),
+            invoice: "lntbs1".to_string(),
+            amount: Amount::from_sat(1_000),
+            settle_index: 7,
+            settled_at: None,
+            custom_records: HashMap::new(),
+        });
+        assert_eq!(event.node_id(), "lnd1");
+        assert_eq!(event.settle_index(), 7);
+    }
+
+    #[test]
+    fn test_keysend_message() {
+        let mut tx = LightningTransaction {
+            node_id: "lnd1".to_string(),
+            r_hash: "r_hash".to_string(),
+            invoice: "".to_string(),
+            amount: Amount::from_sat(1_000),
+            settle_index: 7,
+            settled_at: None,
+            custom_records: HashMap::from([(KEYSEND_MESSAGE_RECORD, b"thanks!".to_vec())]),
+        };
+        assert_eq!(tx.keysend_message(), Some("thanks!".to_string()));
+
+        tx.custom_records
+            .insert(KEYSEND_MESSAGE_RECORD, vec![0xff, 0xfe]);
+        assert_eq!(tx.keysend_message(), None);
+
+        tx.custom_records.clear();
+        assert_eq!(tx.keysend_message(), None);
+    }
+
+    struct MockPaymentStatus(HashMap<String, OutgoingPaymentStatus>);
+
+    #[async_trait]
+    impl LightningPaymentStatusApi for MockPaymentStatus {
+        async fn payment_status(&self, payment_hash: &str) -> PaydayResult<OutgoingPaymentStatus> {
+            Ok(self
+                .0
+                .get(payment_hash)
+                .copied()
+                .unwrap_or(OutgoingPaymentStatus::Unknown))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_payments() {
+        let log = MemPaymentLog::default();
+        for payment_hash in ["settled", "failed", "pending", "unknown"] {
+            log.record(PaymentRecord {
+                payment_id: payment_hash.to_string(),
+                reference: None,
+                amount: PaydayAmount::new(Currency::Btc, 1_000),
+                status: OutgoingPaymentStatus::InFlight,
+            })
+            .await
+            .unwrap();
+        }
+        let node = MockPaymentStatus(HashMap::from([
+            ("settled".to_string(), OutgoingPaymentStatus::Succeeded),
+            ("failed".to_string(), OutgoingPaymentStatus::Failed),
+            ("pending".to_string(), OutgoingPaymentStatus::InFlight),
+        ]));
+
+        assert_eq!(reconcile_payments(&log, &node).await.unwrap(), 2);
+        assert_eq!(
+            log.in_flight().await.unwrap(),
+            vec!["pending".to_string(), "unknown".to_string()]
+        );
+    }
+
+    /// Pays invoices with the given outcome and records the logged status of
+    /// the payment at the time it is sent.
+    struct MockPaymentNode<'a> {
+        log: &'a MemPaymentLog,
+        outcome: fn() -> PaydayResult<LightningPaymentResult>,
+        status_on_send: Mutex<Option<PaymentRecord>>,
+    }
+
+    #[async_trait]
+    impl LightningPaymentApi for MockPaymentNode<'_> {
+        async fn decode_invoice(&self, invoice: &str) -> PaydayResult<(String, Amount)> {
+            Ok((format!("hash_{}", invoice), Amount::from_sat(1_000)))
+        }
+
+        async fn send_payment(
+            &self,
+            request: LightningPaymentRequest,
+        ) -> PaydayResult<LightningPaymentResult> {
+            *self.status_on_send.lock().await = self
+                .log
+                .find_payment_by_reference(request.reference.as_deref().unwrap_or_default())
+                .await?;
+            (self.outcome)()
+        }
+    }
+
+    fn mock_payment_node(
+        log: &MemPaymentLog,
+        outcome: fn() -> PaydayResult<LightningPaymentResult>,
+    ) -> MockPaymentNode<'_> {
+        MockPaymentNode {
+            log,
+            outcome,
+            status_on_send: Mutex::new(None),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pay_invoice_logs_payment() {
+        let log = MemPaymentLog::default();
+        let node = mock_payment_node(&log, || {
+            Ok(LightningPaymentResult {
+                payment_hash: "hash_lntbs1".to_string(),
+                payment_preimage: "preimage".to_string(),
+                amount: Amount::from_sat(1_000),
+                fee: Amount::from_sat(1),
+            })
+        });
+
+        let request = LightningPaymentRequest::new("lntbs1").reference("order-1");
+        let result = pay_invoice(&log, &node, request).await.unwrap();
+        assert_eq!(result.payment_hash, "hash_lntbs1");
+
+        let in_flight = node.status_on_send.lock().await.clone().unwrap();
+        assert_eq!(in_flight.payment_id, "hash_lntbs1");
+        assert_eq!(in_flight.status, OutgoingPaymentStatus::InFlight);
+        assert_eq!(in_flight.amount, PaydayAmount::new(Currency::Btc, 1_000));
+
+        let logged = log.find_payment_by_reference("order-1").await.unwrap();
+        assert_eq!(logged.unwrap().status, OutgoingPaymentStatus::Succeeded);
+    }
+
+    #[tokio::test]
+    async fn test_pay_invoice_logs_failure() {
+        let log = MemPaymentLog::default();
+        let failed = mock_payment_node(&log, || {
+            Err(PaydayError::LightningPaymentFailed(
+                PaymentFailureReason::NoRoute,
+            ))
+        });
+        let request = LightningPaymentRequest::new("lntbs1")
+            .amount(Amount::from_sat(2_000))
+            .reference("order-1");
+        assert!(pay_invoice(&log, &failed, request).await.is_err());
+        let logged = log.find_payment_by_reference("order-1").await.unwrap();
+        let logged = logged.unwrap();
+        assert_eq!(logged.status, OutgoingPaymentStatus::Failed);
+        assert_eq!(logged.amount, PaydayAmount::new(Currency::Btc, 2_000));
+
+        // the outcome of a payment interrupted by a node error is unknown
+        let unknown = mock_payment_node(&log, || {
+            Err(PaydayError::NodeApiError("connection reset".to_string()))
+        });
+        let request = LightningPaymentRequest::new("lntbs2").reference("order-2");
+        assert!(pay_invoice(&log, &unknown, request).await.is_err());
+        assert_eq!(
+            log.in_flight().await.unwrap(),
+            vec!["hash_lntbs2".to_string()]
+        );
+    }
+
+    /// Keeps created invoices by payment hash like a node does, so they
+    /// outlive the caller.
+    #[derive(Default)]
+    struct MockInvoiceNode {
+        invoices: Mutex<HashMap<[u8; 32], LnInvoice>>,
+        added: Mutex<usize>,
+    }
+
+    #[async_trait]
+    impl LightningInvoiceCreateApi for MockInvoiceNode {
+        async fn lookup_invoice(&self, payment_hash: [u8; 32]) -> PaydayResult<Option<LnInvoice>> {
+            Ok(self.invoices.lock().await.get(&payment_hash).cloned())
+        }
+
+        async fn add_invoice(
+            &self,
+            request: LightningInvoiceRequest,
+            preimage: [u8; 32],
+        ) -> PaydayResult<LnInvoice> {
+            let mut added = self.added.lock().await;
+            *added += 1;
+            let invoice = LnInvoice {
+                invoice: format!("lntbs{}_{}", request.amount.to_sat(), added),
+                r_hash: sha256::Hash::hash(&preimage).to_string(),
+                add_index: *added as u64,
+                expires_at: None,
+            };
+            self.invoices
+                .lock()
+                .await
+                .insert(payment_hash(&preimage), invoice.clone());
+            Ok(invoice)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_create_invoice_for_id() {
+        let node = MockInvoiceNode::default();
+        let secret = [7u8; 32];
+        let request = LightningInvoiceRequest::new(Amount::from_sat(1_000));
+        let invoice_id = InvoiceId::from("order-1");
+
+        let first = create_invoice_for_id(&node, &secret, &invoice_id, request.clone())
+            .await
+            .unwrap();
+        let retried = create_invoice_for_id(&node, &secret, &invoice_id, request.clone())
+            .await
+            .unwrap();
+        assert_eq!(first.invoice, retried.invoice);
+        assert_eq!(
+            first.r_hash,
+            sha256::Hash::hash(&derive_preimage(&secret, &invoice_id)).to_string()
+        );
+        assert_eq!(*node.added.lock().await, 1);
+
+        let other = create_invoice_for_id(&node, &secret, &InvoiceId::from("order-2"), request)
+            .await
+            .unwrap();
+        assert_ne!(other.invoice, first.invoice);
+        assert_ne!(
+            derive_preimage(&[8u8; 32], &invoice_id),
+            derive_preimage(&secret, &invoice_id)
+        );
+    }
+
+    #[test]
+    fn test_fixed_preimage_payment_hash() {
+        let source = FixedPreimageSource::new([0u8; 32]);
+        let hash = payment_hash(&source.generate());
+        assert_eq!(hash, payment_hash(&source.generate()));
+        assert_eq!(
+            sha256::Hash::from_byte_array(hash).to_string(),
+            "66687aadf862bd776c8fc18b8e9f8e20089714856ee233b3902a591d0d5f2925"
+        );
+    }
+
+    #[test]
+    fn test_os_preimage_source() {
+        let source = OsPreimageSource;
+        assert_ne!(source.generate(), source.generate());
+    }
+}
@@ -1,52 +1,160 @@
 use async_trait::async_trait;
 use cqrs_es::{Aggregate, DomainEvent};
+use payday_core::date::{is_past, now, DateTime};
 use payday_core::payment::amount::Amount;
 use payday_core::payment::currency::Currency;
-use payday_core::payment::invoice::{InvoiceError, InvoiceId};
+use payday_core::payment::invoice::{AmountLimits, InvoiceError, InvoiceId, InvoiceResult};
 use serde::{Deserialize, Serialize};
 
 use crate::on_chain_processor::OnChainTransactionEvent;
+use crate::ConfirmationTiers;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BtcOnChainInvoice {
     pub invoice_id: InvoiceId,
     pub address: String,
+    pub previous_addresses: Vec<String>,
     pub amount: Amount,
     pub received_amount: Amount,
     pub confirmations: u64,
+    pub required_confirmations: u64,
+    pub underpayment_tolerance: Amount,
+    pub refunded_amount: Amount,
+    /// A refund reserved before sending, until it is confirmed or cancelled.
+    #[serde(default)]
+    pub pending_refund: Option<PendingRefund>,
     pub transaction_id: Option<String>,
+    /// Height of the block confirming the payment, None while unconfirmed.
+    #[serde(default)]
+    pub block_height: Option<i32>,
     pub underpayment: bool,
     pub overpayment: bool,
     pub paid: bool,
+    /// Time of the transaction that settled the invoice, None while open.
+    pub paid_at: Option<DateTime>,
+    pub webhook_url: Option<String>,
+    /// After this time the invoice can be expired, None if it never expires.
+    pub expires_at: Option<DateTime>,
+    pub expired: bool,
+    /// A payment was received after the invoice expired.
+    pub paid_after_expiry: bool,
 }
 
 impl Default for BtcOnChainInvoice {
     fn default() -> Self {
         Self {
-            invoice_id: "".to_string(),
+            invoice_id: InvoiceId::default(),
             address: "".to_string(),
+            previous_addresses: Vec::new(),
             amount: Amount::zero(Currency::Btc),
             received_amount: Amount::zero(Currency::Btc),
             confirmations: 0,
+            required_confirmations: 1,
+            underpayment_tolerance: Amount::zero(Currency::Btc),
+            refunded_amount: Amount::zero(Currency::Btc),
+            pending_refund: None,
             transaction_id: None,
+            block_height: None,
             underpayment: false,
             overpayment: false,
             paid: false,
+            paid_at: None,
+            webhook_url: None,
+            expires_at: None,
+            expired: false,
+            paid_after_expiry: false,
         }
     }
 }
 
+impl BtcOnChainInvoice {
+    /// The URL payment notifications for this invoice are sent to. Uses the
+    /// invoice specific webhook if set, otherwise the given default.
+    pub fn notification_url(&self, default_url: Option<&str>) -> Option<String> {
+        self.webhook_url
+            .to_owned()
+            .or(default_url.map(|u| u.to_string()))
+    }
+
+    /// Whether the received amount falls short of the invoice amount by more
+    /// than the accepted underpayment tolerance.
+    pub fn is_underpayment(&self, received: &Amount) -> InvoiceResult<bool> {
+        let tolerance = &self.underpayment_tolerance;
+        if received.currency != tolerance.currency {
+            return Err(InvoiceError::InvalidCurrency(
+                tolerance.currency.to_string(),
+                received.currency.to_string(),
+            ));
+        }
+        // A sum that overflows covers any invoice amount.
+        match received.checked_add(tolerance) {
+            Some(covered) => covered.lt_checked(&self.amount),
+            None => Ok(false),
+        }
+    }
+
+    /// Whether the expiry time has passed on an invoice that is neither paid nor
+    /// expired yet, so it should be expired.
+    pub fn is_due_for_expiry(&self) -> bool {
+        !self.paid && !self.expired && self.expires_at.is_some_and(is_past)
+    }
+
+    /// The amount still owed on this invoice. Never underflows, an overpaid
+    /// invoice has zero remaining.
+    pub fn amount_remaining(&self) -> Amount {
+        Amount::new(
+            self.amount.currency,
+            self.amount
+                .amount
+                .saturating_sub(self.received_amount.amount),
+        )
+    }
+
+    /// The amount received in excess of the invoice amount that has neither
+    /// been refunded nor reserved for a pending refund.
+    pub fn overpaid_amount(&self) -> Amount {
+        let pending = self.pending_refund.as_ref().map_or(0, |r| r.amount.amount);
+        Amount::new(
+            self.amount.currency,
+            self.received_amount
+                .amount
+                .saturating_sub(self.amount.amount)
+                .saturating_sub(self.refunded_amount.amount)
+                .saturating_sub(pending),
+        )
+    }
+}
+
+/// A refund that was requested but not confirmed or cancelled yet.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PendingRefund {
+    pub amount: Amount,
+    pub address: String,
+}
+
 #[async_trait]
 pub trait OnChainInvoiceService: Send + Sync {}
 
 pub struct OnChainInvoiceServices {}
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub enum OnChainInvoiceCommand {
     CreateInvoice {
         invoice_id: InvoiceId,
         amount: Amount,
         address: String,
+        webhook_url: Option<String>,
+        required_confirmations: u64,
+        underpayment_tolerance: Amount,
+        amount_limits: AmountLimits,
+        /// Raises the required confirmations for high invoice amounts.
+        #[serde(default)]
+        confirmation_tiers: ConfirmationTiers,
+        #[serde(default)]
+        expires_at: Option<DateTime>,
+    },
+    RotateAddress {
+        new_address: String,
     },
     SetPending {
         amount: Amount,
@@ -55,7 +163,34 @@ pub enum OnChainInvoiceCommand {
         confirmations: u64,
         amount: Amount,
         transaction_id: String,
+        /// Block time of the transaction if known by the node.
+        timestamp: Option<DateTime>,
+        /// Height of the block the transaction was confirmed in if known.
+        block_height: Option<i32>,
+    },
+    /// Reserves an overpaid amount for a refund before it is sent, so it can
+    /// not be refunded twice.
+    RequestRefund {
+        amount: Amount,
+        address: String,
+    },
+    /// Confirms the sent pending refund with its transaction.
+    Refund {
+        amount: Amount,
+        address: String,
+        transaction_id: String,
+    },
+    /// Releases the reservation of a pending refund that was not sent.
+    CancelRefund,
+    /// Reverts the confirmations of a payment whose block was reorganized out
+    /// of the chain. The payment is confirmed again by the events of the new
+    /// chain.
+    RollbackConfirmations {
+        from_height: i32,
     },
+    /// Stops expecting a payment. Payments received later are still recorded
+    /// but flagged as paid after expiry.
+    Expire,
 }
 
 #[derive(Debug)]
@@ -73,6 +208,8 @@ impl From<OnChainTransactionEvent> for OnChainCommand {
                     confirmations: tx.confirmations as u64,
                     amount: Amount::new(Currency::Btc, tx.amount.to_sat()),
                     transaction_id: tx.tx_id.to_owned(),
+                    timestamp: tx.timestamp,
+                    block_height: Some(tx.block_height),
                 },
             ),
             OnChainTransactionEvent::ReceivedUnconfirmed(tx) => (
@@ -87,6 +224,8 @@ impl From<OnChainTransactionEvent> for OnChainCommand {
                     confirmations: tx.confirmations as u64,
                     amount: Amount::new(Currency::Btc, tx.amount.to_sat()),
                     transaction_id: tx.tx_id.to_owned(),
+                    timestamp: tx.timestamp,
+                    block_height: Some(tx.block_height),
                 },
             ),
             OnChainTransactionEvent::SentUnconfirmed(tx) => (
@@ -109,27 +248,84 @@ pub enum OnChainInvoiceEvent {
         invoice_id: InvoiceId,
         amount: Amount,
         address: String,
+        #[serde(default)]
+        webhook_url: Option<String>,
+        #[serde(default = "default_required_confirmations")]
+        required_confirmations: u64,
+        #[serde(default)]
+        underpayment_tolerance: Amount,
+        #[serde(default)]
+        expires_at: Option<DateTime>,
+    },
+    AddressRotated {
+        new_address: String,
+        previous_address: String,
     },
     PaymentPending {
         received_amount: Amount,
         underpayment: bool,
         overpayment: bool,
     },
+    /// The payment is confirmed but has less than the required confirmations.
+    ConfirmationProgress {
+        received_amount: Amount,
+        underpayment: bool,
+        overpayment: bool,
+        confirmations: u64,
+        required_confirmations: u64,
+        #[serde(default)]
+        block_height: Option<i32>,
+    },
     PaymentConfirmed {
         received_amount: Amount,
         underpayment: bool,
         overpayment: bool,
         confirmations: u64,
         transaction_id: String,
+        #[serde(default)]
+        paid_at: Option<DateTime>,
+        #[serde(default)]
+        block_height: Option<i32>,
+    },
+    RefundRequested {
+        amount: Amount,
+        address: String,
+    },
+    Refunded {
+        amount: Amount,
+        address: String,
+        transaction_id: String,
     },
+    RefundCancelled {
+        amount: Amount,
+        address: String,
+    },
+    InvoiceExpired {
+        expired_at: DateTime,
+    },
+    ConfirmationsRolledBack {
+        from_height: i32,
+        confirmations: u64,
+    },
+}
+
+fn default_required_confirmations() -> u64 {
+    1
 }
 
 impl DomainEvent for OnChainInvoiceEvent {
     fn event_type(&self) -> String {
         let event_type = match self {
             OnChainInvoiceEvent::InvoiceCreated { .. } => "OnChainInvoiceCreated",
+            OnChainInvoiceEvent::AddressRotated { .. } => "OnChainAddressRotated",
             OnChainInvoiceEvent::PaymentPending { .. } => "OnChainPaymentPending",
+            OnChainInvoiceEvent::ConfirmationProgress { .. } => "OnChainConfirmationProgress",
             OnChainInvoiceEvent::PaymentConfirmed { .. } => "OnChainPaymentConfirmed",
+            OnChainInvoiceEvent::RefundRequested { .. } => "OnChainRefundRequested",
+            OnChainInvoiceEvent::Refunded { .. } => "OnChainRefunded",
+            OnChainInvoiceEvent::RefundCancelled { .. } => "OnChainRefundCancelled",
+            OnChainInvoiceEvent::InvoiceExpired { .. } => "OnChainInvoiceExpired",
+            OnChainInvoiceEvent::ConfirmationsRolledBack { .. } => "OnChainConfirmationsRolledBack",
         };
         event_type.to_string()
     }
@@ -160,6 +356,12 @@ impl Aggregate for BtcOnChainInvoice {
                 invoice_id,
                 amount,
                 address,
+                webhook_url,
+                required_confirmations,
+                underpayment_tolerance,
+                amount_limits,
+                confirmation_tiers,
+                expires_at,
             } => {
                 if amount.currency != Currency::Btc {
                     return Err(InvoiceError::InvalidCurrency(
@@ -167,31 +369,155 @@ impl Aggregate for BtcOnChainInvoice {
                         Currency::Btc.to_string(),
                     ));
                 }
+                if underpayment_tolerance.currency != Currency::Btc {
+                    return Err(InvoiceError::InvalidCurrency(
+                        Currency::Btc.to_string(),
+                        underpayment_tolerance.currency.to_string(),
+                    ));
+                }
+                amount_limits.check(&amount)?;
+
+                if let Some(url) = &webhook_url {
+                    if !url.starts_with("https://") && !url.starts_with("http://") {
+                        return Err(InvoiceError::InvalidWebhookUrl(url.to_string()));
+                    }
+                }
 
                 Ok(vec![OnChainInvoiceEvent::InvoiceCreated {
                     invoice_id,
                     amount,
                     address: address.to_string(),
+                    webhook_url,
+                    required_confirmations: required_confirmations
+                        .max(confirmation_tiers.resolve(&amount).unwrap_or(1))
+                        .max(1),
+                    underpayment_tolerance,
+                    expires_at,
+                }])
+            }
+            OnChainInvoiceCommand::RotateAddress { new_address } => {
+                if self.invoice_id.is_empty() {
+                    return Err(InvoiceError::ServiceError(
+                        "can not rotate the address of an unknown invoice".to_string(),
+                    ));
+                }
+                if self.paid {
+                    return Err(InvoiceError::AlreadyPaid(self.invoice_id.to_owned()));
+                }
+                Ok(vec![OnChainInvoiceEvent::AddressRotated {
+                    new_address,
+                    previous_address: self.address.to_owned(),
                 }])
             }
             OnChainInvoiceCommand::SetPending { amount } => {
+                if self.paid || (amount.amount > 0 && self.received_amount == amount) {
+                    return Ok(vec![]);
+                }
                 Ok(vec![OnChainInvoiceEvent::PaymentPending {
                     received_amount: amount,
-                    underpayment: amount.amount < self.amount.amount,
-                    overpayment: amount.amount > self.amount.amount,
+                    underpayment: self.is_underpayment(&amount)?,
+                    overpayment: amount.gt_checked(&self.amount)?,
                 }])
             }
             OnChainInvoiceCommand::SetConfirmed {
                 confirmations,
                 amount,
                 transaction_id,
-            } => Ok(vec![OnChainInvoiceEvent::PaymentConfirmed {
-                received_amount: amount,
-                underpayment: amount.amount < self.amount.amount,
-                overpayment: amount.amount > self.amount.amount,
-                confirmations,
+                timestamp,
+                block_height,
+            } => {
+                if self.paid {
+                    return Ok(vec![]);
+                }
+                if confirmations < self.required_confirmations {
+                    if self.confirmations == confirmations && self.received_amount == amount {
+                        return Ok(vec![]);
+                    }
+                    return Ok(vec![OnChainInvoiceEvent::ConfirmationProgress {
+                        received_amount: amount,
+                        underpayment: self.is_underpayment(&amount)?,
+                        overpayment: amount.gt_checked(&self.amount)?,
+                        confirmations,
+                        required_confirmations: self.required_confirmations,
+                        block_height,
+                    }]);
+                }
+                Ok(vec![OnChainInvoiceEvent::PaymentConfirmed {
+                    received_amount: amount,
+                    underpayment: self.is_underpayment(&amount)?,
+                    overpayment: amount.gt_checked(&self.amount)?,
+                    confirmations,
+                    transaction_id,
+                    paid_at: Some(timestamp.unwrap_or_else(now)),
+                    block_height,
+                }])
+            }
+            OnChainInvoiceCommand::RequestRefund { amount, address } => {
+                if !self.paid {
+                    return Err(InvoiceError::ServiceError(format!(
+                        "refund of unpaid invoice {}",
+                        self.invoice_id
+                    )));
+                }
+                if self.pending_refund.is_some() {
+                    return Err(InvoiceError::RefundPending(self.invoice_id.to_owned()));
+                }
+                if amount.amount == 0 || amount.gt_checked(&self.overpaid_amount())? {
+                    return Err(InvoiceError::InvalidAmount(amount));
+                }
+                Ok(vec![OnChainInvoiceEvent::RefundRequested {
+                    amount,
+                    address,
+                }])
+            }
+            OnChainInvoiceCommand::Refund {
+                amount,
+                address,
                 transaction_id,
-            }]),
+            } => {
+                let requested = PendingRefund {
+                    amount,
+                    address: address.to_owned(),
+                };
+                if self.pending_refund.as_ref() != Some(&requested) {
+                    return Err(InvoiceError::ServiceError(format!(
+                        "no pending refund of {} to {} on invoice {}",
+                        amount, address, self.invoice_id
+                    )));
+                }
+                Ok(vec![OnChainInvoiceEvent::Refunded {
+                    amount,
+                    address,
+                    transaction_id,
+                }])
+            }
+            OnChainInvoiceCommand::CancelRefund => match &self.pending_refund {
+                Some(pending) => Ok(vec![OnChainInvoiceEvent::RefundCancelled {
+                    amount: pending.amount,
+                    address: pending.address.to_owned(),
+                }]),
+                None => Ok(vec![]),
+            },
+            OnChainInvoiceCommand::RollbackConfirmations { from_height } => {
+                if self.confirmations == 0 && !self.paid {
+                    return Ok(vec![]);
+                }
+                Ok(vec![OnChainInvoiceEvent::ConfirmationsRolledBack {
+                    from_height,
+                    confirmations: self.confirmations,
+                }])
+            }
+            OnChainInvoiceCommand::Expire => {
+                if self.paid {
+                    return Err(InvoiceError::AlreadyPaid(self.invoice_id.to_owned()));
+                }
+                if self.expired {
+                    return Ok(vec![]);
+                }
+                Ok(vec![OnChainInvoiceEvent::InvoiceExpired {
+                    expired_at: now(),
+                }])
+            }
         }
     }
 
@@ -201,10 +527,25 @@ impl Aggregate for BtcOnChainInvoice {
                 invoice_id,
                 amount,
                 address,
+                webhook_url,
+                required_confirmations,
+                underpayment_tolerance,
+                expires_at,
             } => {
                 self.invoice_id = invoice_id;
                 self.amount = amount;
                 self.address = address.to_string();
+                self.webhook_url = webhook_url;
+                self.required_confirmations = required_confirmations;
+                self.underpayment_tolerance = underpayment_tolerance;
+                self.expires_at = expires_at;
+            }
+            OnChainInvoiceEvent::AddressRotated {
+                new_address,
+                previous_address,
+            } => {
+                self.previous_addresses.push(previous_address);
+                self.address = new_address;
             }
             OnChainInvoiceEvent::PaymentPending {
                 received_amount,
@@ -214,6 +555,22 @@ impl Aggregate for BtcOnChainInvoice {
                 self.received_amount = received_amount;
                 self.underpayment = underpayment;
                 self.overpayment = overpayment;
+                self.paid_after_expiry |= self.expired;
+            }
+            OnChainInvoiceEvent::ConfirmationProgress {
+                received_amount,
+                underpayment,
+                overpayment,
+                confirmations,
+                block_height,
+                ..
+            } => {
+                self.received_amount = received_amount;
+                self.underpayment = underpayment;
+                self.overpayment = overpayment;
+                self.confirmations = confirmations;
+                self.block_height = block_height;
+                self.paid_after_expiry |= self.expired;
             }
             OnChainInvoiceEvent::PaymentConfirmed {
                 received_amount,
@@ -221,6 +578,8 @@ impl Aggregate for BtcOnChainInvoice {
                 overpayment,
                 confirmations,
                 transaction_id,
+                paid_at,
+                block_height,
             } => {
                 self.received_amount = received_amount;
                 self.underpayment = underpayment;
@@ -228,6 +587,29 @@ impl Aggregate for BtcOnChainInvoice {
                 self.confirmations = confirmations;
                 self.paid = true;
                 self.transaction_id = Some(transaction_id);
+                self.paid_at = paid_at;
+                self.block_height = block_height;
+                self.paid_after_expiry |= self.expired;
+            }
+            OnChainInvoiceEvent::RefundRequested { amount, address } => {
+                self.pending_refund = Some(PendingRefund { amount, address });
+            }
+            OnChainInvoiceEvent::Refunded { amount, .. } => {
+                self.refunded_amount = self.refunded_amount + amount;
+                self.pending_refund = None;
+            }
+            OnChainInvoiceEvent::RefundCancelled { .. } => {
+                self.pending_refund = None;
+            }
+            OnChainInvoiceEvent::InvoiceExpired { .. } => {
+                self.expired = true;
+            }
+            OnChainInvoiceEvent::ConfirmationsRolledBack { .. } => {
+                self.confirmations = 0;
+                self.paid = false;
+                self.paid_at = None;
+                self.transaction_id = None;
+                self.block_height = None;
             }
         }
     }
@@ -236,9 +618,11 @@ impl Aggregate for BtcOnChainInvoice {
 #[cfg(test)]
 mod aggregate_tests {
     use cqrs_es::test::TestFramework;
+    use payday_core::date::from_timestamp;
     use payday_core::payment::currency::Currency;
 
     use super::*;
+    use crate::ConfirmationTier;
 
     type OnChainInvoiceTestFramework = TestFramework<BtcOnChainInvoice>;
 
@@ -248,13 +632,143 @@ mod aggregate_tests {
         OnChainInvoiceTestFramework::with(())
             .given_no_previous_events()
             .when(OnChainInvoiceCommand::CreateInvoice {
-                invoice_id: "123".to_string(),
+                invoice_id: "123".into(),
                 amount: amount_fn(100_000),
                 address: "tb1q6xm2qgh5r83lvmmu0v7c3d4wrd9k2uxu3sgcr4".to_string(),
+                webhook_url: None,
+                required_confirmations: 1,
+                underpayment_tolerance: Amount::zero(Currency::Btc),
+                expires_at: None,
+                amount_limits: AmountLimits::default(),
+                confirmation_tiers: ConfirmationTiers::default(),
             })
             .then_expect_events(vec![expected])
     }
 
+    #[test]
+    fn test_create_invoice_invalid_webhook_url() {
+        OnChainInvoiceTestFramework::with(())
+            .given_no_previous_events()
+            .when(OnChainInvoiceCommand::CreateInvoice {
+                invoice_id: "123".into(),
+                amount: amount_fn(100_000),
+                address: "tb1q6xm2qgh5r83lvmmu0v7c3d4wrd9k2uxu3sgcr4".to_string(),
+                webhook_url: Some("ftp://example.com/hook".to_string()),
+                required_confirmations: 1,
+                underpayment_tolerance: Amount::zero(Currency::Btc),
+                expires_at: None,
+                amount_limits: AmountLimits::default(),
+                confirmation_tiers: ConfirmationTiers::default(),
+            })
+            .then_expect_error_message("Invoice invalid webhook url: ftp://example.com/hook")
+    }
+
+    #[test]
+    fn test_create_invoice_non_btc_tolerance() {
+        OnChainInvoiceTestFramework::with(())
+            .given_no_previous_events()
+            .when(OnChainInvoiceCommand::CreateInvoice {
+                invoice_id: "123".into(),
+                amount: amount_fn(100_000),
+                address: "tb1q6xm2qgh5r83lvmmu0v7c3d4wrd9k2uxu3sgcr4".to_string(),
+                webhook_url: None,
+                required_confirmations: 1,
+                underpayment_tolerance: Amount::new(Currency::Usd, 500),
+                expires_at: None,
+                amount_limits: AmountLimits::default(),
+                confirmation_tiers: ConfirmationTiers::default(),
+            })
+            .then_expect_error_message("Invoice invalid currency required: BTC received: USD")
+    }
+
+    #[test]
+    fn test_create_invoice_below_minimum() {
+        OnChainInvoiceTestFramework::with(())
+            .given_no_previous_events()
+            .when(OnChainInvoiceCommand::CreateInvoice {
+                invoice_id: "123".into(),
+                amount: amount_fn(545),
+                address: "tb1q6xm2qgh5r83lvmmu0v7c3d4wrd9k2uxu3sgcr4".to_string(),
+                webhook_url: None,
+                required_confirmations: 1,
+                underpayment_tolerance: Amount::zero(Currency::Btc),
+                expires_at: None,
+                amount_limits: AmountLimits::new(Some(amount_fn(546)), None),
+                confirmation_tiers: ConfirmationTiers::default(),
+            })
+            .then_expect_error_message("Invoice invalid amount: 545 sats")
+    }
+
+    #[test]
+    fn test_create_invoice_confirmation_tiers() {
+        let tiers = ConfirmationTiers::new(vec![
+            ConfirmationTier {
+                min_amount: amount_fn(0),
+                confirmations: 1,
+            },
+            ConfirmationTier {
+                min_amount: amount_fn(100_000_000),
+                confirmations: 6,
+            },
+        ]);
+        let create = |amount: u64| OnChainInvoiceCommand::CreateInvoice {
+            invoice_id: "123".into(),
+            amount: amount_fn(amount),
+            address: "tb1q6xm2qgh5r83lvmmu0v7c3d4wrd9k2uxu3sgcr4".to_string(),
+            webhook_url: None,
+            required_confirmations: 1,
+            underpayment_tolerance: Amount::zero(Currency::Btc),
+            expires_at: None,
+            amount_limits: AmountLimits::default(),
+            confirmation_tiers: tiers.clone(),
+        };
+        let created = |amount: u64, required_confirmations: u64| {
+            let mut event = mock_created_event(amount);
+            if let OnChainInvoiceEvent::InvoiceCreated {
+                required_confirmations: r,
+                ..
+            } = &mut event
+            {
+                *r = required_confirmations;
+            }
+            event
+        };
+
+        OnChainInvoiceTestFramework::with(())
+            .given_no_previous_events()
+            .when(create(100_000))
+            .then_expect_events(vec![created(100_000, 1)]);
+
+        OnChainInvoiceTestFramework::with(())
+            .given_no_previous_events()
+            .when(create(100_000_000))
+            .then_expect_events(vec![created(100_000_000, 6)]);
+    }
+
+    #[test]
+    fn test_invoice_webhook_url() {
+        let mut invoice = BtcOnChainInvoice::default();
+        invoice.apply(OnChainInvoiceEvent::InvoiceCreated {
+            invoice_id: "123".into(),
+            amount: amount_fn(100_000),
+            address: "tb1q6xm2qgh5r83lvmmu0v7c3d4wrd9k2uxu3sgcr4".to_string(),
+            webhook_url: Some("https://example.com/invoice".to_string()),
+            required_confirmations: 1,
+            underpayment_tolerance: Amount::zero(Currency::Btc),
+            expires_at: None,
+        });
+        assert_eq!(
+            invoice.notification_url(Some("https://example.com/global")),
+            Some("https://example.com/invoice".to_string())
+        );
+
+        invoice.webhook_url = None;
+        assert_eq!(
+            invoice.notification_url(Some("https://example.com/global")),
+            Some("https://example.com/global".to_string())
+        );
+    }
+
     #[test]
     fn test_set_pending() {
         let amount = amount_fn(100_000);
@@ -285,6 +799,16 @@ mod aggregate_tests {
             .then_expect_events(vec![expected])
     }
 
+    #[test]
+    fn test_pending_currency_mismatch() {
+        OnChainInvoiceTestFramework::with(())
+            .given(vec![mock_created_event(100_000)])
+            .when(OnChainInvoiceCommand::SetPending {
+                amount: Amount::new(Currency::Usd, 100_000),
+            })
+            .then_expect_error_message("Invoice invalid currency required: BTC received: USD")
+    }
+
     #[test]
     fn test_set_confirmed() {
         let expected = OnChainInvoiceEvent::PaymentConfirmed {
@@ -293,6 +817,8 @@ mod aggregate_tests {
             overpayment: false,
             confirmations: 1,
             transaction_id: "txid".to_string(),
+            paid_at: Some(mock_paid_at()),
+            block_height: None,
         };
         OnChainInvoiceTestFramework::with(())
             .given(vec![mock_created_event(100_000)])
@@ -300,10 +826,526 @@ mod aggregate_tests {
                 confirmations: 1,
                 amount: Amount::new(Currency::Btc, 100_000),
                 transaction_id: "txid".to_string(),
+                timestamp: Some(mock_paid_at()),
+                block_height: None,
             })
             .then_expect_events(vec![expected])
     }
 
+    #[test]
+    fn test_required_confirmation_thresholds() {
+        for required in [1, 3, 6] {
+            let mut created = mock_created_event(100_000);
+            if let OnChainInvoiceEvent::InvoiceCreated {
+                required_confirmations,
+                ..
+            } = &mut created
+            {
+                *required_confirmations = required;
+            }
+            let confirm = |confirmations: u64| OnChainInvoiceCommand::SetConfirmed {
+                confirmations,
+                amount: amount_fn(100_000),
+                transaction_id: "txid".to_string(),
+                timestamp: Some(mock_paid_at()),
+                block_height: None,
+            };
+            let progress = |confirmations: u64| OnChainInvoiceEvent::ConfirmationProgress {
+                received_amount: amount_fn(100_000),
+                underpayment: false,
+                overpayment: false,
+                confirmations,
+                required_confirmations: required,
+                block_height: None,
+            };
+            let confirmed = OnChainInvoiceEvent::PaymentConfirmed {
+                received_amount: amount_fn(100_000),
+                underpayment: false,
+                overpayment: false,
+                confirmations: required,
+                transaction_id: "txid".to_string(),
+                paid_at: Some(mock_paid_at()),
+                block_height: None,
+            };
+
+            OnChainInvoiceTestFramework::with(())
+                .given(vec![created.clone()])
+                .when(confirm(required))
+                .then_expect_events(vec![confirmed]);
+
+            if required > 1 {
+                OnChainInvoiceTestFramework::with(())
+                    .given(vec![created.clone()])
+                    .when(confirm(required - 1))
+                    .then_expect_events(vec![progress(required - 1)]);
+
+                // the same confirmation count is not reported twice
+                OnChainInvoiceTestFramework::with(())
+                    .given(vec![created, progress(required - 1)])
+                    .when(confirm(required - 1))
+                    .then_expect_events(vec![]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_rotate_address_unpaid() {
+        let mut invoice = BtcOnChainInvoice::default();
+        invoice.apply(mock_created_event(100_000));
+        let expected = OnChainInvoiceEvent::AddressRotated {
+            new_address: "tb1p96rerkjw5e5ul4fxatc8xjg0jhu7hy4ue57s7jwgyxj2c6shsxystfrxk4"
+                .to_string(),
+            previous_address: invoice.address.to_owned(),
+        };
+        OnChainInvoiceTestFramework::with(())
+            .given(vec![mock_created_event(100_000)])
+            .when(OnChainInvoiceCommand::RotateAddress {
+                new_address: "tb1p96rerkjw5e5ul4fxatc8xjg0jhu7hy4ue57s7jwgyxj2c6shsxystfrxk4"
+                    .to_string(),
+            })
+            .then_expect_events(vec![expected.clone()]);
+
+        invoice.apply(expected);
+        assert_eq!(
+            invoice.address,
+            "tb1p96rerkjw5e5ul4fxatc8xjg0jhu7hy4ue57s7jwgyxj2c6shsxystfrxk4"
+        );
+        assert_eq!(
+            invoice.previous_addresses,
+            vec!["tb1q6xm2qgh5r83lvmmu0v7c3d4wrd9k2uxu3sgcr4".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_rotate_address_unknown_invoice() {
+        OnChainInvoiceTestFramework::with(())
+            .given_no_previous_events()
+            .when(OnChainInvoiceCommand::RotateAddress {
+                new_address: "tb1p96rerkjw5e5ul4fxatc8xjg0jhu7hy4ue57s7jwgyxj2c6shsxystfrxk4"
+                    .to_string(),
+            })
+            .then_expect_error_message(
+                "Invoice service error: can not rotate the address of an unknown invoice",
+            )
+    }
+
+    #[test]
+    fn test_rotate_address_paid() {
+        OnChainInvoiceTestFramework::with(())
+            .given(vec![
+                mock_created_event(100_000),
+                OnChainInvoiceEvent::PaymentConfirmed {
+                    received_amount: amount_fn(100_000),
+                    underpayment: false,
+                    overpayment: false,
+                    confirmations: 1,
+                    transaction_id: "txid".to_string(),
+                    paid_at: Some(mock_paid_at()),
+                    block_height: None,
+                },
+            ])
+            .when(OnChainInvoiceCommand::RotateAddress {
+                new_address: "tb1p96rerkjw5e5ul4fxatc8xjg0jhu7hy4ue57s7jwgyxj2c6shsxystfrxk4"
+                    .to_string(),
+            })
+            .then_expect_error_message("Invoice already paid: 123")
+    }
+
+    #[test]
+    fn test_duplicate_events_are_no_op() {
+        OnChainInvoiceTestFramework::with(())
+            .given(vec![
+                mock_created_event(100_000),
+                mock_pending_event(100_000, false, false),
+            ])
+            .when(OnChainInvoiceCommand::SetPending {
+                amount: amount_fn(100_000),
+            })
+            .then_expect_events(vec![]);
+
+        OnChainInvoiceTestFramework::with(())
+            .given(vec![
+                mock_created_event(100_000),
+                OnChainInvoiceEvent::PaymentConfirmed {
+                    received_amount: amount_fn(100_000),
+                    underpayment: false,
+                    overpayment: false,
+                    confirmations: 1,
+                    transaction_id: "txid".to_string(),
+                    paid_at: Some(mock_paid_at()),
+                    block_height: None,
+                },
+            ])
+            .when(OnChainInvoiceCommand::SetConfirmed {
+                confirmations: 2,
+                amount: amount_fn(100_000),
+                transaction_id: "txid".to_string(),
+                timestamp: Some(mock_paid_at()),
+                block_height: None,
+            })
+            .then_expect_events(vec![]);
+    }
+
+    #[test]
+    fn test_underpayment_tolerance() {
+        let mut created = mock_created_event(100_000);
+        if let OnChainInvoiceEvent::InvoiceCreated {
+            underpayment_tolerance,
+            ..
+        } = &mut created
+        {
+            *underpayment_tolerance = amount_fn(500);
+        }
+
+        let confirm = |amount: u64| OnChainInvoiceCommand::SetConfirmed {
+            confirmations: 1,
+            amount: amount_fn(amount),
+            transaction_id: "txid".to_string(),
+            timestamp: Some(mock_paid_at()),
+            block_height: None,
+        };
+        let confirmed = |amount: u64, underpayment: bool| OnChainInvoiceEvent::PaymentConfirmed {
+            received_amount: amount_fn(amount),
+            underpayment,
+            overpayment: false,
+            confirmations: 1,
+            transaction_id: "txid".to_string(),
+            paid_at: Some(mock_paid_at()),
+            block_height: None,
+        };
+
+        OnChainInvoiceTestFramework::with(())
+            .given(vec![created.clone()])
+            .when(confirm(99_600))
+            .then_expect_events(vec![confirmed(99_600, false)]);
+
+        OnChainInvoiceTestFramework::with(())
+            .given(vec![created])
+            .when(confirm(99_400))
+            .then_expect_events(vec![confirmed(99_400, true)]);
+    }
+
+    #[test]
+    fn test_amount_remaining() {
+        let mut invoice = BtcOnChainInvoice::default();
+        invoice.apply(mock_created_event(100_000));
+
+        invoice.apply(mock_pending_event(60_000, true, false));
+        assert_eq!(invoice.amount_remaining(), amount_fn(40_000));
+
+        invoice.apply(mock_pending_event(100_000, false, false));
+        assert_eq!(invoice.amount_remaining(), amount_fn(0));
+
+        invoice.apply(mock_pending_event(150_000, false, true));
+        assert_eq!(invoice.amount_remaining(), amount_fn(0));
+    }
+
+    #[test]
+    fn test_refund_exceeding_overpayment() {
+        let request = |amount: u64| OnChainInvoiceCommand::RequestRefund {
+            amount: amount_fn(amount),
+            address: "tb1qrefund".to_string(),
+        };
+        let given = vec![mock_created_event(100_000), mock_overpaid_event(100_500)];
+
+        OnChainInvoiceTestFramework::with(())
+            .given(given.clone())
+            .when(request(501))
+            .then_expect_error_message("Invoice invalid amount: 501 sats");
+
+        OnChainInvoiceTestFramework::with(())
+            .given(given)
+            .when(request(500))
+            .then_expect_events(vec![OnChainInvoiceEvent::RefundRequested {
+                amount: amount_fn(500),
+                address: "tb1qrefund".to_string(),
+            }]);
+    }
+
+    #[test]
+    fn test_refund_reserved_until_confirmed() {
+        let requested = OnChainInvoiceEvent::RefundRequested {
+            amount: amount_fn(500),
+            address: "tb1qrefund".to_string(),
+        };
+        let given = vec![
+            mock_created_event(100_000),
+            mock_overpaid_event(100_500),
+            requested,
+        ];
+        let confirm = |address: &str| OnChainInvoiceCommand::Refund {
+            amount: amount_fn(500),
+            address: address.to_string(),
+            transaction_id: "refund_txid".to_string(),
+        };
+
+        OnChainInvoiceTestFramework::with(())
+            .given(given.clone())
+            .when(OnChainInvoiceCommand::RequestRefund {
+                amount: amount_fn(100),
+                address: "tb1qrefund".to_string(),
+            })
+            .then_expect_error_message("Invoice refund pending: 123");
+
+        OnChainInvoiceTestFramework::with(())
+            .given(given.clone())
+            .when(confirm("tb1qother"))
+            .then_expect_error_message(
+                "Invoice service error: no pending refund of 500 sats to tb1qother on invoice 123",
+            );
+
+        OnChainInvoiceTestFramework::with(())
+            .given(given.clone())
+            .when(confirm("tb1qrefund"))
+            .then_expect_events(vec![OnChainInvoiceEvent::Refunded {
+                amount: amount_fn(500),
+                address: "tb1qrefund".to_string(),
+                transaction_id: "refund_txid".to_string(),
+            }]);
+
+        OnChainInvoiceTestFramework::with(())
+            .given(given)
+            .when(OnChainInvoiceCommand::CancelRefund)
+            .then_expect_events(vec![OnChainInvoiceEvent::RefundCancelled {
+                amount: amount_fn(500),
+                address: "tb1qrefund".to_string(),
+            }]);
+
+        let mut invoice = BtcOnChainInvoice::default();
+        invoice.apply(mock_created_event(100_000));
+        invoice.apply(mock_overpaid_event(100_500));
+        invoice.apply(OnChainInvoiceEvent::RefundRequested {
+            amount: amount_fn(500),
+            address: "tb1qrefund".to_string(),
+        });
+        assert_eq!(invoice.overpaid_amount(), amount_fn(0));
+        invoice.apply(OnChainInvoiceEvent::RefundCancelled {
+            amount: amount_fn(500),
+            address: "tb1qrefund".to_string(),
+        });
+        assert_eq!(invoice.overpaid_amount(), amount_fn(500));
+    }
+
+    fn mock_overpaid_event(amount: u64) -> OnChainInvoiceEvent {
+        OnChainInvoiceEvent::PaymentConfirmed {
+            received_amount: amount_fn(amount),
+            underpayment: false,
+            overpayment: true,
+            confirmations: 1,
+            transaction_id: "txid".to_string(),
+            paid_at: Some(mock_paid_at()),
+            block_height: None,
+        }
+    }
+
+    #[test]
+    fn test_paid_at() {
+        let mut invoice = BtcOnChainInvoice::default();
+        invoice.apply(mock_created_event(100_000));
+        invoice.apply(mock_pending_event(100_000, false, false));
+        assert_eq!(invoice.paid_at, None);
+
+        invoice.apply(OnChainInvoiceEvent::PaymentConfirmed {
+            received_amount: amount_fn(100_000),
+            underpayment: false,
+            overpayment: false,
+            confirmations: 1,
+            transaction_id: "txid".to_string(),
+            paid_at: Some(mock_paid_at()),
+            block_height: None,
+        });
+        assert!(invoice.paid);
+        assert_eq!(invoice.paid_at, Some(mock_paid_at()));
+    }
+
+    #[tokio::test]
+    async fn test_paid_at_defaults_to_now() {
+        let mut invoice = BtcOnChainInvoice::default();
+        invoice.apply(mock_created_event(100_000));
+        let events = invoice
+            .handle(
+                OnChainInvoiceCommand::SetConfirmed {
+                    confirmations: 1,
+                    amount: amount_fn(100_000),
+                    transaction_id: "txid".to_string(),
+                    timestamp: None,
+                    block_height: None,
+                },
+                &(),
+            )
+            .await
+            .unwrap();
+        match &events[..] {
+            [OnChainInvoiceEvent::PaymentConfirmed {
+                paid_at: Some(paid_at),
+                ..
+            }] => assert!(*paid_at > mock_paid_at()),
+            e => panic!("expected payment confirmed event, got {:?}", e),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_expire_unpaid() {
+        let mut invoice = BtcOnChainInvoice::default();
+        invoice.apply(mock_created_event(100_000));
+        let events = invoice
+            .handle(OnChainInvoiceCommand::Expire, &())
+            .await
+            .unwrap();
+        assert!(matches!(
+            &events[..],
+            [OnChainInvoiceEvent::InvoiceExpired { .. }]
+        ));
+
+        invoice.apply(events[0].clone());
+        assert!(invoice.expired);
+        let events = invoice
+            .handle(OnChainInvoiceCommand::Expire, &())
+            .await
+            .unwrap();
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn test_expire_then_pay() {
+        let expired = OnChainInvoiceEvent::InvoiceExpired {
+            expired_at: mock_paid_at(),
+        };
+        let confirmed = OnChainInvoiceEvent::PaymentConfirmed {
+            received_amount: amount_fn(100_000),
+            underpayment: false,
+            overpayment: false,
+            confirmations: 1,
+            transaction_id: "txid".to_string(),
+            paid_at: Some(mock_paid_at()),
+            block_height: None,
+        };
+        OnChainInvoiceTestFramework::with(())
+            .given(vec![mock_created_event(100_000), expired.clone()])
+            .when(OnChainInvoiceCommand::SetConfirmed {
+                confirmations: 1,
+                amount: amount_fn(100_000),
+                transaction_id: "txid".to_string(),
+                timestamp: Some(mock_paid_at()),
+                block_height: None,
+            })
+            .then_expect_events(vec![confirmed.clone()]);
+
+        let mut invoice = BtcOnChainInvoice::default();
+        for event in [mock_created_event(100_000), expired, confirmed] {
+            invoice.apply(event);
+        }
+        assert!(invoice.paid);
+        assert!(invoice.paid_after_expiry);
+    }
+
+    #[test]
+    fn test_pay_then_expire() {
+        OnChainInvoiceTestFramework::with(())
+            .given(vec![
+                mock_created_event(100_000),
+                OnChainInvoiceEvent::PaymentConfirmed {
+                    received_amount: amount_fn(100_000),
+                    underpayment: false,
+                    overpayment: false,
+                    confirmations: 1,
+                    transaction_id: "txid".to_string(),
+                    paid_at: Some(mock_paid_at()),
+                    block_height: None,
+                },
+            ])
+            .when(OnChainInvoiceCommand::Expire)
+            .then_expect_error_message("Invoice already paid: 123")
+    }
+
+    #[tokio::test]
+    async fn test_rollback_confirmations() {
+        let confirmed = OnChainInvoiceEvent::PaymentConfirmed {
+            received_amount: amount_fn(100_000),
+            underpayment: false,
+            overpayment: false,
+            confirmations: 1,
+            transaction_id: "txid".to_string(),
+            paid_at: Some(mock_paid_at()),
+            block_height: None,
+        };
+        let rolled_back = OnChainInvoiceEvent::ConfirmationsRolledBack {
+            from_height: 800_000,
+            confirmations: 1,
+        };
+        let mut invoice = BtcOnChainInvoice::default();
+        invoice.apply(mock_created_event(100_000));
+        invoice.apply(confirmed);
+        let events = invoice
+            .handle(
+                OnChainInvoiceCommand::RollbackConfirmations {
+                    from_height: 800_000,
+                },
+                &(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(events, vec![rolled_back.clone()]);
+
+        invoice.apply(rolled_back);
+        assert!(!invoice.paid);
+        assert_eq!(invoice.confirmations, 0);
+        assert_eq!(invoice.transaction_id, None);
+        assert_eq!(invoice.received_amount, amount_fn(100_000));
+
+        // nothing left to roll back, the new chain confirms the payment again
+        let events = invoice
+            .handle(
+                OnChainInvoiceCommand::RollbackConfirmations {
+                    from_height: 800_000,
+                },
+                &(),
+            )
+            .await
+            .unwrap();
+        assert!(events.is_empty());
+        let events = invoice
+            .handle(
+                OnChainInvoiceCommand::SetConfirmed {
+                    confirmations: 1,
+                    amount: amount_fn(100_000),
+                    transaction_id: "txid".to_string(),
+                    timestamp: None,
+                    block_height: None,
+                },
+                &(),
+            )
+            .await
+            .unwrap();
+        assert!(matches!(
+            &events[..],
+            [OnChainInvoiceEvent::PaymentConfirmed { .. }]
+        ));
+    }
+
+    #[test]
+    fn test_is_due_for_expiry() {
+        let mut created = mock_created_event(100_000);
+        if let OnChainInvoiceEvent::InvoiceCreated { expires_at, .. } = &mut created {
+            *expires_at = Some(mock_paid_at());
+        }
+        let mut invoice = BtcOnChainInvoice::default();
+        invoice.apply(mock_created_event(100_000));
+        assert!(!invoice.is_due_for_expiry());
+
+        invoice.apply(created);
+        assert!(invoice.is_due_for_expiry());
+        invoice.apply(OnChainInvoiceEvent::InvoiceExpired {
+            expired_at: mock_paid_at(),
+        });
+        assert!(!invoice.is_due_for_expiry());
+        assert!(!invoice.paid_after_expiry);
+    }
+
+    fn mock_paid_at() -> DateTime {
+        from_timestamp(1_700_000_000)
+    }
+
     fn amount_fn(amount: u64) -> Amount {
         Amount::new(Currency::Btc, amount)
     }
@@ -322,9 +1364,13 @@ mod aggregate_tests {
 
     fn mock_created_event(amount: u64) -> OnChainInvoiceEvent {
         OnChainInvoiceEvent::InvoiceCreated {
-            invoice_id: "123".to_string(),
+            invoice_id: "123".into(),
             amount: amount_fn(amount),
             address: "tb1q6xm2qgh5r83lvmmu0v7c3d4wrd9k2uxu3sgcr4".to_string(),
+            webhook_url: None,
+            required_confirmations: 1,
+            underpayment_tolerance: Amount::zero(Currency::Btc),
+            expires_at: None,
         }
     }
 }
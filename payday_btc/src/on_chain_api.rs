@@ -2,7 +2,7 @@ use std::collections::HashMap;
 
 use async_trait::async_trait;
 use bitcoin::{Address, Amount};
-use payday_core::PaydayResult;
+use payday_core::{node::NodeApi, payment::amount::Amount as PaydayAmount, PaydayResult};
 use tokio::task::JoinHandle;
 
 use crate::on_chain_processor::OnChainTransactionEvent;
@@ -25,29 +25,51 @@ pub trait OnChainPaymentApi: Send + Sync {
     /// address for this nodes network.
     fn validate_address(&self, address: &str) -> PaydayResult<Address>;
 
-    /// Estimate the fee for a transaction.
+    /// Estimate the fee rate for a transaction.
     async fn estimate_fee(
         &self,
         target_conf: i32,
         outputs: HashMap<String, Amount>,
-    ) -> PaydayResult<Amount>;
+    ) -> PaydayResult<FeeRate>;
 
-    /// Send coins to an address.
+    /// Estimate the absolute fee in sats of sending amount to address,
+    /// including the change output, without sending anything.
+    async fn estimate_send_fee(
+        &self,
+        amount: Amount,
+        address: String,
+        fee_rate: FeeRate,
+    ) -> PaydayResult<PaydayAmount>;
+
+    /// Send coins to an address. The optional reference, e.g. an order id, is
+    /// attached to the transaction as label. Change below the dust limit is
+    /// added to the fee.
     async fn send(
         &self,
         amount: Amount,
         address: String,
-        sats_per_vbyte: Amount,
+        fee_rate: FeeRate,
+        reference: Option<String>,
     ) -> PaydayResult<OnChainPaymentResult>;
 
-    /// Send coins to multiple addresses.
+    /// Send coins to multiple addresses. Change below the dust limit is added
+    /// to the fee.
     async fn batch_send(
         &self,
         outputs: HashMap<String, Amount>,
-        sats_per_vbyte: Amount,
+        fee_rate: FeeRate,
+        reference: Option<String>,
     ) -> PaydayResult<OnChainPaymentResult>;
 }
 
+/// Estimates fee rates independent of a node, e.g. from a block explorer. The
+/// estimate can be passed to OnChainPaymentApi::send.
+#[async_trait]
+pub trait FeeEstimator: Send + Sync {
+    /// The fee rate for a confirmation within target_conf blocks.
+    async fn sats_per_vbyte(&self, target_conf: u16) -> PaydayResult<FeeRate>;
+}
+
 #[async_trait]
 pub trait OnChainTransactionApi: Send + Sync {
     /// Get history of onchain transactions between start_height and end_height.
@@ -59,15 +81,67 @@ pub trait OnChainTransactionApi: Send + Sync {
 }
 
 #[async_trait]
-pub trait OnChainStreamApi: Send + Sync {
+pub trait OnChainStreamApi: NodeApi {
     async fn process_events(&self) -> PaydayResult<JoinHandle<()>>;
 }
 
+/// A fee rate in satoshis per virtual byte. A dedicated type so fee rates can not
+/// be mixed up with payment or fee amounts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FeeRate(u64);
+
+impl FeeRate {
+    pub fn from_sat_per_vb(sat_per_vb: u64) -> Self {
+        Self(sat_per_vb)
+    }
+
+    pub fn to_sat_per_vb(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Which on-chain transactions a subscription forwards.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TransactionDirection {
+    /// Only transactions received by the wallet, e.g. for invoicing.
+    Received,
+    /// Only transactions sent by the wallet.
+    Sent,
+    /// All transactions.
+    #[default]
+    Both,
+}
+
+impl TransactionDirection {
+    /// Whether the event should be forwarded for this direction.
+    pub fn matches(&self, event: &OnChainTransactionEvent) -> bool {
+        match self {
+            TransactionDirection::Received => event.is_received(),
+            TransactionDirection::Sent => !event.is_received(),
+            TransactionDirection::Both => true,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct OnChainBalance {
     pub total_balance: Amount,
     pub unconfirmed_balance: Amount,
     pub confirmed_balance: Amount,
+    /// Balance locked by in-flight transactions.
+    pub locked_balance: Amount,
+    /// Balance reserved for fee bumping of anchor channels.
+    pub reserved_balance_anchor_chan: Amount,
+}
+
+impl OnChainBalance {
+    /// The confirmed balance that is not locked or reserved.
+    pub fn spendable_balance(&self) -> Amount {
+        self.confirmed_balance
+            .checked_sub(self.locked_balance)
+            .and_then(|b| b.checked_sub(self.reserved_balance_anchor_chan))
+            .unwrap_or(Amount::ZERO)
+    }
 }
 
 #[derive(Debug)]
@@ -82,9 +156,114 @@ pub struct Balance {
     pub channel: ChannelBalance,
 }
 
+/// Status of a sent on-chain transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxStatus {
+    /// Published to the network but not confirmed yet.
+    Broadcast,
+    /// Included in a block.
+    Confirmed,
+}
+
+/// The result of an on-chain payment. Amounts are given in the domain amount
+/// with BTC currency.
 #[derive(Debug)]
 pub struct OnChainPaymentResult {
     pub tx_id: String,
-    pub amounts: HashMap<String, Amount>,
-    pub fee: Amount,
+    pub amounts: HashMap<String, PaydayAmount>,
+    /// The fee rate the transaction was sent with.
+    pub fee_rate: FeeRate,
+    pub status: TxStatus,
+    /// The serialized transaction if the node returned it, e.g. for recording
+    /// or broadcasting it elsewhere.
+    pub raw_tx_hex: Option<String>,
+}
+
+impl OnChainPaymentResult {
+    /// Creates a payment result from node amounts.
+    pub fn new(tx_id: String, amounts: &HashMap<String, Amount>, fee_rate: FeeRate) -> Self {
+        Self {
+            tx_id,
+            amounts: amounts
+                .iter()
+                .map(|(address, amount)| (address.to_owned(), (*amount).into()))
+                .collect(),
+            fee_rate,
+            status: TxStatus::Broadcast,
+            raw_tx_hex: None,
+        }
+    }
+
+    pub fn with_raw_tx_hex(mut self, raw_tx_hex: Option<String>) -> Self {
+        self.raw_tx_hex = raw_tx_hex;
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use payday_core::payment::currency::Currency;
+
+    use super::*;
+    use crate::on_chain_processor::OnChainTransaction;
+
+    #[test]
+    fn test_spendable_balance() {
+        let balance = OnChainBalance {
+            total_balance: Amount::from_sat(120_000),
+            unconfirmed_balance: Amount::from_sat(20_000),
+            confirmed_balance: Amount::from_sat(100_000),
+            locked_balance: Amount::from_sat(30_000),
+            reserved_balance_anchor_chan: Amount::from_sat(10_000),
+        };
+        assert_eq!(balance.spendable_balance(), Amount::from_sat(60_000));
+
+        let reserved = OnChainBalance {
+            reserved_balance_anchor_chan: Amount::from_sat(200_000),
+            ..balance
+        };
+        assert_eq!(reserved.spendable_balance(), Amount::ZERO);
+    }
+
+    #[test]
+    fn test_transaction_direction() {
+        let tx = OnChainTransaction {
+            tx_id: "txid".to_string(),
+            block_height: 800_000,
+            block_hash: None,
+            address: crate::to_address(
+                "tb1q6xm2qgh5r83lvmmu0v7c3d4wrd9k2uxu3sgcr4",
+                bitcoin::Network::Signet,
+            )
+            .unwrap(),
+            amount: Amount::from_sat(100_000),
+            confirmations: 1,
+            timestamp: None,
+        };
+        let received = OnChainTransactionEvent::ReceivedConfirmed(tx.clone());
+        let sent = OnChainTransactionEvent::SentUnconfirmed(tx);
+
+        assert!(TransactionDirection::Received.matches(&received));
+        assert!(!TransactionDirection::Received.matches(&sent));
+        assert!(TransactionDirection::Sent.matches(&sent));
+        assert!(!TransactionDirection::Sent.matches(&received));
+        assert!(TransactionDirection::Both.matches(&received));
+        assert!(TransactionDirection::Both.matches(&sent));
+    }
+
+    #[test]
+    fn test_payment_result_domain_amounts() {
+        let result = OnChainPaymentResult::new(
+            "txid".to_string(),
+            &HashMap::from([("tb1qaddress".to_string(), Amount::from_sat(250_000))]),
+            FeeRate::from_sat_per_vb(2),
+        );
+        assert_eq!(
+            result.amounts.get("tb1qaddress"),
+            Some(&PaydayAmount::new(Currency::Btc, 250_000))
+        );
+        assert_eq!(result.fee_rate.to_sat_per_vb(), 2);
+        assert_eq!(result.status, TxStatus::Broadcast);
+        assert_eq!(result.raw_tx_hex, None);
+    }
 }
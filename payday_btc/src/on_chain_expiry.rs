@@ -0,0 +1,216 @@
+use std::{sync::Arc, time::Duration};
+
+use cqrs_es::EventStore;
+use payday_core::{shutdown::ShutdownSignal, PaydayResult};
+use tokio::task::JoinHandle;
+
+use crate::{
+    on_chain_aggregate::{BtcOnChainInvoice, OnChainCommand, OnChainInvoiceCommand},
+    on_chain_service::{CommandOutcome, OnChainService},
+    on_chain_view::OnChainInvoiceViewRepository,
+};
+
+/// Interval between two sweeps of a started sweeper.
+const DEFAULT_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+/// Number of overdue invoices read from the read model at once.
+const DEFAULT_BATCH_SIZE: u32 = 100;
+
+/// Expires overdue on-chain invoices in periodic sweeps over the invoice read
+/// model. Invoices that became overdue while the service was down are expired
+/// by the first sweep after the start.
+pub struct ExpirySweeper<ES>
+where
+    ES: EventStore<BtcOnChainInvoice>,
+{
+    service: Arc<OnChainService<ES>>,
+    views: Arc<dyn OnChainInvoiceViewRepository>,
+    interval: Duration,
+    batch_size: u32,
+}
+
+impl<ES> ExpirySweeper<ES>
+where
+    ES: EventStore<BtcOnChainInvoice> + 'static,
+    ES::AC: Send,
+{
+    /// The views must be kept up to date by a query of the CQRS framework of
+    /// the service.
+    pub fn new(
+        service: Arc<OnChainService<ES>>,
+        views: Arc<dyn OnChainInvoiceViewRepository>,
+    ) -> Self {
+        Self {
+            service,
+            views,
+            interval: DEFAULT_SWEEP_INTERVAL,
+            batch_size: DEFAULT_BATCH_SIZE,
+        }
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn with_batch_size(mut self, batch_size: u32) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Expires all overdue invoices in batches and returns the number of
+    /// expired invoices. Invoices that can not be expired, e.g. because they
+    /// were paid meanwhile, are skipped until the next sweep.
+    pub async fn sweep(&self) -> PaydayResult<usize> {
+        let mut expired = 0;
+        let mut skipped = 0;
+        loop {
+            let overdue = self.views.list_overdue(self.batch_size, skipped).await?;
+            if overdue.is_empty() {
+                return Ok(expired);
+            }
+            for aggregate_id in overdue {
+                let command = OnChainCommand {
+                    id: aggregate_id.to_owned(),
+                    command: OnChainInvoiceCommand::Expire,
+                };
+                match self.service.execute(command).await {
+                    Ok(CommandOutcome::Applied) => expired += 1,
+                    Ok(_) => skipped += 1,
+                    Err(e) => {
+                        println!(
+                            "Failed to expire on-chain invoice {}: {:?}",
+                            aggregate_id, e
+                        );
+                        skipped += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sweeps right away and then every interval until shutdown fires. A
+    /// running sweep is finished first.
+    pub async fn run(&self, mut shutdown: ShutdownSignal) {
+        let mut interval = tokio::time::interval(self.interval);
+        loop {
+            tokio::select! {
+                _ = shutdown.wait() => break,
+                _ = interval.tick() => {}
+            }
+            match self.sweep().await {
+                Ok(0) => {}
+                Ok(expired) => println!("Expired {} overdue on-chain invoices", expired),
+                Err(e) => println!("Failed to sweep overdue on-chain invoices: {:?}", e),
+            }
+        }
+    }
+
+    /// Runs the sweeper on its own task.
+    pub fn start(self, shutdown: ShutdownSignal) -> JoinHandle<()> {
+        tokio::spawn(async move { self.run(shutdown).await })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cqrs_es::{mem_store::MemStore, persist::GenericQuery, CqrsFramework};
+    use payday_core::{
+        date::{add_duration, from_timestamp, now},
+        payment::{
+            amount::Amount,
+            currency::Currency,
+            invoice::{AmountLimits, InvoiceId},
+        },
+        shutdown::shutdown_signal,
+    };
+
+    use super::*;
+    use crate::{on_chain_view::MemOnChainInvoiceViews, ConfirmationTiers};
+
+    #[tokio::test]
+    async fn test_sweep_expires_overdue_invoices() {
+        let store = MemStore::<BtcOnChainInvoice>::default();
+        let views = Arc::new(MemOnChainInvoiceViews::default());
+        let cqrs = CqrsFramework::new(
+            store.clone(),
+            vec![Box::new(GenericQuery::new(views.clone()))],
+            (),
+        );
+        let service = Arc::new(OnChainService::new(cqrs, store));
+        let btc = |sats: u64| Amount::new(Currency::Btc, sats);
+        let overdue = Some(from_timestamp(1_700_000_000));
+        let later = Some(add_duration(now(), Duration::from_secs(3600)));
+        for (id, expires_at) in [
+            ("overdue1", overdue),
+            ("overdue2", overdue),
+            ("paid", overdue),
+            ("overdue3", overdue),
+            ("open", later),
+            ("never", None),
+        ] {
+            service
+                .execute(OnChainCommand {
+                    id: id.to_string(),
+                    command: OnChainInvoiceCommand::CreateInvoice {
+                        invoice_id: InvoiceId::new(id),
+                        amount: btc(100_000),
+                        address: id.to_string(),
+                        webhook_url: None,
+                        required_confirmations: 1,
+                        underpayment_tolerance: btc(0),
+                        expires_at,
+                        amount_limits: AmountLimits::default(),
+                        confirmation_tiers: ConfirmationTiers::default(),
+                    },
+                })
+                .await
+                .unwrap();
+        }
+        service
+            .execute(OnChainCommand {
+                id: "paid".to_string(),
+                command: OnChainInvoiceCommand::SetConfirmed {
+                    confirmations: 1,
+                    amount: btc(100_000),
+                    transaction_id: "txid".to_string(),
+                    timestamp: None,
+                    block_height: None,
+                },
+            })
+            .await
+            .unwrap();
+
+        let sweeper = ExpirySweeper::new(service.clone(), views.clone()).with_batch_size(2);
+        assert_eq!(sweeper.sweep().await.unwrap(), 3);
+        for (id, expired) in [
+            ("overdue1", true),
+            ("overdue2", true),
+            ("overdue3", true),
+            ("paid", false),
+            ("open", false),
+            ("never", false),
+        ] {
+            let invoice = service.load_on_chain_invoice(id).await.unwrap().unwrap();
+            assert_eq!(invoice.expired, expired, "{}", id);
+        }
+        assert!(views.list_overdue(10, 0).await.unwrap().is_empty());
+        assert_eq!(sweeper.sweep().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_start_stops_on_shutdown() {
+        let store = MemStore::<BtcOnChainInvoice>::default();
+        let views = Arc::new(MemOnChainInvoiceViews::default());
+        let cqrs = CqrsFramework::new(store.clone(), vec![], ());
+        let service = Arc::new(OnChainService::new(cqrs, store));
+        let (trigger, shutdown) = shutdown_signal();
+        let handle = ExpirySweeper::new(service, views)
+            .with_interval(Duration::from_millis(10))
+            .start(shutdown);
+        trigger.trigger();
+        tokio::time::timeout(Duration::from_secs(1), handle)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
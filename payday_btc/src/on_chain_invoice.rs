@@ -0,0 +1,254 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bitcoin::Network;
+use cqrs_es::EventStore;
+use payday_core::{
+    payment::{
+        amount::Amount,
+        invoice::{
+            AmountLimits, Invoice, InvoiceId, InvoiceIdGenerator, PaymentProcessorApi, PaymentType,
+            UlidGenerator,
+        },
+    },
+    PaydayResult,
+};
+use serde_json::Value;
+
+use crate::{
+    on_chain_aggregate::{BtcOnChainInvoice, OnChainCommand, OnChainInvoiceCommand},
+    on_chain_api::OnChainInvoiceApi,
+    on_chain_service::OnChainService,
+    required_confirmations, ConfirmationTiers,
+};
+
+/// The payment type of on-chain invoices.
+pub const ON_CHAIN_PAYMENT_TYPE: &str = "on_chain";
+
+/// Creates on-chain invoices for a fresh wallet address of the node. Payments
+/// are processed by the transaction stream of the node.
+pub struct OnChainInvoiceProcessor<ES>
+where
+    ES: EventStore<BtcOnChainInvoice>,
+{
+    name: String,
+    network: Network,
+    invoice_api: Arc<dyn OnChainInvoiceApi>,
+    service: Arc<OnChainService<ES>>,
+    required_confirmations: u64,
+    amount_limits: AmountLimits,
+    confirmation_tiers: ConfirmationTiers,
+    id_generator: Arc<dyn InvoiceIdGenerator>,
+}
+
+impl<ES> OnChainInvoiceProcessor<ES>
+where
+    ES: EventStore<BtcOnChainInvoice>,
+{
+    /// Invoices need the default confirmations of the network.
+    pub fn new(
+        name: &str,
+        network: Network,
+        invoice_api: Arc<dyn OnChainInvoiceApi>,
+        service: Arc<OnChainService<ES>>,
+    ) -> Self {
+        Self {
+            name: name.to_string(),
+            network,
+            invoice_api,
+            service,
+            required_confirmations: required_confirmations(network, None),
+            amount_limits: AmountLimits::default(),
+            confirmation_tiers: ConfirmationTiers::default(),
+            id_generator: Arc::new(UlidGenerator::default()),
+        }
+    }
+
+    /// Overrides the default confirmations of the network if set.
+    pub fn with_required_confirmations(mut self, explicit: Option<u64>) -> Self {
+        self.required_confirmations = required_confirmations(self.network, explicit);
+        self
+    }
+
+    /// Sets the limits the invoice amount is checked against on creation.
+    pub fn with_amount_limits(mut self, amount_limits: AmountLimits) -> Self {
+        self.amount_limits = amount_limits;
+        self
+    }
+
+    pub fn with_confirmation_tiers(mut self, confirmation_tiers: ConfirmationTiers) -> Self {
+        self.confirmation_tiers = confirmation_tiers;
+        self
+    }
+
+    /// Sets the generator for ids of invoices created without an id.
+    pub fn with_id_generator(mut self, id_generator: Arc<dyn InvoiceIdGenerator>) -> Self {
+        self.id_generator = id_generator;
+        self
+    }
+}
+
+#[async_trait]
+impl<ES> PaymentProcessorApi for OnChainInvoiceProcessor<ES>
+where
+    ES: EventStore<BtcOnChainInvoice> + 'static,
+    ES::AC: Send,
+{
+    fn name(&self) -> String {
+        self.name.to_owned()
+    }
+
+    fn supported_payment_type(&self) -> PaymentType {
+        ON_CHAIN_PAYMENT_TYPE.to_string()
+    }
+
+    async fn create_invoice(
+        &self,
+        invoice_id: Option<InvoiceId>,
+        amount: Amount,
+        _memo: Option<String>,
+    ) -> PaydayResult<Invoice> {
+        let invoice_id = invoice_id.unwrap_or_else(|| self.id_generator.generate());
+        let address = self.invoice_api.new_address().await?.to_string();
+        self.service
+            .execute(OnChainCommand {
+                id: address.to_owned(),
+                command: OnChainInvoiceCommand::CreateInvoice {
+                    invoice_id: invoice_id.clone(),
+                    amount,
+                    address: address.to_owned(),
+                    webhook_url: None,
+                    required_confirmations: self.required_confirmations,
+                    underpayment_tolerance: Amount::zero(amount.currency),
+                    amount_limits: self.amount_limits.clone(),
+                    confirmation_tiers: self.confirmation_tiers.clone(),
+                    expires_at: None,
+                },
+            })
+            .await?;
+        Ok(Invoice {
+            service_name: self.name(),
+            invoice_id,
+            amount,
+            payment_type: self.supported_payment_type(),
+            payment_info: Value::String(address),
+        })
+    }
+
+    /// Payments are processed by the transaction stream of the node.
+    async fn process_payment_events(&self) -> PaydayResult<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use bitcoin::Address;
+    use cqrs_es::{mem_store::MemStore, CqrsFramework};
+    use payday_core::{payment::currency::Currency, PaydayError};
+
+    use super::*;
+
+    struct FixedAddress;
+
+    #[async_trait]
+    impl OnChainInvoiceApi for FixedAddress {
+        async fn new_address(&self) -> PaydayResult<Address> {
+            Ok(
+                Address::from_str("tb1q6xm2qgh5r83lvmmu0v7c3d4wrd9k2uxu3sgcr4")?
+                    .require_network(Network::Signet)?,
+            )
+        }
+    }
+
+    /// Hands out a fresh address per call, so every invoice gets its own
+    /// aggregate.
+    #[derive(Default)]
+    struct FreshAddresses(std::sync::Mutex<u32>);
+
+    #[async_trait]
+    impl OnChainInvoiceApi for FreshAddresses {
+        async fn new_address(&self) -> PaydayResult<Address> {
+            let mut next = self.0.lock().unwrap();
+            *next += 1;
+            let program = bitcoin::WitnessProgram::new(
+                bitcoin::WitnessVersion::V0,
+                &[(*next % 256) as u8, (*next / 256) as u8].repeat(10),
+            )
+            .unwrap();
+            Ok(Address::from_witness_program(program, Network::Signet))
+        }
+    }
+
+    fn processor(network: Network) -> OnChainInvoiceProcessor<MemStore<BtcOnChainInvoice>> {
+        let store = MemStore::<BtcOnChainInvoice>::default();
+        let cqrs = CqrsFramework::new(store.clone(), vec![], ());
+        let service = Arc::new(OnChainService::new(cqrs, store));
+        OnChainInvoiceProcessor::new("lnd", network, Arc::new(FixedAddress), service)
+    }
+
+    async fn created_confirmations(
+        processor: &OnChainInvoiceProcessor<MemStore<BtcOnChainInvoice>>,
+    ) -> u64 {
+        let invoice = processor
+            .create_invoice(
+                Some(InvoiceId::new("123")),
+                Amount::new(Currency::Btc, 100_000),
+                None,
+            )
+            .await
+            .unwrap();
+        let address = invoice.payment_info.as_str().unwrap();
+        processor
+            .service
+            .load_on_chain_invoice(address)
+            .await
+            .unwrap()
+            .unwrap()
+            .required_confirmations
+    }
+
+    #[tokio::test]
+    async fn test_create_invoice_required_confirmations() {
+        assert_eq!(created_confirmations(&processor(Network::Signet)).await, 1);
+        assert_eq!(created_confirmations(&processor(Network::Bitcoin)).await, 3);
+        let explicit = processor(Network::Bitcoin).with_required_confirmations(Some(6));
+        assert_eq!(created_confirmations(&explicit).await, 6);
+    }
+
+    #[tokio::test]
+    async fn test_create_invoice_checks_amount_limits() {
+        let processor = processor(Network::Signet).with_amount_limits(AmountLimits::new(
+            Some(Amount::new(Currency::Btc, 546)),
+            None,
+        ));
+        let res = processor
+            .create_invoice(None, Amount::new(Currency::Btc, 545), None)
+            .await;
+        assert!(matches!(res, Err(PaydayError::InvoiceError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_generated_invoice_ids_are_ordered() {
+        let store = MemStore::<BtcOnChainInvoice>::default();
+        let cqrs = CqrsFramework::new(store.clone(), vec![], ());
+        let service = Arc::new(OnChainService::new(cqrs, store));
+        let processor = OnChainInvoiceProcessor::new(
+            "lnd",
+            Network::Signet,
+            Arc::new(FreshAddresses::default()),
+            service,
+        );
+        let mut ids = Vec::new();
+        for _ in 0..100 {
+            let invoice = processor
+                .create_invoice(None, Amount::new(Currency::Btc, 100_000), None)
+                .await
+                .unwrap();
+            ids.push(invoice.invoice_id);
+        }
+        assert!(ids.windows(2).all(|w| w[0] < w[1]));
+    }
+}
@@ -1,14 +1,34 @@
-use std::sync::Arc;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::Arc,
+    time::Duration,
+};
 
 use async_trait::async_trait;
-use bitcoin::{Address, Amount};
-use payday_core::{persistence::block_height::BlockHeightStoreApi, PaydayResult};
-use tokio::sync::Mutex;
+use bitcoin::{Address, Amount, Network};
+use payday_core::{
+    date::{add_duration, is_past, now, DateTime},
+    events::{
+        handler::TaskHandler,
+        publisher::TaskPublisher,
+        task::{Task, TaskResult},
+    },
+    persistence::block_height::BlockHeightStoreApi,
+    PaydayError, PaydayResult,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc::UnboundedSender, Mutex};
+
+use crate::to_address;
+
+/// Task type of persisted dead letters, see DeadLetter.
+pub const DEAD_LETTER_TASK_TYPE: &str = "on_chain_dead_letter";
 
 #[async_trait]
 pub trait OnChainTransactionEventProcessorApi: Send + Sync {
     fn node_id(&self) -> String;
-    async fn get_block_height(&self) -> PaydayResult<i32>;
+    /// The last processed block height or None if the node was never seen.
+    async fn get_block_height(&self) -> PaydayResult<Option<i32>>;
     async fn set_block_height(&self, block_height: i32) -> PaydayResult<()>;
     async fn process_event(&self, event: OnChainTransactionEvent) -> PaydayResult<()>;
 }
@@ -16,9 +36,27 @@ pub trait OnChainTransactionEventProcessorApi: Send + Sync {
 #[async_trait]
 pub trait OnChainTransactionEventHandler: Send + Sync {
     async fn process_event(&self, event: OnChainTransactionEvent) -> PaydayResult<()>;
+    /// Called when a reorg was detected, so confirmations seen from from_height
+    /// on can be rolled back. The events of the new chain are processed
+    /// afterwards.
+    async fn on_reorg(&self, _from_height: i32) -> PaydayResult<()> {
+        Ok(())
+    }
+}
+
+/// A shared handler, e.g. a service that is also used outside the processor.
+#[async_trait]
+impl<H: OnChainTransactionEventHandler + ?Sized> OnChainTransactionEventHandler for Arc<H> {
+    async fn process_event(&self, event: OnChainTransactionEvent) -> PaydayResult<()> {
+        self.as_ref().process_event(event).await
+    }
+
+    async fn on_reorg(&self, from_height: i32) -> PaydayResult<()> {
+        self.as_ref().on_reorg(from_height).await
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum OnChainTransactionEvent {
     ReceivedUnconfirmed(OnChainTransaction),
     ReceivedConfirmed(OnChainTransaction),
@@ -27,6 +65,15 @@ pub enum OnChainTransactionEvent {
 }
 
 impl OnChainTransactionEvent {
+    /// Whether the transaction was received by the wallet.
+    pub fn is_received(&self) -> bool {
+        matches!(
+            self,
+            OnChainTransactionEvent::ReceivedUnconfirmed(_)
+                | OnChainTransactionEvent::ReceivedConfirmed(_)
+        )
+    }
+
     pub fn block_height(&self) -> Option<i32> {
         match self {
             OnChainTransactionEvent::ReceivedConfirmed(tx) => Some(tx.block_height),
@@ -34,15 +81,313 @@ impl OnChainTransactionEvent {
             _ => None,
         }
     }
+
+    pub fn confirmations(&self) -> i32 {
+        match self {
+            OnChainTransactionEvent::ReceivedUnconfirmed(tx) => tx.confirmations,
+            OnChainTransactionEvent::ReceivedConfirmed(tx) => tx.confirmations,
+            OnChainTransactionEvent::SentUnconfirmed(tx) => tx.confirmations,
+            OnChainTransactionEvent::SentConfirmed(tx) => tx.confirmations,
+        }
+    }
+
+    pub fn transaction(&self) -> &OnChainTransaction {
+        match self {
+            OnChainTransactionEvent::ReceivedUnconfirmed(tx) => tx,
+            OnChainTransactionEvent::ReceivedConfirmed(tx) => tx,
+            OnChainTransactionEvent::SentUnconfirmed(tx) => tx,
+            OnChainTransactionEvent::SentConfirmed(tx) => tx,
+        }
+    }
+
+    pub fn block_hash(&self) -> Option<String> {
+        match self {
+            OnChainTransactionEvent::ReceivedConfirmed(tx) => tx.block_hash.to_owned(),
+            OnChainTransactionEvent::SentConfirmed(tx) => tx.block_hash.to_owned(),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct OnChainTransaction {
     pub tx_id: String,
     pub block_height: i32,
+    pub block_hash: Option<String>,
     pub address: Address,
     pub amount: Amount,
     pub confirmations: i32,
+    /// Block time of a confirmed transaction or the time the node first saw it.
+    pub timestamp: Option<DateTime>,
+}
+
+/// Drops events that do not change the state of a transaction output, e.g. the
+/// same transaction seen again during a catch-up. Confirmed events are kept if
+/// the confirmations grew or the block changed. Remembers at most capacity
+/// outputs, the oldest are forgotten first.
+pub struct EventDeduplicator {
+    capacity: usize,
+    seen: HashMap<(String, String, bool), (i32, i32)>,
+    order: VecDeque<(String, String, bool)>,
+}
+
+impl EventDeduplicator {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            seen: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Whether the event is a state transition and should be forwarded.
+    pub fn is_new(&self, event: &OnChainTransactionEvent) -> bool {
+        let (key, state) = Self::key_and_state(event);
+        match self.seen.get(&key) {
+            Some(previous) => previous.0 < state.0 || previous.1 != state.1,
+            None => true,
+        }
+    }
+
+    /// Remembers the state of a forwarded event. Should only be called once
+    /// the event was handled, so a failed event is forwarded again.
+    pub fn record(&mut self, event: &OnChainTransactionEvent) {
+        let (key, state) = Self::key_and_state(event);
+        if self.seen.insert(key.clone(), state).is_none() {
+            self.order.push_back(key);
+            if self.order.len() > self.capacity {
+                if let Some(oldest) = self.order.pop_front() {
+                    self.seen.remove(&oldest);
+                }
+            }
+        }
+    }
+
+    fn key_and_state(event: &OnChainTransactionEvent) -> ((String, String, bool), (i32, i32)) {
+        let tx = event.transaction();
+        let key = (
+            tx.tx_id.to_owned(),
+            tx.address.to_string(),
+            event.block_height().is_some(),
+        );
+        (key, (tx.confirmations, tx.block_height))
+    }
+}
+
+impl Default for EventDeduplicator {
+    fn default() -> Self {
+        Self::new(10_000)
+    }
+}
+
+/// Raised when the handler failed repeatedly for the same transaction output.
+/// The output is dead-lettered, its events are persisted as dead letters
+/// instead of being handled.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlertEvent {
+    pub node_id: String,
+    pub tx_id: String,
+    pub address: String,
+    pub failures: u32,
+    pub error: String,
+}
+
+/// Transaction outputs whose confirmed events were handled, remembering at
+/// most capacity outputs. The oldest are forgotten first.
+struct ConfirmedOutputs {
+    capacity: usize,
+    seen: HashSet<(String, String)>,
+    order: VecDeque<(String, String)>,
+}
+
+impl ConfirmedOutputs {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn key(event: &OnChainTransactionEvent) -> (String, String) {
+        let tx = event.transaction();
+        (tx.tx_id.to_owned(), tx.address.to_string())
+    }
+
+    fn contains(&self, event: &OnChainTransactionEvent) -> bool {
+        self.seen.contains(&Self::key(event))
+    }
+
+    fn record(&mut self, key: (String, String)) {
+        if self.seen.insert(key.clone()) {
+            self.order.push_back(key);
+            if self.order.len() > self.capacity {
+                if let Some(oldest) = self.order.pop_front() {
+                    self.seen.remove(&oldest);
+                }
+            }
+        }
+    }
+}
+
+/// Block hashes and confirmation heights of the last depth blocks below the
+/// highest confirmed event seen.
+struct ReorgDetector {
+    depth: i32,
+    tip: i32,
+    block_hashes: HashMap<i32, String>,
+    tx_heights: HashMap<String, i32>,
+}
+
+impl ReorgDetector {
+    fn new(depth: i32) -> Self {
+        Self {
+            depth,
+            tip: -1,
+            block_hashes: HashMap::new(),
+            tx_heights: HashMap::new(),
+        }
+    }
+
+    /// Records the block of a confirmed event and returns the height to roll
+    /// back from if a different block hash was seen at the same height or the
+    /// transaction was confirmed at a higher block before. Blocks deeper than
+    /// the depth are not checked.
+    fn record(&mut self, event: &OnChainTransactionEvent) -> Option<i32> {
+        let height = event.block_height()?;
+        self.tip = self.tip.max(height);
+        let oldest = self.tip - self.depth;
+        if height < oldest {
+            return None;
+        }
+        let hash_changed = event.block_hash().is_some_and(|hash| {
+            self.block_hashes
+                .insert(height, hash.to_owned())
+                .is_some_and(|previous| previous != hash)
+        });
+        let lower = self
+            .tx_heights
+            .insert(event.transaction().tx_id.to_owned(), height)
+            .is_some_and(|previous| previous > height);
+
+        self.block_hashes.retain(|h, _| *h >= oldest);
+        self.tx_heights.retain(|_, h| *h >= oldest);
+        (hash_changed || lower).then_some(height)
+    }
+}
+
+/// A transaction event that was not handled, persisted as a task of
+/// DEAD_LETTER_TASK_TYPE so it can be replayed with DeadLetterReplayHandler.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub node_id: String,
+    pub tx_id: String,
+    pub address: String,
+    pub amount_sat: u64,
+    pub block_height: i32,
+    pub block_hash: Option<String>,
+    pub confirmations: i32,
+    pub timestamp: Option<DateTime>,
+    pub received: bool,
+    pub confirmed: bool,
+    /// Failures of the handler on this event, 0 for events of an output that
+    /// was dead-lettered before.
+    pub failures: u32,
+    pub error: String,
+}
+
+impl DeadLetter {
+    fn new(node_id: &str, event: &OnChainTransactionEvent, failures: u32, error: String) -> Self {
+        let tx = event.transaction();
+        Self {
+            node_id: node_id.to_string(),
+            tx_id: tx.tx_id.to_owned(),
+            address: tx.address.to_string(),
+            amount_sat: tx.amount.to_sat(),
+            block_height: tx.block_height,
+            block_hash: tx.block_hash.to_owned(),
+            confirmations: tx.confirmations,
+            timestamp: tx.timestamp,
+            received: event.is_received(),
+            confirmed: event.block_height().is_some(),
+            failures,
+            error,
+        }
+    }
+
+    /// The dead-lettered event with the address checked against the network.
+    pub fn to_event(&self, network: Network) -> PaydayResult<OnChainTransactionEvent> {
+        let tx = OnChainTransaction {
+            tx_id: self.tx_id.to_owned(),
+            block_height: self.block_height,
+            block_hash: self.block_hash.to_owned(),
+            address: to_address(&self.address, network)?,
+            amount: Amount::from_sat(self.amount_sat),
+            confirmations: self.confirmations,
+            timestamp: self.timestamp,
+        };
+        Ok(match (self.received, self.confirmed) {
+            (true, true) => OnChainTransactionEvent::ReceivedConfirmed(tx),
+            (true, false) => OnChainTransactionEvent::ReceivedUnconfirmed(tx),
+            (false, true) => OnChainTransactionEvent::SentConfirmed(tx),
+            (false, false) => OnChainTransactionEvent::SentUnconfirmed(tx),
+        })
+    }
+}
+
+/// Replays persisted dead letters into an event handler. A dead letter that
+/// fails again is marked as failed and can be replayed once more from the task
+/// queue.
+pub struct DeadLetterReplayHandler {
+    network: Network,
+    handler: Arc<dyn OnChainTransactionEventHandler>,
+}
+
+impl DeadLetterReplayHandler {
+    pub fn new(network: Network, handler: Arc<dyn OnChainTransactionEventHandler>) -> Self {
+        Self { network, handler }
+    }
+
+    async fn replay(&self, task: Task) -> PaydayResult<()> {
+        let dead_letter: DeadLetter = serde_json::from_value(task.payload)
+            .map_err(|e| PaydayError::EventError(e.to_string()))?;
+        self.handler
+            .process_event(dead_letter.to_event(self.network)?)
+            .await
+    }
+}
+
+#[async_trait]
+impl TaskHandler for DeadLetterReplayHandler {
+    fn allow_retry(&self) -> bool {
+        false
+    }
+
+    fn allow_recovery(&self) -> bool {
+        true
+    }
+
+    fn handles(&self, task_type: &str) -> bool {
+        task_type == DEAD_LETTER_TASK_TYPE
+    }
+
+    async fn handle(&self, task: Task) -> payday_core::events::Result<TaskResult> {
+        match self.replay(task).await {
+            Ok(()) => Ok(TaskResult::Success),
+            Err(e) => {
+                println!("Failed to replay on-chain dead letter: {:?}", e);
+                Ok(TaskResult::Failed)
+            }
+        }
+    }
+}
+
+struct FailureAlerts {
+    threshold: u32,
+    sender: UnboundedSender<AlertEvent>,
+    dead_letters: Arc<dyn TaskPublisher + Send + Sync>,
+    failures: HashMap<String, u32>,
+    dead_lettered: HashSet<String>,
 }
 
 pub struct OnChainTransactionProcessor {
@@ -50,6 +395,21 @@ pub struct OnChainTransactionProcessor {
     block_height_store: Box<dyn BlockHeightStoreApi>,
     handler: Box<dyn OnChainTransactionEventHandler>,
     current_block_height: Arc<Mutex<i32>>,
+    reorg_detector: Mutex<ReorgDetector>,
+    started_at: DateTime,
+    last_event_at: Arc<Mutex<Option<DateTime>>>,
+    confirmation_ceiling: Option<i32>,
+    confirmed_outputs: Mutex<ConfirmedOutputs>,
+    failure_alerts: Option<Mutex<FailureAlerts>>,
+}
+
+/// Liveness of a node subscription.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodeStatus {
+    pub node_id: String,
+    pub last_event_at: Option<DateTime>,
+    /// No event was processed within the staleness threshold.
+    pub stale: bool,
 }
 
 impl OnChainTransactionProcessor {
@@ -63,8 +423,146 @@ impl OnChainTransactionProcessor {
             block_height_store,
             handler,
             current_block_height: Arc::new(Mutex::new(-1)),
+            reorg_detector: Mutex::new(ReorgDetector::new(6)),
+            started_at: now(),
+            last_event_at: Arc::new(Mutex::new(None)),
+            confirmation_ceiling: None,
+            confirmed_outputs: Mutex::new(ConfirmedOutputs::new(10_000)),
+            failure_alerts: None,
         }
     }
+
+    /// After the handler failed threshold times for the same transaction output,
+    /// an alert is sent and the event is published to dead_letters instead of
+    /// failing the stream again and again. Later events of the output are
+    /// published there as well, so they are replayed in order. Until then
+    /// failures are returned, so the event is retried.
+    pub fn with_failure_alerts(
+        mut self,
+        threshold: u32,
+        sender: UnboundedSender<AlertEvent>,
+        dead_letters: Arc<dyn TaskPublisher + Send + Sync>,
+    ) -> Self {
+        self.failure_alerts = Some(Mutex::new(FailureAlerts {
+            threshold: threshold.max(1),
+            sender,
+            dead_letters,
+            failures: HashMap::new(),
+            dead_lettered: HashSet::new(),
+        }));
+        self
+    }
+
+    async fn handle_event(&self, event: OnChainTransactionEvent) -> PaydayResult<()> {
+        let Some(alerts) = &self.failure_alerts else {
+            return self.handler.process_event(event).await;
+        };
+        let tx = event.transaction();
+        let key = format!("{}:{}", tx.tx_id, tx.address);
+        {
+            let alerts = alerts.lock().await;
+            if alerts.dead_lettered.contains(&key) {
+                let dead_letter = DeadLetter::new(
+                    &self.node_id,
+                    &event,
+                    0,
+                    "output was dead-lettered before".to_string(),
+                );
+                return publish_dead_letter(&alerts, &dead_letter).await;
+            }
+        }
+
+        let failed = event.clone();
+        let result = self.handler.process_event(event).await;
+        let mut alerts = alerts.lock().await;
+        let Err(error) = result else {
+            alerts.failures.remove(&key);
+            return Ok(());
+        };
+        let failures = alerts.failures.get(&key).copied().unwrap_or(0) + 1;
+        if failures < alerts.threshold {
+            alerts.failures.insert(key, failures);
+            return Err(error);
+        }
+        let dead_letter = DeadLetter::new(&self.node_id, &failed, failures, format!("{:?}", error));
+        // the event is retried if it could not be persisted
+        publish_dead_letter(&alerts, &dead_letter).await?;
+        alerts.failures.remove(&key);
+        alerts.dead_lettered.insert(key);
+        // a closed alert channel must not stop event processing
+        let _ = alerts.sender.send(AlertEvent {
+            node_id: dead_letter.node_id,
+            tx_id: dead_letter.tx_id,
+            address: dead_letter.address,
+            failures,
+            error: dead_letter.error,
+        });
+        Ok(())
+    }
+
+    /// Stops forwarding events of transaction outputs with more confirmations
+    /// than the ceiling once a confirmed event of the output was handled. An
+    /// output first seen above the ceiling, e.g. after a long downtime, is still
+    /// forwarded. Should be above the required confirmations of all invoices,
+    /// e.g. required confirmations plus a small buffer.
+    pub fn with_confirmation_ceiling(mut self, ceiling: u64) -> Self {
+        self.confirmation_ceiling = Some(i32::try_from(ceiling).unwrap_or(i32::MAX));
+        self
+    }
+
+    async fn above_ceiling(&self, event: &OnChainTransactionEvent) -> bool {
+        match self.confirmation_ceiling {
+            Some(ceiling) if event.confirmations() > ceiling => {
+                self.confirmed_outputs.lock().await.contains(event)
+            }
+            _ => false,
+        }
+    }
+
+    /// The number of blocks a reorg is detected for. Block hashes and
+    /// confirmation heights further below the latest confirmed event are
+    /// forgotten. Defaults to 6.
+    pub fn with_reorg_depth(mut self, reorg_depth: u32) -> Self {
+        self.reorg_detector = Mutex::new(ReorgDetector::new(
+            i32::try_from(reorg_depth).unwrap_or(i32::MAX),
+        ));
+        self
+    }
+
+    /// Lowers the block height to the last block below a reorg, so a restart
+    /// catches up on the new chain from there.
+    async fn reset_block_height(&self, block_height: i32) -> PaydayResult<()> {
+        let mut current_block_height = self.current_block_height.lock().await;
+        if *current_block_height > block_height {
+            self.block_height_store
+                .reset_block_height(&self.node_id, block_height.max(0) as u64)
+                .await?;
+            *current_block_height = block_height;
+        }
+        Ok(())
+    }
+
+    /// Returns the status of the node. A node is stale if no event was processed
+    /// within stale_after, counting from the start of the processor if it has not
+    /// seen any event yet.
+    pub async fn status(&self, stale_after: Duration) -> NodeStatus {
+        let last_event_at = *self.last_event_at.lock().await;
+        let stale = is_past(add_duration(
+            last_event_at.unwrap_or(self.started_at),
+            stale_after,
+        ));
+        NodeStatus {
+            node_id: self.node_id.to_string(),
+            last_event_at,
+            stale,
+        }
+    }
+
+    /// Records the block of a confirmed event and returns the height to roll
+    /// back from if the event indicates a reorg.
+    pub async fn detect_reorg(&self, event: &OnChainTransactionEvent) -> Option<i32> {
+        self.reorg_detector.lock().await.record(event)
+    }
 }
 
 #[async_trait]
@@ -72,16 +570,19 @@ impl OnChainTransactionEventProcessorApi for OnChainTransactionProcessor {
     fn node_id(&self) -> String {
         self.node_id.to_string()
     }
-    async fn get_block_height(&self) -> PaydayResult<i32> {
+    async fn get_block_height(&self) -> PaydayResult<Option<i32>> {
         let mut current_block_height = self.current_block_height.lock().await;
         if *current_block_height < 0 {
-            *current_block_height = self
+            match self
                 .block_height_store
                 .get_block_height(&self.node_id)
                 .await?
-                .block_height as i32;
+            {
+                Some(stored) => *current_block_height = stored.block_height as i32,
+                None => return Ok(None),
+            }
         }
-        Ok(*current_block_height)
+        Ok(Some(*current_block_height))
     }
     async fn set_block_height(&self, block_height: i32) -> PaydayResult<()> {
         let mut current_block_height = self.current_block_height.lock().await;
@@ -95,7 +596,23 @@ impl OnChainTransactionEventProcessorApi for OnChainTransactionProcessor {
     }
     async fn process_event(&self, event: OnChainTransactionEvent) -> PaydayResult<()> {
         let block_height = event.block_height();
-        self.handler.process_event(event).await?;
+        if let Some(from_height) = self.detect_reorg(&event).await {
+            println!(
+                "Reorg detected, rolling back from block height {} for node {}",
+                from_height, self.node_id
+            );
+            self.handler.on_reorg(from_height).await?;
+            self.reset_block_height(from_height - 1).await?;
+        }
+        if !self.above_ceiling(&event).await {
+            let confirmed = (self.confirmation_ceiling.is_some() && block_height.is_some())
+                .then(|| ConfirmedOutputs::key(&event));
+            self.handle_event(event).await?;
+            if let Some(key) = confirmed {
+                self.confirmed_outputs.lock().await.record(key);
+            }
+        }
+        *self.last_event_at.lock().await = Some(now());
         if let Some(bh) = block_height {
             self.set_block_height(bh).await?;
         }
@@ -103,6 +620,14 @@ impl OnChainTransactionEventProcessorApi for OnChainTransactionProcessor {
     }
 }
 
+async fn publish_dead_letter(alerts: &FailureAlerts, dead_letter: &DeadLetter) -> PaydayResult<()> {
+    alerts
+        .dead_letters
+        .once(Task::new(DEAD_LETTER_TASK_TYPE.to_string(), dead_letter))
+        .await?;
+    Ok(())
+}
+
 pub struct OnChainTransactionPrintHandler;
 
 #[async_trait]
@@ -112,3 +637,414 @@ impl OnChainTransactionEventHandler for OnChainTransactionPrintHandler {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use payday_core::{
+        persistence::block_height::{BlockHeight, MemBlockHeightStore},
+        PaydayError,
+    };
+
+    use super::*;
+
+    struct NoopBlockHeightStore;
+
+    #[async_trait]
+    impl BlockHeightStoreApi for NoopBlockHeightStore {
+        async fn get_block_height(&self, _node_id: &str) -> PaydayResult<Option<BlockHeight>> {
+            Ok(None)
+        }
+        async fn set_block_height(&self, _node_id: &str, _block_height: u64) -> PaydayResult<()> {
+            Ok(())
+        }
+    }
+
+    struct ZeroBlockHeightStore;
+
+    #[async_trait]
+    impl BlockHeightStoreApi for ZeroBlockHeightStore {
+        async fn get_block_height(&self, node_id: &str) -> PaydayResult<Option<BlockHeight>> {
+            Ok(Some(BlockHeight {
+                node_id: node_id.to_string(),
+                block_height: 0,
+            }))
+        }
+        async fn set_block_height(&self, _node_id: &str, _block_height: u64) -> PaydayResult<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_missing_vs_zero_block_height() {
+        let missing = OnChainTransactionProcessor::new(
+            "node",
+            Box::new(NoopBlockHeightStore),
+            Box::new(OnChainTransactionPrintHandler),
+        );
+        assert_eq!(missing.get_block_height().await.unwrap(), None);
+        missing.set_block_height(800_000).await.unwrap();
+        assert_eq!(missing.get_block_height().await.unwrap(), Some(800_000));
+
+        let zero = OnChainTransactionProcessor::new(
+            "node",
+            Box::new(ZeroBlockHeightStore),
+            Box::new(OnChainTransactionPrintHandler),
+        );
+        assert_eq!(zero.get_block_height().await.unwrap(), Some(0));
+    }
+
+    #[tokio::test]
+    async fn test_detect_reorg_by_block_hash() {
+        let processor = OnChainTransactionProcessor::new(
+            "node",
+            Box::new(NoopBlockHeightStore),
+            Box::new(OnChainTransactionPrintHandler),
+        );
+
+        let first = confirmed_event(800_000, "hash_a");
+        assert_eq!(processor.detect_reorg(&first).await, None);
+        assert_eq!(processor.detect_reorg(&first).await, None);
+        assert_eq!(
+            processor
+                .detect_reorg(&confirmed_event(800_000, "hash_b"))
+                .await,
+            Some(800_000)
+        );
+
+        // hashes below the reorg depth are forgotten
+        processor
+            .detect_reorg(&confirmed_event(800_010, "hash_c"))
+            .await;
+        assert_eq!(processor.reorg_detector.lock().await.block_hashes.len(), 1);
+        assert_eq!(
+            processor
+                .detect_reorg(&confirmed_event(800_000, "hash_d"))
+                .await,
+            None
+        );
+    }
+
+    struct CountingHandler(Arc<Mutex<u32>>);
+
+    #[async_trait]
+    impl OnChainTransactionEventHandler for CountingHandler {
+        async fn process_event(&self, _event: OnChainTransactionEvent) -> PaydayResult<()> {
+            *self.0.lock().await += 1;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_large_confirmation_ceiling_does_not_wrap() {
+        let processor = OnChainTransactionProcessor::new(
+            "node",
+            Box::new(NoopBlockHeightStore),
+            Box::new(CountingHandler(Arc::new(Mutex::new(0)))),
+        )
+        .with_confirmation_ceiling(u64::MAX);
+        assert_eq!(processor.confirmation_ceiling, Some(i32::MAX));
+    }
+
+    #[tokio::test]
+    async fn test_large_reorg_depth_does_not_wrap() {
+        let processor = OnChainTransactionProcessor::new(
+            "node",
+            Box::new(NoopBlockHeightStore),
+            Box::new(CountingHandler(Arc::new(Mutex::new(0)))),
+        )
+        .with_reorg_depth(u32::MAX);
+        assert_eq!(processor.reorg_detector.lock().await.depth, i32::MAX);
+    }
+
+    #[tokio::test]
+    async fn test_confirmation_ceiling() {
+        let handled = Arc::new(Mutex::new(0));
+        let processor = OnChainTransactionProcessor::new(
+            "node",
+            Box::new(NoopBlockHeightStore),
+            Box::new(CountingHandler(handled.clone())),
+        )
+        .with_confirmation_ceiling(3);
+
+        let event = |block_height: i32, confirmations: i32| {
+            let mut event = confirmed_event(block_height, "hash");
+            if let OnChainTransactionEvent::ReceivedConfirmed(tx) = &mut event {
+                tx.confirmations = confirmations;
+            }
+            event
+        };
+
+        processor.process_event(event(800_000, 3)).await.unwrap();
+        assert_eq!(*handled.lock().await, 1);
+
+        processor.process_event(event(800_001, 4)).await.unwrap();
+        assert_eq!(*handled.lock().await, 1);
+        assert_eq!(processor.get_block_height().await.unwrap(), Some(800_001));
+
+        // an output first seen deep in the chain is handled once
+        let deep = || {
+            let mut deep = event(800_002, 10);
+            if let OnChainTransactionEvent::ReceivedConfirmed(tx) = &mut deep {
+                tx.tx_id = "deep".to_string();
+            }
+            deep
+        };
+        processor.process_event(deep()).await.unwrap();
+        assert_eq!(*handled.lock().await, 2);
+        processor.process_event(deep()).await.unwrap();
+        assert_eq!(*handled.lock().await, 2);
+    }
+
+    struct FailingHandler(Arc<Mutex<u32>>);
+
+    #[async_trait]
+    impl OnChainTransactionEventHandler for FailingHandler {
+        async fn process_event(&self, _event: OnChainTransactionEvent) -> PaydayResult<()> {
+            *self.0.lock().await += 1;
+            Err(PaydayError::DbError("handler failed".to_string()))
+        }
+    }
+
+    #[derive(Default)]
+    struct RecordingPublisher(Mutex<Vec<Task>>);
+
+    #[async_trait]
+    impl TaskPublisher for RecordingPublisher {
+        async fn once(&self, task: Task) -> payday_core::events::Result<()> {
+            self.0.lock().await.push(task);
+            Ok(())
+        }
+        async fn retry(
+            &self,
+            task: Task,
+            _params: payday_core::events::task::RetryType,
+        ) -> payday_core::events::Result<()> {
+            self.once(task).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_repeated_failures_alert_and_dead_letter() {
+        let handled = Arc::new(Mutex::new(0));
+        let (sender, mut alerts) = tokio::sync::mpsc::unbounded_channel();
+        let dead_letters = Arc::new(RecordingPublisher::default());
+        let processor = OnChainTransactionProcessor::new(
+            "node",
+            Box::new(NoopBlockHeightStore),
+            Box::new(FailingHandler(handled.clone())),
+        )
+        .with_failure_alerts(3, sender, dead_letters.clone());
+
+        for _ in 0..2 {
+            let res = processor
+                .process_event(confirmed_event(800_000, "hash"))
+                .await;
+            assert!(res.is_err());
+        }
+        assert!(alerts.try_recv().is_err());
+
+        processor
+            .process_event(confirmed_event(800_000, "hash"))
+            .await
+            .unwrap();
+        let alert = alerts.try_recv().unwrap();
+        assert_eq!(alert.tx_id, "txid");
+        assert_eq!(alert.failures, 3);
+
+        // later events of dead-lettered outputs are not handled anymore
+        processor
+            .process_event(confirmed_event(800_001, "hash"))
+            .await
+            .unwrap();
+        assert_eq!(*handled.lock().await, 3);
+        assert!(alerts.try_recv().is_err());
+
+        let tasks = dead_letters.0.lock().await;
+        let letters: Vec<DeadLetter> = tasks
+            .iter()
+            .map(|t| {
+                assert_eq!(t.task_type, DEAD_LETTER_TASK_TYPE);
+                serde_json::from_value(t.payload.clone()).unwrap()
+            })
+            .collect();
+        assert_eq!(letters.len(), 2);
+        assert_eq!(letters[0].failures, 3);
+        assert_eq!(letters[0].address, alert.address);
+        assert_eq!(letters[1].failures, 0);
+        assert_eq!(letters[1].block_height, 800_001);
+
+        // a replayed dead letter reaches the handler again
+        let replayed = Arc::new(Mutex::new(0));
+        let replay = DeadLetterReplayHandler::new(
+            Network::Signet,
+            Arc::new(CountingHandler(replayed.clone())),
+        );
+        assert!(replay.handles(DEAD_LETTER_TASK_TYPE));
+        let res = replay.handle(tasks[0].clone()).await.unwrap();
+        assert!(matches!(res, TaskResult::Success));
+        assert_eq!(*replayed.lock().await, 1);
+        assert!(matches!(
+            letters[1].to_event(Network::Signet).unwrap(),
+            OnChainTransactionEvent::ReceivedConfirmed(_)
+        ));
+    }
+
+    struct RollbackHandler(Arc<Mutex<Vec<i32>>>);
+
+    #[async_trait]
+    impl OnChainTransactionEventHandler for RollbackHandler {
+        async fn process_event(&self, _event: OnChainTransactionEvent) -> PaydayResult<()> {
+            Ok(())
+        }
+
+        async fn on_reorg(&self, from_height: i32) -> PaydayResult<()> {
+            self.0.lock().await.push(from_height);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reorg_to_lower_block_height() {
+        let rollbacks = Arc::new(Mutex::new(Vec::new()));
+        let processor = OnChainTransactionProcessor::new(
+            "node",
+            Box::new(NoopBlockHeightStore),
+            Box::new(RollbackHandler(rollbacks.clone())),
+        )
+        .with_reorg_depth(3);
+
+        processor
+            .process_event(confirmed_event(800_000, "hash_a"))
+            .await
+            .unwrap();
+        processor
+            .process_event(confirmed_event(800_000, "hash_a"))
+            .await
+            .unwrap();
+        assert!(rollbacks.lock().await.is_empty());
+
+        processor
+            .process_event(confirmed_event(799_998, "hash_b"))
+            .await
+            .unwrap();
+        assert_eq!(*rollbacks.lock().await, vec![799_998]);
+
+        // heights below the reorg depth are forgotten
+        processor
+            .process_event(confirmed_event(800_010, "hash_c"))
+            .await
+            .unwrap();
+        let mut other = confirmed_event(800_014, "hash_d");
+        if let OnChainTransactionEvent::ReceivedConfirmed(tx) = &mut other {
+            tx.tx_id = "other".to_string();
+        }
+        processor.process_event(other).await.unwrap();
+        processor
+            .process_event(confirmed_event(800_005, "hash_e"))
+            .await
+            .unwrap();
+        assert_eq!(*rollbacks.lock().await, vec![799_998]);
+    }
+
+    #[tokio::test]
+    async fn test_reorg_resets_block_height() {
+        let processor = OnChainTransactionProcessor::new(
+            "node",
+            Box::new(MemBlockHeightStore::default()),
+            Box::new(OnChainTransactionPrintHandler),
+        );
+
+        processor
+            .process_event(confirmed_event(800_000, "hash_a"))
+            .await
+            .unwrap();
+        processor.set_block_height(800_003).await.unwrap();
+        processor
+            .process_event(confirmed_event(800_000, "hash_b"))
+            .await
+            .unwrap();
+        assert_eq!(processor.get_block_height().await.unwrap(), Some(800_000));
+    }
+
+    #[test]
+    fn test_deduplicate_events() {
+        let mut dedup = EventDeduplicator::new(2);
+        let mut forward = |event: OnChainTransactionEvent| {
+            let new = dedup.is_new(&event);
+            if new {
+                dedup.record(&event);
+            }
+            new
+        };
+        let unconfirmed = |tx_id: &str| {
+            let OnChainTransactionEvent::ReceivedConfirmed(mut tx) = confirmed_event(0, "hash")
+            else {
+                unreachable!()
+            };
+            tx.tx_id = tx_id.to_string();
+            tx.confirmations = 0;
+            OnChainTransactionEvent::ReceivedUnconfirmed(tx)
+        };
+        let confirmed = |block_height: i32, confirmations: i32| {
+            let mut event = confirmed_event(block_height, "hash");
+            if let OnChainTransactionEvent::ReceivedConfirmed(tx) = &mut event {
+                tx.confirmations = confirmations;
+            }
+            event
+        };
+
+        // the same transaction replayed forwards one event per state
+        assert!(forward(unconfirmed("txid")));
+        assert!(forward(confirmed(800_000, 1)));
+        assert!(!forward(unconfirmed("txid")));
+        assert!(!forward(confirmed(800_000, 1)));
+
+        // more confirmations and a reorg to another block are transitions
+        assert!(forward(confirmed(800_000, 3)));
+        assert!(!forward(confirmed(800_000, 2)));
+        assert!(forward(confirmed(799_998, 3)));
+
+        // the oldest outputs are forgotten beyond the capacity
+        assert!(forward(unconfirmed("other")));
+        assert!(forward(unconfirmed("txid")));
+    }
+
+    #[tokio::test]
+    async fn test_stale_node_status() {
+        let processor = OnChainTransactionProcessor::new(
+            "node",
+            Box::new(NoopBlockHeightStore),
+            Box::new(OnChainTransactionPrintHandler),
+        );
+        let stale_after = Duration::from_secs(60);
+        assert!(!processor.status(stale_after).await.stale);
+
+        processor
+            .process_event(confirmed_event(800_000, "hash_a"))
+            .await
+            .unwrap();
+        let status = processor.status(stale_after).await;
+        assert_eq!(status.node_id, "node");
+        assert!(status.last_event_at.is_some());
+        assert!(!status.stale);
+
+        *processor.last_event_at.lock().await = Some(now() - Duration::from_secs(120));
+        assert!(processor.status(stale_after).await.stale);
+    }
+
+    fn confirmed_event(block_height: i32, block_hash: &str) -> OnChainTransactionEvent {
+        OnChainTransactionEvent::ReceivedConfirmed(OnChainTransaction {
+            tx_id: "txid".to_string(),
+            block_height,
+            block_hash: Some(block_hash.to_string()),
+            address: Address::from_str("tb1q6xm2qgh5r83lvmmu0v7c3d4wrd9k2uxu3sgcr4")
+                .unwrap()
+                .assume_checked(),
+            amount: Amount::from_sat(100_000),
+            confirmations: 1,
+            timestamp: None,
+        })
+    }
+}
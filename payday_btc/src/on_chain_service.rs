@@ -0,0 +1,838 @@
+use std::{collections::HashMap, sync::Arc};
+
+use async_trait::async_trait;
+use cqrs_es::{Aggregate, AggregateContext, AggregateError, CqrsFramework, EventStore};
+use payday_core::{
+    payment::{
+        currency::Currency,
+        exchange_rate::{ExchangeRateApi, IndicativeFiatValue},
+        invoice::{InvoiceError, InvoiceId},
+    },
+    PaydayError, PaydayResult,
+};
+use tokio::sync::Mutex;
+
+use crate::{
+    on_chain_aggregate::{BtcOnChainInvoice, OnChainCommand, OnChainInvoiceCommand},
+    on_chain_api::{FeeRate, OnChainPaymentApi, OnChainPaymentResult},
+    on_chain_processor::{OnChainTransactionEvent, OnChainTransactionEventHandler},
+    on_chain_view::OnChainInvoiceViewRepository,
+};
+
+/// The outcome of executing a command on an on-chain invoice.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandOutcome {
+    /// The command produced events that were committed.
+    Applied,
+    /// The command produced no events, e.g. a duplicate transaction event.
+    NoOp,
+    /// The transaction event does not belong to a known invoice, e.g. a payment
+    /// to a reused or unrelated wallet address. No command was executed.
+    Unexpected,
+}
+
+/// The state of an on-chain invoice, optionally annotated with an indicative
+/// fiat value of the invoice amount.
+#[derive(Debug, Clone)]
+pub struct OnChainInvoiceStatus {
+    pub invoice: BtcOnChainInvoice,
+    pub fiat_value: Option<IndicativeFiatValue>,
+}
+
+pub struct OnChainService<ES>
+where
+    ES: EventStore<BtcOnChainInvoice>,
+{
+    cqrs: CqrsFramework<BtcOnChainInvoice, ES>,
+    store: ES,
+    payment_api: Option<Arc<dyn OnChainPaymentApi>>,
+    fiat_display: Option<(Arc<dyn ExchangeRateApi>, Currency)>,
+    views: Option<Arc<dyn OnChainInvoiceViewRepository>>,
+    /// Serializes refunds per invoice, entries are removed once no refund
+    /// holds them.
+    refund_locks: Mutex<HashMap<String, Arc<Mutex<()>>>>,
+}
+
+impl<ES> OnChainService<ES>
+where
+    ES: EventStore<BtcOnChainInvoice>,
+{
+    /// Creates a new service. The store is used to read the current aggregate state
+    /// and must be backed by the same event repository as the CQRS framework.
+    pub fn new(cqrs: CqrsFramework<BtcOnChainInvoice, ES>, store: ES) -> Self {
+        Self {
+            cqrs,
+            store,
+            payment_api: None,
+            fiat_display: None,
+            views: None,
+            refund_locks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Sets the payment API used to send refunds.
+    pub fn with_payment_api(mut self, payment_api: Arc<dyn OnChainPaymentApi>) -> Self {
+        self.payment_api = Some(payment_api);
+        self
+    }
+
+    /// Sets the invoice read models used to find invoices by a rotated address,
+    /// by invoice id and by confirmation height. The views must be written by a
+    /// query of the CQRS framework.
+    pub fn with_views(mut self, views: Arc<dyn OnChainInvoiceViewRepository>) -> Self {
+        self.views = Some(views);
+        self
+    }
+
+    /// Annotates invoice status with the invoice amount in the given fiat
+    /// currency. Rates should be cached by the provider.
+    pub fn with_fiat_display(
+        mut self,
+        rate_provider: Arc<dyn ExchangeRateApi>,
+        currency: Currency,
+    ) -> Self {
+        self.fiat_display = Some((rate_provider, currency));
+        self
+    }
+
+    /// Loads the status of the on-chain invoice for the given address. The fiat
+    /// value is only indicative and left empty if no rate is available.
+    pub async fn invoice_status(
+        &self,
+        address: &str,
+    ) -> PaydayResult<Option<OnChainInvoiceStatus>> {
+        let invoice = match self.load_on_chain_invoice(address).await? {
+            Some(invoice) => invoice,
+            None => return Ok(None),
+        };
+        let fiat_value = match &self.fiat_display {
+            Some((rate_provider, currency)) => match rate_provider.get_rate(*currency).await {
+                Ok(rate) => IndicativeFiatValue::from_btc(&invoice.amount, *currency, rate).ok(),
+                Err(_) => None,
+            },
+            None => None,
+        };
+        Ok(Some(OnChainInvoiceStatus {
+            invoice,
+            fiat_value,
+        }))
+    }
+
+    /// Loads the current state of the on-chain invoice for the given address.
+    /// Returns None if no invoice was created for the address.
+    pub async fn load_on_chain_invoice(
+        &self,
+        address: &str,
+    ) -> PaydayResult<Option<BtcOnChainInvoice>> {
+        let invoice = self
+            .store
+            .load_aggregate(&self.aggregate_id(address).await?)
+            .await
+            .map_err(to_payday_error)?
+            .aggregate()
+            .clone();
+        if invoice.invoice_id.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(invoice))
+        }
+    }
+
+    /// The aggregate id of the invoice with the given address. Invoices are
+    /// created with their address as id, rotated addresses are looked up in
+    /// the views.
+    async fn aggregate_id(&self, address: &str) -> PaydayResult<String> {
+        let found = match &self.views {
+            Some(views) => views.find_aggregate_id(address).await?,
+            None => None,
+        };
+        Ok(found.unwrap_or_else(|| address.to_string()))
+    }
+
+    /// Executes a command. Commands that do not produce any events are not
+    /// committed and reported as a no-op. Commands for a rotated address are
+    /// executed on the invoice the address was rotated to.
+    pub async fn execute(&self, mut command: OnChainCommand) -> PaydayResult<CommandOutcome> {
+        command.id = self.aggregate_id(&command.id).await?;
+        let aggregate = self
+            .store
+            .load_aggregate(&command.id)
+            .await
+            .map_err(to_payday_error)?
+            .aggregate()
+            .clone();
+        let events = aggregate.handle(command.command.clone(), &()).await?;
+
+        if events.is_empty() {
+            println!("Ignored duplicate on-chain command for {}", command.id);
+            return Ok(CommandOutcome::NoOp);
+        }
+
+        self.cqrs
+            .execute(&command.id, command.command)
+            .await
+            .map_err(to_payday_error)?;
+        println!("Successfully executed on-chain command for {}", command.id);
+        Ok(CommandOutcome::Applied)
+    }
+
+    /// Executes the command of a transaction event if its address belongs to a
+    /// known on-chain invoice. Other wallet activity is reported as unexpected
+    /// instead of creating an event stream for the address.
+    pub async fn route_event(
+        &self,
+        event: OnChainTransactionEvent,
+    ) -> PaydayResult<CommandOutcome> {
+        let command: OnChainCommand = event.into();
+        if self.load_on_chain_invoice(&command.id).await?.is_none() {
+            println!(
+                "Unexpected on-chain transaction to non-invoice address {}",
+                command.id
+            );
+            return Ok(CommandOutcome::Unexpected);
+        }
+        self.execute(command).await
+    }
+
+    /// Sends the not yet refunded overpayment of the paid invoice to the refund
+    /// address. The refund is reserved on the invoice before sending and
+    /// confirmed with the transaction afterwards, so a concurrent or repeated
+    /// call can not refund the same amount twice.
+    pub async fn refund_overpayment(
+        &self,
+        invoice_id: &InvoiceId,
+        refund_address: &str,
+        fee_rate: FeeRate,
+    ) -> PaydayResult<OnChainPaymentResult> {
+        let payment_api = self.payment_api.as_ref().ok_or(PaydayError::NodeApiError(
+            "no payment api configured for refunds".to_string(),
+        ))?;
+        payment_api.validate_address(refund_address)?;
+        let views = self.views.as_ref().ok_or(PaydayError::InvalidConfig(
+            "no invoice views configured for refunds".to_string(),
+        ))?;
+        let view = views
+            .get_invoice(invoice_id)
+            .await?
+            .ok_or(PaydayError::InvoiceNotFound(invoice_id.to_string()))?;
+        let aggregate_id = self.aggregate_id(&view.address).await?;
+
+        let lock = self.refund_lock(&aggregate_id).await;
+        let guard = lock.lock().await;
+        let result = self
+            .send_refund(
+                payment_api.as_ref(),
+                &aggregate_id,
+                refund_address,
+                fee_rate,
+            )
+            .await;
+        drop(guard);
+        self.release_refund_lock(&aggregate_id, lock).await;
+        result
+    }
+
+    async fn send_refund(
+        &self,
+        payment_api: &dyn OnChainPaymentApi,
+        aggregate_id: &str,
+        refund_address: &str,
+        fee_rate: FeeRate,
+    ) -> PaydayResult<OnChainPaymentResult> {
+        let invoice = self
+            .load_on_chain_invoice(aggregate_id)
+            .await?
+            .ok_or(PaydayError::InvoiceNotFound(aggregate_id.to_string()))?;
+        let amount = invoice.overpaid_amount();
+        let send_amount: bitcoin::Amount = amount.try_into()?;
+        self.execute(OnChainCommand {
+            id: aggregate_id.to_string(),
+            command: OnChainInvoiceCommand::RequestRefund {
+                amount,
+                address: refund_address.to_string(),
+            },
+        })
+        .await?;
+
+        let result = match payment_api
+            .send(send_amount, refund_address.to_string(), fee_rate, None)
+            .await
+        {
+            Ok(result) => result,
+            Err(e) => {
+                self.execute(OnChainCommand {
+                    id: aggregate_id.to_string(),
+                    command: OnChainInvoiceCommand::CancelRefund,
+                })
+                .await?;
+                return Err(e);
+            }
+        };
+        self.execute(OnChainCommand {
+            id: aggregate_id.to_string(),
+            command: OnChainInvoiceCommand::Refund {
+                amount,
+                address: refund_address.to_string(),
+                transaction_id: result.tx_id.to_owned(),
+            },
+        })
+        .await?;
+        Ok(result)
+    }
+
+    async fn refund_lock(&self, aggregate_id: &str) -> Arc<Mutex<()>> {
+        self.refund_locks
+            .lock()
+            .await
+            .entry(aggregate_id.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Removes the refund lock of an invoice unless another refund waits for it.
+    async fn release_refund_lock(&self, aggregate_id: &str, lock: Arc<Mutex<()>>) {
+        let mut locks = self.refund_locks.lock().await;
+        // only referenced by the map and the caller
+        if Arc::strong_count(&lock) == 2 {
+            locks.remove(aggregate_id);
+        }
+    }
+}
+
+#[async_trait]
+impl<ES> OnChainTransactionEventHandler for OnChainService<ES>
+where
+    ES: EventStore<BtcOnChainInvoice>,
+    ES::AC: Send,
+{
+    async fn process_event(&self, event: OnChainTransactionEvent) -> PaydayResult<()> {
+        self.route_event(event).await?;
+        Ok(())
+    }
+
+    /// Rolls back the confirmations of invoices paid in a block from from_height
+    /// on. The invoices are found by the confirmation height in the views.
+    async fn on_reorg(&self, from_height: i32) -> PaydayResult<()> {
+        let views = self.views.as_ref().ok_or(PaydayError::InvalidConfig(
+            "no invoice views configured for reorg handling".to_string(),
+        ))?;
+        for aggregate_id in views.list_confirmed_from(from_height).await? {
+            println!(
+                "Rolling back confirmations of on-chain invoice {} from block height {}",
+                aggregate_id, from_height
+            );
+            self.execute(OnChainCommand {
+                id: aggregate_id,
+                command: OnChainInvoiceCommand::RollbackConfirmations { from_height },
+            })
+            .await?;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn to_payday_error(e: AggregateError<InvoiceError>) -> PaydayError {
+    match e {
+        AggregateError::UserError(e) => PaydayError::InvoiceError(e),
+        e => PaydayError::DbError(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use bitcoin::{Address, Network};
+    use cqrs_es::{mem_store::MemStore, persist::GenericQuery, DomainEvent, EventEnvelope, Query};
+    use payday_core::date::from_timestamp;
+    use payday_core::payment::{
+        amount::Amount, exchange_rate::FixedExchangeRate, invoice::AmountLimits,
+    };
+    use payday_core::persistence::block_height::MemBlockHeightStore;
+
+    use super::*;
+    use crate::{
+        on_chain_processor::{
+            OnChainTransaction, OnChainTransactionEventProcessorApi, OnChainTransactionProcessor,
+        },
+        on_chain_view::MemOnChainInvoiceViews,
+        to_address, ConfirmationTiers,
+    };
+
+    const ADDRESS: &str = "tb1q6xm2qgh5r83lvmmu0v7c3d4wrd9k2uxu3sgcr4";
+    const ROTATED_ADDRESS: &str = "tb1p96rerkjw5e5ul4fxatc8xjg0jhu7hy4ue57s7jwgyxj2c6shsxystfrxk4";
+
+    #[tokio::test]
+    async fn test_duplicate_command_is_no_op() {
+        let service = mock_service();
+        let created = service.execute(create_command()).await.unwrap();
+        assert_eq!(created, CommandOutcome::Applied);
+
+        assert_eq!(
+            service.execute(confirm_command()).await.unwrap(),
+            CommandOutcome::Applied
+        );
+        assert_eq!(
+            service.execute(confirm_command()).await.unwrap(),
+            CommandOutcome::NoOp
+        );
+    }
+
+    #[tokio::test]
+    async fn test_route_event_to_non_invoice_address() {
+        let service = mock_service();
+        let event = || OnChainTransactionEvent::ReceivedConfirmed(mock_transaction(1));
+
+        assert_eq!(
+            service.route_event(event()).await.unwrap(),
+            CommandOutcome::Unexpected
+        );
+        assert!(service.store.load_events(ADDRESS).await.unwrap().is_empty());
+
+        service.execute(create_command()).await.unwrap();
+        assert_eq!(
+            service.route_event(event()).await.unwrap(),
+            CommandOutcome::Applied
+        );
+    }
+
+    #[tokio::test]
+    async fn test_route_event_to_rotated_address() {
+        let store = MemStore::<BtcOnChainInvoice>::default();
+        let views = Arc::new(MemOnChainInvoiceViews::default());
+        let service = service_with(store.clone(), views.clone());
+        service.execute(create_command()).await.unwrap();
+        service
+            .execute(OnChainCommand {
+                id: ADDRESS.to_string(),
+                command: OnChainInvoiceCommand::RotateAddress {
+                    new_address: ROTATED_ADDRESS.to_string(),
+                },
+            })
+            .await
+            .unwrap();
+
+        // the rotation is found by a service created after a restart
+        let service = service_with(store, views);
+        let mut tx = mock_transaction(1);
+        tx.address = to_address(ROTATED_ADDRESS, Network::Signet).unwrap();
+        assert_eq!(
+            service
+                .route_event(OnChainTransactionEvent::ReceivedConfirmed(tx))
+                .await
+                .unwrap(),
+            CommandOutcome::Applied
+        );
+        assert!(service
+            .store
+            .load_events(ROTATED_ADDRESS)
+            .await
+            .unwrap()
+            .is_empty());
+
+        let invoice = service
+            .load_on_chain_invoice(ROTATED_ADDRESS)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(invoice.invoice_id, "123".into());
+        assert_eq!(invoice.address, ROTATED_ADDRESS);
+        assert!(invoice.paid);
+    }
+
+    #[tokio::test]
+    async fn test_load_on_chain_invoice() {
+        let service = mock_service();
+        assert!(service
+            .load_on_chain_invoice(ADDRESS)
+            .await
+            .unwrap()
+            .is_none());
+
+        service.execute(create_command()).await.unwrap();
+        service.execute(confirm_command()).await.unwrap();
+
+        let invoice = service
+            .load_on_chain_invoice(ADDRESS)
+            .await
+            .unwrap()
+            .expect("invoice exists");
+        assert_eq!(invoice.invoice_id, "123".into());
+        assert!(invoice.paid);
+    }
+
+    #[tokio::test]
+    async fn test_reorg_rolls_back_confirmed_invoice() {
+        let store = MemStore::<BtcOnChainInvoice>::default();
+        let views = Arc::new(MemOnChainInvoiceViews::default());
+        let service = service_with(store.clone(), views.clone());
+        service.execute(create_command()).await.unwrap();
+        service
+            .process_event(OnChainTransactionEvent::ReceivedConfirmed(
+                mock_transaction(1),
+            ))
+            .await
+            .unwrap();
+
+        // the confirmation height is found by a service created after a restart
+        let service = service_with(store, views);
+        assert!(
+            service
+                .load_on_chain_invoice(ADDRESS)
+                .await
+                .unwrap()
+                .unwrap()
+                .paid
+        );
+
+        // a reorg above the confirming block does not affect the invoice
+        service.on_reorg(800_001).await.unwrap();
+        assert!(
+            service
+                .load_on_chain_invoice(ADDRESS)
+                .await
+                .unwrap()
+                .unwrap()
+                .paid
+        );
+
+        service.on_reorg(800_000).await.unwrap();
+        let invoice = service
+            .load_on_chain_invoice(ADDRESS)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!invoice.paid);
+        assert_eq!(invoice.confirmations, 0);
+
+        // the payment is confirmed again on the new chain
+        let mut tx = mock_transaction(1);
+        tx.block_height = 800_001;
+        service
+            .process_event(OnChainTransactionEvent::ReceivedConfirmed(tx))
+            .await
+            .unwrap();
+        assert!(
+            service
+                .load_on_chain_invoice(ADDRESS)
+                .await
+                .unwrap()
+                .unwrap()
+                .paid
+        );
+    }
+
+    async fn overpaid_service(
+        payment_api: Arc<MockPaymentApi>,
+    ) -> OnChainService<MemStore<BtcOnChainInvoice>> {
+        let service = mock_service().with_payment_api(payment_api);
+        service.execute(create_command()).await.unwrap();
+        service
+            .execute(OnChainCommand {
+                id: ADDRESS.to_string(),
+                command: OnChainInvoiceCommand::SetConfirmed {
+                    confirmations: 1,
+                    amount: Amount::new(Currency::Btc, 100_500),
+                    transaction_id: "txid".to_string(),
+                    timestamp: None,
+                    block_height: None,
+                },
+            })
+            .await
+            .unwrap();
+        service
+    }
+
+    #[tokio::test]
+    async fn test_refund_overpayment() {
+        let payment_api = Arc::new(MockPaymentApi::default());
+        let service = overpaid_service(payment_api.clone()).await;
+
+        let result = service
+            .refund_overpayment(&"123".into(), REFUND_ADDRESS, FeeRate::from_sat_per_vb(2))
+            .await
+            .unwrap();
+        assert_eq!(result.tx_id, "refund_txid");
+        assert_eq!(result.fee_rate, FeeRate::from_sat_per_vb(2));
+        assert_eq!(
+            *payment_api.sent.lock().await,
+            vec![(
+                REFUND_ADDRESS.to_string(),
+                bitcoin::Amount::from_sat(500),
+                FeeRate::from_sat_per_vb(2)
+            )]
+        );
+
+        let invoice = service
+            .load_on_chain_invoice(ADDRESS)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(invoice.refunded_amount, Amount::new(Currency::Btc, 500));
+        assert_eq!(invoice.overpaid_amount(), Amount::zero(Currency::Btc));
+
+        // nothing left to refund
+        let res = service
+            .refund_overpayment(&"123".into(), REFUND_ADDRESS, FeeRate::from_sat_per_vb(2))
+            .await;
+        assert!(matches!(
+            res,
+            Err(PaydayError::InvoiceError(InvoiceError::InvalidAmount(_)))
+        ));
+        assert_eq!(payment_api.sent.lock().await.len(), 1);
+
+        let res = service
+            .refund_overpayment(&"456".into(), REFUND_ADDRESS, FeeRate::from_sat_per_vb(2))
+            .await;
+        assert!(matches!(res, Err(PaydayError::InvoiceNotFound(_))));
+        assert!(service.refund_locks.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_refunds_send_once() {
+        let payment_api = Arc::new(MockPaymentApi::default());
+        let service = overpaid_service(payment_api.clone()).await;
+        let invoice_id = "123".into();
+        let refund =
+            || service.refund_overpayment(&invoice_id, REFUND_ADDRESS, FeeRate::from_sat_per_vb(2));
+
+        let (first, second) = tokio::join!(refund(), refund());
+        assert!(first.is_ok() != second.is_ok());
+        assert_eq!(payment_api.sent.lock().await.len(), 1);
+        assert!(service.refund_locks.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_failed_refund_releases_reservation() {
+        let payment_api = Arc::new(MockPaymentApi {
+            fails: true,
+            ..Default::default()
+        });
+        let service = overpaid_service(payment_api.clone()).await;
+        let res = service
+            .refund_overpayment(&"123".into(), REFUND_ADDRESS, FeeRate::from_sat_per_vb(2))
+            .await;
+        assert!(matches!(res, Err(PaydayError::InsufficientFunds(_))));
+
+        let invoice = service
+            .load_on_chain_invoice(ADDRESS)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(invoice.pending_refund, None);
+        assert_eq!(invoice.overpaid_amount(), Amount::new(Currency::Btc, 500));
+    }
+
+    #[tokio::test]
+    async fn test_invoice_status_fiat_display() {
+        let rates = FixedExchangeRate::new(HashMap::from([(Currency::Usd, 5_000_000)]));
+        let service = mock_service().with_fiat_display(Arc::new(rates), Currency::Usd);
+        service.execute(create_command()).await.unwrap();
+
+        let status = service.invoice_status(ADDRESS).await.unwrap().unwrap();
+        assert_eq!(status.invoice.amount, Amount::new(Currency::Btc, 100_000));
+        assert_eq!(
+            status.fiat_value.unwrap().amount,
+            Amount::new(Currency::Usd, 5_000)
+        );
+
+        let status = mock_service().invoice_status(ADDRESS).await.unwrap();
+        assert!(status.is_none());
+    }
+
+    /// Runs an invoice through its whole lifecycle: create, receive a payment,
+    /// confirm it, and notify a query about the payment.
+    #[tokio::test]
+    async fn test_invoice_lifecycle() {
+        let notifications = Arc::new(Mutex::new(Vec::new()));
+        let store = MemStore::<BtcOnChainInvoice>::default();
+        let cqrs = CqrsFramework::new(
+            store.clone(),
+            vec![Box::new(NotificationQuery(notifications.clone()))],
+            (),
+        );
+        let service = Arc::new(OnChainService::new(cqrs, store));
+        let processor = OnChainTransactionProcessor::new(
+            "node",
+            Box::new(MemBlockHeightStore::default()),
+            Box::new(service.clone()),
+        );
+
+        service.execute(create_command()).await.unwrap();
+        processor
+            .process_event(OnChainTransactionEvent::ReceivedUnconfirmed(
+                mock_transaction(0),
+            ))
+            .await
+            .unwrap();
+        let invoice = service
+            .load_on_chain_invoice(ADDRESS)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!invoice.paid);
+        assert_eq!(invoice.paid_at, None);
+        assert_eq!(invoice.received_amount, Amount::new(Currency::Btc, 100_000));
+
+        processor
+            .process_event(OnChainTransactionEvent::ReceivedConfirmed(
+                mock_transaction(1),
+            ))
+            .await
+            .unwrap();
+        let invoice = service
+            .load_on_chain_invoice(ADDRESS)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(invoice.paid);
+        assert_eq!(invoice.paid_at, Some(from_timestamp(1_700_000_000)));
+        assert_eq!(invoice.transaction_id, Some("txid".to_string()));
+        assert_eq!(processor.get_block_height().await.unwrap(), Some(800_000));
+
+        assert_eq!(
+            *notifications.lock().await,
+            vec![
+                "OnChainInvoiceCreated".to_string(),
+                "OnChainPaymentPending".to_string(),
+                "OnChainPaymentConfirmed".to_string(),
+            ]
+        );
+    }
+
+    struct NotificationQuery(Arc<Mutex<Vec<String>>>);
+
+    #[async_trait]
+    impl Query<BtcOnChainInvoice> for NotificationQuery {
+        async fn dispatch(&self, _aggregate_id: &str, events: &[EventEnvelope<BtcOnChainInvoice>]) {
+            let mut notifications = self.0.lock().await;
+            for event in events {
+                notifications.push(event.payload.event_type());
+            }
+        }
+    }
+
+    fn mock_transaction(confirmations: i32) -> OnChainTransaction {
+        OnChainTransaction {
+            tx_id: "txid".to_string(),
+            block_height: 800_000,
+            block_hash: Some("hash".to_string()),
+            address: to_address(ADDRESS, Network::Signet).unwrap(),
+            amount: bitcoin::Amount::from_sat(100_000),
+            confirmations,
+            timestamp: Some(from_timestamp(1_700_000_000)),
+        }
+    }
+
+    const REFUND_ADDRESS: &str = "tb1pwrwjsyhgurspa7k7eqlvkphxllqh4yvz2w37hzcv0rpfnq749j2svganhr";
+
+    #[derive(Default)]
+    struct MockPaymentApi {
+        sent: Mutex<Vec<(String, bitcoin::Amount, FeeRate)>>,
+        fails: bool,
+    }
+
+    #[async_trait]
+    impl OnChainPaymentApi for MockPaymentApi {
+        fn validate_address(&self, address: &str) -> PaydayResult<Address> {
+            to_address(address, Network::Signet)
+        }
+
+        async fn estimate_fee(
+            &self,
+            _target_conf: i32,
+            _outputs: HashMap<String, bitcoin::Amount>,
+        ) -> PaydayResult<FeeRate> {
+            Ok(FeeRate::from_sat_per_vb(1))
+        }
+
+        async fn estimate_send_fee(
+            &self,
+            _amount: bitcoin::Amount,
+            _address: String,
+            fee_rate: FeeRate,
+        ) -> PaydayResult<Amount> {
+            // a one input, two output segwit transaction
+            Ok(Amount::new(Currency::Btc, fee_rate.to_sat_per_vb() * 141))
+        }
+
+        async fn send(
+            &self,
+            amount: bitcoin::Amount,
+            address: String,
+            fee_rate: FeeRate,
+            reference: Option<String>,
+        ) -> PaydayResult<OnChainPaymentResult> {
+            self.batch_send(HashMap::from([(address, amount)]), fee_rate, reference)
+                .await
+        }
+
+        async fn batch_send(
+            &self,
+            outputs: HashMap<String, bitcoin::Amount>,
+            fee_rate: FeeRate,
+            _reference: Option<String>,
+        ) -> PaydayResult<OnChainPaymentResult> {
+            if self.fails {
+                return Err(PaydayError::InsufficientFunds("mock".to_string()));
+            }
+            // yield, so concurrent refunds interleave with the send
+            tokio::task::yield_now().await;
+            let mut sent = self.sent.lock().await;
+            for (address, amount) in outputs.iter() {
+                sent.push((address.to_owned(), *amount, fee_rate));
+            }
+            Ok(OnChainPaymentResult::new(
+                "refund_txid".to_string(),
+                &outputs,
+                fee_rate,
+            ))
+        }
+    }
+
+    fn mock_service() -> OnChainService<MemStore<BtcOnChainInvoice>> {
+        service_with(
+            MemStore::default(),
+            Arc::new(MemOnChainInvoiceViews::default()),
+        )
+    }
+
+    fn service_with(
+        store: MemStore<BtcOnChainInvoice>,
+        views: Arc<MemOnChainInvoiceViews>,
+    ) -> OnChainService<MemStore<BtcOnChainInvoice>> {
+        let query = GenericQuery::new(views.clone());
+        let cqrs = CqrsFramework::new(store.clone(), vec![Box::new(query)], ());
+        OnChainService::new(cqrs, store).with_views(views)
+    }
+
+    fn create_command() -> OnChainCommand {
+        OnChainCommand {
+            id: ADDRESS.to_string(),
+            command: OnChainInvoiceCommand::CreateInvoice {
+                invoice_id: "123".into(),
+                amount: Amount::new(Currency::Btc, 100_000),
+                address: ADDRESS.to_string(),
+                webhook_url: None,
+                required_confirmations: 1,
+                underpayment_tolerance: Amount::zero(Currency::Btc),
+                expires_at: None,
+                amount_limits: AmountLimits::default(),
+                confirmation_tiers: ConfirmationTiers::default(),
+            },
+        }
+    }
+
+    fn confirm_command() -> OnChainCommand {
+        OnChainCommand {
+            id: ADDRESS.to_string(),
+            command: OnChainInvoiceCommand::SetConfirmed {
+                confirmations: 1,
+                amount: Amount::new(Currency::Btc, 100_000),
+                transaction_id: "txid".to_string(),
+                timestamp: None,
+                block_height: None,
+            },
+        }
+    }
+}
@@ -0,0 +1,227 @@
+use std::{collections::HashMap, sync::Arc};
+
+use payday_core::{PaydayError, PaydayResult};
+use tokio::{
+    sync::{Mutex, Semaphore},
+    task::{JoinHandle, JoinSet},
+};
+
+use crate::on_chain_api::OnChainStreamApi;
+
+/// Runs the on-chain transaction streams of multiple nodes and allows pausing
+/// a single node, e.g. for maintenance, while the other nodes keep running.
+pub struct OnChainStreams {
+    streams: HashMap<String, Arc<dyn OnChainStreamApi>>,
+    handles: Mutex<HashMap<String, JoinHandle<()>>>,
+    max_concurrent_starts: usize,
+}
+
+impl OnChainStreams {
+    pub fn new(streams: Vec<Arc<dyn OnChainStreamApi>>) -> Self {
+        Self {
+            streams: streams
+                .into_iter()
+                .map(|stream| (stream.node_id(), stream))
+                .collect(),
+            handles: Mutex::new(HashMap::new()),
+            max_concurrent_starts: 1,
+        }
+    }
+
+    /// The number of node subscriptions start establishes at the same time.
+    /// Defaults to one, so nodes subscribe one after another.
+    pub fn with_max_concurrent_starts(mut self, max_concurrent_starts: usize) -> Self {
+        self.max_concurrent_starts = max_concurrent_starts.max(1);
+        self
+    }
+
+    /// Starts the streams of all nodes that are not running. If a node fails to
+    /// subscribe, the other nodes are still started and the first error is
+    /// returned.
+    pub async fn start(&self) -> PaydayResult<()> {
+        let mut handles = self.handles.lock().await;
+        let semaphore = Arc::new(Semaphore::new(self.max_concurrent_starts));
+        let mut starts = JoinSet::new();
+        for (node_id, stream) in self.streams.iter() {
+            if handles.get(node_id).is_some_and(|h| !h.is_finished()) {
+                continue;
+            }
+            let (node_id, stream, semaphore) =
+                (node_id.to_owned(), stream.clone(), semaphore.clone());
+            starts.spawn(async move {
+                let _permit = semaphore.acquire_owned().await;
+                (node_id, stream.process_events().await)
+            });
+        }
+
+        let mut result = Ok(());
+        while let Some(started) = starts.join_next().await {
+            match started {
+                Ok((node_id, Ok(handle))) => {
+                    handles.insert(node_id, handle);
+                }
+                Ok((_, Err(e))) => result = result.and(Err(e)),
+                Err(e) => result = result.and(Err(PaydayError::NodeApiError(e.to_string()))),
+            }
+        }
+        result
+    }
+
+    /// Stops processing events of the node. The processed block height is kept,
+    /// so no events are lost when the node is resumed.
+    pub async fn pause_node(&self, node_id: &str) -> PaydayResult<()> {
+        self.stream(node_id)?;
+        if let Some(handle) = self.handles.lock().await.remove(node_id) {
+            handle.abort();
+        }
+        Ok(())
+    }
+
+    /// Starts processing events of a paused node. The stream catches up from
+    /// the last processed block height before it subscribes to new events.
+    pub async fn resume_node(&self, node_id: &str) -> PaydayResult<()> {
+        let stream = self.stream(node_id)?;
+        let mut handles = self.handles.lock().await;
+        if handles.get(node_id).is_some_and(|h| !h.is_finished()) {
+            return Ok(());
+        }
+        let handle = stream.process_events().await?;
+        handles.insert(node_id.to_string(), handle);
+        Ok(())
+    }
+
+    /// Whether events of the node are currently processed.
+    pub async fn is_running(&self, node_id: &str) -> bool {
+        self.handles
+            .lock()
+            .await
+            .get(node_id)
+            .is_some_and(|h| !h.is_finished())
+    }
+
+    fn stream(&self, node_id: &str) -> PaydayResult<&Arc<dyn OnChainStreamApi>> {
+        self.streams
+            .get(node_id)
+            .ok_or(PaydayError::NodeApiError(format!(
+                "unknown node {}",
+                node_id
+            )))
+    }
+}
+
+impl Drop for OnChainStreams {
+    fn drop(&mut self) {
+        for handle in self.handles.get_mut().values() {
+            handle.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+    use payday_core::node::NodeApi;
+
+    use super::*;
+
+    /// Records the offset each subscription started from.
+    struct MockStream {
+        node_id: String,
+        offset: Arc<Mutex<i32>>,
+        started_from: Arc<Mutex<Vec<i32>>>,
+    }
+
+    impl NodeApi for MockStream {
+        fn node_id(&self) -> String {
+            self.node_id.to_string()
+        }
+    }
+
+    #[async_trait]
+    impl OnChainStreamApi for MockStream {
+        async fn process_events(&self) -> PaydayResult<JoinHandle<()>> {
+            self.started_from
+                .lock()
+                .await
+                .push(*self.offset.lock().await);
+            Ok(tokio::spawn(std::future::pending()))
+        }
+    }
+
+    fn mock_stream(node_id: &str) -> Arc<MockStream> {
+        Arc::new(MockStream {
+            node_id: node_id.to_string(),
+            offset: Arc::new(Mutex::new(0)),
+            started_from: Arc::new(Mutex::new(Vec::new())),
+        })
+    }
+
+    /// Tracks how many subscriptions are established at the same time.
+    struct SlowStream {
+        node_id: String,
+        active: Arc<Mutex<(usize, usize)>>,
+    }
+
+    impl NodeApi for SlowStream {
+        fn node_id(&self) -> String {
+            self.node_id.to_string()
+        }
+    }
+
+    #[async_trait]
+    impl OnChainStreamApi for SlowStream {
+        async fn process_events(&self) -> PaydayResult<JoinHandle<()>> {
+            {
+                let mut active = self.active.lock().await;
+                active.0 += 1;
+                active.1 = active.1.max(active.0);
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            self.active.lock().await.0 -= 1;
+            Ok(tokio::spawn(std::future::pending()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_max_concurrent_starts() {
+        let active = Arc::new(Mutex::new((0, 0)));
+        let nodes: Vec<Arc<dyn OnChainStreamApi>> = (0..20)
+            .map(|i| {
+                Arc::new(SlowStream {
+                    node_id: format!("node{}", i),
+                    active: active.clone(),
+                }) as Arc<dyn OnChainStreamApi>
+            })
+            .collect();
+        let streams = OnChainStreams::new(nodes).with_max_concurrent_starts(3);
+        streams.start().await.unwrap();
+
+        for i in 0..20 {
+            assert!(streams.is_running(&format!("node{}", i)).await);
+        }
+        assert_eq!(*active.lock().await, (0, 3));
+    }
+
+    #[tokio::test]
+    async fn test_pause_and_resume_node() {
+        let node1 = mock_stream("node1");
+        let (offset, started_from) = (node1.offset.clone(), node1.started_from.clone());
+        let streams = OnChainStreams::new(vec![node1, mock_stream("node2")]);
+        streams.start().await.unwrap();
+        assert!(streams.is_running("node1").await);
+
+        *offset.lock().await = 800_000;
+        streams.pause_node("node1").await.unwrap();
+        assert!(!streams.is_running("node1").await);
+        assert!(streams.is_running("node2").await);
+
+        streams.resume_node("node1").await.unwrap();
+        assert!(streams.is_running("node1").await);
+        assert_eq!(*started_from.lock().await, vec![0, 800_000]);
+
+        // resuming a running node does not start a second subscription
+        streams.resume_node("node1").await.unwrap();
+        assert_eq!(started_from.lock().await.len(), 2);
+        assert!(streams.pause_node("unknown").await.is_err());
+    }
+}
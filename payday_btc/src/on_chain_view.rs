@@ -0,0 +1,393 @@
+use async_trait::async_trait;
+use cqrs_es::{
+    persist::{PersistenceError, ViewContext, ViewRepository},
+    EventEnvelope, View,
+};
+use payday_core::{
+    date::{is_past, DateTime},
+    payment::{amount::Amount, invoice::InvoiceId},
+    PaydayResult,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::on_chain_aggregate::{BtcOnChainInvoice, OnChainInvoiceEvent};
+
+/// A flat row of an on-chain invoice for lookups and listings, projected from
+/// the invoice events.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OnChainInvoiceReadModel {
+    pub invoice_id: InvoiceId,
+    pub address: String,
+    /// Addresses the invoice was rotated away from, oldest first.
+    #[serde(default)]
+    pub previous_addresses: Vec<String>,
+    pub amount: Amount,
+    pub received_amount: Amount,
+    pub refunded_amount: Amount,
+    pub confirmations: u64,
+    pub transaction_id: Option<String>,
+    /// Height of the block confirming the payment, None while unconfirmed.
+    #[serde(default)]
+    pub block_height: Option<i32>,
+    pub paid: bool,
+    pub paid_at: Option<DateTime>,
+    /// After this time the invoice can be expired, None if it never expires.
+    #[serde(default)]
+    pub expires_at: Option<DateTime>,
+    pub expired: bool,
+}
+
+impl OnChainInvoiceReadModel {
+    pub fn apply(&mut self, event: &OnChainInvoiceEvent) {
+        match event {
+            OnChainInvoiceEvent::InvoiceCreated {
+                invoice_id,
+                amount,
+                address,
+                expires_at,
+                ..
+            } => {
+                self.invoice_id = invoice_id.to_owned();
+                self.amount = *amount;
+                self.address = address.to_owned();
+                self.expires_at = *expires_at;
+            }
+            OnChainInvoiceEvent::AddressRotated {
+                new_address,
+                previous_address,
+            } => {
+                self.previous_addresses.push(previous_address.to_owned());
+                self.address = new_address.to_owned();
+            }
+            OnChainInvoiceEvent::PaymentPending {
+                received_amount, ..
+            } => {
+                self.received_amount = *received_amount;
+            }
+            OnChainInvoiceEvent::ConfirmationProgress {
+                received_amount,
+                confirmations,
+                block_height,
+                ..
+            } => {
+                self.received_amount = *received_amount;
+                self.confirmations = *confirmations;
+                self.block_height = *block_height;
+            }
+            OnChainInvoiceEvent::PaymentConfirmed {
+                received_amount,
+                confirmations,
+                transaction_id,
+                paid_at,
+                block_height,
+                ..
+            } => {
+                self.received_amount = *received_amount;
+                self.confirmations = *confirmations;
+                self.block_height = *block_height;
+                self.transaction_id = Some(transaction_id.to_owned());
+                self.paid = true;
+                self.paid_at = *paid_at;
+            }
+            OnChainInvoiceEvent::Refunded { amount, .. } => {
+                self.refunded_amount = self.refunded_amount + *amount;
+            }
+            OnChainInvoiceEvent::InvoiceExpired { .. } => {
+                self.expired = true;
+            }
+            OnChainInvoiceEvent::ConfirmationsRolledBack { .. } => {
+                self.confirmations = 0;
+                self.transaction_id = None;
+                self.block_height = None;
+                self.paid = false;
+                self.paid_at = None;
+            }
+            OnChainInvoiceEvent::RefundRequested { .. }
+            | OnChainInvoiceEvent::RefundCancelled { .. } => {}
+        }
+    }
+
+    /// Whether the invoice can still be paid, i.e. it is neither paid nor
+    /// expired and its expiry time has not passed.
+    pub fn is_open(&self) -> bool {
+        !self.paid && !self.expired && !self.expires_at.is_some_and(is_past)
+    }
+}
+
+/// Keeps the read models up to date when used with a GenericQuery.
+impl View<BtcOnChainInvoice> for OnChainInvoiceReadModel {
+    fn update(&mut self, event: &EventEnvelope<BtcOnChainInvoice>) {
+        self.apply(&event.payload);
+    }
+}
+
+/// Lookups of on-chain invoice read models. The read models are written by a
+/// GenericQuery over a view repository of the same storage.
+#[async_trait]
+pub trait OnChainInvoiceViewRepository: Send + Sync {
+    async fn get_invoice(
+        &self,
+        invoice_id: &InvoiceId,
+    ) -> PaydayResult<Option<OnChainInvoiceReadModel>>;
+    /// Returns the aggregate id of the invoice that uses or used the address.
+    async fn find_aggregate_id(&self, address: &str) -> PaydayResult<Option<String>>;
+    /// Returns the aggregate ids of invoices with a payment confirmed in a
+    /// block at or above the height.
+    async fn list_confirmed_from(&self, block_height: i32) -> PaydayResult<Vec<String>>;
+    /// Returns open invoices, oldest first. Paid and expired invoices are left
+    /// out.
+    async fn list_unpaid(
+        &self,
+        limit: u32,
+        offset: u32,
+    ) -> PaydayResult<Vec<OnChainInvoiceReadModel>>;
+    /// Returns the aggregate ids of unpaid invoices past their expiry time that
+    /// are not expired yet, oldest first.
+    async fn list_overdue(&self, limit: u32, offset: u32) -> PaydayResult<Vec<String>>;
+}
+
+/// A read model repository kept in memory. Useful for tests and setups that
+/// rebuild their views on start.
+#[derive(Debug, Default)]
+pub struct MemOnChainInvoiceViews {
+    /// Read models with their version by aggregate id, in creation order.
+    views: Mutex<Vec<(String, i64, OnChainInvoiceReadModel)>>,
+}
+
+#[async_trait]
+impl ViewRepository<OnChainInvoiceReadModel, BtcOnChainInvoice> for MemOnChainInvoiceViews {
+    async fn load(
+        &self,
+        view_id: &str,
+    ) -> Result<Option<OnChainInvoiceReadModel>, PersistenceError> {
+        Ok(self.load_with_context(view_id).await?.map(|(view, _)| view))
+    }
+
+    async fn load_with_context(
+        &self,
+        view_id: &str,
+    ) -> Result<Option<(OnChainInvoiceReadModel, ViewContext)>, PersistenceError> {
+        Ok(self
+            .views
+            .lock()
+            .await
+            .iter()
+            .find(|(id, _, _)| id == view_id)
+            .map(|(id, version, view)| (view.clone(), ViewContext::new(id.to_owned(), *version))))
+    }
+
+    async fn update_view(
+        &self,
+        view: OnChainInvoiceReadModel,
+        context: ViewContext,
+    ) -> Result<(), PersistenceError> {
+        let mut views = self.views.lock().await;
+        match views
+            .iter_mut()
+            .find(|(id, _, _)| id == &context.view_instance_id)
+        {
+            Some((_, version, existing)) if *version == context.version => {
+                *version += 1;
+                *existing = view;
+            }
+            None if context.version == 0 => views.push((context.view_instance_id, 1, view)),
+            _ => return Err(PersistenceError::OptimisticLockError),
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl OnChainInvoiceViewRepository for MemOnChainInvoiceViews {
+    async fn get_invoice(
+        &self,
+        invoice_id: &InvoiceId,
+    ) -> PaydayResult<Option<OnChainInvoiceReadModel>> {
+        Ok(self
+            .views
+            .lock()
+            .await
+            .iter()
+            .find(|(_, _, view)| &view.invoice_id == invoice_id)
+            .map(|(_, _, view)| view.clone()))
+    }
+
+    async fn find_aggregate_id(&self, address: &str) -> PaydayResult<Option<String>> {
+        Ok(self
+            .views
+            .lock()
+            .await
+            .iter()
+            .find(|(_, _, view)| {
+                view.address == address || view.previous_addresses.iter().any(|a| a == address)
+            })
+            .map(|(id, _, _)| id.to_owned()))
+    }
+
+    async fn list_confirmed_from(&self, block_height: i32) -> PaydayResult<Vec<String>> {
+        Ok(self
+            .views
+            .lock()
+            .await
+            .iter()
+            .filter(|(_, _, view)| view.block_height.is_some_and(|h| h >= block_height))
+            .map(|(id, _, _)| id.to_owned())
+            .collect())
+    }
+
+    async fn list_unpaid(
+        &self,
+        limit: u32,
+        offset: u32,
+    ) -> PaydayResult<Vec<OnChainInvoiceReadModel>> {
+        Ok(self
+            .views
+            .lock()
+            .await
+            .iter()
+            .filter(|(_, _, view)| view.is_open())
+            .skip(offset as usize)
+            .take(limit as usize)
+            .map(|(_, _, view)| view.clone())
+            .collect())
+    }
+
+    async fn list_overdue(&self, limit: u32, offset: u32) -> PaydayResult<Vec<String>> {
+        Ok(self
+            .views
+            .lock()
+            .await
+            .iter()
+            .filter(|(_, _, view)| {
+                !view.paid && !view.expired && view.expires_at.is_some_and(is_past)
+            })
+            .skip(offset as usize)
+            .take(limit as usize)
+            .map(|(id, _, _)| id.to_owned())
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use cqrs_es::{mem_store::MemStore, persist::GenericQuery, CqrsFramework};
+    use payday_core::{
+        date::from_timestamp,
+        payment::{currency::Currency, invoice::AmountLimits},
+    };
+
+    use super::*;
+    use crate::{on_chain_aggregate::OnChainInvoiceCommand, ConfirmationTiers};
+
+    #[tokio::test]
+    async fn test_view_reflects_created_and_paid() {
+        let views = Arc::new(MemOnChainInvoiceViews::default());
+        let cqrs = CqrsFramework::new(
+            MemStore::<BtcOnChainInvoice>::default(),
+            vec![Box::new(GenericQuery::new(views.clone()))],
+            (),
+        );
+        let btc = |sats: u64| Amount::new(Currency::Btc, sats);
+        let expired_at = from_timestamp(1_700_000_000);
+        for (id, address, expires_at) in [
+            ("1", "address1", None),
+            ("2", "address2", None),
+            ("3", "address3", Some(expired_at)),
+        ] {
+            cqrs.execute(
+                address,
+                OnChainInvoiceCommand::CreateInvoice {
+                    invoice_id: InvoiceId::new(id),
+                    amount: btc(100_000),
+                    address: address.to_string(),
+                    webhook_url: None,
+                    required_confirmations: 1,
+                    underpayment_tolerance: btc(0),
+                    expires_at,
+                    amount_limits: AmountLimits::default(),
+                    confirmation_tiers: ConfirmationTiers::default(),
+                },
+            )
+            .await
+            .unwrap();
+        }
+
+        let created = views
+            .get_invoice(&InvoiceId::new("1"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(created.address, "address1");
+        assert_eq!(created.amount, btc(100_000));
+        assert!(!created.paid);
+        // invoices past their expiry are not listed before they are expired
+        assert_eq!(views.list_unpaid(10, 0).await.unwrap().len(), 2);
+
+        cqrs.execute(
+            "address1",
+            OnChainInvoiceCommand::SetConfirmed {
+                confirmations: 1,
+                amount: btc(100_000),
+                transaction_id: "txid".to_string(),
+                timestamp: Some(from_timestamp(1_700_000_000)),
+                block_height: None,
+            },
+        )
+        .await
+        .unwrap();
+
+        let paid = views
+            .get_invoice(&InvoiceId::new("1"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(paid.paid);
+        assert_eq!(paid.received_amount, btc(100_000));
+        assert_eq!(paid.transaction_id, Some("txid".to_string()));
+        assert_eq!(paid.paid_at, Some(from_timestamp(1_700_000_000)));
+
+        let unpaid = views.list_unpaid(10, 0).await.unwrap();
+        assert_eq!(unpaid.len(), 1);
+        assert_eq!(unpaid[0].invoice_id, InvoiceId::new("2"));
+        assert!(views.list_unpaid(10, 1).await.unwrap().is_empty());
+        assert!(views
+            .get_invoice(&InvoiceId::new("4"))
+            .await
+            .unwrap()
+            .is_none());
+
+        let expiring = views
+            .get_invoice(&InvoiceId::new("3"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(expiring.expires_at, Some(expired_at));
+        cqrs.execute("address3", OnChainInvoiceCommand::Expire)
+            .await
+            .unwrap();
+        let (expired, context) = views.load_with_context("address3").await.unwrap().unwrap();
+        assert!(expired.expired);
+        assert_eq!(context.version, 2);
+    }
+
+    #[tokio::test]
+    async fn test_stale_view_update_conflicts() {
+        let views = MemOnChainInvoiceViews::default();
+        let view = OnChainInvoiceReadModel::default();
+        views
+            .update_view(view.clone(), ViewContext::new("address1".to_string(), 0))
+            .await
+            .unwrap();
+        let stale = views
+            .update_view(view.clone(), ViewContext::new("address1".to_string(), 0))
+            .await;
+        assert!(matches!(stale, Err(PersistenceError::OptimisticLockError)));
+        views
+            .update_view(view, ViewContext::new("address1".to_string(), 1))
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,229 @@
+use std::{sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use cqrs_es::{AggregateContext, DomainEvent, EventEnvelope, EventStore, Query};
+use payday_core::{
+    events::{
+        publisher::TaskPublisher,
+        task::{RetryType, Task, PRIORITY_HIGH},
+        webhook::{WebhookSigner, WEBHOOK_TASK_TYPE},
+    },
+    PaydayError, PaydayResult,
+};
+
+use crate::{on_chain_aggregate::BtcOnChainInvoice, on_chain_service::to_payday_error};
+
+/// Retries of webhook deliveries the receiver did not accept.
+const WEBHOOK_RETRY: RetryType = RetryType::ExponentialJitter {
+    max_retries: 10,
+    base: Duration::from_secs(10),
+    cap: Duration::from_secs(3600),
+};
+
+/// Publishes a signed webhook task for every committed on-chain invoice event.
+/// Tasks are sent to the notification url of the invoice, invoices without a
+/// webhook and no default url are skipped. Pass it to the queries of the CQRS
+/// framework.
+pub struct OnChainInvoiceWebhooks<ES>
+where
+    ES: EventStore<BtcOnChainInvoice>,
+{
+    store: ES,
+    signer: WebhookSigner,
+    publisher: Arc<dyn TaskPublisher + Send + Sync>,
+    default_url: Option<String>,
+}
+
+impl<ES> OnChainInvoiceWebhooks<ES>
+where
+    ES: EventStore<BtcOnChainInvoice>,
+{
+    /// The store is used to read the notification url of an invoice and must be
+    /// backed by the same event repository as the CQRS framework.
+    pub fn new(
+        store: ES,
+        signer: WebhookSigner,
+        publisher: Arc<dyn TaskPublisher + Send + Sync>,
+    ) -> Self {
+        Self {
+            store,
+            signer,
+            publisher,
+            default_url: None,
+        }
+    }
+
+    /// Sets the url of invoices created without a webhook.
+    pub fn with_default_url(mut self, default_url: &str) -> Self {
+        self.default_url = Some(default_url.to_string());
+        self
+    }
+
+    async fn publish(
+        &self,
+        aggregate_id: &str,
+        events: &[EventEnvelope<BtcOnChainInvoice>],
+    ) -> PaydayResult<()> {
+        let invoice = self
+            .store
+            .load_aggregate(aggregate_id)
+            .await
+            .map_err(to_payday_error)?
+            .aggregate()
+            .clone();
+        let url = match invoice.notification_url(self.default_url.as_deref()) {
+            Some(url) => url,
+            None => return Ok(()),
+        };
+        for event in events {
+            let data = serde_json::to_value(&event.payload)
+                .map_err(|e| PaydayError::EventError(e.to_string()))?;
+            // stays the same when the delivery is retried
+            let event_id = format!("{}-{}", aggregate_id, event.sequence);
+            let signed = self
+                .signer
+                .sign(&event_id, &event.payload.event_type(), data)?;
+            let task = Task::new(WEBHOOK_TASK_TYPE.to_string(), signed.to_request(&url))
+                .with_priority(PRIORITY_HIGH)
+                .with_context("invoice_id", invoice.invoice_id.as_str());
+            self.publisher.retry(task, WEBHOOK_RETRY).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<ES> Query<BtcOnChainInvoice> for OnChainInvoiceWebhooks<ES>
+where
+    ES: EventStore<BtcOnChainInvoice>,
+{
+    async fn dispatch(&self, aggregate_id: &str, events: &[EventEnvelope<BtcOnChainInvoice>]) {
+        if let Err(e) = self.publish(aggregate_id, events).await {
+            println!(
+                "Failed to publish webhooks for on-chain invoice {}: {:?}",
+                aggregate_id, e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cqrs_es::{mem_store::MemStore, CqrsFramework};
+    use payday_core::{
+        events::webhook::{WebhookPayload, WebhookRequest, WEBHOOK_SIGNATURE_HEADER},
+        payment::{
+            amount::Amount,
+            currency::Currency,
+            invoice::{AmountLimits, InvoiceId},
+        },
+    };
+    use tokio::sync::Mutex;
+
+    use super::*;
+    use crate::{on_chain_aggregate::OnChainInvoiceCommand, ConfirmationTiers};
+
+    #[derive(Default)]
+    struct RecordingPublisher(Mutex<Vec<Task>>);
+
+    #[async_trait]
+    impl TaskPublisher for RecordingPublisher {
+        async fn once(&self, task: Task) -> payday_core::events::Result<()> {
+            self.0.lock().await.push(task);
+            Ok(())
+        }
+
+        async fn retry(&self, task: Task, _params: RetryType) -> payday_core::events::Result<()> {
+            self.once(task).await
+        }
+    }
+
+    fn create_invoice(invoice_id: &str, webhook_url: Option<&str>) -> OnChainInvoiceCommand {
+        OnChainInvoiceCommand::CreateInvoice {
+            invoice_id: InvoiceId::new(invoice_id),
+            amount: Amount::new(Currency::Btc, 100_000),
+            address: invoice_id.to_string(),
+            webhook_url: webhook_url.map(|u| u.to_string()),
+            required_confirmations: 1,
+            underpayment_tolerance: Amount::zero(Currency::Btc),
+            expires_at: None,
+            amount_limits: AmountLimits::default(),
+            confirmation_tiers: ConfirmationTiers::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_publishes_signed_webhooks_to_notification_url() {
+        let store = MemStore::<BtcOnChainInvoice>::default();
+        let publisher = Arc::new(RecordingPublisher::default());
+        let webhooks = OnChainInvoiceWebhooks::new(
+            store.clone(),
+            WebhookSigner::new(b"secret"),
+            publisher.clone(),
+        );
+        let cqrs = CqrsFramework::new(store, vec![Box::new(webhooks)], ());
+
+        cqrs.execute(
+            "invoice1",
+            create_invoice("invoice1", Some("https://example.com/invoice1")),
+        )
+        .await
+        .unwrap();
+        cqrs.execute(
+            "invoice1",
+            OnChainInvoiceCommand::SetConfirmed {
+                confirmations: 1,
+                amount: Amount::new(Currency::Btc, 100_000),
+                transaction_id: "txid".to_string(),
+                timestamp: None,
+                block_height: None,
+            },
+        )
+        .await
+        .unwrap();
+        // no webhook and no default url
+        cqrs.execute("invoice2", create_invoice("invoice2", None))
+            .await
+            .unwrap();
+
+        let tasks = publisher.0.lock().await;
+        assert_eq!(tasks.len(), 2);
+        let signer = WebhookSigner::new(b"secret");
+        let payloads: Vec<WebhookPayload> = tasks
+            .iter()
+            .map(|task| {
+                assert_eq!(task.task_type, WEBHOOK_TASK_TYPE);
+                assert_eq!(task.context.get("invoice_id").unwrap(), "invoice1");
+                let request: WebhookRequest = serde_json::from_value(task.payload.clone()).unwrap();
+                assert_eq!(request.url, "https://example.com/invoice1");
+                let signature = request.headers.get(WEBHOOK_SIGNATURE_HEADER).unwrap();
+                assert!(signer.verify(&request.body, signature));
+                serde_json::from_str(&request.body).unwrap()
+            })
+            .collect();
+        assert_eq!(payloads[0].event_type, "OnChainInvoiceCreated");
+        assert_eq!(payloads[0].event_id, "invoice1-1");
+        assert_eq!(payloads[1].event_type, "OnChainPaymentConfirmed");
+        assert_eq!(payloads[1].event_id, "invoice1-2");
+    }
+
+    #[tokio::test]
+    async fn test_default_url() {
+        let store = MemStore::<BtcOnChainInvoice>::default();
+        let publisher = Arc::new(RecordingPublisher::default());
+        let webhooks = OnChainInvoiceWebhooks::new(
+            store.clone(),
+            WebhookSigner::new(b"secret"),
+            publisher.clone(),
+        )
+        .with_default_url("https://example.com/global");
+        let cqrs = CqrsFramework::new(store, vec![Box::new(webhooks)], ());
+        cqrs.execute("invoice1", create_invoice("invoice1", None))
+            .await
+            .unwrap();
+
+        let tasks = publisher.0.lock().await;
+        let request: WebhookRequest = serde_json::from_value(tasks[0].payload.clone()).unwrap();
+        assert_eq!(request.url, "https://example.com/global");
+    }
+}
@@ -20,10 +20,132 @@ pub fn from_timestamp_millis(timestamp: i64) -> DateTime {
 
 /// Returns a date and time after given duration
 pub fn date_after(duration: Duration) -> DateTime {
-    now() + duration
+    add_duration(now(), duration)
+}
+
+/// Returns the given date and time shifted by duration
+pub fn add_duration(date: DateTime, duration: Duration) -> DateTime {
+    date + duration
+}
+
+/// Returns true if the given date and time is in the past
+pub fn is_past(date: DateTime) -> bool {
+    date < now()
+}
+
+/// Returns the duration until the given date and time or None if it is in the past
+pub fn until(date: DateTime) -> Option<Duration> {
+    (date - now()).to_std().ok()
 }
 
 /// Returns current date and time with given offset in seconds
 pub fn after_seconds(seconds: u64) -> DateTime {
     date_after(Duration::from_secs(seconds))
 }
+
+/// Serializes a date and time as an RFC3339 string.
+/// Use with `#[serde(with = "payday_core::date::rfc3339")]`.
+pub mod rfc3339 {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    use super::DateTime;
+
+    pub fn serialize<S>(date: &DateTime, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        s.serialize_str(&date.to_rfc3339())
+    }
+
+    pub fn deserialize<'de, D>(d: D) -> Result<DateTime, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let value = String::deserialize(d)?;
+        chrono::DateTime::parse_from_rfc3339(&value)
+            .map(|date| date.to_utc())
+            .map_err(D::Error::custom)
+    }
+}
+
+/// Serializes a date and time as a unix timestamp in seconds.
+/// Use with `#[serde(with = "payday_core::date::timestamp")]`.
+pub mod timestamp {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    use super::DateTime;
+
+    pub fn serialize<S>(date: &DateTime, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        s.serialize_i64(date.timestamp())
+    }
+
+    pub fn deserialize<'de, D>(d: D) -> Result<DateTime, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let value = i64::deserialize(d)?;
+        DateTime::from_timestamp(value, 0)
+            .ok_or_else(|| D::Error::custom(format!("invalid timestamp: {}", value)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Dates {
+        #[serde(with = "rfc3339")]
+        rfc3339: DateTime,
+        #[serde(with = "timestamp")]
+        timestamp: DateTime,
+    }
+
+    #[test]
+    fn test_is_past() {
+        let fixed = from_timestamp(1_700_000_000);
+        assert!(is_past(fixed));
+        assert!(!is_past(add_duration(now(), Duration::from_secs(60))));
+    }
+
+    #[test]
+    fn test_until() {
+        assert_eq!(until(from_timestamp(1_700_000_000)), None);
+        let remaining = until(after_seconds(60)).expect("date in the future");
+        assert!(remaining <= Duration::from_secs(60));
+        assert!(remaining > Duration::from_secs(50));
+    }
+
+    #[test]
+    fn test_add_duration() {
+        let fixed = from_timestamp(1_700_000_000);
+        assert_eq!(
+            add_duration(fixed, Duration::from_secs(30)),
+            from_timestamp(1_700_000_030)
+        );
+    }
+
+    #[test]
+    fn test_date_serde_round_trip() {
+        let date = from_timestamp(1_700_000_000);
+        let dates = Dates {
+            rfc3339: date,
+            timestamp: date,
+        };
+        let json = serde_json::to_value(&dates).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "rfc3339": "2023-11-14T22:13:20+00:00",
+                "timestamp": 1_700_000_000,
+            })
+        );
+        let parsed: Dates = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed, dates);
+    }
+}
@@ -3,6 +3,7 @@ use bitcoin::amount::ParseAmountError;
 use bitcoin::network::ParseNetworkError;
 
 use crate::events::MessageError;
+use crate::payment::invoice::InvoiceError;
 
 #[derive(Debug)]
 pub enum PaydayError {
@@ -12,7 +13,37 @@ pub enum PaydayError {
     InvalidBitcoinAddress(String),
     InvalidBitcoinNetwork(String),
     InvalidBitcoinAmount(String),
+    InvalidAmount(String),
+    InsufficientFunds(String),
+    FeeRateTooLow(String),
+    InvalidLightningOffer(String),
+    InvalidPaymentUri(String),
+    InvalidPaymentType(String),
     EventError(String),
+    LightningPaymentFailed(PaymentFailureReason),
+    TaskFailed(String),
+    InvoiceNotFound(String),
+    /// A configuration value is missing or malformed.
+    InvalidConfig(String),
+    /// A command was rejected by the invoice.
+    InvoiceError(InvoiceError),
+}
+
+/// The reason an outgoing lightning payment failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaymentFailureReason {
+    /// The payment did not fail or no reason is known.
+    None,
+    /// The payment did not complete within the timeout.
+    Timeout,
+    /// No route to the destination was found.
+    NoRoute,
+    /// A non recoverable error occurred.
+    Error,
+    /// The destination rejected the payment details (unknown hash, wrong amount).
+    IncorrectPaymentDetails,
+    /// The local balance is insufficient.
+    InsufficientBalance,
 }
 
 impl From<ParseNetworkError> for PaydayError {
@@ -32,6 +63,12 @@ impl From<ParseAmountError> for PaydayError {
     }
 }
 
+impl From<InvoiceError> for PaydayError {
+    fn from(value: InvoiceError) -> Self {
+        PaydayError::InvoiceError(value)
+    }
+}
+
 impl From<MessageError> for PaydayError {
     fn from(value: MessageError) -> Self {
         match value {
@@ -44,6 +81,10 @@ impl From<MessageError> for PaydayError {
             MessageError::ConfirmError(m) => {
                 PaydayError::EventError(format!("unable to confirm event processing: {}", m))
             }
+            MessageError::PayloadTooLarge(size, max) => PaydayError::EventError(format!(
+                "payload of {} bytes exceeds maximum of {} bytes",
+                size, max
+            )),
         }
     }
 }
@@ -6,6 +6,7 @@ use serde_json::Value;
 pub mod handler;
 pub mod publisher;
 pub mod task;
+pub mod webhook;
 
 pub type Result<T> = std::result::Result<T, MessageError>;
 
@@ -14,6 +15,8 @@ pub enum MessageError {
     PublishError(String),
     SubscribeError(String),
     ConfirmError(String),
+    /// The serialized payload exceeds the configured maximum (size, max).
+    PayloadTooLarge(usize, usize),
 }
 
 /// A unique name for this event type. Not an enum so application can define their own.
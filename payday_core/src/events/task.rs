@@ -1,18 +1,31 @@
-use std::time::Duration;
+use std::{collections::HashMap, time::Duration};
 
 use async_trait::async_trait;
+use rand::Rng;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::Value;
 use tokio::task::JoinHandle;
 
-use crate::date::{date_after, now, DateTime};
+use crate::date::{add_duration, date_after, now, DateTime};
 use crate::events::Result;
 
-use super::{Message, MessageType};
+use super::{Message, MessageError, MessageType};
 
 /// A unique name for this task type. Not an enum so application can define their own.
 pub type TaskType = String;
 
+/// Priority for tasks that can wait, e.g. cleanups or notifications.
+pub const PRIORITY_LOW: u8 = 0;
+/// Default priority of tasks.
+pub const PRIORITY_NORMAL: u8 = 100;
+/// Priority for urgent tasks, e.g. payment confirmations.
+pub const PRIORITY_HIGH: u8 = 200;
+
+/// Priority of tasks stored before priorities existed.
+pub fn default_priority() -> u8 {
+    PRIORITY_NORMAL
+}
+
 /// Status of a task.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum TaskStatus {
@@ -40,22 +53,35 @@ pub enum RetryType {
     Fixed(u32, Duration),
     /// Retry the task with an exponential backoff.
     Exponential(u32, Duration),
+    /// Retry the task with an exponential backoff of at most cap and a random
+    /// jitter of up to 20%, so failed tasks are not retried all at once.
+    ExponentialJitter {
+        max_retries: u32,
+        base: Duration,
+        cap: Duration,
+    },
 }
 
 impl RetryType {
     pub fn is_retry(&self) -> bool {
-        match self {
-            RetryType::Fixed(..) => true,
-            RetryType::Exponential(..) => true,
-            _ => false,
-        }
+        matches!(
+            self,
+            RetryType::Fixed(..) | RetryType::Exponential(..) | RetryType::ExponentialJitter { .. }
+        )
     }
-    pub fn next_retry(&self) -> Option<DateTime> {
+
+    /// The time of the next retry after num_retry retries.
+    pub fn next_retry(&self, num_retry: u32) -> Option<DateTime> {
         match self {
-            RetryType::Fixed(_, d) => Some(now() + fixed_backoff(d.as_secs() as u32)),
-            RetryType::Exponential(r, d) => {
-                Some(now() + exponential_backoff(*r, d.as_secs() as u32))
-            }
+            RetryType::Fixed(_, d) => Some(add_duration(now(), fixed_backoff(d.as_secs() as u32))),
+            RetryType::Exponential(_, d) => Some(add_duration(
+                now(),
+                exponential_backoff(num_retry, d.as_secs() as u32),
+            )),
+            RetryType::ExponentialJitter { base, cap, .. } => Some(add_duration(
+                now(),
+                jitter(capped_backoff(num_retry, *base, *cap)),
+            )),
             _ => None,
         }
     }
@@ -65,12 +91,52 @@ impl RetryType {
 pub struct Task {
     pub task_type: TaskType,
     pub payload: Value,
+    /// Tasks with a higher priority are processed first.
+    #[serde(default = "default_priority")]
+    pub priority: u8,
+    /// Key-value pairs for tracing, e.g. the invoice id and a correlation id
+    /// of the processing that enqueued the task. Handed to the handler with
+    /// the task.
+    #[serde(default)]
+    pub context: HashMap<String, String>,
 }
 
 impl Task {
     pub fn new<T: Serialize>(task_type: TaskType, payload: T) -> Self {
         let payload = serde_json::to_value(payload).expect("could not serialize payload");
-        Self { task_type, payload }
+        Self {
+            task_type,
+            payload,
+            priority: PRIORITY_NORMAL,
+            context: HashMap::new(),
+        }
+    }
+
+    pub fn with_priority(mut self, priority: u8) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn with_context(mut self, key: &str, value: &str) -> Self {
+        self.context.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// Returns the size of the serialized payload in bytes.
+    pub fn payload_size(&self) -> usize {
+        serde_json::to_vec(&self.payload)
+            .map(|v| v.len())
+            .unwrap_or(0)
+    }
+
+    /// Checks the serialized payload against an optional maximum size in bytes.
+    pub fn check_payload_size(&self, max_payload_size: Option<usize>) -> Result<()> {
+        match max_payload_size {
+            Some(max) if self.payload_size() > max => {
+                Err(MessageError::PayloadTooLarge(self.payload_size(), max))
+            }
+            _ => Ok(()),
+        }
     }
 }
 
@@ -84,6 +150,30 @@ impl Message for Task {
     }
 }
 
+/// Selects dead-lettered tasks, i.e. tasks that failed and will not be retried
+/// anymore. An empty filter selects all of them.
+#[derive(Debug, Clone, Default)]
+pub struct DeadLetterFilter {
+    pub task_type: Option<TaskType>,
+    /// Tasks that failed at or after this time.
+    pub from: Option<DateTime>,
+    /// Tasks that failed before this time.
+    pub to: Option<DateTime>,
+}
+
+impl DeadLetterFilter {
+    pub fn with_task_type(mut self, task_type: &str) -> Self {
+        self.task_type = Some(task_type.to_string());
+        self
+    }
+
+    pub fn with_date_range(mut self, from: DateTime, to: DateTime) -> Self {
+        self.from = Some(from);
+        self.to = Some(to);
+        self
+    }
+}
+
 /// Returns a fixed backoff duration.
 pub fn fixed_backoff(offset: u32) -> Duration {
     Duration::from_secs(offset as u64)
@@ -93,3 +183,91 @@ pub fn fixed_backoff(offset: u32) -> Duration {
 pub fn exponential_backoff(count: u32, offset: u32) -> Duration {
     Duration::from_secs(offset as u64 * 2_u64.pow(count))
 }
+
+/// Returns an exponential backoff duration of at most cap. Saturates at cap
+/// instead of overflowing for large counts.
+pub fn capped_backoff(count: u32, base: Duration, cap: Duration) -> Duration {
+    2_u32
+        .checked_pow(count)
+        .and_then(|factor| base.checked_mul(factor))
+        .map_or(cap, |backoff| backoff.min(cap))
+}
+
+/// Returns the duration shifted randomly by up to 20% in either direction.
+pub fn jitter(duration: Duration) -> Duration {
+    duration.mul_f64(rand::thread_rng().gen_range(0.8..=1.2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_payload_size_rejects_oversized() {
+        let task = Task::new("test".to_string(), "x".repeat(1024));
+        let res = task.check_payload_size(Some(512));
+        assert!(matches!(res, Err(MessageError::PayloadTooLarge(_, 512))));
+    }
+
+    #[test]
+    fn test_task_priority() {
+        let task = Task::new("test".to_string(), "payload");
+        assert_eq!(task.priority, PRIORITY_NORMAL);
+        assert_eq!(task.with_priority(PRIORITY_HIGH).priority, PRIORITY_HIGH);
+
+        let stored: Task =
+            serde_json::from_str(r#"{"task_type": "test", "payload": "payload"}"#).unwrap();
+        assert_eq!(stored.priority, PRIORITY_NORMAL);
+    }
+
+    #[test]
+    fn test_capped_backoff() {
+        let (base, cap) = (Duration::from_secs(2), Duration::from_secs(300));
+        assert_eq!(capped_backoff(0, base, cap), base);
+        assert_eq!(capped_backoff(3, base, cap), Duration::from_secs(16));
+        for count in [8, 31, 32, 64, u32::MAX] {
+            assert_eq!(capped_backoff(count, base, cap), cap);
+        }
+    }
+
+    #[test]
+    fn test_jitter_within_bounds() {
+        let duration = Duration::from_secs(100);
+        for _ in 0..1_000 {
+            let jittered = jitter(duration);
+            assert!(jittered >= Duration::from_secs(80));
+            assert!(jittered <= Duration::from_secs(120));
+        }
+
+        let retry = RetryType::ExponentialJitter {
+            max_retries: 100,
+            base: Duration::from_secs(1),
+            cap: Duration::from_secs(60),
+        };
+        assert!(retry.is_retry());
+        let next_retry = retry.next_retry(u32::MAX).unwrap();
+        assert!(next_retry <= now() + Duration::from_secs(72));
+        assert!(next_retry >= now() + Duration::from_secs(47));
+    }
+
+    #[test]
+    fn test_task_context() {
+        let task = Task::new("test".to_string(), "payload")
+            .with_context("invoice_id", "123")
+            .with_context("correlation_id", "abc");
+        let stored: Task = serde_json::from_value(serde_json::to_value(&task).unwrap()).unwrap();
+        assert_eq!(stored.context, task.context);
+        assert_eq!(stored.context.get("invoice_id").unwrap(), "123");
+
+        let stored: Task =
+            serde_json::from_str(r#"{"task_type": "test", "payload": "payload"}"#).unwrap();
+        assert!(stored.context.is_empty());
+    }
+
+    #[test]
+    fn test_payload_size_accepts_normal() {
+        let task = Task::new("test".to_string(), "small payload");
+        assert!(task.check_payload_size(Some(512)).is_ok());
+        assert!(task.check_payload_size(None).is_ok());
+    }
+}
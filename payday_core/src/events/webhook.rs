@@ -0,0 +1,309 @@
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use async_trait::async_trait;
+use bitcoin::hashes::{hmac, sha256, Hash, HashEngine};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::date::{now, DateTime};
+
+use super::{
+    handler::TaskHandler,
+    task::{Task, TaskResult},
+    MessageError, MessageType, Result,
+};
+
+/// The task type of webhook deliveries.
+pub const WEBHOOK_TASK_TYPE: &str = "webhook";
+
+/// The header carrying the signature of a signed webhook delivery.
+pub const WEBHOOK_SIGNATURE_HEADER: &str = "X-Payday-Signature";
+
+/// The body of a webhook delivery. The signature proves the origin of a
+/// delivery but does not prevent a captured delivery from being sent again, so
+/// receivers should reject deliveries with a stale timestamp and delivery ids
+/// they have already seen.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WebhookPayload {
+    /// Increases with every delivery, also across restarts.
+    pub delivery_id: u64,
+    /// Time of the delivery as unix timestamp in seconds.
+    #[serde(with = "crate::date::timestamp")]
+    pub timestamp: DateTime,
+    /// Unique id of the delivered event. Retries of a delivery keep the id.
+    pub event_id: String,
+    pub event_type: MessageType,
+    pub data: Value,
+}
+
+/// A serialized webhook payload and the hex encoded HMAC-SHA256 of it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SignedWebhook {
+    pub body: String,
+    pub signature: String,
+}
+
+impl SignedWebhook {
+    /// The request delivering this webhook to the url, with the signature in
+    /// the signature header.
+    pub fn to_request(&self, url: &str) -> WebhookRequest {
+        WebhookRequest {
+            url: url.to_string(),
+            body: self.body.to_owned(),
+            headers: HashMap::from([(
+                WEBHOOK_SIGNATURE_HEADER.to_string(),
+                self.signature.to_owned(),
+            )]),
+        }
+    }
+}
+
+/// Builds and signs webhook payloads with a shared secret. The signature is
+/// computed over the exact body, so it covers the delivery id, timestamp and
+/// event id.
+pub struct WebhookSigner {
+    secret: Vec<u8>,
+    last_delivery_id: AtomicU64,
+}
+
+impl WebhookSigner {
+    pub fn new(secret: &[u8]) -> Self {
+        Self {
+            secret: secret.to_vec(),
+            last_delivery_id: AtomicU64::new(0),
+        }
+    }
+
+    pub fn sign(&self, event_id: &str, event_type: &str, data: Value) -> Result<SignedWebhook> {
+        let payload = WebhookPayload {
+            delivery_id: self.next_delivery_id(),
+            timestamp: now(),
+            event_id: event_id.to_string(),
+            event_type: event_type.to_string(),
+            data,
+        };
+        let body = serde_json::to_string(&payload)
+            .map_err(|e| MessageError::PublishError(e.to_string()))?;
+        let signature = self.signature(&body);
+        Ok(SignedWebhook { body, signature })
+    }
+
+    /// Whether the signature matches the body.
+    pub fn verify(&self, body: &str, signature: &str) -> bool {
+        let expected = self.signature(body);
+        expected.len() == signature.len()
+            && expected
+                .bytes()
+                .zip(signature.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+
+    fn signature(&self, body: &str) -> String {
+        let mut engine = hmac::HmacEngine::<sha256::Hash>::new(&self.secret);
+        engine.input(body.as_bytes());
+        hmac::Hmac::<sha256::Hash>::from_engine(engine).to_string()
+    }
+
+    /// Delivery ids start at the current time in microseconds, so they keep
+    /// increasing after a restart.
+    fn next_delivery_id(&self) -> u64 {
+        let clock = now().timestamp_micros() as u64;
+        let next = |last: u64| (last + 1).max(clock);
+        let last = self
+            .last_delivery_id
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| Some(next(last)))
+            .expect("delivery id update never fails");
+        next(last)
+    }
+}
+
+/// The payload of a webhook delivery task, e.g. a signed webhook with the
+/// signature in a header.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WebhookRequest {
+    pub url: String,
+    pub body: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+/// A minimal HTTP client, so deliveries can be tested without a server.
+#[async_trait]
+pub trait HttpPostApi: Send + Sync {
+    /// Posts the body to the url and returns the response status code. Errors
+    /// without a response, e.g. timeouts, are returned as errors.
+    async fn post(&self, request: &WebhookRequest) -> Result<u16>;
+}
+
+#[async_trait]
+impl HttpPostApi for reqwest::Client {
+    async fn post(&self, request: &WebhookRequest) -> Result<u16> {
+        let mut builder = self
+            .post(&request.url)
+            .header("Content-Type", "application/json")
+            .body(request.body.to_owned());
+        for (name, value) in request.headers.iter() {
+            builder = builder.header(name, value);
+        }
+        let response = builder
+            .send()
+            .await
+            .map_err(|e| MessageError::PublishError(e.to_string()))?;
+        Ok(response.status().as_u16())
+    }
+}
+
+/// Delivers webhook tasks. Server errors, rate limits and requests without a
+/// response are retried, other client errors and invalid payloads fail.
+pub struct WebhookTaskHandler {
+    client: Box<dyn HttpPostApi>,
+}
+
+impl WebhookTaskHandler {
+    /// Timeouts are configured on the client, e.g. a reqwest::Client built
+    /// with a timeout.
+    pub fn new(client: Box<dyn HttpPostApi>) -> Self {
+        Self { client }
+    }
+}
+
+#[async_trait]
+impl TaskHandler for WebhookTaskHandler {
+    fn allow_retry(&self) -> bool {
+        true
+    }
+
+    fn allow_recovery(&self) -> bool {
+        true
+    }
+
+    fn handles(&self, task_type: &str) -> bool {
+        task_type == WEBHOOK_TASK_TYPE
+    }
+
+    async fn handle(&self, task: Task) -> Result<TaskResult> {
+        let request: WebhookRequest = match serde_json::from_value(task.payload) {
+            Ok(request) => request,
+            Err(e) => {
+                println!("Invalid webhook task payload: {}", e);
+                return Ok(TaskResult::Failed);
+            }
+        };
+        let result = match self.client.post(&request).await {
+            Ok(200..=299) => TaskResult::Success,
+            Ok(408 | 429 | 500..=599) => TaskResult::Retry,
+            Ok(status) => {
+                println!("Webhook to {} rejected with {}", request.url, status);
+                TaskResult::Failed
+            }
+            Err(e) => {
+                println!("Webhook to {} failed: {:?}", request.url, e);
+                TaskResult::Retry
+            }
+        };
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::Mutex;
+
+    use super::*;
+
+    /// Responds with the given status codes in order, None for a timeout.
+    struct MockHttp {
+        responses: Mutex<Vec<Option<u16>>>,
+    }
+
+    #[async_trait]
+    impl HttpPostApi for MockHttp {
+        async fn post(&self, _request: &WebhookRequest) -> Result<u16> {
+            self.responses
+                .lock()
+                .await
+                .remove(0)
+                .ok_or(MessageError::PublishError("timeout".to_string()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_webhook_task_result_mapping() {
+        let responses = vec![Some(200), Some(503), None, Some(429), Some(404), Some(400)];
+        let handler = WebhookTaskHandler::new(Box::new(MockHttp {
+            responses: Mutex::new(responses.clone()),
+        }));
+        assert!(handler.handles(WEBHOOK_TASK_TYPE));
+        assert!(!handler.handles("other"));
+        assert!(handler.allow_retry());
+
+        let request = WebhookRequest {
+            url: "https://example.com/hook".to_string(),
+            body: "{}".to_string(),
+            headers: HashMap::from([("X-Signature".to_string(), "sig".to_string())]),
+        };
+        let mut results = Vec::new();
+        for _ in responses {
+            let task = Task::new(WEBHOOK_TASK_TYPE.to_string(), &request);
+            results.push(handler.handle(task).await.unwrap());
+        }
+        assert!(matches!(
+            results.as_slice(),
+            [
+                TaskResult::Success,
+                TaskResult::Retry,
+                TaskResult::Retry,
+                TaskResult::Retry,
+                TaskResult::Failed,
+                TaskResult::Failed,
+            ]
+        ));
+
+        let invalid = Task::new(WEBHOOK_TASK_TYPE.to_string(), serde_json::json!({}));
+        assert!(matches!(
+            handler.handle(invalid).await.unwrap(),
+            TaskResult::Failed
+        ));
+    }
+
+    #[test]
+    fn test_signed_payload_binds_replay_fields() {
+        let signer = WebhookSigner::new(b"secret");
+        let first = signer
+            .sign("event-1", "OnChainPaymentConfirmed", serde_json::json!({}))
+            .unwrap();
+        let second = signer
+            .sign("event-2", "OnChainPaymentConfirmed", serde_json::json!({}))
+            .unwrap();
+        assert!(signer.verify(&first.body, &first.signature));
+        assert!(!WebhookSigner::new(b"other").verify(&first.body, &first.signature));
+
+        let payload: WebhookPayload = serde_json::from_str(&first.body).unwrap();
+        let next: WebhookPayload = serde_json::from_str(&second.body).unwrap();
+        assert_eq!(payload.event_id, "event-1");
+        assert!(next.delivery_id > payload.delivery_id);
+
+        let tampered = [
+            WebhookPayload {
+                delivery_id: payload.delivery_id + 1,
+                ..payload.clone()
+            },
+            WebhookPayload {
+                timestamp: now() + std::time::Duration::from_secs(60),
+                ..payload.clone()
+            },
+            WebhookPayload {
+                event_id: "event-2".to_string(),
+                ..payload.clone()
+            },
+        ];
+        for payload in tampered {
+            let body = serde_json::to_string(&payload).unwrap();
+            assert!(!signer.verify(&body, &first.signature));
+        }
+    }
+}
@@ -7,8 +7,12 @@ pub use error::PaydayError;
 pub mod date;
 pub mod error;
 pub mod events;
+pub mod node;
 pub mod payment;
 pub mod persistence;
+pub mod retry;
+pub mod shutdown;
+pub mod supervisor;
 
 pub type PaydayResult<T> = Result<T, PaydayError>;
 pub type PaydayStream<T> = Pin<Box<dyn Stream<Item = T>>>;
@@ -0,0 +1,5 @@
+/// Common interface of all node facing types. The node id is the configured
+/// name of a node and identifies it in logs, stored block heights and events.
+pub trait NodeApi: Send + Sync {
+    fn node_id(&self) -> String;
+}
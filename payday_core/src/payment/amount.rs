@@ -1,8 +1,19 @@
-use std::fmt::{Display, Formatter};
+use std::{
+    fmt::{Display, Formatter},
+    ops::{Add, Sub},
+    str::FromStr,
+};
 
+use bitcoin::Denomination;
 use serde::{Deserialize, Serialize};
 
-use crate::payment::currency::Currency;
+use crate::{
+    payment::{
+        currency::Currency,
+        invoice::{InvoiceError, InvoiceResult},
+    },
+    PaydayError, PaydayResult,
+};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Amount {
@@ -21,6 +32,81 @@ impl Amount {
             amount: 0,
         }
     }
+
+    /// A BTC amount from millisatoshis. Amounts are accounted in whole
+    /// satoshis, a sub-satoshi remainder is rounded down.
+    pub fn msats(msats: u64) -> Self {
+        Self::new(Currency::Btc, msats / 1_000)
+    }
+
+    /// The amount in millisatoshis. Only meaningful for BTC amounts.
+    pub fn to_msats(&self) -> u64 {
+        self.amount.saturating_mul(1_000)
+    }
+
+    /// A BTC amount from a decimal BTC value rounded to the nearest satoshi.
+    /// Fails for negative, non finite and out of range values.
+    pub fn from_btc(btc: f64) -> PaydayResult<Self> {
+        let sats = (btc * 100_000_000.0).round();
+        if !sats.is_finite() || sats < 0.0 || sats >= u64::MAX as f64 {
+            return Err(PaydayError::InvalidBitcoinAmount(format!(
+                "invalid BTC amount {}",
+                btc
+            )));
+        }
+        Ok(Self::new(Currency::Btc, sats as u64))
+    }
+
+    /// Adds other, None if the currencies differ or the sum overflows.
+    pub fn checked_add(&self, other: &Amount) -> Option<Amount> {
+        if self.currency != other.currency {
+            return None;
+        }
+        self.amount
+            .checked_add(other.amount)
+            .map(|amount| Self::new(self.currency, amount))
+    }
+
+    /// Subtracts other, None if the currencies differ or other is greater.
+    pub fn checked_sub(&self, other: &Amount) -> Option<Amount> {
+        if self.currency != other.currency {
+            return None;
+        }
+        self.amount
+            .checked_sub(other.amount)
+            .map(|amount| Self::new(self.currency, amount))
+    }
+
+    /// Whether this amount is greater than other. Fails if the currencies
+    /// differ, as the raw amounts are not comparable then.
+    pub fn gt_checked(&self, other: &Amount) -> InvoiceResult<bool> {
+        self.check_currency(other)?;
+        Ok(self.amount > other.amount)
+    }
+
+    /// Whether this amount is less than other. Fails if the currencies differ.
+    pub fn lt_checked(&self, other: &Amount) -> InvoiceResult<bool> {
+        self.check_currency(other)?;
+        Ok(self.amount < other.amount)
+    }
+
+    /// Whether this amount equals other. Fails if the currencies differ.
+    pub fn eq_checked(&self, other: &Amount) -> InvoiceResult<bool> {
+        self.check_currency(other)?;
+        Ok(self.amount == other.amount)
+    }
+
+    /// Other is the amount compared against, so its currency is the required
+    /// one.
+    fn check_currency(&self, other: &Amount) -> InvoiceResult<()> {
+        if self.currency != other.currency {
+            return Err(InvoiceError::InvalidCurrency(
+                other.currency.to_string(),
+                self.currency.to_string(),
+            ));
+        }
+        Ok(())
+    }
 }
 
 impl Default for Amount {
@@ -32,8 +118,330 @@ impl Default for Amount {
     }
 }
 
+/// Panics if the currencies differ or the sum overflows, use checked_add
+/// where that is possible.
+impl Add for Amount {
+    type Output = Amount;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        self.checked_add(&rhs)
+            .unwrap_or_else(|| panic!("can not add {} to {}", rhs, self))
+    }
+}
+
+/// Panics if the currencies differ or rhs is greater, use checked_sub where
+/// that is possible.
+impl Sub for Amount {
+    type Output = Amount;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        self.checked_sub(&rhs)
+            .unwrap_or_else(|| panic!("can not subtract {} from {}", rhs, self))
+    }
+}
+
+impl From<bitcoin::Amount> for Amount {
+    fn from(value: bitcoin::Amount) -> Self {
+        Self::new(Currency::Btc, value.to_sat())
+    }
+}
+
+/// Converts to a bitcoin amount at the node boundary. Amounts of other
+/// currencies are rejected instead of being taken as sats.
+impl TryFrom<Amount> for bitcoin::Amount {
+    type Error = InvoiceError;
+
+    fn try_from(value: Amount) -> Result<Self, Self::Error> {
+        if value.currency != Currency::Btc {
+            return Err(InvoiceError::InvalidCurrency(
+                Currency::Btc.to_string(),
+                value.currency.to_string(),
+            ));
+        }
+        Ok(bitcoin::Amount::from_sat(value.amount))
+    }
+}
+
+/// BTC amounts are shown in sats, e.g. "100000 sats", fiat amounts in the
+/// major unit with the decimals of the currency, e.g. "10.00 USD".
 impl Display for Amount {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{} {}", self.amount, self.currency)
+        if self.currency == Currency::Btc {
+            return write!(f, "{} sats", self.amount);
+        }
+        let decimals = self.currency.decimal_places();
+        let unit = 10u64.pow(decimals);
+        write!(
+            f,
+            "{}.{:0width$} {}",
+            self.amount / unit,
+            self.amount % unit,
+            self.currency,
+            width = decimals as usize
+        )
+    }
+}
+
+/// Parses the Display format back. Only the canonical format is accepted,
+/// fiat amounts need all decimals of the currency.
+impl FromStr for Amount {
+    type Err = PaydayError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || PaydayError::InvalidAmount(s.to_string());
+        let (value, unit) = s.split_once(' ').ok_or_else(invalid)?;
+        let is_digits = |v: &str| !v.is_empty() && v.bytes().all(|b| b.is_ascii_digit());
+        if unit == "sats" {
+            if !is_digits(value) {
+                return Err(invalid());
+            }
+            let sats = value.parse().map_err(|_| invalid())?;
+            return Ok(Amount::new(Currency::Btc, sats));
+        }
+
+        let currency = Currency::from_str(unit).map_err(|_| invalid())?;
+        if currency == Currency::Btc {
+            return Err(invalid());
+        }
+        let (major, minor) = value.split_once('.').ok_or_else(invalid)?;
+        if !is_digits(major)
+            || !is_digits(minor)
+            || minor.len() != currency.decimal_places() as usize
+        {
+            return Err(invalid());
+        }
+        let amount = major
+            .parse::<u64>()
+            .ok()
+            .and_then(|major| major.checked_mul(10u64.pow(currency.decimal_places())))
+            .and_then(|major| major.checked_add(minor.parse().ok()?))
+            .ok_or_else(invalid)?;
+        Ok(Amount::new(currency, amount))
+    }
+}
+
+/// A BTC amount given by an API client either as satoshi integer or as BTC
+/// decimal string. Exactly one of both must be set. The string form avoids
+/// precision issues in clients that can not represent large integers.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BtcAmountInput {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub amount_sats: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub amount_btc: Option<String>,
+}
+
+impl BtcAmountInput {
+    pub fn to_amount(&self) -> PaydayResult<Amount> {
+        let sats = match (self.amount_sats, &self.amount_btc) {
+            (Some(sats), None) => sats,
+            (None, Some(btc)) => bitcoin::Amount::from_str_in(btc, Denomination::Bitcoin)?.to_sat(),
+            _ => {
+                return Err(PaydayError::InvalidBitcoinAmount(
+                    "exactly one of amount_sats or amount_btc is required".to_string(),
+                ))
+            }
+        };
+        Ok(Amount::new(Currency::Btc, sats))
+    }
+}
+
+/// A BTC amount returned to API clients in both satoshi and BTC string form.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BtcAmountOutput {
+    pub amount_sats: u64,
+    pub amount_btc: String,
+}
+
+/// Only BTC amounts can be shown in sats and BTC, other currencies are
+/// rejected.
+impl TryFrom<Amount> for BtcAmountOutput {
+    type Error = InvoiceError;
+
+    fn try_from(value: Amount) -> Result<Self, Self::Error> {
+        let btc = bitcoin::Amount::try_from(value)?;
+        Ok(Self {
+            amount_sats: btc.to_sat(),
+            amount_btc: btc.to_string_in(Denomination::Bitcoin),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_amount_input_sats() {
+        let input: BtcAmountInput = serde_json::from_str(r#"{"amount_sats": 100000}"#).unwrap();
+        assert_eq!(
+            input.to_amount().unwrap(),
+            Amount::new(Currency::Btc, 100_000)
+        );
+    }
+
+    #[test]
+    fn test_amount_input_btc() {
+        let input: BtcAmountInput = serde_json::from_str(r#"{"amount_btc": "0.001"}"#).unwrap();
+        assert_eq!(
+            input.to_amount().unwrap(),
+            Amount::new(Currency::Btc, 100_000)
+        );
+    }
+
+    #[test]
+    fn test_amount_input_requires_exactly_one() {
+        let both = BtcAmountInput {
+            amount_sats: Some(100_000),
+            amount_btc: Some("0.001".to_string()),
+        };
+        assert!(matches!(
+            both.to_amount(),
+            Err(PaydayError::InvalidBitcoinAmount(_))
+        ));
+        assert!(matches!(
+            BtcAmountInput::default().to_amount(),
+            Err(PaydayError::InvalidBitcoinAmount(_))
+        ));
+    }
+
+    #[test]
+    fn test_checked_comparison() {
+        let amount = Amount::new(Currency::Btc, 1_000);
+        assert!(amount.gt_checked(&Amount::new(Currency::Btc, 999)).unwrap());
+        assert!(!amount
+            .lt_checked(&Amount::new(Currency::Btc, 1_000))
+            .unwrap());
+        assert!(amount
+            .lt_checked(&Amount::new(Currency::Btc, 1_001))
+            .unwrap());
+        assert!(amount
+            .eq_checked(&Amount::new(Currency::Btc, 1_000))
+            .unwrap());
+    }
+
+    #[test]
+    fn test_checked_comparison_rejects_currency_mismatch() {
+        let amount = Amount::new(Currency::Btc, 1_000);
+        let fiat = Amount::new(Currency::Usd, 1);
+        assert!(matches!(
+            amount.gt_checked(&fiat),
+            Err(InvoiceError::InvalidCurrency(required, received)) if required == "USD" && received == "BTC"
+        ));
+        assert!(amount.lt_checked(&fiat).is_err());
+        assert!(amount.eq_checked(&fiat).is_err());
+    }
+
+    #[test]
+    fn test_msats() {
+        assert_eq!(Amount::msats(1_999), Amount::new(Currency::Btc, 1));
+        assert_eq!(Amount::msats(2_000).to_msats(), 2_000);
+        assert_eq!(Amount::new(Currency::Btc, u64::MAX).to_msats(), u64::MAX);
+    }
+
+    #[test]
+    fn test_from_btc_rounding() {
+        let sats = |btc: f64| Amount::from_btc(btc).unwrap().amount;
+        assert_eq!(sats(0.000000005), 1);
+        assert_eq!(sats(0.0000000049), 0);
+        assert_eq!(sats(0.00000001), 1);
+        assert_eq!(sats(0.1), 10_000_000);
+        assert_eq!(sats(21_000_000.0), 2_100_000_000_000_000);
+        assert!(Amount::from_btc(-0.00000001).is_err());
+        assert!(Amount::from_btc(f64::NAN).is_err());
+        assert!(Amount::from_btc(f64::MAX).is_err());
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let btc = |amount: u64| Amount::new(Currency::Btc, amount);
+        assert_eq!(btc(1_000) + btc(500), btc(1_500));
+        assert_eq!(btc(1_000) - btc(500), btc(500));
+        assert_eq!(btc(500).checked_sub(&btc(1_000)), None);
+        assert_eq!(btc(u64::MAX).checked_add(&btc(1)), None);
+        let fiat = Amount::new(Currency::Usd, 500);
+        assert_eq!(btc(1_000).checked_sub(&fiat), None);
+        assert_eq!(btc(1_000).checked_add(&fiat), None);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_add_overflow_panics() {
+        let _ = Amount::new(Currency::Btc, u64::MAX) + Amount::new(Currency::Btc, 1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_add_currency_mismatch_panics() {
+        let _ = Amount::new(Currency::Btc, 1) + Amount::new(Currency::Usd, 1);
+    }
+
+    #[test]
+    fn test_display_round_trip() {
+        for (amount, display) in [
+            (Amount::new(Currency::Btc, 100_000), "100000 sats"),
+            (Amount::new(Currency::Btc, 0), "0 sats"),
+            (Amount::new(Currency::Usd, 1_000), "10.00 USD"),
+            (Amount::new(Currency::Usd, 1_005), "10.05 USD"),
+            (Amount::new(Currency::Eur, 7), "0.07 EUR"),
+        ] {
+            assert_eq!(amount.to_string(), display);
+            assert_eq!(Amount::from_str(display).unwrap(), amount);
+        }
+    }
+
+    #[test]
+    fn test_from_str_rejects_malformed() {
+        for input in [
+            "100000",
+            "100000 ",
+            " 100000 sats",
+            "100000  sats",
+            "100000 sats ",
+            "10.00",
+            "10 USD",
+            "10.0 USD",
+            "10.000 USD",
+            "-10.00 USD",
+            "+1 sats",
+            "1.00 BTC",
+            "10.00 XYZ",
+            "",
+        ] {
+            assert!(
+                matches!(Amount::from_str(input), Err(PaydayError::InvalidAmount(_))),
+                "{:?} should be rejected",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_try_into_bitcoin_amount() {
+        let sats: bitcoin::Amount = Amount::new(Currency::Btc, 2_500).try_into().unwrap();
+        assert_eq!(sats, bitcoin::Amount::from_sat(2_500));
+
+        let usd: Result<bitcoin::Amount, _> = Amount::new(Currency::Usd, 2_500).try_into();
+        assert!(matches!(
+            usd,
+            Err(InvoiceError::InvalidCurrency(required, received))
+                if required == "BTC" && received == "USD"
+        ));
+    }
+
+    #[test]
+    fn test_from_bitcoin_amount() {
+        let amount: Amount = bitcoin::Amount::from_sat(2_500).into();
+        assert_eq!(amount, Amount::new(Currency::Btc, 2_500));
+    }
+
+    #[test]
+    fn test_amount_output() {
+        let output = BtcAmountOutput::try_from(Amount::new(Currency::Btc, 100_000)).unwrap();
+        assert_eq!(output.amount_sats, 100_000);
+        assert_eq!(output.amount_btc, "0.001");
+
+        let res = BtcAmountOutput::try_from(Amount::new(Currency::Usd, 1_000));
+        assert!(matches!(res, Err(InvoiceError::InvalidCurrency(_, _))));
     }
 }
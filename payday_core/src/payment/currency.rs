@@ -1,8 +1,13 @@
-use std::fmt::{Display, Formatter};
+use std::{
+    fmt::{Display, Formatter},
+    str::FromStr,
+};
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Serialize, Deserialize)]
+use crate::PaydayError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Hash, Serialize, Deserialize)]
 pub enum Currency {
     Btc,
     Usd,
@@ -24,3 +29,33 @@ impl Display for Currency {
         }
     }
 }
+
+impl Currency {
+    /// Decimal places of the major unit. Amounts are stored in the minor unit,
+    /// which is sats for BTC and e.g. cents for USD.
+    pub fn decimal_places(&self) -> u32 {
+        match self {
+            Currency::Btc => 8,
+            _ => 2,
+        }
+    }
+}
+
+impl FromStr for Currency {
+    type Err = PaydayError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "BTC" => Ok(Currency::Btc),
+            "USD" => Ok(Currency::Usd),
+            "EUR" => Ok(Currency::Eur),
+            "CAD" => Ok(Currency::Cad),
+            "GBP" => Ok(Currency::Gbp),
+            "AUD" => Ok(Currency::Aud),
+            _ => Err(PaydayError::InvalidAmount(format!(
+                "unknown currency {}",
+                s
+            ))),
+        }
+    }
+}
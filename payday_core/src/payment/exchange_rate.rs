@@ -0,0 +1,198 @@
+use std::{collections::HashMap, time::Duration};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::{
+    date::{add_duration, is_past, now, DateTime},
+    payment::{amount::Amount, currency::Currency},
+    PaydayError, PaydayResult,
+};
+
+const SATS_PER_BTC: u128 = 100_000_000;
+
+/// Provides exchange rates for displaying BTC amounts in fiat currencies.
+#[async_trait]
+pub trait ExchangeRateApi: Send + Sync {
+    /// The price of one BTC in minor units (e.g. cents) of the given currency.
+    async fn get_rate(&self, currency: Currency) -> PaydayResult<u64>;
+}
+
+/// Returns the same configured rates all the time.
+pub struct FixedExchangeRate {
+    rates: HashMap<Currency, u64>,
+}
+
+impl FixedExchangeRate {
+    pub fn new(rates: HashMap<Currency, u64>) -> Self {
+        Self { rates }
+    }
+}
+
+#[async_trait]
+impl ExchangeRateApi for FixedExchangeRate {
+    async fn get_rate(&self, currency: Currency) -> PaydayResult<u64> {
+        self.rates
+            .get(&currency)
+            .copied()
+            .ok_or(PaydayError::NodeApiError(format!(
+                "no exchange rate for {}",
+                currency
+            )))
+    }
+}
+
+/// Caches the rates of another provider for the given time to live.
+pub struct CachedExchangeRate {
+    provider: Box<dyn ExchangeRateApi>,
+    ttl: Duration,
+    cache: Mutex<HashMap<Currency, (u64, DateTime)>>,
+}
+
+impl CachedExchangeRate {
+    pub fn new(provider: Box<dyn ExchangeRateApi>, ttl: Duration) -> Self {
+        Self {
+            provider,
+            ttl,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl ExchangeRateApi for CachedExchangeRate {
+    async fn get_rate(&self, currency: Currency) -> PaydayResult<u64> {
+        let mut cache = self.cache.lock().await;
+        if let Some((rate, fetched_at)) = cache.get(&currency) {
+            if !is_past(add_duration(*fetched_at, self.ttl)) {
+                return Ok(*rate);
+            }
+        }
+        let rate = self.provider.get_rate(currency).await?;
+        cache.insert(currency, (rate, now()));
+        Ok(rate)
+    }
+}
+
+/// An approximate fiat value of a BTC amount. Only meant for display, it must
+/// never be used for settlement.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndicativeFiatValue {
+    pub amount: Amount,
+    /// The rate used in minor units per BTC.
+    pub rate: u64,
+}
+
+impl IndicativeFiatValue {
+    /// Converts a BTC amount in sats to the fiat currency with the given rate.
+    pub fn from_btc(btc: &Amount, currency: Currency, rate: u64) -> PaydayResult<Self> {
+        if btc.currency != Currency::Btc {
+            return Err(PaydayError::InvalidBitcoinAmount(btc.to_string()));
+        }
+        let minor_units = btc.amount as u128 * rate as u128 / SATS_PER_BTC;
+        Ok(Self {
+            amount: Amount::new(currency, minor_units as u64),
+            rate,
+        })
+    }
+}
+
+/// Rounding of fiat amounts converted to sats. Invoices round up, so the
+/// merchant never receives less than the fiat amount asked for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Rounding {
+    #[default]
+    RoundUp,
+    RoundDown,
+    Nearest,
+}
+
+/// Converts a fiat amount in minor units to sats with the given rate in minor
+/// units per BTC, rounding fractions of a sat with the given policy.
+pub fn fiat_to_sats(fiat: &Amount, rate: u64, rounding: Rounding) -> PaydayResult<Amount> {
+    if fiat.currency == Currency::Btc || rate == 0 {
+        return Err(PaydayError::InvalidBitcoinAmount(format!(
+            "can not convert {} at rate {}",
+            fiat, rate
+        )));
+    }
+    let numerator = fiat.amount as u128 * SATS_PER_BTC;
+    let rate = rate as u128;
+    let sats = match rounding {
+        Rounding::RoundUp => numerator.div_ceil(rate),
+        Rounding::RoundDown => numerator / rate,
+        Rounding::Nearest => (numerator + rate / 2) / rate,
+    };
+    let sats = u64::try_from(sats)
+        .map_err(|_| PaydayError::InvalidBitcoinAmount(format!("{} sats", sats)))?;
+    Ok(Amount::new(Currency::Btc, sats))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_indicative_fiat_value() {
+        let value = IndicativeFiatValue::from_btc(
+            &Amount::new(Currency::Btc, 100_000),
+            Currency::Usd,
+            5_000_000,
+        )
+        .unwrap();
+        assert_eq!(value.amount, Amount::new(Currency::Usd, 5_000));
+        assert!(IndicativeFiatValue::from_btc(
+            &Amount::new(Currency::Eur, 100_000),
+            Currency::Usd,
+            5_000_000
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_fiat_to_sats_rounding() {
+        // $10 at $67,000 per BTC is 14925.37 sats
+        let fiat = Amount::new(Currency::Usd, 1_000);
+        let sats = |rounding| fiat_to_sats(&fiat, 6_700_000, rounding).unwrap().amount;
+        assert_eq!(sats(Rounding::default()), 14_926);
+        assert_eq!(sats(Rounding::RoundUp), 14_926);
+        assert_eq!(sats(Rounding::RoundDown), 14_925);
+        assert_eq!(sats(Rounding::Nearest), 14_925);
+
+        // an even division is not rounded
+        assert_eq!(
+            fiat_to_sats(&fiat, 5_000_000, Rounding::RoundUp).unwrap(),
+            Amount::new(Currency::Btc, 20_000)
+        );
+        assert!(fiat_to_sats(&fiat, 0, Rounding::RoundUp).is_err());
+        assert!(fiat_to_sats(&Amount::new(Currency::Btc, 1_000), 1, Rounding::RoundUp).is_err());
+    }
+
+    struct CountingRate(Arc<AtomicU32>);
+
+    #[async_trait]
+    impl ExchangeRateApi for CountingRate {
+        async fn get_rate(&self, _currency: Currency) -> PaydayResult<u64> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(5_000_000)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cached_exchange_rate() {
+        let calls = Arc::new(AtomicU32::new(0));
+        let cached = CachedExchangeRate::new(
+            Box::new(CountingRate(calls.clone())),
+            Duration::from_secs(60),
+        );
+        assert_eq!(cached.get_rate(Currency::Usd).await.unwrap(), 5_000_000);
+        assert_eq!(cached.get_rate(Currency::Usd).await.unwrap(), 5_000_000);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
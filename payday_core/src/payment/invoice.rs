@@ -1,12 +1,100 @@
-use std::fmt::{Display, Formatter};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    fmt::{Display, Formatter},
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
 
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use ulid::{Generator, Ulid};
 
-use crate::{payment::amount::Amount, PaydayResult};
+use crate::{
+    date::DateTime,
+    payment::{
+        amount::Amount,
+        currency::Currency,
+        exchange_rate::{fiat_to_sats, ExchangeRateApi, Rounding},
+    },
+    PaydayError, PaydayResult,
+};
+
+/// The id of an invoice. A dedicated type so invoice ids can not be mixed up
+/// with node ids, addresses or transaction ids.
+///
+/// ```compile_fail
+/// use payday_core::payment::invoice::InvoiceId;
+///
+/// fn create_invoice(invoice_id: InvoiceId, node_id: String) {}
+/// create_invoice("lnd1".to_string(), InvoiceId::from("123"));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct InvoiceId(String);
+
+impl InvoiceId {
+    pub fn new(id: &str) -> Self {
+        Self(id.to_string())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl Display for InvoiceId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for InvoiceId {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self::new(s))
+    }
+}
+
+impl From<&str> for InvoiceId {
+    fn from(value: &str) -> Self {
+        Self::new(value)
+    }
+}
+
+impl From<String> for InvoiceId {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+/// Generates ids for invoices created without an id.
+pub trait InvoiceIdGenerator: Send + Sync {
+    fn generate(&self) -> InvoiceId;
+}
+
+/// Generates ULIDs, which are unique and sort in creation order. Ids generated
+/// within the same millisecond are incremented, so they sort as well.
+#[derive(Default)]
+pub struct UlidGenerator {
+    generator: Mutex<Generator>,
+}
+
+impl InvoiceIdGenerator for UlidGenerator {
+    fn generate(&self) -> InvoiceId {
+        let mut generator = self.generator.lock().unwrap_or_else(|e| e.into_inner());
+        // only fails after 2^80 ids within one millisecond
+        let id = generator.generate().unwrap_or_else(|_| Ulid::new());
+        InvoiceId(id.to_string())
+    }
+}
 
-pub type InvoiceId = String;
 pub type PaymentType = String;
 pub type InvoiceResult<T> = Result<T, InvoiceError>;
 
@@ -14,6 +102,10 @@ pub type InvoiceResult<T> = Result<T, InvoiceError>;
 pub enum InvoiceError {
     InvalidAmount(Amount),
     InvalidCurrency(String, String),
+    InvalidWebhookUrl(String),
+    AlreadyPaid(InvoiceId),
+    /// Another refund of the invoice is not completed yet.
+    RefundPending(InvoiceId),
     ServiceError(String),
 }
 
@@ -28,6 +120,11 @@ impl Display for InvoiceError {
                 "Invoice invalid currency required: {} received: {}",
                 required, received
             ),
+            InvoiceError::InvalidWebhookUrl(url) => {
+                write!(f, "Invoice invalid webhook url: {}", url)
+            }
+            InvoiceError::AlreadyPaid(id) => write!(f, "Invoice already paid: {}", id),
+            InvoiceError::RefundPending(id) => write!(f, "Invoice refund pending: {}", id),
             InvoiceError::ServiceError(err) => write!(f, "Invoice service error: {}", err),
         }
     }
@@ -42,6 +139,63 @@ pub struct Invoice {
     pub payment_info: Value,
 }
 
+/// Minimum and maximum amount accepted for an invoice. Both bounds are inclusive.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AmountLimits {
+    pub min: Option<Amount>,
+    pub max: Option<Amount>,
+}
+
+impl AmountLimits {
+    pub fn new(min: Option<Amount>, max: Option<Amount>) -> Self {
+        Self { min, max }
+    }
+
+    /// Checks the amount is within the limits.
+    pub fn check(&self, amount: &Amount) -> InvoiceResult<()> {
+        let below_min = match &self.min {
+            Some(min) => amount.lt_checked(min)?,
+            None => false,
+        };
+        let above_max = match &self.max {
+            Some(max) => amount.gt_checked(max)?,
+            None => false,
+        };
+        if below_min || above_max {
+            return Err(InvoiceError::InvalidAmount(*amount));
+        }
+        Ok(())
+    }
+}
+
+/// Amount limits configured per payment type. Payment types without
+/// configured limits accept any amount.
+#[derive(Debug, Clone, Default)]
+pub struct PaymentTypeLimits {
+    limits: HashMap<PaymentType, AmountLimits>,
+}
+
+impl PaymentTypeLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_limits(mut self, payment_type: &str, limits: AmountLimits) -> Self {
+        self.limits.insert(payment_type.to_string(), limits);
+        self
+    }
+
+    /// The limits for a payment type.
+    pub fn get(&self, payment_type: &str) -> AmountLimits {
+        self.limits.get(payment_type).cloned().unwrap_or_default()
+    }
+
+    /// Checks the amount is within the limits of the payment type.
+    pub fn check(&self, payment_type: &str, amount: &Amount) -> InvoiceResult<()> {
+        self.get(payment_type).check(amount)
+    }
+}
+
 #[async_trait]
 pub trait PaymentProcessorApi: Send + Sync {
     /// A unique name for this processor.
@@ -50,10 +204,11 @@ pub trait PaymentProcessorApi: Send + Sync {
     /// The payment type this processor supports.
     fn supported_payment_type(&self) -> PaymentType;
 
-    /// Create an invoice.
+    /// Create an invoice. Without an invoice id, the processor generates one,
+    /// which is returned with the invoice.
     async fn create_invoice(
         &self,
-        invoice_id: InvoiceId,
+        invoice_id: Option<InvoiceId>,
         amount: Amount,
         memo: Option<String>,
     ) -> PaydayResult<Invoice>;
@@ -62,9 +217,313 @@ pub trait PaymentProcessorApi: Send + Sync {
     async fn process_payment_events(&self) -> PaydayResult<()>;
 }
 
+/// Creates invoices with the processors supporting the requested payment type.
+pub struct PaymentProcessors {
+    processors: Vec<Arc<dyn PaymentProcessorApi>>,
+    id_generator: Arc<dyn InvoiceIdGenerator>,
+    limits: PaymentTypeLimits,
+    exchange_rate: Option<Arc<dyn ExchangeRateApi>>,
+}
+
+impl PaymentProcessors {
+    pub fn new(processors: Vec<Arc<dyn PaymentProcessorApi>>) -> Self {
+        Self {
+            processors,
+            id_generator: Arc::new(UlidGenerator::default()),
+            limits: PaymentTypeLimits::default(),
+            exchange_rate: None,
+        }
+    }
+
+    /// Sets the amount limits checked before an invoice is created.
+    pub fn with_limits(mut self, limits: PaymentTypeLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Sets the rates fiat invoice amounts are converted to sats with. The
+    /// conversion rounds up, so the merchant never receives less than asked.
+    pub fn with_exchange_rate(mut self, exchange_rate: Arc<dyn ExchangeRateApi>) -> Self {
+        self.exchange_rate = Some(exchange_rate);
+        self
+    }
+
+    /// Sets the generator for ids of invoices created without an id.
+    pub fn with_id_generator(mut self, id_generator: Arc<dyn InvoiceIdGenerator>) -> Self {
+        self.id_generator = id_generator;
+        self
+    }
+
+    /// Creates the invoice with the first supporting processor that succeeds.
+    /// Without an invoice id, one is generated up front, so every processor
+    /// tried creates the invoice with the same id.
+    /// Fiat amounts are converted to sats if an exchange rate is set. The amount
+    /// in sats is checked against the limits of the payment type.
+    /// Returns InvalidPaymentType if no processor supports the payment type and
+    /// the error of the last processor if all supporting processors failed.
+    pub async fn create_invoice(
+        &self,
+        payment_type: &str,
+        invoice_id: Option<InvoiceId>,
+        amount: Amount,
+        memo: Option<String>,
+    ) -> PaydayResult<Invoice> {
+        let amount = match &self.exchange_rate {
+            Some(exchange_rate) if amount.currency != Currency::Btc => {
+                let rate = exchange_rate.get_rate(amount.currency).await?;
+                fiat_to_sats(&amount, rate, Rounding::RoundUp)?
+            }
+            _ => amount,
+        };
+        self.limits.check(payment_type, &amount)?;
+        let invoice_id = invoice_id.unwrap_or_else(|| self.id_generator.generate());
+        let mut result = Err(PaydayError::InvalidPaymentType(payment_type.to_string()));
+        for processor in self
+            .processors
+            .iter()
+            .filter(|p| p.supported_payment_type() == payment_type)
+        {
+            result = processor
+                .create_invoice(Some(invoice_id.clone()), amount, memo.clone())
+                .await;
+            match &result {
+                Ok(_) => break,
+                Err(e) => println!(
+                    "Processor {} failed to create invoice: {:?}",
+                    processor.name(),
+                    e
+                ),
+            }
+        }
+        result
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LnInvoice {
     pub invoice: String,
     pub r_hash: String,
     pub add_index: u64,
+    /// After this time the node rejects payments of the invoice.
+    #[serde(default)]
+    pub expires_at: Option<DateTime>,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::payment::exchange_rate::FixedExchangeRate;
+
+    use super::*;
+
+    struct MockProcessor {
+        name: String,
+        payment_type: PaymentType,
+        fails: bool,
+    }
+
+    #[async_trait]
+    impl PaymentProcessorApi for MockProcessor {
+        fn name(&self) -> String {
+            self.name.to_string()
+        }
+
+        fn supported_payment_type(&self) -> PaymentType {
+            self.payment_type.to_string()
+        }
+
+        async fn create_invoice(
+            &self,
+            invoice_id: Option<InvoiceId>,
+            amount: Amount,
+            _memo: Option<String>,
+        ) -> PaydayResult<Invoice> {
+            if self.fails {
+                return Err(PaydayError::NodeConnectError(self.name.to_string()));
+            }
+            Ok(Invoice {
+                service_name: self.name.to_string(),
+                invoice_id: invoice_id.unwrap_or_default(),
+                amount,
+                payment_type: self.payment_type.to_string(),
+                payment_info: Value::Null,
+            })
+        }
+
+        async fn process_payment_events(&self) -> PaydayResult<()> {
+            Ok(())
+        }
+    }
+
+    fn mock_processor(name: &str, payment_type: &str, fails: bool) -> Arc<dyn PaymentProcessorApi> {
+        Arc::new(MockProcessor {
+            name: name.to_string(),
+            payment_type: payment_type.to_string(),
+            fails,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_create_invoice_unsupported_payment_type() {
+        let processors = PaymentProcessors::new(vec![mock_processor("lnd", "lightning", false)]);
+        let res = processors
+            .create_invoice("on_chain", None, Amount::new(Currency::Btc, 1_000), None)
+            .await;
+        assert!(matches!(res, Err(PaydayError::InvalidPaymentType(t)) if t == "on_chain"));
+    }
+
+    #[tokio::test]
+    async fn test_create_invoice_failing_processors() {
+        let amount = Amount::new(Currency::Btc, 1_000);
+        let processors = PaymentProcessors::new(vec![
+            mock_processor("node1", "on_chain", true),
+            mock_processor("node2", "lightning", false),
+            mock_processor("node3", "on_chain", true),
+        ]);
+        let res = processors
+            .create_invoice("on_chain", None, amount, None)
+            .await;
+        assert!(matches!(res, Err(PaydayError::NodeConnectError(n)) if n == "node3"));
+
+        let processors = PaymentProcessors::new(vec![
+            mock_processor("node1", "on_chain", true),
+            mock_processor("node2", "on_chain", false),
+        ]);
+        let invoice = processors
+            .create_invoice("on_chain", None, amount, None)
+            .await
+            .unwrap();
+        assert_eq!(invoice.service_name, "node2");
+    }
+
+    struct SequenceGenerator(Mutex<u32>);
+
+    impl InvoiceIdGenerator for SequenceGenerator {
+        fn generate(&self) -> InvoiceId {
+            let mut next = self.0.lock().unwrap();
+            *next += 1;
+            InvoiceId::from(next.to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_create_invoice_generates_id() {
+        let amount = Amount::new(Currency::Btc, 1_000);
+        let processors = PaymentProcessors::new(vec![
+            mock_processor("node1", "on_chain", true),
+            mock_processor("node2", "on_chain", false),
+        ])
+        .with_id_generator(Arc::new(SequenceGenerator(Mutex::new(0))));
+
+        let invoice = processors
+            .create_invoice("on_chain", None, amount, None)
+            .await
+            .unwrap();
+        assert_eq!(invoice.invoice_id, InvoiceId::from("1"));
+
+        let invoice = processors
+            .create_invoice("on_chain", Some(InvoiceId::from("abc")), amount, None)
+            .await
+            .unwrap();
+        assert_eq!(invoice.invoice_id, InvoiceId::from("abc"));
+
+        let invoice = PaymentProcessors::new(vec![mock_processor("node", "on_chain", false)])
+            .create_invoice("on_chain", None, amount, None)
+            .await
+            .unwrap();
+        assert_eq!(invoice.invoice_id.as_str().len(), 26);
+    }
+
+    #[tokio::test]
+    async fn test_create_invoice_checks_limits() {
+        let processors = PaymentProcessors::new(vec![mock_processor("node", "on_chain", false)])
+            .with_limits(
+                PaymentTypeLimits::new()
+                    .with_limits("on_chain", AmountLimits::new(Some(sats(546)), None)),
+            );
+        let res = processors
+            .create_invoice("on_chain", None, sats(545), None)
+            .await;
+        assert!(matches!(
+            res,
+            Err(PaydayError::InvoiceError(InvoiceError::InvalidAmount(_)))
+        ));
+        let invoice = processors
+            .create_invoice("on_chain", None, sats(546), None)
+            .await
+            .unwrap();
+        assert_eq!(invoice.amount, sats(546));
+    }
+
+    #[tokio::test]
+    async fn test_create_invoice_converts_fiat() {
+        let rates = FixedExchangeRate::new(HashMap::from([(Currency::Usd, 6_700_000)]));
+        let processors = PaymentProcessors::new(vec![mock_processor("node", "on_chain", false)])
+            .with_exchange_rate(Arc::new(rates))
+            .with_limits(
+                PaymentTypeLimits::new()
+                    .with_limits("on_chain", AmountLimits::new(Some(sats(546)), None)),
+            );
+
+        // $10 at $67,000 is 14925.37 sats, rounded up for the merchant
+        let invoice = processors
+            .create_invoice("on_chain", None, Amount::new(Currency::Usd, 1_000), None)
+            .await
+            .unwrap();
+        assert_eq!(invoice.amount, sats(14_926));
+
+        // the converted amount is checked against the limits
+        let res = processors
+            .create_invoice("on_chain", None, Amount::new(Currency::Usd, 10), None)
+            .await;
+        assert!(matches!(
+            res,
+            Err(PaydayError::InvoiceError(InvoiceError::InvalidAmount(_)))
+        ));
+        let res = processors
+            .create_invoice("on_chain", None, Amount::new(Currency::Eur, 1_000), None)
+            .await;
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_invoice_id_serde() {
+        let id = InvoiceId::from("123");
+        assert_eq!(serde_json::to_string(&id).unwrap(), r#""123""#);
+        assert_eq!(serde_json::from_str::<InvoiceId>(r#""123""#).unwrap(), id);
+        assert_eq!(InvoiceId::from_str("123").unwrap(), id);
+        assert_eq!(id.to_string(), "123");
+    }
+
+    #[test]
+    fn test_ulid_invoice_ids_unique_and_sorted() {
+        let generator = UlidGenerator::default();
+        let ids: Vec<InvoiceId> = (0..1_000).map(|_| generator.generate()).collect();
+        assert!(ids.windows(2).all(|w| w[0] < w[1]));
+        assert!(ids.iter().all(|id| id.as_str().len() == 26));
+    }
+
+    fn sats(amount: u64) -> Amount {
+        Amount::new(Currency::Btc, amount)
+    }
+
+    #[test]
+    fn test_payment_type_limits() {
+        let limits = PaymentTypeLimits::new()
+            .with_limits("on_chain", AmountLimits::new(Some(sats(546)), None))
+            .with_limits("lightning", AmountLimits::new(None, Some(sats(4_294_967))));
+
+        assert!(limits.check("on_chain", &sats(546)).is_ok());
+        assert!(limits.check("on_chain", &sats(545)).is_err());
+        assert!(limits.check("lightning", &sats(1)).is_ok());
+        assert!(limits.check("lightning", &sats(4_294_967)).is_ok());
+        assert!(limits.check("lightning", &sats(4_294_968)).is_err());
+        assert!(limits.check("other", &sats(u64::MAX)).is_ok());
+    }
+
+    #[test]
+    fn test_amount_limits_currency_mismatch() {
+        let limits = AmountLimits::new(Some(sats(546)), None);
+        let res = limits.check(&Amount::new(Currency::Usd, 1_000));
+        assert!(matches!(res, Err(InvoiceError::InvalidCurrency(_, _))));
+    }
 }
@@ -1,3 +1,5 @@
 pub mod amount;
 pub mod currency;
+pub mod exchange_rate;
 pub mod invoice;
+pub mod offer;
@@ -0,0 +1,343 @@
+//! BOLT12 lightning offers.
+//!
+//! Offers are bech32 encoded without a checksum and may be split with `+`
+//! followed by optional whitespace. Parsing validates the encoding and the
+//! offer TLVs and keeps the raw TLV stream for backends that can pay offers.
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use bitcoin::secp256k1::PublicKey;
+
+use crate::PaydayError;
+
+const OFFER_HRP: &str = "lno";
+const BECH32_CHARSET: &str = "qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+const OFFER_CURRENCY: u64 = 6;
+const OFFER_AMOUNT: u64 = 8;
+const OFFER_DESCRIPTION: u64 = 10;
+const OFFER_PATHS: u64 = 16;
+const OFFER_ISSUER_ID: u64 = 22;
+/// The offer TLV types defined by BOLT12.
+const KNOWN_OFFER_TYPES: [u64; 11] = [2, 4, 6, 8, 10, 12, 14, 16, 18, 20, 22];
+
+/// A parsed and validated BOLT12 offer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Offer {
+    encoded: String,
+    data: Vec<u8>,
+    amount: Option<u64>,
+    currency: Option<String>,
+    description: Option<String>,
+    node_id: Option<PublicKey>,
+}
+
+impl Offer {
+    /// The offer string without `+` continuations.
+    pub fn encoded(&self) -> &str {
+        &self.encoded
+    }
+
+    /// The raw TLV stream of the offer.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// The amount of the offer, in millisatoshis unless a currency is set.
+    /// Offers without an amount are paid with an amount of the payer's choice.
+    pub fn amount(&self) -> Option<u64> {
+        self.amount
+    }
+
+    /// The ISO 4217 currency code of the amount, if not denominated in bitcoin.
+    pub fn currency(&self) -> Option<&str> {
+        self.currency.as_deref()
+    }
+
+    pub fn description(&self) -> Option<&str> {
+        self.description.as_deref()
+    }
+
+    /// The node id of the issuer. Offers with blinded paths may omit it.
+    pub fn node_id(&self) -> Option<PublicKey> {
+        self.node_id
+    }
+}
+
+impl FromStr for Offer {
+    type Err = PaydayError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |m: &str| PaydayError::InvalidLightningOffer(format!("{}: {}", m, s));
+
+        let trimmed = s.trim();
+        if trimmed.ends_with('+') || trimmed.starts_with('+') {
+            return Err(invalid("invalid continuation"));
+        }
+        let mut joined = String::new();
+        for part in trimmed.split('+') {
+            let part = part.trim_start();
+            if part.is_empty() || part.contains(char::is_whitespace) {
+                return Err(invalid("invalid continuation"));
+            }
+            joined.push_str(part);
+        }
+
+        let is_lower = joined == joined.to_lowercase();
+        let is_upper = joined == joined.to_uppercase();
+        if !is_lower && !is_upper {
+            return Err(invalid("mixed case"));
+        }
+        let encoded = joined.to_lowercase();
+
+        let (hrp, payload) = encoded
+            .rsplit_once('1')
+            .ok_or_else(|| invalid("missing separator"))?;
+        if hrp != OFFER_HRP {
+            return Err(invalid("not an offer"));
+        }
+        if payload.is_empty() {
+            return Err(invalid("empty offer"));
+        }
+
+        let data = decode_5bit(payload).ok_or_else(|| invalid("invalid encoding"))?;
+        let records = decode_tlv_stream(&data).ok_or_else(|| invalid("invalid tlv stream"))?;
+
+        let mut offer = Self {
+            encoded,
+            data: data.to_owned(),
+            amount: None,
+            currency: None,
+            description: None,
+            node_id: None,
+        };
+        let mut has_paths = false;
+        for (tlv_type, value) in records {
+            let in_range =
+                (1..80).contains(&tlv_type) || (1_000_000_000..2_000_000_000).contains(&tlv_type);
+            if !in_range {
+                return Err(invalid("unexpected tlv type"));
+            }
+            match tlv_type {
+                OFFER_CURRENCY => {
+                    let currency = String::from_utf8(value.to_vec())
+                        .ok()
+                        .filter(|c| c.len() == 3 && c.chars().all(|c| c.is_ascii_uppercase()))
+                        .ok_or_else(|| invalid("invalid currency"))?;
+                    offer.currency = Some(currency);
+                }
+                OFFER_AMOUNT => {
+                    offer.amount =
+                        Some(decode_tu64(value).ok_or_else(|| invalid("invalid amount"))?);
+                }
+                OFFER_DESCRIPTION => {
+                    let description = String::from_utf8(value.to_vec())
+                        .map_err(|_| invalid("invalid description"))?;
+                    offer.description = Some(description);
+                }
+                OFFER_PATHS => has_paths = !value.is_empty(),
+                OFFER_ISSUER_ID => {
+                    let node_id =
+                        PublicKey::from_slice(value).map_err(|_| invalid("invalid node id"))?;
+                    offer.node_id = Some(node_id);
+                }
+                // unknown even types are mandatory to understand
+                t if t % 2 == 0 && !KNOWN_OFFER_TYPES.contains(&t) => {
+                    return Err(invalid("unknown mandatory tlv"));
+                }
+                _ => {}
+            }
+        }
+
+        if offer.amount == Some(0) {
+            return Err(invalid("zero amount"));
+        }
+        if offer.amount.is_some() && offer.description.is_none() {
+            return Err(invalid("missing description"));
+        }
+        if offer.currency.is_some() && offer.amount.is_none() {
+            return Err(invalid("currency without amount"));
+        }
+        if offer.node_id.is_none() && !has_paths {
+            return Err(invalid("missing node id"));
+        }
+        Ok(offer)
+    }
+}
+
+impl Display for Offer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.encoded)
+    }
+}
+
+/// Converts a bech32 character string to bytes, rejecting non zero padding.
+fn decode_5bit(payload: &str) -> Option<Vec<u8>> {
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    let mut out = Vec::with_capacity(payload.len() * 5 / 8);
+    for c in payload.chars() {
+        let value = BECH32_CHARSET.find(c)? as u32;
+        acc = (acc << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+    if bits >= 5 || acc != 0 {
+        return None;
+    }
+    Some(out)
+}
+
+/// Splits a TLV stream into its records. Types have to be strictly increasing
+/// and all numbers minimally encoded.
+fn decode_tlv_stream(mut data: &[u8]) -> Option<Vec<(u64, &[u8])>> {
+    let mut records: Vec<(u64, &[u8])> = Vec::new();
+    while !data.is_empty() {
+        let tlv_type = read_bigsize(&mut data)?;
+        if records.last().is_some_and(|(last, _)| *last >= tlv_type) {
+            return None;
+        }
+        let len = usize::try_from(read_bigsize(&mut data)?).ok()?;
+        if data.len() < len {
+            return None;
+        }
+        let (value, rest) = data.split_at(len);
+        records.push((tlv_type, value));
+        data = rest;
+    }
+    Some(records)
+}
+
+/// Reads a BigSize number and advances the input past it.
+fn read_bigsize(data: &mut &[u8]) -> Option<u64> {
+    let (&first, rest) = data.split_first()?;
+    let (width, min) = match first {
+        0xfd => (2, 0xfd),
+        0xfe => (4, 0x1_0000),
+        0xff => (8, 0x1_0000_0000),
+        n => {
+            *data = rest;
+            return Some(n as u64);
+        }
+    };
+    if rest.len() < width {
+        return None;
+    }
+    let (bytes, rest) = rest.split_at(width);
+    let value = bytes.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64);
+    if value < min {
+        return None;
+    }
+    *data = rest;
+    Some(value)
+}
+
+/// Decodes a truncated u64, which has no leading zero bytes.
+fn decode_tu64(value: &[u8]) -> Option<u64> {
+    if value.len() > 8 || value.first() == Some(&0) {
+        return None;
+    }
+    Some(value.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OFFER: &str = "lno1pqps7sjqpgtyzm3qv4uxzmtsd3jjqer9wd3hy6tsw35k7msjzfpy7nz5yqcnygrfdej82um5wf5k2uckyypwa3eyt44h6txtxquqh7lz5djge4afgfjn7k4rgrkuag0jsd5xvxg";
+
+    #[test]
+    fn test_parse_offer() {
+        let offer = Offer::from_str(OFFER).expect("valid offer");
+        assert_eq!(offer.encoded(), OFFER);
+        assert!(!offer.data().is_empty());
+    }
+
+    #[test]
+    fn test_parse_split_offer() {
+        let split = format!("{}+\n {}", &OFFER[..40], &OFFER[40..]);
+        let offer = Offer::from_str(&split).expect("valid split offer");
+        assert_eq!(offer, Offer::from_str(OFFER).unwrap());
+        assert!(Offer::from_str(&OFFER.to_uppercase()).is_ok());
+    }
+
+    #[test]
+    fn test_parse_invalid_offer() {
+        assert!(Offer::from_str("lnbc1pqps7sjqpgtyzm3qv4uxzmtsd3jjqer9wd3hy6ts").is_err());
+        assert!(Offer::from_str("lno1pqps7sjqpgtyzm3qv4uxzmtsd3jjqer9wd3hy6tsb").is_err());
+        assert!(Offer::from_str(&format!("{}+", OFFER)).is_err());
+        assert!(Offer::from_str("lno1pqps7sjqpgtyzm3qv4uXzmtsd3jjqer9wd3hy6ts").is_err());
+        assert!(Offer::from_str("lno1").is_err());
+    }
+
+    const NODE_ID: &str = "02eec7245d6b7d2ccb30380bfbe2a3648cd7a942653f5aa340edcea1f283686619";
+
+    fn encode_offer(records: &[(u8, Vec<u8>)]) -> String {
+        let mut data = Vec::new();
+        for (tlv_type, value) in records {
+            data.push(*tlv_type);
+            data.push(value.len() as u8);
+            data.extend_from_slice(value);
+        }
+        let mut encoded = format!("{}1", OFFER_HRP);
+        let (mut acc, mut bits) = (0u32, 0);
+        for byte in data {
+            acc = (acc << 8) | byte as u32;
+            bits += 8;
+            while bits >= 5 {
+                bits -= 5;
+                encoded.push(BECH32_CHARSET.as_bytes()[(acc >> bits) as usize & 31] as char);
+            }
+        }
+        if bits > 0 {
+            encoded.push(BECH32_CHARSET.as_bytes()[(acc << (5 - bits)) as usize & 31] as char);
+        }
+        encoded
+    }
+
+    fn node_id() -> Vec<u8> {
+        NODE_ID.parse::<PublicKey>().unwrap().serialize().to_vec()
+    }
+
+    #[test]
+    fn test_offer_fields() {
+        let offer = Offer::from_str(OFFER).unwrap();
+        assert_eq!(offer.amount(), Some(1_000_000));
+        assert_eq!(offer.currency(), None);
+        assert_eq!(offer.description(), Some("An example description"));
+        assert_eq!(offer.node_id().unwrap().to_string(), NODE_ID);
+
+        let encoded = encode_offer(&[(22, node_id())]);
+        let offer = Offer::from_str(&encoded).expect("offer without amount");
+        assert_eq!(offer.amount(), None);
+        assert_eq!(offer.description(), None);
+    }
+
+    #[test]
+    fn test_offer_validates_tlvs() {
+        let description = b"coffee".to_vec();
+        // amount without description
+        assert!(Offer::from_str(&encode_offer(&[(8, vec![0x64]), (22, node_id())])).is_err());
+        // zero and non minimal amounts
+        let zero = encode_offer(&[(8, vec![]), (10, description.clone()), (22, node_id())]);
+        assert!(Offer::from_str(&zero).is_err());
+        let padded = encode_offer(&[
+            (8, vec![0, 0x64]),
+            (10, description.clone()),
+            (22, node_id()),
+        ]);
+        assert!(Offer::from_str(&padded).is_err());
+        // missing and invalid node id
+        assert!(Offer::from_str(&encode_offer(&[(10, description.clone())])).is_err());
+        assert!(Offer::from_str(&encode_offer(&[(22, vec![4; 33])])).is_err());
+        // unknown even type and types out of order
+        assert!(Offer::from_str(&encode_offer(&[(22, node_id()), (24, vec![])])).is_err());
+        assert!(Offer::from_str(&encode_offer(&[(22, node_id()), (10, description)])).is_err());
+        // unknown odd types are ignored
+        assert!(Offer::from_str(&encode_offer(&[(22, node_id()), (25, vec![1])])).is_ok());
+    }
+}
@@ -1,12 +1,23 @@
+use std::collections::HashMap;
+
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
 
 use crate::PaydayResult;
 
 #[async_trait]
 pub trait BlockHeightStoreApi: Send + Sync {
-    async fn get_block_height(&self, node_id: &str) -> PaydayResult<BlockHeight>;
+    /// Returns the stored block height of the node or None if no block height
+    /// was stored for the node yet.
+    async fn get_block_height(&self, node_id: &str) -> PaydayResult<Option<BlockHeight>>;
     async fn set_block_height(&self, node_id: &str, block_height: u64) -> PaydayResult<()>;
+    /// Lowers the stored block height after a reorg, so the blocks above it
+    /// are processed again. Stores that overwrite the height on set can use
+    /// the default.
+    async fn reset_block_height(&self, node_id: &str, block_height: u64) -> PaydayResult<()> {
+        self.set_block_height(node_id, block_height).await
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -14,3 +25,69 @@ pub struct BlockHeight {
     pub node_id: String,
     pub block_height: u64,
 }
+
+/// A block height store kept in memory. Useful for tests and single process
+/// setups that can catch up from a configured start height after a restart.
+/// Block heights never decrease on set, a lower height than the stored one is
+/// ignored unless it is reset.
+#[derive(Debug, Default)]
+pub struct MemBlockHeightStore {
+    heights: Mutex<HashMap<String, u64>>,
+}
+
+#[async_trait]
+impl BlockHeightStoreApi for MemBlockHeightStore {
+    async fn get_block_height(&self, node_id: &str) -> PaydayResult<Option<BlockHeight>> {
+        Ok(self.heights.lock().await.get(node_id).map(|h| BlockHeight {
+            node_id: node_id.to_string(),
+            block_height: *h,
+        }))
+    }
+
+    async fn set_block_height(&self, node_id: &str, block_height: u64) -> PaydayResult<()> {
+        let mut heights = self.heights.lock().await;
+        let height = heights.entry(node_id.to_string()).or_insert(block_height);
+        *height = (*height).max(block_height);
+        Ok(())
+    }
+
+    async fn reset_block_height(&self, node_id: &str, block_height: u64) -> PaydayResult<()> {
+        self.heights
+            .lock()
+            .await
+            .insert(node_id.to_string(), block_height);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_mem_block_height_never_decreases() {
+        let store = Arc::new(MemBlockHeightStore::default());
+        assert!(store.get_block_height("node1").await.unwrap().is_none());
+
+        let tasks: Vec<_> = (0..100u64)
+            .map(|i| {
+                let store = store.clone();
+                tokio::spawn(async move { store.set_block_height("node1", i * 7 % 100).await })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+        store.set_block_height("node1", 10).await.unwrap();
+
+        let height = store.get_block_height("node1").await.unwrap().unwrap();
+        assert_eq!(height.block_height, 99);
+        assert!(store.get_block_height("node2").await.unwrap().is_none());
+
+        store.reset_block_height("node1", 50).await.unwrap();
+        let height = store.get_block_height("node1").await.unwrap().unwrap();
+        assert_eq!(height.block_height, 50);
+    }
+}
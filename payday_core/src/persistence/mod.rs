@@ -1,2 +1,3 @@
 pub mod block_height;
 pub mod cqrs;
+pub mod payment_log;
@@ -0,0 +1,141 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::{payment::amount::Amount, PaydayResult};
+
+/// Status of an outgoing payment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OutgoingPaymentStatus {
+    InFlight,
+    Succeeded,
+    Failed,
+    /// The node does not know the payment, e.g. the service stopped after it
+    /// was logged but before it was sent. Not final, the node is asked again.
+    Unknown,
+}
+
+/// An outgoing payment as stored in the payment log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PaymentRecord {
+    /// Payment hash of lightning payments, transaction id of on-chain payments.
+    pub payment_id: String,
+    /// Internal reference for reconciliation, e.g. an order id.
+    pub reference: Option<String>,
+    pub amount: Amount,
+    pub status: OutgoingPaymentStatus,
+}
+
+/// Log of outgoing payments by payment hash. Payments are marked in flight
+/// before they are sent, so payments interrupted by a restart can be resolved
+/// instead of being sent again.
+#[async_trait]
+pub trait PaymentLogApi: Send + Sync {
+    /// Returns the payment hashes of all payments that are in flight.
+    async fn in_flight(&self) -> PaydayResult<Vec<String>>;
+    async fn set_status(
+        &self,
+        payment_hash: &str,
+        status: OutgoingPaymentStatus,
+    ) -> PaydayResult<()>;
+    /// Stores the payment, replacing an existing record with the same id.
+    async fn record(&self, payment: PaymentRecord) -> PaydayResult<()>;
+    /// Returns the latest payment recorded with the reference. A reference can
+    /// be used again, e.g. when a failed payment for an order is retried.
+    async fn find_payment_by_reference(
+        &self,
+        reference: &str,
+    ) -> PaydayResult<Option<PaymentRecord>>;
+}
+
+/// A payment log kept in memory. Useful for tests and setups that do not need
+/// to resolve payments after a restart.
+#[derive(Debug, Default)]
+pub struct MemPaymentLog {
+    payments: Mutex<Vec<PaymentRecord>>,
+}
+
+#[async_trait]
+impl PaymentLogApi for MemPaymentLog {
+    async fn in_flight(&self) -> PaydayResult<Vec<String>> {
+        Ok(self
+            .payments
+            .lock()
+            .await
+            .iter()
+            .filter(|p| p.status == OutgoingPaymentStatus::InFlight)
+            .map(|p| p.payment_id.to_owned())
+            .collect())
+    }
+
+    async fn set_status(
+        &self,
+        payment_hash: &str,
+        status: OutgoingPaymentStatus,
+    ) -> PaydayResult<()> {
+        if let Some(payment) = self
+            .payments
+            .lock()
+            .await
+            .iter_mut()
+            .find(|p| p.payment_id == payment_hash)
+        {
+            payment.status = status;
+        }
+        Ok(())
+    }
+
+    async fn record(&self, payment: PaymentRecord) -> PaydayResult<()> {
+        let mut payments = self.payments.lock().await;
+        payments.retain(|p| p.payment_id != payment.payment_id);
+        payments.push(payment);
+        Ok(())
+    }
+
+    async fn find_payment_by_reference(
+        &self,
+        reference: &str,
+    ) -> PaydayResult<Option<PaymentRecord>> {
+        Ok(self
+            .payments
+            .lock()
+            .await
+            .iter()
+            .rev()
+            .find(|p| p.reference.as_deref() == Some(reference))
+            .cloned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::payment::currency::Currency;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_find_payment_by_reference() {
+        let log = MemPaymentLog::default();
+        let payment = |payment_id: &str, reference: Option<&str>| PaymentRecord {
+            payment_id: payment_id.to_string(),
+            reference: reference.map(|r| r.to_string()),
+            amount: Amount::new(Currency::Btc, 10_000),
+            status: OutgoingPaymentStatus::InFlight,
+        };
+        log.record(payment("failed_hash", Some("order-1")))
+            .await
+            .unwrap();
+        log.set_status("failed_hash", OutgoingPaymentStatus::Failed)
+            .await
+            .unwrap();
+        log.record(payment("txid", Some("order-1"))).await.unwrap();
+        log.record(payment("other", None)).await.unwrap();
+
+        let found = log.find_payment_by_reference("order-1").await.unwrap();
+        assert_eq!(found, Some(payment("txid", Some("order-1"))));
+        assert_eq!(
+            log.find_payment_by_reference("order-2").await.unwrap(),
+            None
+        );
+    }
+}
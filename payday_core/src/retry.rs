@@ -0,0 +1,70 @@
+use std::{future::Future, time::Duration};
+
+use crate::PaydayResult;
+
+/// Runs the given operation until it succeeds or max_attempts is reached. The
+/// delay between attempts starts at backoff and doubles after every failure.
+/// Returns the last error if all attempts fail.
+pub async fn retry_with_backoff<T, F, Fut>(
+    max_attempts: u32,
+    backoff: Duration,
+    mut operation: F,
+) -> PaydayResult<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = PaydayResult<T>>,
+{
+    let mut attempt = 1;
+    let mut delay = backoff;
+    loop {
+        match operation().await {
+            Ok(res) => return Ok(res),
+            Err(e) if attempt >= max_attempts => return Err(e),
+            Err(e) => {
+                println!(
+                    "Attempt {}/{} failed: {:?}, retrying in {:?}",
+                    attempt, max_attempts, e, delay
+                );
+                tokio::time::sleep(delay).await;
+                delay = delay.saturating_mul(2);
+                attempt += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use crate::PaydayError;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_retry_transient_failure() {
+        let calls = AtomicU32::new(0);
+        let res = retry_with_backoff(3, Duration::from_millis(1), || async {
+            if calls.fetch_add(1, Ordering::SeqCst) == 0 {
+                Err(PaydayError::NodeConnectError("unreachable".to_string()))
+            } else {
+                Ok(42)
+            }
+        })
+        .await;
+        assert_eq!(res.unwrap(), 42);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_retry_gives_up() {
+        let calls = AtomicU32::new(0);
+        let res: PaydayResult<()> = retry_with_backoff(3, Duration::from_millis(1), || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err(PaydayError::NodeConnectError("unreachable".to_string()))
+        })
+        .await;
+        assert!(matches!(res, Err(PaydayError::NodeConnectError(_))));
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,171 @@
+use std::{future::Future, time::Duration};
+
+use tokio::{sync::watch, task::AbortHandle};
+
+/// Fires the shutdown signal of all tasks holding one of its signals.
+pub struct ShutdownTrigger(watch::Sender<bool>);
+
+impl ShutdownTrigger {
+    pub fn trigger(&self) {
+        self.0.send_replace(true);
+    }
+}
+
+/// Tells a task to stop at its next safe point, e.g. after the event it is
+/// handling has been stored. A signal whose trigger was dropped never fires.
+#[derive(Clone)]
+pub struct ShutdownSignal(watch::Receiver<bool>);
+
+impl ShutdownSignal {
+    pub fn is_triggered(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Resolves once the shutdown was triggered.
+    pub async fn wait(&mut self) {
+        if self.0.wait_for(|triggered| *triggered).await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+/// Creates a shutdown signal and the trigger that fires it. Clones of the
+/// signal all fire with the trigger.
+pub fn shutdown_signal() -> (ShutdownTrigger, ShutdownSignal) {
+    let (sender, receiver) = watch::channel(false);
+    (ShutdownTrigger(sender), ShutdownSignal(receiver))
+}
+
+/// Runs the given future until it completes or the shutdown signal resolves.
+/// On shutdown all given tasks are aborted so that node streams and database
+/// connections they hold are released. Returns true if shutdown was signaled.
+pub async fn run_until_shutdown<R, S>(run: R, shutdown: S, tasks: Vec<AbortHandle>) -> bool
+where
+    R: Future,
+    S: Future<Output = ()>,
+{
+    let signaled = tokio::select! {
+        _ = run => false,
+        _ = shutdown => true,
+    };
+    if signaled {
+        for task in tasks {
+            task.abort();
+        }
+    }
+    signaled
+}
+
+/// Runs the given future until it completes or the shutdown signal resolves.
+/// On shutdown the tasks are told to stop by the trigger and the future, e.g.
+/// joining all tasks, gets grace_period to complete, so tasks can store their
+/// progress and release connections. Tasks still running after that are
+/// aborted. Returns true if shutdown was signaled.
+pub async fn run_until_graceful_shutdown<R, S>(
+    run: R,
+    shutdown: S,
+    trigger: ShutdownTrigger,
+    tasks: Vec<AbortHandle>,
+    grace_period: Duration,
+) -> bool
+where
+    R: Future,
+    S: Future<Output = ()>,
+{
+    tokio::pin!(run);
+    let signaled = tokio::select! {
+        _ = &mut run => false,
+        _ = shutdown => true,
+    };
+    if signaled {
+        trigger.trigger();
+        if tokio::time::timeout(grace_period, run).await.is_err() {
+            println!("Tasks did not stop within {:?}, aborting", grace_period);
+            for task in tasks {
+                task.abort();
+            }
+        }
+    }
+    signaled
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::{pending, ready},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_shutdown_aborts_tasks() {
+        let processor = tokio::spawn(pending::<()>());
+        let signaled =
+            run_until_shutdown(pending::<()>(), ready(()), vec![processor.abort_handle()]).await;
+        assert!(signaled);
+        assert!(processor.await.unwrap_err().is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn test_completed_run_does_not_abort() {
+        let processor = tokio::spawn(async { 42 });
+        let abort = processor.abort_handle();
+        let signaled = run_until_shutdown(processor, pending::<()>(), vec![abort]).await;
+        assert!(!signaled);
+    }
+
+    #[tokio::test]
+    async fn test_graceful_shutdown_lets_tasks_finish() {
+        let (trigger, mut signal) = shutdown_signal();
+        let flushed = Arc::new(AtomicBool::new(false));
+        let task_flushed = flushed.clone();
+        let processor = tokio::spawn(async move {
+            signal.wait().await;
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            task_flushed.store(true, Ordering::SeqCst);
+        });
+        let abort = processor.abort_handle();
+        let signaled = run_until_graceful_shutdown(
+            async move {
+                let _ = processor.await;
+            },
+            ready(()),
+            trigger,
+            vec![abort],
+            Duration::from_secs(5),
+        )
+        .await;
+        assert!(signaled);
+        assert!(flushed.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_graceful_shutdown_aborts_after_grace_period() {
+        let (trigger, _signal) = shutdown_signal();
+        let processor = tokio::spawn(pending::<()>());
+        let abort = processor.abort_handle();
+        let signaled = run_until_graceful_shutdown(
+            pending::<()>(),
+            ready(()),
+            trigger,
+            vec![abort],
+            Duration::from_millis(10),
+        )
+        .await;
+        assert!(signaled);
+        assert!(processor.await.unwrap_err().is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn test_dropped_trigger_never_fires() {
+        let (trigger, mut signal) = shutdown_signal();
+        drop(trigger);
+        assert!(!signal.is_triggered());
+        let waited = tokio::time::timeout(Duration::from_millis(10), signal.wait()).await;
+        assert!(waited.is_err());
+    }
+}
@@ -0,0 +1,90 @@
+use std::{future::Future, time::Duration};
+
+use tokio::task::{AbortHandle, JoinHandle};
+
+use crate::{PaydayError, PaydayResult};
+
+/// Aborts the supervised task when the supervisor itself is dropped or aborted.
+struct AbortOnDrop(AbortHandle);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Spawns the task created by factory and restarts it if it panics. Restarts are
+/// delayed by backoff which doubles after every panic. After max_restarts
+/// restarts the next panic is returned as TaskFailed error. The supervisor ends
+/// with Ok if the task completes or gets cancelled.
+pub fn supervise<F, Fut>(
+    name: &str,
+    max_restarts: u32,
+    backoff: Duration,
+    factory: F,
+) -> JoinHandle<PaydayResult<()>>
+where
+    F: Fn() -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let name = name.to_owned();
+    tokio::spawn(async move {
+        let mut restarts = 0;
+        let mut delay = backoff;
+        loop {
+            let handle = tokio::spawn(factory());
+            let _guard = AbortOnDrop(handle.abort_handle());
+            match handle.await {
+                Ok(_) => return Ok(()),
+                Err(e) if e.is_cancelled() => return Ok(()),
+                Err(_) if restarts >= max_restarts => {
+                    return Err(PaydayError::TaskFailed(format!(
+                        "task {} panicked after {} restarts",
+                        name, restarts
+                    )));
+                }
+                Err(_) => {
+                    println!("Task {} panicked, restarting in {:?}", name, delay);
+                    tokio::time::sleep(delay).await;
+                    delay = delay.saturating_mul(2);
+                    restarts += 1;
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_supervise_restarts_panicked_task() {
+        let runs = Arc::new(AtomicU32::new(0));
+        let counter = runs.clone();
+        let handle = supervise("subscriber", 3, Duration::from_millis(1), move || {
+            let counter = counter.clone();
+            async move {
+                if counter.fetch_add(1, Ordering::SeqCst) == 0 {
+                    panic!("subscriber failed");
+                }
+            }
+        });
+        assert!(handle.await.unwrap().is_ok());
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_supervise_repeated_panics_are_fatal() {
+        let handle = supervise("subscriber", 2, Duration::from_millis(1), || async {
+            panic!("subscriber failed");
+        });
+        let res = handle.await.unwrap();
+        assert!(matches!(res, Err(PaydayError::TaskFailed(_))));
+    }
+}
@@ -1,25 +1,44 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, future::Future, sync::Arc, time::Duration};
 
 use async_trait::async_trait;
-use bitcoin::{Address, Amount, Network};
+use bitcoin::{hex::DisplayHex, Address, Amount, Network};
 
 use fedimint_tonic_lnd::{
-    lnrpc::{GetTransactionsRequest, Transaction},
+    lnrpc::{
+        self, invoice::InvoiceState, GetTransactionsRequest, Invoice, Transaction,
+        WalletBalanceResponse,
+    },
     Client,
 };
 use payday_btc::{
+    lightning_api::{
+        LightningInvoiceApi, LightningInvoiceCreateApi, LightningInvoiceRequest, LightningOfferApi,
+        LightningPaymentApi, LightningPaymentRequest, LightningPaymentResult,
+        LightningPaymentStatusApi, LightningTransaction, LightningTransactionEvent,
+    },
     on_chain_api::{
-        GetOnChainBalanceApi, OnChainBalance, OnChainInvoiceApi, OnChainPaymentApi,
-        OnChainPaymentResult, OnChainStreamApi, OnChainTransactionApi,
+        FeeRate, GetOnChainBalanceApi, OnChainBalance, OnChainInvoiceApi, OnChainPaymentApi,
+        OnChainPaymentResult, OnChainStreamApi, OnChainTransactionApi, TransactionDirection,
     },
     on_chain_processor::{
-        OnChainTransaction, OnChainTransactionEvent, OnChainTransactionEventProcessorApi,
+        EventDeduplicator, OnChainTransaction, OnChainTransactionEvent,
+        OnChainTransactionEventProcessorApi,
     },
     to_address,
 };
-use payday_core::PaydayResult;
+use payday_core::{
+    date::from_timestamp,
+    error::PaymentFailureReason,
+    events::task::capped_backoff,
+    node::NodeApi,
+    payment::{amount::Amount as PaydayAmount, invoice::LnInvoice, offer::Offer},
+    persistence::payment_log::OutgoingPaymentStatus,
+    retry::retry_with_backoff,
+    shutdown::{shutdown_signal, ShutdownSignal},
+    PaydayError, PaydayResult,
+};
 use tokio::{sync::Mutex, task::JoinHandle};
-use tokio_stream::StreamExt;
+use tokio_stream::{Stream, StreamExt};
 
 use crate::wrapper::LndRpcWrapper;
 
@@ -35,15 +54,17 @@ impl Lnd {
     }
 }
 
+impl NodeApi for Lnd {
+    fn node_id(&self) -> String {
+        self.config.node_id()
+    }
+}
+
 #[async_trait]
 impl GetOnChainBalanceApi for Lnd {
     async fn get_onchain_balance(&self) -> PaydayResult<OnChainBalance> {
         let res = self.client.get_onchain_balance().await?;
-        Ok(OnChainBalance {
-            total_balance: to_amount(res.total_balance),
-            unconfirmed_balance: to_amount(res.unconfirmed_balance),
-            confirmed_balance: to_amount(res.confirmed_balance),
-        })
+        Ok(to_on_chain_balance(&res))
     }
 }
 
@@ -64,37 +85,58 @@ impl OnChainPaymentApi for Lnd {
         &self,
         target_conf: i32,
         outputs: HashMap<String, Amount>,
-    ) -> PaydayResult<Amount> {
+    ) -> PaydayResult<FeeRate> {
         let out = outputs
             .iter()
             .map(|p| (p.0.to_owned(), p.1.to_sat() as i64))
             .collect();
-        let fee = self.client.estimate_fee(target_conf, out).await?;
-        Ok(fee)
+        let fee_rate = self.client.estimate_fee(target_conf, out).await?;
+        Ok(fee_rate)
+    }
+
+    async fn estimate_send_fee(
+        &self,
+        amount: Amount,
+        address: String,
+        fee_rate: FeeRate,
+    ) -> PaydayResult<PaydayAmount> {
+        let fee = self
+            .client
+            .estimate_send_fee(amount, &address, fee_rate)
+            .await?;
+        Ok(fee.into())
     }
 
     async fn send(
         &self,
         amount: Amount,
         address: String,
-        sats_per_vbyte: Amount,
+        fee_rate: FeeRate,
+        reference: Option<String>,
     ) -> PaydayResult<OnChainPaymentResult> {
         let tx_id = self
             .client
-            .send_coins(amount, &address, sats_per_vbyte)
+            .send_coins(amount, &address, fee_rate, reference)
             .await?;
+        // the coins are sent, so a failed lookup only leaves out the raw transaction
+        let raw_tx_hex = self
+            .client
+            .get_recent_raw_transaction(&tx_id)
+            .await
+            .ok()
+            .flatten();
 
-        Ok(OnChainPaymentResult {
-            tx_id,
-            amounts: HashMap::from([(address.to_owned(), amount.to_owned())]),
-            fee: sats_per_vbyte,
-        })
+        Ok(
+            OnChainPaymentResult::new(tx_id, &HashMap::from([(address, amount)]), fee_rate)
+                .with_raw_tx_hex(raw_tx_hex),
+        )
     }
 
     async fn batch_send(
         &self,
         outputs: HashMap<String, Amount>,
-        sats_per_vbyte: Amount,
+        fee_rate: FeeRate,
+        reference: Option<String>,
     ) -> PaydayResult<OnChainPaymentResult> {
         let out = outputs
             .iter()
@@ -104,15 +146,14 @@ impl OnChainPaymentApi for Lnd {
                     .map(|a| (a, v.to_sat() as i64))
             })
             .collect();
-        let tx_id = self.client.batch_send(out, sats_per_vbyte).await?;
-        Ok(OnChainPaymentResult {
-            tx_id,
-            amounts: outputs
-                .iter()
-                .map(|(k, v)| (k.to_string(), v.to_owned()))
-                .collect(),
-            fee: sats_per_vbyte,
-        })
+        let tx_id = self.client.batch_send(out, fee_rate, reference).await?;
+        let raw_tx_hex = self
+            .client
+            .get_recent_raw_transaction(&tx_id)
+            .await
+            .ok()
+            .flatten();
+        Ok(OnChainPaymentResult::new(tx_id, &outputs, fee_rate).with_raw_tx_hex(raw_tx_hex))
     }
 }
 
@@ -135,6 +176,76 @@ impl OnChainTransactionApi for Lnd {
     }
 }
 
+#[async_trait]
+impl LightningInvoiceApi for Lnd {
+    async fn create_hold_invoice(
+        &self,
+        amount: Amount,
+        payment_hash: [u8; 32],
+        memo: Option<String>,
+        ttl: Option<i64>,
+    ) -> PaydayResult<LnInvoice> {
+        self.client
+            .create_hold_invoice(amount, payment_hash, memo, ttl)
+            .await
+    }
+
+    async fn settle_hold_invoice(&self, preimage: [u8; 32]) -> PaydayResult<()> {
+        self.client.settle_hold_invoice(preimage).await
+    }
+
+    async fn cancel_hold_invoice(&self, payment_hash: [u8; 32]) -> PaydayResult<()> {
+        self.client.cancel_hold_invoice(payment_hash).await
+    }
+}
+
+#[async_trait]
+impl LightningInvoiceCreateApi for Lnd {
+    async fn lookup_invoice(&self, payment_hash: [u8; 32]) -> PaydayResult<Option<LnInvoice>> {
+        self.client.lookup_invoice(payment_hash).await
+    }
+
+    async fn add_invoice(
+        &self,
+        request: LightningInvoiceRequest,
+        preimage: [u8; 32],
+    ) -> PaydayResult<LnInvoice> {
+        self.client.add_invoice(&request, Some(preimage)).await
+    }
+}
+
+#[async_trait]
+impl LightningPaymentApi for Lnd {
+    async fn decode_invoice(&self, invoice: &str) -> PaydayResult<(String, Amount)> {
+        self.client.decode_invoice(invoice).await
+    }
+
+    async fn send_payment(
+        &self,
+        request: LightningPaymentRequest,
+    ) -> PaydayResult<LightningPaymentResult> {
+        self.client.pay_invoice(request).await
+    }
+}
+
+#[async_trait]
+impl LightningPaymentStatusApi for Lnd {
+    async fn payment_status(&self, payment_hash: &str) -> PaydayResult<OutgoingPaymentStatus> {
+        self.client.payment_status(payment_hash).await
+    }
+}
+
+#[async_trait]
+impl LightningOfferApi for Lnd {
+    async fn pay_offer(
+        &self,
+        _offer: &Offer,
+        _amount: Option<PaydayAmount>,
+    ) -> PaydayResult<LightningPaymentResult> {
+        Err(PaydayError::NodeApiError("offers unsupported".to_string()))
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct LndConfig {
     pub name: String,
@@ -144,6 +255,13 @@ pub struct LndConfig {
     pub network: Network,
 }
 
+impl LndConfig {
+    /// The id of the configured node, shared by all node facing types.
+    pub fn node_id(&self) -> String {
+        self.name.to_owned()
+    }
+}
+
 /// Converts a satoshi amount to an Amount
 fn to_amount(sats: i64) -> Amount {
     if sats < 0 {
@@ -153,6 +271,74 @@ fn to_amount(sats: i64) -> Amount {
     }
 }
 
+/// Converts an LND payment failure reason to a PaymentFailureReason
+pub fn to_payment_failure_reason(reason: i32) -> PaymentFailureReason {
+    match lnrpc::PaymentFailureReason::try_from(reason) {
+        Ok(lnrpc::PaymentFailureReason::FailureReasonNone) => PaymentFailureReason::None,
+        Ok(lnrpc::PaymentFailureReason::FailureReasonTimeout) => PaymentFailureReason::Timeout,
+        Ok(lnrpc::PaymentFailureReason::FailureReasonNoRoute) => PaymentFailureReason::NoRoute,
+        Ok(lnrpc::PaymentFailureReason::FailureReasonError) => PaymentFailureReason::Error,
+        Ok(lnrpc::PaymentFailureReason::FailureReasonIncorrectPaymentDetails) => {
+            PaymentFailureReason::IncorrectPaymentDetails
+        }
+        Ok(lnrpc::PaymentFailureReason::FailureReasonInsufficientBalance) => {
+            PaymentFailureReason::InsufficientBalance
+        }
+        Err(_) => PaymentFailureReason::Error,
+    }
+}
+
+/// Converts an LND payment status to an OutgoingPaymentStatus. Statuses this
+/// version does not know are kept in flight rather than risking a second send.
+pub fn to_outgoing_payment_status(status: i32) -> OutgoingPaymentStatus {
+    match lnrpc::payment::PaymentStatus::try_from(status) {
+        Ok(lnrpc::payment::PaymentStatus::Succeeded) => OutgoingPaymentStatus::Succeeded,
+        Ok(lnrpc::payment::PaymentStatus::Failed) => OutgoingPaymentStatus::Failed,
+        Ok(lnrpc::payment::PaymentStatus::InFlight)
+        | Ok(lnrpc::payment::PaymentStatus::Unknown)
+        | Err(_) => OutgoingPaymentStatus::InFlight,
+    }
+}
+
+/// Converts a WalletBalanceResponse to an OnChainBalance
+fn to_on_chain_balance(res: &WalletBalanceResponse) -> OnChainBalance {
+    OnChainBalance {
+        total_balance: to_amount(res.total_balance),
+        unconfirmed_balance: to_amount(res.unconfirmed_balance),
+        confirmed_balance: to_amount(res.confirmed_balance),
+        locked_balance: to_amount(res.locked_balance),
+        reserved_balance_anchor_chan: to_amount(res.reserved_balance_anchor_chan),
+    }
+}
+
+/// Converts an LND invoice update to a LightningTransactionEvent. Open invoices and
+/// unknown states do not produce an event.
+pub fn to_lightning_event(invoice: &Invoice, node_id: &str) -> Option<LightningTransactionEvent> {
+    let tx = LightningTransaction {
+        node_id: node_id.to_owned(),
+        r_hash: invoice.r_hash.to_lower_hex_string(),
+        invoice: invoice.payment_request.to_owned(),
+        amount: to_amount(invoice.amt_paid_sat),
+        settle_index: invoice.settle_index,
+        settled_at: if invoice.settle_date > 0 {
+            Some(from_timestamp(invoice.settle_date))
+        } else {
+            None
+        },
+        custom_records: invoice
+            .htlcs
+            .iter()
+            .flat_map(|htlc| htlc.custom_records.to_owned())
+            .collect(),
+    };
+    match InvoiceState::try_from(invoice.state) {
+        Ok(InvoiceState::Settled) => Some(LightningTransactionEvent::Settled(tx)),
+        Ok(InvoiceState::Accepted) => Some(LightningTransactionEvent::Accepted(tx)),
+        Ok(InvoiceState::Canceled) => Some(LightningTransactionEvent::Canceled(tx)),
+        Ok(InvoiceState::Open) | Err(_) => None,
+    }
+}
+
 /// Converts a Transaction to a list of OnChainTransactionEvents.
 fn to_on_chain_events(
     tx: &Transaction,
@@ -177,9 +363,19 @@ fn to_on_chain_events(
                 let payload = OnChainTransaction {
                     tx_id: tx.tx_hash.to_owned(),
                     block_height: tx.block_height,
+                    block_hash: if tx.block_hash.is_empty() {
+                        None
+                    } else {
+                        Some(tx.block_hash.to_owned())
+                    },
                     confirmations: tx.num_confirmations,
                     amount: Amount::from_sat(tx.amount.unsigned_abs()),
                     address,
+                    timestamp: if tx.time_stamp > 0 {
+                        Some(from_timestamp(tx.time_stamp))
+                    } else {
+                        None
+                    },
                 };
 
                 match (confirmed, received) {
@@ -192,14 +388,39 @@ fn to_on_chain_events(
                 None
             }
         })
-        .collect();
+        .collect::<Vec<_>>();
+
+    // intentional for e.g. a send to an own address, which has no external output,
+    // but also the symptom of a misclassified transaction.
+    if res.is_empty() {
+        println!(
+            "No on-chain events for LND transaction {}, no {} output with a valid address",
+            tx.tx_hash,
+            if received { "wallet" } else { "external" }
+        );
+    }
     Ok(res)
 }
 
+/// The longest delay between attempts to re-subscribe to a node.
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Clone)]
 pub struct LndTransactionStream {
     config: LndConfig,
     handler: Arc<Mutex<dyn OnChainTransactionEventProcessorApi>>,
     start_height: Option<i32>,
+    backfill_window: i32,
+    catch_up_attempts: u32,
+    catch_up_backoff: Duration,
+    direction: TransactionDirection,
+    idle_timeout: Duration,
+    reconnect_backoff: Duration,
+    backfill: bool,
+    shutdown: ShutdownSignal,
+    /// Shared by the catch-up and all subscriptions, so transactions seen by
+    /// both are forwarded once per state.
+    seen: Arc<Mutex<EventDeduplicator>>,
 }
 
 impl LndTransactionStream {
@@ -212,66 +433,794 @@ impl LndTransactionStream {
             config,
             handler,
             start_height,
+            backfill_window: 1000,
+            catch_up_attempts: 5,
+            catch_up_backoff: Duration::from_secs(1),
+            direction: TransactionDirection::default(),
+            idle_timeout: Duration::from_secs(1800),
+            reconnect_backoff: Duration::from_secs(1),
+            backfill: true,
+            shutdown: shutdown_signal().1,
+            seen: Arc::new(Mutex::new(EventDeduplicator::default())),
+        }
+    }
+
+    /// Only forwards transactions of the given direction to the handler.
+    /// Invoicing only consumers should use TransactionDirection::Received.
+    pub fn with_direction(mut self, direction: TransactionDirection) -> Self {
+        self.direction = direction;
+        self
+    }
+
+    /// Sets how often the catch-up is attempted and the initial delay between
+    /// attempts. The delay doubles after every failed attempt.
+    pub fn with_catch_up_retry(mut self, attempts: u32, backoff: Duration) -> Self {
+        self.catch_up_attempts = attempts.max(1);
+        self.catch_up_backoff = backoff;
+        self
+    }
+
+    /// Sets how long the subscription may go without an event before it is torn
+    /// down and reconnected from the last processed block height. A quiet wallet
+    /// will reconnect once per window, so this should not be too short.
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Sets the initial delay before re-subscribing after the subscription ended
+    /// or could not be created, e.g. while the node restarts. The delay doubles
+    /// with every failed attempt up to a minute.
+    pub fn with_reconnect_backoff(mut self, reconnect_backoff: Duration) -> Self {
+        self.reconnect_backoff = reconnect_backoff;
+        self
+    }
+
+    /// Sets the number of blocks fetched per query during the backfill.
+    pub fn with_backfill_window(mut self, backfill_window: i32) -> Self {
+        self.backfill_window = backfill_window.max(1);
+        self
+    }
+
+    /// Enables or disables the catch-up of missed transactions on start and
+    /// reconnect. Without it only transactions seen by the live subscription are
+    /// handled, for setups that reconcile historic payments elsewhere.
+    pub fn with_backfill(mut self, backfill: bool) -> Self {
+        self.backfill = backfill;
+        self
+    }
+
+    /// Stops the subscription once the signal fires. An event being handled
+    /// is stored before the stream returns.
+    pub fn with_shutdown(mut self, shutdown: ShutdownSignal) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// The block windows to fetch when catching up from start_height to tip,
+    /// none if the backfill is disabled.
+    fn catch_up_windows(&self, start_height: i32, tip: i32) -> Vec<(i32, i32)> {
+        match self.backfill {
+            true => backfill_windows(start_height, tip, self.backfill_window),
+            false => Vec::new(),
         }
     }
 
-    /// does fetch potential missing events from the current start_height
-    async fn start_subscription(&self) -> PaydayResult<Vec<OnChainTransactionEvent>> {
+    /// does fetch potential missing events from the current start_height in windows
+    /// of backfill_window blocks, handling each window before fetching the next.
+    /// Node errors are returned so that the catch-up can be retried.
+    async fn start_subscription(&self) -> PaydayResult<()> {
         let lnd = Lnd::new(self.config.clone()).await?;
+        let tip = lnd.client.get_block_height().await?;
         let start_height = match self.start_height {
             Some(start_height) => start_height,
-            None => self.handler.lock().await.get_block_height().await?,
+            None => {
+                let handler = self.handler.lock().await;
+                match handler.get_block_height().await? {
+                    Some(block_height) => block_height,
+                    // a node seen for the first time starts at its current height
+                    // instead of scanning the chain from genesis.
+                    None => {
+                        handler.set_block_height(tip).await?;
+                        tip
+                    }
+                }
+            }
         };
 
-        let events = lnd.get_onchain_transactions(start_height, -1).await?;
-        Ok(events)
+        if !self.backfill {
+            // the live subscription starts from the tip, skipped blocks are not
+            // fetched on the next start either.
+            self.handler.lock().await.set_block_height(tip).await?;
+        }
+        for (start, end) in self.catch_up_windows(start_height, tip) {
+            let events = lnd.get_onchain_transactions(start, end).await?;
+            for event in events.into_iter().filter(|e| self.direction.matches(e)) {
+                if self.seen.lock().await.is_new(&event) {
+                    let seen = event.clone();
+                    self.handler.lock().await.process_event(event).await?;
+                    self.seen.lock().await.record(&seen);
+                }
+            }
+        }
+        Ok(())
     }
 }
 
-#[async_trait]
-impl OnChainStreamApi for LndTransactionStream {
-    async fn process_events(&self) -> PaydayResult<JoinHandle<()>> {
-        let start_events = self.start_subscription().await.ok().unwrap_or(vec![]);
-        for event in start_events {
-            self.handler.lock().await.process_event(event).await?;
+/// Splits the range from start_height to the chain tip into windows of at most
+/// window_size blocks. The last window is open ended to include unconfirmed
+/// transactions.
+fn backfill_windows(start_height: i32, tip: i32, window_size: i32) -> Vec<(i32, i32)> {
+    let window_size = window_size.max(1);
+    let mut windows = Vec::new();
+    let mut start = start_height;
+    while start + window_size <= tip {
+        windows.push((start, start + window_size - 1));
+        start += window_size;
+    }
+    windows.push((start, -1));
+    windows
+}
+
+/// Hands every item of the subscription to handle_event. A subscription that
+/// yields nothing within idle_timeout may be silently dead, e.g. half closed by a
+/// proxy, so it is dropped and a new one is created right away. A subscription
+/// that ended, could not be created or whose item failed to be handled is
+/// created again after a backoff starting at reconnect_backoff. Returns once
+/// shutdown fires, but never while an item is being handled.
+async fn consume_with_reconnect<S, Sub, SubFut, H, HFut>(
+    idle_timeout: Duration,
+    reconnect_backoff: Duration,
+    mut shutdown: ShutdownSignal,
+    mut subscribe: Sub,
+    mut handle_event: H,
+) where
+    S: Stream + Unpin,
+    Sub: FnMut() -> SubFut,
+    SubFut: Future<Output = PaydayResult<S>>,
+    H: FnMut(S::Item) -> HFut,
+    HFut: Future<Output = PaydayResult<()>>,
+{
+    let mut failures = 0;
+    while !shutdown.is_triggered() {
+        let idle = match subscribe().await {
+            Ok(mut stream) => loop {
+                let next = tokio::select! {
+                    _ = shutdown.wait() => return,
+                    next = tokio::time::timeout(idle_timeout, stream.next()) => next,
+                };
+                match next {
+                    Ok(Some(item)) => match handle_event(item).await {
+                        Ok(()) => failures = 0,
+                        Err(e) => {
+                            println!("Failed to handle event, reconnecting: {:?}", e);
+                            break false;
+                        }
+                    },
+                    Ok(None) => {
+                        println!("Subscription ended, reconnecting");
+                        break false;
+                    }
+                    Err(_) => {
+                        println!(
+                            "No event within {:?}, reconnecting subscription",
+                            idle_timeout
+                        );
+                        break true;
+                    }
+                }
+            },
+            Err(e) => {
+                println!("Failed to subscribe: {:?}", e);
+                false
+            }
+        };
+        if !idle {
+            let backoff = capped_backoff(failures, reconnect_backoff, MAX_RECONNECT_BACKOFF);
+            tokio::select! {
+                _ = shutdown.wait() => return,
+                _ = tokio::time::sleep(backoff) => {}
+            }
+            failures = failures.saturating_add(1);
         }
+    }
+}
+
+impl NodeApi for LndTransactionStream {
+    fn node_id(&self) -> String {
+        self.config.node_id()
+    }
+}
+
+impl LndTransactionStream {
+    /// Consumes the live subscription on the current task until shutdown.
+    /// Missed transactions are fetched before every subscription, including
+    /// the first one, and a failed catch-up is retried like a failed
+    /// subscription, so a restarted stream resumes from the last processed
+    /// block height.
+    pub fn run(&self) -> impl Future<Output = ()> + Send + 'static {
+        self.consume(true)
+    }
+
+    fn consume(&self, catch_up_first: bool) -> impl Future<Output = ()> + Send + 'static {
         let service = self.handler.clone();
         let config = self.config.clone();
+        let direction = self.direction;
+        let seen = self.seen.clone();
+        let (idle_timeout, reconnect_backoff) = (self.idle_timeout, self.reconnect_backoff);
+        let shutdown = self.shutdown.clone();
+        // reconnects resume from the last processed block height, not from the
+        // configured start height.
+        let resume = LndTransactionStream {
+            start_height: None,
+            ..self.clone()
+        };
+        let mut next_catch_up = catch_up_first.then(|| self.clone());
 
-        let handle = tokio::spawn(async move {
-            let mut lnd: Client = fedimint_tonic_lnd::connect(
-                config.address.to_string(),
-                config.cert_path.to_string(),
-                config.macaroon_file.to_string(),
-            )
-            .await
-            .expect("Failed to connect to LND on-chain transaction stream");
-
-            let mut stream = lnd
-                .lightning()
-                .subscribe_transactions(GetTransactionsRequest::default())
-                .await
-                .expect("Failed to subscribe to LND on-chain transaction events")
-                .into_inner()
-                .filter(|tx| tx.is_ok())
-                .map(|tx| tx.unwrap());
-
-            while let Some(event) = stream.next().await {
-                let events = to_on_chain_events(&event, config.network)
-                    .expect("Failed to parse LND on-chain transaction");
-
-                for event in events {
-                    service
-                        .lock()
-                        .await
-                        .process_event(event)
+        async move {
+            let subscribe = move || {
+                let config = resume.config.clone();
+                let catch_up = std::mem::replace(&mut next_catch_up, Some(resume.clone()));
+                async move {
+                    // missed transactions are fetched from the last processed
+                    // block height before the new subscription starts.
+                    if let Some(catch_up) = catch_up {
+                        retry_with_backoff(
+                            catch_up.catch_up_attempts,
+                            catch_up.catch_up_backoff,
+                            || catch_up.start_subscription(),
+                        )
+                        .await?;
+                    }
+
+                    let mut lnd: Client = fedimint_tonic_lnd::connect(
+                        config.address.to_string(),
+                        config.cert_path.to_string(),
+                        config.macaroon_file.to_string(),
+                    )
+                    .await
+                    .map_err(|e| PaydayError::NodeConnectError(e.to_string()))?;
+
+                    let stream = lnd
+                        .lightning()
+                        .subscribe_transactions(GetTransactionsRequest::default())
                         .await
-                        .expect("Failed to process LND on chain transaction event");
+                        .map_err(|e| PaydayError::NodeApiError(e.to_string()))?
+                        .into_inner()
+                        .filter(|tx| tx.is_ok())
+                        .map(|tx| tx.unwrap());
+                    Ok::<_, PaydayError>(stream)
                 }
-            }
+            };
+
+            let handle_event = move |event: Transaction| {
+                let service = service.clone();
+                let config = config.clone();
+                let seen = seen.clone();
+                // a failed event is not recorded as seen, so the catch-up of
+                // the next subscription handles it again.
+                async move {
+                    let events = to_on_chain_events(&event, config.network)?;
+
+                    for event in events.into_iter().filter(|e| direction.matches(e)) {
+                        if !seen.lock().await.is_new(&event) {
+                            continue;
+                        }
+                        let recorded = event.clone();
+                        service.lock().await.process_event(event).await?;
+                        seen.lock().await.record(&recorded);
+                    }
+                    Ok(())
+                }
+            };
+
+            consume_with_reconnect(
+                idle_timeout,
+                reconnect_backoff,
+                shutdown,
+                subscribe,
+                handle_event,
+            )
+            .await;
+        }
+    }
+}
+
+#[async_trait]
+impl OnChainStreamApi for LndTransactionStream {
+    async fn process_events(&self) -> PaydayResult<JoinHandle<()>> {
+        // the live subscription must not start from a wrong offset, so a failed
+        // catch-up is returned instead of being skipped.
+        retry_with_backoff(self.catch_up_attempts, self.catch_up_backoff, || {
+            self.start_subscription()
+        })
+        .await?;
+        Ok(tokio::spawn(self.consume(false)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fedimint_tonic_lnd::lnrpc::{InvoiceHtlc, OutputDetail};
+    use payday_btc::lightning_api::KEYSEND_MESSAGE_RECORD;
+    use payday_core::PaydayStream;
+
+    use super::*;
+
+    #[test]
+    fn test_to_on_chain_events_block_hash() {
+        let tx = Transaction {
+            tx_hash: "txid".to_string(),
+            amount: 100_000,
+            num_confirmations: 1,
+            block_hash: "000000000000000000025bc1".to_string(),
+            block_height: 800_000,
+            output_details: vec![OutputDetail {
+                address: "tb1q6xm2qgh5r83lvmmu0v7c3d4wrd9k2uxu3sgcr4".to_string(),
+                amount: 100_000,
+                is_our_address: true,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        let events = to_on_chain_events(&tx, Network::Signet).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            events[0].block_hash(),
+            Some("000000000000000000025bc1".to_string())
+        );
+    }
+
+    #[test]
+    fn test_to_on_chain_events_timestamp() {
+        let tx = |time_stamp: i64| Transaction {
+            tx_hash: "txid".to_string(),
+            amount: 100_000,
+            time_stamp,
+            output_details: vec![OutputDetail {
+                address: "tb1q6xm2qgh5r83lvmmu0v7c3d4wrd9k2uxu3sgcr4".to_string(),
+                amount: 100_000,
+                is_our_address: true,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let timestamp =
+            |tx: &Transaction| match to_on_chain_events(tx, Network::Signet).unwrap().as_slice() {
+                [OnChainTransactionEvent::ReceivedUnconfirmed(tx)] => tx.timestamp,
+                events => panic!("expected one unconfirmed event, got {:?}", events),
+            };
+
+        assert_eq!(
+            timestamp(&tx(1_700_000_000)),
+            Some(from_timestamp(1_700_000_000))
+        );
+        assert_eq!(timestamp(&tx(0)), None);
+    }
+
+    #[test]
+    fn test_filtered_outputs_produce_no_events() {
+        let tx = |amount: i64, is_our_address: bool| Transaction {
+            tx_hash: "txid".to_string(),
+            amount,
+            num_confirmations: 1,
+            output_details: vec![OutputDetail {
+                address: "tb1q6xm2qgh5r83lvmmu0v7c3d4wrd9k2uxu3sgcr4".to_string(),
+                amount: amount.abs(),
+                is_our_address,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        // a receive without a wallet output and a send to an own address only
+        assert!(to_on_chain_events(&tx(100_000, false), Network::Signet)
+            .unwrap()
+            .is_empty());
+        assert!(to_on_chain_events(&tx(-100_000, true), Network::Signet)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_received_direction_skips_sent_events() {
+        let tx = |amount: i64, is_our_address: bool| Transaction {
+            tx_hash: "txid".to_string(),
+            amount,
+            num_confirmations: 1,
+            output_details: vec![OutputDetail {
+                address: "tb1q6xm2qgh5r83lvmmu0v7c3d4wrd9k2uxu3sgcr4".to_string(),
+                amount: amount.abs(),
+                is_our_address,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let events = [tx(100_000, true), tx(-100_000, false)]
+            .iter()
+            .flat_map(|t| to_on_chain_events(t, Network::Signet).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(events.len(), 2);
+
+        let received: Vec<_> = events
+            .iter()
+            .filter(|e| TransactionDirection::Received.matches(e))
+            .collect();
+        assert_eq!(received.len(), 1);
+        assert!(matches!(
+            received[0],
+            OnChainTransactionEvent::ReceivedConfirmed(_)
+        ));
+        assert_eq!(
+            events
+                .iter()
+                .filter(|e| TransactionDirection::Both.matches(e))
+                .count(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_to_on_chain_balance() {
+        let balance = to_on_chain_balance(&WalletBalanceResponse {
+            total_balance: 1_000_000,
+            confirmed_balance: 900_000,
+            unconfirmed_balance: 100_000,
+            locked_balance: 50_000,
+            reserved_balance_anchor_chan: 10_000,
+            ..Default::default()
         });
+        assert_eq!(balance.locked_balance, Amount::from_sat(50_000));
+        assert_eq!(
+            balance.reserved_balance_anchor_chan,
+            Amount::from_sat(10_000)
+        );
+        assert_eq!(balance.spendable_balance(), Amount::from_sat(840_000));
+    }
+
+    #[test]
+    fn test_to_payment_failure_reason() {
+        let expected = [
+            PaymentFailureReason::None,
+            PaymentFailureReason::Timeout,
+            PaymentFailureReason::NoRoute,
+            PaymentFailureReason::Error,
+            PaymentFailureReason::IncorrectPaymentDetails,
+            PaymentFailureReason::InsufficientBalance,
+        ];
+        for (reason, expected) in expected.iter().enumerate() {
+            assert_eq!(to_payment_failure_reason(reason as i32), *expected);
+        }
+        assert_eq!(to_payment_failure_reason(99), PaymentFailureReason::Error);
+    }
+
+    #[test]
+    fn test_to_outgoing_payment_status() {
+        let expected = [
+            OutgoingPaymentStatus::InFlight,
+            OutgoingPaymentStatus::InFlight,
+            OutgoingPaymentStatus::Succeeded,
+            OutgoingPaymentStatus::Failed,
+        ];
+        for (status, expected) in expected.iter().enumerate() {
+            assert_eq!(to_outgoing_payment_status(status as i32), *expected);
+        }
+        assert_eq!(
+            to_outgoing_payment_status(99),
+            OutgoingPaymentStatus::InFlight
+        );
+    }
+
+    #[test]
+    fn test_to_lightning_event() {
+        let invoice = |state: InvoiceState| Invoice {
+            r_hash: vec![0xab, 0xcd],
+            payment_request: "lntbs1".to_string(),
+            amt_paid_sat: 1_000,
+            settle_index: 3,
+            state: state as i32,
+            ..Default::default()
+        };
+
+        assert!(to_lightning_event(&invoice(InvoiceState::Open), "lnd1").is_none());
+        assert!(matches!(
+            to_lightning_event(&invoice(InvoiceState::Accepted), "lnd1"),
+            Some(LightningTransactionEvent::Accepted(_))
+        ));
+        assert!(matches!(
+            to_lightning_event(&invoice(InvoiceState::Canceled), "lnd1"),
+            Some(LightningTransactionEvent::Canceled(_))
+        ));
+
+        let mut settled = invoice(InvoiceState::Settled);
+        settled.settle_date = 1_700_000_000;
+        settled.htlcs = vec![InvoiceHtlc {
+            custom_records: HashMap::from([(KEYSEND_MESSAGE_RECORD, b"thanks!".to_vec())]),
+            ..Default::default()
+        }];
+        match to_lightning_event(&settled, "lnd1") {
+            Some(LightningTransactionEvent::Settled(tx)) => {
+                assert_eq!(tx.node_id, "lnd1");
+                assert_eq!(tx.r_hash, "abcd");
+                assert_eq!(tx.amount, Amount::from_sat(1_000));
+                assert_eq!(tx.settle_index, 3);
+                assert_eq!(tx.settled_at, Some(from_timestamp(1_700_000_000)));
+                assert_eq!(tx.keysend_message(), Some("thanks!".to_string()));
+            }
+            e => panic!("expected settled event, got {:?}", e),
+        }
+
+        let mut unknown = invoice(InvoiceState::Open);
+        unknown.state = 42;
+        assert!(to_lightning_event(&unknown, "lnd1").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_node_id() {
+        let config = LndConfig {
+            name: "lnd1".to_string(),
+            address: "https://localhost:10009".to_string(),
+            cert_path: "tls.cert".to_string(),
+            macaroon_file: "admin.macaroon".to_string(),
+            network: Network::Signet,
+        };
+        let processor = MockProcessor(config.node_id());
+        let stream =
+            LndTransactionStream::new(config.clone(), Arc::new(Mutex::new(processor)), None);
+        assert_eq!(config.node_id(), "lnd1");
+        assert_eq!(stream.node_id(), config.node_id());
+        assert_eq!(stream.handler.lock().await.node_id(), config.node_id());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_subscription_reconnects() {
+        let subscriptions = Arc::new(Mutex::new(0));
+        let handled = Arc::new(Mutex::new(Vec::new()));
+
+        let subscribe = || {
+            let subscriptions = subscriptions.clone();
+            async move {
+                let mut count = subscriptions.lock().await;
+                *count += 1;
+                // the first subscription goes silent after one event, the
+                // reconnected one delivers another event and stays open.
+                let stream: PaydayStream<u32> = if *count == 1 {
+                    Box::pin(tokio_stream::iter(vec![1]).chain(tokio_stream::pending()))
+                } else {
+                    Box::pin(tokio_stream::iter(vec![2]).chain(tokio_stream::pending()))
+                };
+                Ok(stream)
+            }
+        };
+        let handle_event = |event: u32| {
+            let handled = handled.clone();
+            async move {
+                handled.lock().await.push(event);
+                Ok(())
+            }
+        };
+
+        let consumer = consume_with_reconnect(
+            Duration::from_secs(60),
+            Duration::from_secs(10),
+            shutdown_signal().1,
+            subscribe,
+            handle_event,
+        );
+        let checks = async {
+            tokio::time::sleep(Duration::from_secs(59)).await;
+            assert_eq!(*subscriptions.lock().await, 1);
+            assert_eq!(*handled.lock().await, vec![1]);
+
+            // reconnects right after the idle timeout, without a backoff
+            tokio::time::sleep(Duration::from_secs(2)).await;
+            assert_eq!(*subscriptions.lock().await, 2);
+            assert_eq!(*handled.lock().await, vec![1, 2]);
+        };
+
+        tokio::select! {
+            _ = consumer => unreachable!("never returns"),
+            _ = checks => {}
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_dropped_subscription_resubscribes() {
+        let subscriptions = Arc::new(Mutex::new(0));
+        let handled = Arc::new(Mutex::new(Vec::new()));
+
+        let subscribe = || {
+            let subscriptions = subscriptions.clone();
+            async move {
+                let mut count = subscriptions.lock().await;
+                *count += 1;
+                // the node is unreachable, then the stream drops after one
+                // event and the third subscription stays open.
+                let stream: PaydayStream<u32> = match *count {
+                    1 => return Err(PaydayError::NodeConnectError("restarting".to_string())),
+                    2 => Box::pin(tokio_stream::iter(vec![1])),
+                    _ => Box::pin(tokio_stream::iter(vec![2]).chain(tokio_stream::pending())),
+                };
+                Ok(stream)
+            }
+        };
+        let handle_event = |event: u32| {
+            let handled = handled.clone();
+            async move {
+                handled.lock().await.push(event);
+                Ok(())
+            }
+        };
+
+        let consumer = consume_with_reconnect(
+            Duration::from_secs(600),
+            Duration::from_secs(10),
+            shutdown_signal().1,
+            subscribe,
+            handle_event,
+        );
+        let checks = async {
+            // waits for the backoff after the failed subscription
+            tokio::time::sleep(Duration::from_secs(9)).await;
+            assert_eq!(*subscriptions.lock().await, 1);
+            assert!(handled.lock().await.is_empty());
+
+            tokio::time::sleep(Duration::from_secs(20)).await;
+            assert_eq!(*subscriptions.lock().await, 3);
+            assert_eq!(*handled.lock().await, vec![1, 2]);
+        };
+
+        tokio::select! {
+            _ = consumer => unreachable!("never returns"),
+            _ = checks => {}
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_failed_event_resubscribes() {
+        let subscriptions = Arc::new(Mutex::new(0));
+        let handled = Arc::new(Mutex::new(Vec::new()));
+
+        // every subscription catches up on the not yet handled event 1
+        let subscribe = || {
+            let subscriptions = subscriptions.clone();
+            async move {
+                *subscriptions.lock().await += 1;
+                let stream: PaydayStream<u32> =
+                    Box::pin(tokio_stream::iter(vec![1, 2]).chain(tokio_stream::pending()));
+                Ok(stream)
+            }
+        };
+        let handle_event = |event: u32| {
+            let handled = handled.clone();
+            async move {
+                let mut handled = handled.lock().await;
+                handled.push(event);
+                // the handler fails once on the first event
+                if handled.len() == 1 {
+                    return Err(PaydayError::DbError("unavailable".to_string()));
+                }
+                Ok(())
+            }
+        };
+
+        let consumer = consume_with_reconnect(
+            Duration::from_secs(600),
+            Duration::from_secs(10),
+            shutdown_signal().1,
+            subscribe,
+            handle_event,
+        );
+        let checks = async {
+            // the failed subscription is dropped before event 2
+            tokio::time::sleep(Duration::from_secs(9)).await;
+            assert_eq!(*subscriptions.lock().await, 1);
+            assert_eq!(*handled.lock().await, vec![1]);
+
+            tokio::time::sleep(Duration::from_secs(2)).await;
+            assert_eq!(*subscriptions.lock().await, 2);
+            assert_eq!(*handled.lock().await, vec![1, 1, 2]);
+        };
+
+        tokio::select! {
+            _ = consumer => unreachable!("never returns"),
+            _ = checks => {}
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_shutdown_stops_reconnect_after_handled_event() {
+        let subscriptions = Arc::new(Mutex::new(0));
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let (trigger, shutdown) = shutdown_signal();
+
+        let subscribe = || {
+            let subscriptions = subscriptions.clone();
+            async move {
+                *subscriptions.lock().await += 1;
+                let stream: PaydayStream<u32> =
+                    Box::pin(tokio_stream::iter(vec![1, 2]).chain(tokio_stream::pending()));
+                Ok(stream)
+            }
+        };
+        let handle_event = |event: u32| {
+            let handled = handled.clone();
+            async move {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                handled.lock().await.push(event);
+                Ok(())
+            }
+        };
+
+        let consumer = consume_with_reconnect(
+            Duration::from_secs(60),
+            Duration::from_secs(10),
+            shutdown,
+            subscribe,
+            handle_event,
+        );
+        let stop = async {
+            // fires while event 1 is being handled
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            trigger.trigger();
+            std::future::pending::<()>().await;
+        };
+
+        tokio::select! {
+            _ = consumer => {}
+            _ = stop => unreachable!("never returns"),
+        }
+        assert_eq!(*subscriptions.lock().await, 1);
+        assert_eq!(*handled.lock().await, vec![1]);
+    }
+
+    struct MockProcessor(String);
+
+    #[async_trait]
+    impl OnChainTransactionEventProcessorApi for MockProcessor {
+        fn node_id(&self) -> String {
+            self.0.to_owned()
+        }
+        async fn get_block_height(&self) -> PaydayResult<Option<i32>> {
+            Ok(None)
+        }
+        async fn set_block_height(&self, _block_height: i32) -> PaydayResult<()> {
+            Ok(())
+        }
+        async fn process_event(&self, _event: OnChainTransactionEvent) -> PaydayResult<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_disabled_backfill_fetches_nothing() {
+        let config = LndConfig {
+            name: "lnd1".to_string(),
+            address: "https://localhost:10009".to_string(),
+            cert_path: "tls.cert".to_string(),
+            macaroon_file: "admin.macaroon".to_string(),
+            network: Network::Signet,
+        };
+        let handler = Arc::new(Mutex::new(MockProcessor(config.node_id())));
+        let stream = LndTransactionStream::new(config, handler, Some(0));
+        assert_eq!(stream.catch_up_windows(2_400, 2_500), vec![(2_400, -1)]);
+
+        let stream = stream.with_backfill(false);
+        assert!(stream.catch_up_windows(0, 2_500).is_empty());
+        assert!(stream.catch_up_windows(2_500, 2_500).is_empty());
+    }
 
-        Ok(handle)
+    #[test]
+    fn test_backfill_windows() {
+        assert_eq!(
+            backfill_windows(0, 2_500, 1_000),
+            vec![(0, 999), (1_000, 1_999), (2_000, -1)]
+        );
+        assert_eq!(backfill_windows(2_400, 2_500, 1_000), vec![(2_400, -1)]);
+        assert_eq!(
+            backfill_windows(10, 12, 0),
+            vec![(10, 10), (11, 11), (12, -1)]
+        );
     }
 }
 
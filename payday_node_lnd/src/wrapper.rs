@@ -4,28 +4,50 @@
 //! handles connection and network checks, maps errors to project
 //! specific errors, and provides a convenient interface for the
 //! operations needed for invoicing.
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
-use bitcoin::{hex::DisplayHex, Address, Amount, Network};
+use bitcoin::{
+    hex::{DisplayHex, FromHex},
+    Address, Amount, Psbt,
+};
 use fedimint_tonic_lnd::{
+    invoicesrpc::{AddHoldInvoiceRequest, CancelInvoiceMsg, SettleInvoiceMsg},
     lnrpc::{
-        ChannelBalanceRequest, ChannelBalanceResponse, GetInfoRequest, GetTransactionsRequest,
-        Invoice, SendCoinsRequest, SendManyRequest, Transaction, WalletBalanceRequest,
-        WalletBalanceResponse,
+        payment::PaymentStatus, ChannelBalanceRequest, ChannelBalanceResponse, GetInfoRequest,
+        GetTransactionsRequest, Invoice, PayReqString, PaymentHash, SendCoinsRequest,
+        SendManyRequest, Transaction, WalletBalanceRequest, WalletBalanceResponse,
     },
+    routerrpc::{SendPaymentRequest, TrackPaymentRequest},
+    tonic::Code,
+    walletrpc::{fund_psbt_request, FundPsbtRequest, ReleaseOutputRequest, TxTemplate},
     Client,
 };
-use payday_btc::to_address;
-use payday_core::{payment::invoice::LnInvoice, PaydayError, PaydayResult, PaydayStream};
+use payday_btc::{
+    lightning_api::{
+        LightningInvoiceRequest, LightningPaymentRequest, LightningPaymentResult, OsPreimageSource,
+        PreimageSource,
+    },
+    on_chain_api::FeeRate,
+    parse_network, to_address,
+};
+use payday_core::{
+    date::{add_duration, from_timestamp, now, DateTime},
+    node::NodeApi,
+    payment::invoice::LnInvoice,
+    persistence::payment_log::OutgoingPaymentStatus,
+    PaydayError, PaydayResult, PaydayStream,
+};
 use tokio::sync::{Mutex, MutexGuard};
 use tokio_stream::StreamExt;
 
-use crate::lnd::LndConfig;
+use crate::lnd::{to_outgoing_payment_status, to_payment_failure_reason, LndConfig};
 
 #[derive(Clone)]
 pub struct LndRpcWrapper {
     config: LndConfig,
     client: Arc<Mutex<Client>>,
+    preimage_source: Arc<dyn PreimageSource>,
+    default_fee_limit: Amount,
 }
 
 impl LndRpcWrapper {
@@ -52,26 +74,55 @@ impl LndRpcWrapper {
             .network
             .to_string();
 
-        let network = Network::from_core_arg(network_info.as_str())?;
+        let network = parse_network(network_info.as_str())?;
         if config.network != network {
             return Err(PaydayError::InvalidBitcoinNetwork(network_info));
         }
         Ok(Self {
             config,
             client: Arc::new(Mutex::new(lnd)),
+            preimage_source: Arc::new(OsPreimageSource),
+            default_fee_limit: DEFAULT_FEE_LIMIT,
         })
     }
 
+    /// Sets the source for preimages of invoices created without an explicit
+    /// preimage.
+    pub fn with_preimage_source(mut self, preimage_source: Arc<dyn PreimageSource>) -> Self {
+        self.preimage_source = preimage_source;
+        self
+    }
+
+    /// Sets the routing fee limit of payments requested without a fee limit.
+    pub fn with_default_fee_limit(mut self, fee_limit: Amount) -> Self {
+        self.default_fee_limit = fee_limit;
+        self
+    }
+
     /// Get the unique name of the LND server. Names are used to
     /// identify the server in logs and associated addresses and invoices.
+    #[deprecated(note = "use NodeApi::node_id")]
     pub fn get_name(&self) -> String {
-        self.config.name.to_string()
+        self.node_id()
     }
 
     async fn client(&self) -> MutexGuard<Client> {
         self.client.lock().await
     }
 
+    /// Get the current block height of the node.
+    pub async fn get_block_height(&self) -> PaydayResult<i32> {
+        let mut lnd = self.client().await;
+        let height = lnd
+            .lightning()
+            .get_info(GetInfoRequest {})
+            .await
+            .map_err(|e| PaydayError::NodeApiError(e.to_string()))?
+            .into_inner()
+            .block_height;
+        Ok(height as i32)
+    }
+
     pub async fn get_onchain_balance(&self) -> PaydayResult<WalletBalanceResponse> {
         let mut lnd = self.client().await;
         Ok(lnd
@@ -120,12 +171,13 @@ impl LndRpcWrapper {
     }
 
     /// Send coins to an address. Address is parsed and validated for the configure network.
-    /// Returns the transaction id.
+    /// The reference is stored as transaction label. Returns the transaction id.
     pub async fn send_coins(
         &self,
         amount: Amount,
         address: &str,
-        sats_per_vbyte: Amount,
+        fee_rate: FeeRate,
+        reference: Option<String>,
     ) -> PaydayResult<String> {
         let checked_address = to_address(address, self.config.network)?;
         let txid = self
@@ -135,22 +187,77 @@ impl LndRpcWrapper {
             .send_coins(SendCoinsRequest {
                 addr: checked_address.to_string(),
                 amount: amount.to_sat() as i64,
-                sat_per_vbyte: sats_per_vbyte.to_sat(),
+                sat_per_vbyte: fee_rate.to_sat_per_vb(),
+                min_confs: MIN_CONFS,
+                label: reference.unwrap_or_default(),
                 ..Default::default()
             })
             .await
-            .map_err(|e| PaydayError::NodeApiError(e.to_string()))?
+            .map_err(|e| to_send_error(e.message()))?
             .into_inner()
             .txid;
 
         Ok(txid.to_string())
     }
 
+    /// Estimate the absolute fee of sending amount to address. LND funds a
+    /// PSBT for the send without broadcasting it, so the fee covers the actual
+    /// inputs and change output. All inputs locked for the PSBT are released
+    /// right away, failures are reported together once every release ran.
+    pub async fn estimate_send_fee(
+        &self,
+        amount: Amount,
+        address: &str,
+        fee_rate: FeeRate,
+    ) -> PaydayResult<Amount> {
+        let checked_address = to_address(address, self.config.network)?;
+        let mut lnd = self.client().await;
+        let funded = lnd
+            .wallet()
+            .fund_psbt(FundPsbtRequest {
+                template: Some(fund_psbt_request::Template::Raw(TxTemplate {
+                    outputs: HashMap::from([(checked_address.to_string(), amount.to_sat())]),
+                    ..Default::default()
+                })),
+                fees: Some(fund_psbt_request::Fees::SatPerVbyte(
+                    fee_rate.to_sat_per_vb(),
+                )),
+                min_confs: MIN_CONFS,
+                ..Default::default()
+            })
+            .await
+            .map_err(|e| to_send_error(e.message()))?
+            .into_inner();
+
+        let mut release_errors = Vec::new();
+        for lease in funded.locked_utxos {
+            if let Err(e) = lnd
+                .wallet()
+                .release_output(ReleaseOutputRequest {
+                    id: lease.id,
+                    outpoint: lease.outpoint,
+                })
+                .await
+            {
+                release_errors.push(e.message().to_string());
+            }
+        }
+        if !release_errors.is_empty() {
+            return Err(PaydayError::NodeApiError(format!(
+                "failed to release {} leased outputs: {}",
+                release_errors.len(),
+                release_errors.join("; ")
+            )));
+        }
+        psbt_fee(&funded.funded_psbt)
+    }
+
     /// Send coins to multiple addresses.
     pub async fn batch_send(
         &self,
         outputs: HashMap<Address, i64>,
-        sats_per_vbyte: Amount,
+        fee_rate: FeeRate,
+        reference: Option<String>,
     ) -> PaydayResult<String> {
         let out = outputs
             .iter()
@@ -162,23 +269,25 @@ impl LndRpcWrapper {
             .lightning()
             .send_many(SendManyRequest {
                 addr_to_amount: out,
-                sat_per_vbyte: sats_per_vbyte.to_sat(),
+                sat_per_vbyte: fee_rate.to_sat_per_vb(),
+                min_confs: MIN_CONFS,
+                label: reference.unwrap_or_default(),
                 ..Default::default()
             })
             .await
-            .map_err(|e| PaydayError::NodeApiError(e.to_string()))?
+            .map_err(|e| to_send_error(e.message()))?
             .into_inner()
             .txid;
 
         Ok(txid.to_owned())
     }
 
-    /// Estimate the fee for a transaction.
+    /// Estimate the fee rate for a transaction.
     pub async fn estimate_fee(
         &self,
         target_conf: i32,
         outputs: HashMap<String, i64>,
-    ) -> PaydayResult<Amount> {
+    ) -> PaydayResult<FeeRate> {
         let fee = self
             .client()
             .await
@@ -193,7 +302,7 @@ impl LndRpcWrapper {
             .into_inner()
             .sat_per_vbyte;
 
-        Ok(Amount::from_sat(fee))
+        Ok(FeeRate::from_sat_per_vb(fee))
     }
 
     pub async fn create_invoice(
@@ -202,13 +311,90 @@ impl LndRpcWrapper {
         memo: Option<String>,
         ttl: Option<i64>,
     ) -> PaydayResult<LnInvoice> {
+        let request = LightningInvoiceRequest {
+            memo,
+            ttl,
+            ..LightningInvoiceRequest::new(amount)
+        };
+        self.create_ln_invoice(request).await
+    }
+
+    /// Create an invoice from the request. If a fallback address is requested,
+    /// a new on-chain address is generated for the invoice.
+    pub async fn create_ln_invoice(
+        &self,
+        request: LightningInvoiceRequest,
+    ) -> PaydayResult<LnInvoice> {
+        self.add_invoice(&request, None).await
+    }
+
+    /// Look up an invoice by its payment hash. Returns None if the node does not
+    /// know the invoice.
+    pub async fn lookup_invoice(&self, payment_hash: [u8; 32]) -> PaydayResult<Option<LnInvoice>> {
+        let res = self
+            .client()
+            .await
+            .lightning()
+            .lookup_invoice(PaymentHash {
+                r_hash: payment_hash.to_vec(),
+                ..Default::default()
+            })
+            .await;
+        match res {
+            Ok(invoice) => Ok(Some(to_ln_invoice(&invoice.into_inner()))),
+            Err(e) if e.code() == Code::NotFound => Ok(None),
+            Err(e) => Err(PaydayError::NodeApiError(e.to_string())),
+        }
+    }
+
+    /// Create an invoice settled by revealing the given preimage. Without a
+    /// preimage, one is taken from the preimage source of the wrapper.
+    pub async fn add_invoice(
+        &self,
+        request: &LightningInvoiceRequest,
+        preimage: Option<[u8; 32]>,
+    ) -> PaydayResult<LnInvoice> {
+        let preimage = preimage.unwrap_or_else(|| self.preimage_source.generate());
+        let fallback_address = match request.fallback_address {
+            true => Some(self.new_address().await?),
+            false => None,
+        };
         let mut lnd = self.client().await;
+        let created_at = now();
         let invoice = lnd
             .lightning()
-            .add_invoice(Invoice {
+            .add_invoice(to_invoice(request, preimage, fallback_address))
+            .await
+            .map_err(|e| PaydayError::NodeApiError(e.to_string()))?
+            .into_inner();
+
+        Ok(LnInvoice {
+            invoice: invoice.payment_request,
+            r_hash: invoice.r_hash.as_hex().to_string(),
+            add_index: invoice.add_index,
+            expires_at: Some(invoice_expires_at(created_at, request.ttl)),
+        })
+    }
+
+    /// Create a hold invoice for the given payment hash. The invoice is
+    /// accepted when paid and only settled by settle_hold_invoice.
+    pub async fn create_hold_invoice(
+        &self,
+        amount: Amount,
+        payment_hash: [u8; 32],
+        memo: Option<String>,
+        ttl: Option<i64>,
+    ) -> PaydayResult<LnInvoice> {
+        let created_at = now();
+        let invoice = self
+            .client()
+            .await
+            .invoices()
+            .add_hold_invoice(AddHoldInvoiceRequest {
+                hash: payment_hash.to_vec(),
                 value: amount.to_sat() as i64,
-                memo: memo.unwrap_or("ln invoice".to_string()),
-                expiry: ttl.unwrap_or(3600i64),
+                memo: memo.unwrap_or("ln hold invoice".to_string()),
+                expiry: ttl.unwrap_or(DEFAULT_INVOICE_TTL),
                 ..Default::default()
             })
             .await
@@ -217,11 +403,123 @@ impl LndRpcWrapper {
 
         Ok(LnInvoice {
             invoice: invoice.payment_request,
-            r_hash: invoice.r_hash.as_hex().to_string(),
+            r_hash: payment_hash.as_hex().to_string(),
             add_index: invoice.add_index,
+            expires_at: Some(invoice_expires_at(created_at, ttl)),
         })
     }
 
+    /// Settle an accepted hold invoice with its preimage.
+    pub async fn settle_hold_invoice(&self, preimage: [u8; 32]) -> PaydayResult<()> {
+        self.client()
+            .await
+            .invoices()
+            .settle_invoice(SettleInvoiceMsg {
+                preimage: preimage.to_vec(),
+            })
+            .await
+            .map_err(|e| PaydayError::NodeApiError(e.to_string()))?;
+        Ok(())
+    }
+
+    /// Cancel a hold invoice, returning an accepted payment to the sender.
+    pub async fn cancel_hold_invoice(&self, payment_hash: [u8; 32]) -> PaydayResult<()> {
+        self.client()
+            .await
+            .invoices()
+            .cancel_invoice(CancelInvoiceMsg {
+                payment_hash: payment_hash.to_vec(),
+            })
+            .await
+            .map_err(|e| PaydayError::NodeApiError(e.to_string()))?;
+        Ok(())
+    }
+
+    /// Decodes a BOLT11 invoice into its hex encoded payment hash and amount.
+    pub async fn decode_invoice(&self, invoice: &str) -> PaydayResult<(String, Amount)> {
+        let pay_req = self
+            .client()
+            .await
+            .lightning()
+            .decode_pay_req(PayReqString {
+                pay_req: invoice.to_string(),
+            })
+            .await
+            .map_err(|e| PaydayError::NodeApiError(e.to_string()))?
+            .into_inner();
+        Ok((
+            pay_req.payment_hash,
+            Amount::from_sat(pay_req.num_satoshis.unsigned_abs()),
+        ))
+    }
+
+    /// Pay a BOLT11 invoice and wait for the payment to complete.
+    pub async fn pay_invoice(
+        &self,
+        request: LightningPaymentRequest,
+    ) -> PaydayResult<LightningPaymentResult> {
+        let mut stream = {
+            let mut lnd = self.client().await;
+            lnd.router()
+                .send_payment_v2(to_send_payment_request(&request, self.default_fee_limit))
+                .await
+                .map_err(|e| PaydayError::NodeApiError(e.to_string()))?
+                .into_inner()
+        };
+
+        while let Some(payment) = stream
+            .message()
+            .await
+            .map_err(|e| PaydayError::NodeApiError(e.to_string()))?
+        {
+            if payment.status == PaymentStatus::Succeeded as i32 {
+                return Ok(LightningPaymentResult {
+                    payment_hash: payment.payment_hash,
+                    payment_preimage: payment.payment_preimage,
+                    amount: Amount::from_sat(payment.value_sat.unsigned_abs()),
+                    fee: Amount::from_sat(payment.fee_sat.unsigned_abs()),
+                });
+            }
+            if payment.status == PaymentStatus::Failed as i32 {
+                return Err(PaydayError::LightningPaymentFailed(
+                    to_payment_failure_reason(payment.failure_reason),
+                ));
+            }
+        }
+        Err(PaydayError::NodeApiError(
+            "payment stream ended without final status".to_string(),
+        ))
+    }
+
+    /// Returns the current status of an outgoing payment by its hex encoded
+    /// payment hash. Payments LND does not know about are reported as unknown,
+    /// they may still be sent.
+    pub async fn payment_status(&self, payment_hash: &str) -> PaydayResult<OutgoingPaymentStatus> {
+        let payment_hash = Vec::<u8>::from_hex(payment_hash)
+            .map_err(|e| PaydayError::NodeApiError(e.to_string()))?;
+        let response = {
+            let mut lnd = self.client().await;
+            lnd.router()
+                .track_payment_v2(TrackPaymentRequest {
+                    payment_hash,
+                    no_inflight_updates: false,
+                })
+                .await
+        };
+        let payment = match response {
+            Ok(stream) => stream.into_inner().message().await,
+            Err(e) => Err(e),
+        };
+        match payment {
+            Ok(Some(payment)) => Ok(to_outgoing_payment_status(payment.status)),
+            Ok(None) => Err(PaydayError::NodeApiError(
+                "payment stream ended without status".to_string(),
+            )),
+            Err(e) if e.code() == Code::NotFound => Ok(OutgoingPaymentStatus::Unknown),
+            Err(e) => Err(PaydayError::NodeApiError(e.to_string())),
+        }
+    }
+
     /// Get a stream of onchain transactions relevant to the wallet. As LND RPC does not handle
     /// the request arguments, we do not provide any on this method to avoid confusion.
     pub async fn subscribe_transactions(&self) -> PaydayResult<PaydayStream<Transaction>> {
@@ -256,4 +554,297 @@ impl LndRpcWrapper {
             .into_inner()
             .transactions)
     }
+
+    /// Returns the raw transaction hex of a wallet transaction that is
+    /// unconfirmed or confirmed in the latest block, e.g. right after sending.
+    pub async fn get_recent_raw_transaction(&self, tx_id: &str) -> PaydayResult<Option<String>> {
+        let tip = self.get_block_height().await?;
+        let transactions = self.get_transactions(tip, -1).await?;
+        Ok(find_raw_transaction(&transactions, tx_id))
+    }
+}
+
+impl NodeApi for LndRpcWrapper {
+    fn node_id(&self) -> String {
+        self.config.node_id()
+    }
+}
+
+/// Maps the message of a failed LND send request to a specific error so callers
+/// can react to invalid addresses, missing funds or too low fee rates. Unknown
+/// messages are returned as NodeApiError.
+fn to_send_error(message: &str) -> PaydayError {
+    let msg = message.to_lowercase();
+    if msg.contains("insufficient funds") || msg.contains("not enough witness outputs") {
+        PaydayError::InsufficientFunds(message.to_string())
+    } else if msg.contains("min relay fee not met")
+        || (msg.contains("fee rate") && (msg.contains("too low") || msg.contains("below")))
+    {
+        PaydayError::FeeRateTooLow(message.to_string())
+    } else if msg.contains("address")
+        && (msg.contains("invalid")
+            || msg.contains("unknown format")
+            || msg.contains("not for the")
+            || msg.contains("checksum"))
+    {
+        PaydayError::InvalidBitcoinAddress(message.to_string())
+    } else {
+        PaydayError::NodeApiError(message.to_string())
+    }
+}
+
+/// Expiry in seconds of invoices created without a ttl.
+const DEFAULT_INVOICE_TTL: i64 = 3600;
+
+/// The expiry of an invoice created with ttl. Taken from before the invoice is
+/// added, so it is never later than the expiry known to the node.
+fn invoice_expires_at(created_at: DateTime, ttl: Option<i64>) -> DateTime {
+    let ttl = ttl.unwrap_or(DEFAULT_INVOICE_TTL).max(0) as u64;
+    add_duration(created_at, Duration::from_secs(ttl))
+}
+
+/// Maps an invoice known to LND to an LnInvoice. The expiry is taken from the
+/// creation date and expiry stored on the node.
+fn to_ln_invoice(invoice: &Invoice) -> LnInvoice {
+    LnInvoice {
+        invoice: invoice.payment_request.to_owned(),
+        r_hash: invoice.r_hash.as_hex().to_string(),
+        add_index: invoice.add_index,
+        expires_at: Some(add_duration(
+            from_timestamp(invoice.creation_date),
+            Duration::from_secs(invoice.expiry.max(0) as u64),
+        )),
+    }
+}
+
+/// Maps an invoice request to an LND invoice with the given preimage and
+/// optional on-chain fallback address.
+fn to_invoice(
+    request: &LightningInvoiceRequest,
+    preimage: [u8; 32],
+    fallback_address: Option<Address>,
+) -> Invoice {
+    Invoice {
+        value: request.amount.to_sat() as i64,
+        memo: request.memo.to_owned().unwrap_or("ln invoice".to_string()),
+        expiry: request.ttl.unwrap_or(DEFAULT_INVOICE_TTL),
+        r_preimage: preimage.to_vec(),
+        fallback_addr: fallback_address.map(|a| a.to_string()).unwrap_or_default(),
+        ..Default::default()
+    }
+}
+
+/// Routing fee limit of payments requested without a fee limit. LND only
+/// considers zero fee routes when the limit is left at zero.
+const DEFAULT_FEE_LIMIT: Amount = Amount::from_sat(1_000);
+
+/// Maps a payment request to an LND router payment request. The default fee
+/// limit applies when the request does not set one.
+fn to_send_payment_request(
+    request: &LightningPaymentRequest,
+    default_fee_limit: Amount,
+) -> SendPaymentRequest {
+    SendPaymentRequest {
+        payment_request: request.invoice.to_owned(),
+        amt: request
+            .amount
+            .map(|a| a.to_sat() as i64)
+            .unwrap_or_default(),
+        fee_limit_sat: request.fee_limit.unwrap_or(default_fee_limit).to_sat() as i64,
+        timeout_seconds: request.timeout_seconds,
+        outgoing_chan_ids: request.outgoing_chan_ids.to_owned(),
+        last_hop_pubkey: request
+            .last_hop_pubkey
+            .map(|p| p.serialize().to_vec())
+            .unwrap_or_default(),
+        ..Default::default()
+    }
+}
+
+/// The raw hex of the transaction with tx_id, None if it is not in the list or
+/// LND did not return the raw transaction.
+fn find_raw_transaction(transactions: &[Transaction], tx_id: &str) -> Option<String> {
+    transactions
+        .iter()
+        .find(|tx| tx.tx_hash == tx_id && !tx.raw_tx_hex.is_empty())
+        .map(|tx| tx.raw_tx_hex.to_owned())
+}
+
+/// Sends and fee estimates only spend confirmed outputs. LND adds change below
+/// the dust limit to the fee.
+const MIN_CONFS: i32 = 1;
+
+/// The fee of a funded PSBT, the sum of its inputs minus the sum of its outputs.
+fn psbt_fee(funded_psbt: &[u8]) -> PaydayResult<Amount> {
+    Psbt::deserialize(funded_psbt)
+        .map_err(|e| PaydayError::NodeApiError(e.to_string()))?
+        .fee()
+        .map_err(|e| PaydayError::NodeApiError(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[test]
+    fn test_to_send_error() {
+        assert!(matches!(
+            to_send_error("insufficient funds available to construct transaction"),
+            PaydayError::InsufficientFunds(_)
+        ));
+        assert!(matches!(
+            to_send_error("fee rate of 250 sat/kw is too low, minimum is 253 sat/kw"),
+            PaydayError::FeeRateTooLow(_)
+        ));
+        assert!(matches!(
+            to_send_error("min relay fee not met"),
+            PaydayError::FeeRateTooLow(_)
+        ));
+        assert!(matches!(
+            to_send_error("decoded address is of unknown format"),
+            PaydayError::InvalidBitcoinAddress(_)
+        ));
+        assert!(matches!(
+            to_send_error("invalid address: checksum mismatch"),
+            PaydayError::InvalidBitcoinAddress(_)
+        ));
+        assert!(matches!(
+            to_send_error("wallet locked"),
+            PaydayError::NodeApiError(_)
+        ));
+    }
+
+    #[test]
+    fn test_invoice_fallback_address() {
+        let address = Address::from_str("tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx")
+            .unwrap()
+            .assume_checked();
+        let request = LightningInvoiceRequest::new(Amount::from_sat(10_000))
+            .memo("order 1")
+            .fallback_address(true);
+        let invoice = to_invoice(&request, [1; 32], Some(address.clone()));
+        assert_eq!(invoice.fallback_addr, address.to_string());
+        assert_eq!(invoice.value, 10_000);
+        assert_eq!(invoice.memo, "order 1");
+
+        let invoice = to_invoice(
+            &LightningInvoiceRequest::new(Amount::ONE_SAT),
+            [1; 32],
+            None,
+        );
+        assert!(invoice.fallback_addr.is_empty());
+    }
+
+    #[test]
+    fn test_invoice_expires_at() {
+        let created_at = payday_core::date::from_timestamp(1_700_000_000);
+        assert_eq!(
+            invoice_expires_at(created_at, None),
+            payday_core::date::from_timestamp(1_700_003_600)
+        );
+        assert_eq!(
+            invoice_expires_at(created_at, Some(600)),
+            payday_core::date::from_timestamp(1_700_000_600)
+        );
+    }
+
+    #[test]
+    fn test_to_ln_invoice() {
+        let invoice = to_ln_invoice(&Invoice {
+            payment_request: "lntbs1".to_string(),
+            r_hash: vec![0xab, 0xcd],
+            add_index: 3,
+            creation_date: 1_700_000_000,
+            expiry: 600,
+            ..Default::default()
+        });
+        assert_eq!(invoice.invoice, "lntbs1");
+        assert_eq!(invoice.r_hash, "abcd");
+        assert_eq!(invoice.add_index, 3);
+        assert_eq!(
+            invoice.expires_at,
+            Some(payday_core::date::from_timestamp(1_700_000_600))
+        );
+    }
+
+    #[test]
+    fn test_psbt_fee() {
+        let script = |address: &str| {
+            Address::from_str(address)
+                .unwrap()
+                .assume_checked()
+                .script_pubkey()
+        };
+        let tx = bitcoin::Transaction {
+            version: bitcoin::transaction::Version::TWO,
+            lock_time: bitcoin::absolute::LockTime::ZERO,
+            input: vec![bitcoin::TxIn::default()],
+            output: vec![
+                bitcoin::TxOut {
+                    value: Amount::from_sat(50_000),
+                    script_pubkey: script("tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx"),
+                },
+                bitcoin::TxOut {
+                    value: Amount::from_sat(49_718),
+                    script_pubkey: script(
+                        "tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7",
+                    ),
+                },
+            ],
+        };
+        let mut psbt = Psbt::from_unsigned_tx(tx).unwrap();
+        psbt.inputs[0].witness_utxo = Some(bitcoin::TxOut {
+            value: Amount::from_sat(100_000),
+            script_pubkey: script("tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7"),
+        });
+
+        // 141 vbytes of a single input, two output P2WPKH send at 2 sat/vbyte
+        assert_eq!(psbt_fee(&psbt.serialize()).unwrap(), Amount::from_sat(282));
+        assert!(psbt_fee(b"not a psbt").is_err());
+    }
+
+    #[test]
+    fn test_find_raw_transaction() {
+        let tx = |tx_hash: &str, raw_tx_hex: &str| Transaction {
+            tx_hash: tx_hash.to_string(),
+            raw_tx_hex: raw_tx_hex.to_string(),
+            ..Default::default()
+        };
+        let transactions = vec![tx("other", "0100"), tx("txid", "0200"), tx("empty", "")];
+        assert_eq!(
+            find_raw_transaction(&transactions, "txid"),
+            Some("0200".to_string())
+        );
+        assert_eq!(find_raw_transaction(&transactions, "empty"), None);
+        assert_eq!(find_raw_transaction(&transactions, "unknown"), None);
+    }
+
+    #[test]
+    fn test_payment_route_constraints() {
+        let pubkey = bitcoin::secp256k1::PublicKey::from_str(
+            "02eec7245d6b7d2ccb30380bfbe2a3648cd7a942653f5aa340edcea1f283686619",
+        )
+        .unwrap();
+        let request = LightningPaymentRequest::new("lntbs1")
+            .outgoing_chan_ids(vec![123, 456])
+            .last_hop_pubkey(pubkey);
+
+        let send = to_send_payment_request(&request, DEFAULT_FEE_LIMIT);
+        assert_eq!(send.payment_request, "lntbs1");
+        assert_eq!(send.outgoing_chan_ids, vec![123, 456]);
+        assert_eq!(send.last_hop_pubkey, pubkey.serialize().to_vec());
+    }
+
+    #[test]
+    fn test_payment_fee_limit() {
+        let request = LightningPaymentRequest::new("lntbs1");
+        let send = to_send_payment_request(&request, Amount::from_sat(250));
+        assert_eq!(send.fee_limit_sat, 250);
+
+        let request = request.fee_limit(Amount::from_sat(10));
+        let send = to_send_payment_request(&request, Amount::from_sat(250));
+        assert_eq!(send.fee_limit_sat, 10);
+    }
 }
@@ -0,0 +1,2 @@
+-- the time an event was stored, used to find invoices that stopped progressing
+ALTER TABLE events ADD COLUMN IF NOT EXISTS created_at timestamptz NOT NULL DEFAULT now();
@@ -0,0 +1,12 @@
+-- outgoing payments, looked up by status for reconciliation and by reference
+CREATE TABLE IF NOT EXISTS payment_log
+(
+    payment_id text        NOT NULL,
+    reference  text,
+    amount     json        NOT NULL,
+    status     text        NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT now(),
+    PRIMARY KEY (payment_id)
+);
+
+CREATE INDEX IF NOT EXISTS payment_log_reference ON payment_log (reference);
@@ -0,0 +1,19 @@
+-- read models of on-chain invoices, written by a versioned postgres-es view
+-- repository. Lookups read the invoice id, addresses and state from the payload.
+CREATE TABLE IF NOT EXISTS on_chain_invoice_view
+(
+    view_id    text        NOT NULL,
+    version    bigint CHECK (version >= 0) NOT NULL,
+    payload    json        NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT now(),
+    PRIMARY KEY (view_id)
+);
+
+CREATE INDEX IF NOT EXISTS on_chain_invoice_view_invoice_id ON on_chain_invoice_view ((payload ->> 'invoice_id'));
+CREATE INDEX IF NOT EXISTS on_chain_invoice_view_address ON on_chain_invoice_view ((payload ->> 'address'));
+CREATE INDEX IF NOT EXISTS on_chain_invoice_view_previous_addresses ON on_chain_invoice_view
+    USING gin (((payload -> 'previous_addresses')::jsonb));
+CREATE INDEX IF NOT EXISTS on_chain_invoice_view_block_height ON on_chain_invoice_view
+    (((payload ->> 'block_height')::integer)) WHERE payload ->> 'block_height' IS NOT NULL;
+CREATE INDEX IF NOT EXISTS on_chain_invoice_view_open ON on_chain_invoice_view (created_at)
+    WHERE NOT (payload ->> 'paid')::boolean AND NOT (payload ->> 'expired')::boolean;
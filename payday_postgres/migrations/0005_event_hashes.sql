@@ -0,0 +1,82 @@
+-- hash chain over all stored events, extended when an event is stored, so a
+-- later change to an event row no longer matches its hash
+CREATE TABLE IF NOT EXISTS event_hashes
+(
+    position       bigserial NOT NULL,
+    aggregate_type text      NOT NULL,
+    aggregate_id   text      NOT NULL,
+    sequence       bigint    NOT NULL,
+    previous_hash  bytea     NOT NULL,
+    hash           bytea     NOT NULL,
+    PRIMARY KEY (position),
+    UNIQUE (aggregate_type, aggregate_id, sequence)
+);
+
+CREATE OR REPLACE FUNCTION length_prefixed(value text) RETURNS bytea AS
+$$
+SELECT int8send(octet_length(convert_to(value, 'UTF8'))::bigint) || convert_to(value, 'UTF8')
+$$ LANGUAGE sql IMMUTABLE;
+
+-- must match ExportedEvent::to_bytes
+CREATE OR REPLACE FUNCTION event_hash(previous_hash bytea, event events) RETURNS bytea AS
+$$
+SELECT sha256(
+    previous_hash
+    || length_prefixed(event.aggregate_type)
+    || length_prefixed(event.aggregate_id)
+    || length_prefixed(event.event_type)
+    || length_prefixed(event.event_version)
+    || length_prefixed(event.payload::text)
+    || int8send(event.sequence)
+    || int8send((extract(epoch FROM event.created_at) * 1000)::bigint)
+)
+$$ LANGUAGE sql IMMUTABLE;
+
+CREATE OR REPLACE FUNCTION chain_event_hash() RETURNS trigger AS
+$$
+DECLARE
+    previous bytea;
+BEGIN
+    -- held until commit, so concurrent writers extend the chain one at a time
+    PERFORM pg_advisory_xact_lock(7089068753736314728);
+    SELECT hash INTO previous FROM event_hashes ORDER BY position DESC LIMIT 1;
+    previous := coalesce(previous, decode(repeat('00', 32), 'hex'));
+    INSERT INTO event_hashes (aggregate_type, aggregate_id, sequence, previous_hash, hash)
+    VALUES (NEW.aggregate_type, NEW.aggregate_id, NEW.sequence, previous, event_hash(previous, NEW));
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS chain_event_hash ON events;
+CREATE TRIGGER chain_event_hash
+    AFTER INSERT
+    ON events
+    FOR EACH ROW
+EXECUTE FUNCTION chain_event_hash();
+
+-- events stored before the chain existed are chained in export order
+DO
+$$
+    DECLARE
+        previous bytea;
+        event    events;
+    BEGIN
+        SELECT hash INTO previous FROM event_hashes ORDER BY position DESC LIMIT 1;
+        previous := coalesce(previous, decode(repeat('00', 32), 'hex'));
+        FOR event IN
+            SELECT *
+            FROM events e
+            WHERE NOT EXISTS (SELECT 1
+                              FROM event_hashes h
+                              WHERE h.aggregate_type = e.aggregate_type
+                                AND h.aggregate_id = e.aggregate_id
+                                AND h.sequence = e.sequence)
+            ORDER BY created_at, aggregate_type, aggregate_id, sequence
+            LOOP
+                INSERT INTO event_hashes (aggregate_type, aggregate_id, sequence, previous_hash, hash)
+                VALUES (event.aggregate_type, event.aggregate_id, event.sequence, previous,
+                        event_hash(previous, event));
+                previous := event_hash(previous, event);
+            END LOOP;
+    END
+$$;
@@ -28,18 +28,12 @@ impl BlockHeightStore {
 
 #[async_trait]
 impl BlockHeightStoreApi for BlockHeightStore {
-    async fn get_block_height(&self, node_id: &str) -> PaydayResult<BlockHeight> {
+    async fn get_block_height(&self, node_id: &str) -> PaydayResult<Option<BlockHeight>> {
         let height: Option<u64> = self.get_block_height_internal(node_id).await?;
-        match height {
-            Some(height) => Ok(BlockHeight {
-                node_id: node_id.to_string(),
-                block_height: height,
-            }),
-            None => Ok(BlockHeight {
-                node_id: node_id.to_string(),
-                block_height: 0,
-            }),
-        }
+        Ok(height.map(|height| BlockHeight {
+            node_id: node_id.to_string(),
+            block_height: height,
+        }))
     }
 
     async fn set_block_height(&self, node_id: &str, block_height: u64) -> PaydayResult<()> {
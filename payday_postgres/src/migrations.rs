@@ -0,0 +1,166 @@
+use payday_core::{PaydayError, PaydayResult};
+use sqlx::{Executor, Pool, Postgres, Row};
+
+/// A numbered schema migration. Migrations are applied in version order and
+/// each version is applied exactly once.
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+/// All schema migrations of this crate. New migrations must use a higher version.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "init",
+        sql: include_str!("../migrations/0001_init.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "event_created_at",
+        sql: include_str!("../migrations/0002_event_created_at.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "payment_log",
+        sql: include_str!("../migrations/0003_payment_log.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "on_chain_invoice_view",
+        sql: include_str!("../migrations/0004_on_chain_invoice_view.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "event_hashes",
+        sql: include_str!("../migrations/0005_event_hashes.sql"),
+    },
+];
+
+/// Applies all pending migrations and returns the versions that were applied.
+pub async fn run_migrations(db: &Pool<Postgres>) -> PaydayResult<Vec<i64>> {
+    run(db, MIGRATIONS).await
+}
+
+/// Key of the advisory lock that serializes migration runs of concurrently
+/// starting instances, the ASCII bytes of "payday".
+const MIGRATION_LOCK_KEY: i64 = 0x7061_7964_6179;
+
+/// Runs all pending migrations in one transaction holding the migration lock,
+/// so a concurrent run waits and then finds them applied.
+async fn run(db: &Pool<Postgres>, migrations: &[Migration]) -> PaydayResult<Vec<i64>> {
+    let mut tx = db
+        .begin()
+        .await
+        .map_err(|e| PaydayError::DbError(e.to_string()))?;
+    sqlx::query("SELECT pg_advisory_xact_lock($1)")
+        .bind(MIGRATION_LOCK_KEY)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| PaydayError::DbError(e.to_string()))?;
+    tx.execute(
+        "CREATE SCHEMA IF NOT EXISTS payday; \
+        CREATE TABLE IF NOT EXISTS payday.schema_migrations ( \
+            version bigint NOT NULL PRIMARY KEY, \
+            name text NOT NULL, \
+            applied_at timestamptz NOT NULL DEFAULT now())",
+    )
+    .await
+    .map_err(|e| PaydayError::DbError(e.to_string()))?;
+
+    let applied: Vec<i64> = sqlx::query("SELECT version FROM payday.schema_migrations")
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(|e| PaydayError::DbError(e.to_string()))?
+        .iter()
+        .map(|r| r.get("version"))
+        .collect();
+
+    let mut result = Vec::new();
+    for migration in pending_migrations(migrations, &applied) {
+        tx.execute(migration.sql)
+            .await
+            .map_err(|e| PaydayError::DbError(e.to_string()))?;
+        sqlx::query("INSERT INTO payday.schema_migrations (version, name) VALUES ($1, $2)")
+            .bind(migration.version)
+            .bind(migration.name)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| PaydayError::DbError(e.to_string()))?;
+        result.push(migration.version);
+    }
+    tx.commit()
+        .await
+        .map_err(|e| PaydayError::DbError(e.to_string()))?;
+    Ok(result)
+}
+
+/// Returns the migrations not yet applied in version order.
+fn pending_migrations<'a>(migrations: &'a [Migration], applied: &[i64]) -> Vec<&'a Migration> {
+    let mut pending: Vec<&Migration> = migrations
+        .iter()
+        .filter(|m| !applied.contains(&m.version))
+        .collect();
+    pending.sort_by_key(|m| m.version);
+    pending
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_MIGRATIONS: &[Migration] = &[
+        Migration {
+            version: 2,
+            name: "second",
+            sql: "",
+        },
+        Migration {
+            version: 1,
+            name: "first",
+            sql: "",
+        },
+    ];
+
+    #[test]
+    fn test_pending_migrations_in_order() {
+        let pending: Vec<i64> = pending_migrations(TEST_MIGRATIONS, &[])
+            .iter()
+            .map(|m| m.version)
+            .collect();
+        assert_eq!(pending, vec![1, 2]);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a Postgres database, see DATABASE_URL"]
+    async fn test_concurrent_migration_runs() {
+        const CONCURRENT_MIGRATIONS: &[Migration] = &[Migration {
+            version: 1_000_001,
+            name: "concurrent",
+            sql: "SELECT 1",
+        }];
+        let pool = crate::test_pool().await;
+        let delete = "DELETE FROM payday.schema_migrations WHERE version = 1000001";
+        pool.execute(delete).await.unwrap();
+
+        let (first, second) = tokio::join!(
+            run(&pool, CONCURRENT_MIGRATIONS),
+            run(&pool, CONCURRENT_MIGRATIONS)
+        );
+        let mut applied = [first.unwrap(), second.unwrap()];
+        applied.sort();
+        assert_eq!(applied, [vec![], vec![1_000_001]]);
+        pool.execute(delete).await.unwrap();
+    }
+
+    #[test]
+    fn test_applied_migrations_are_skipped() {
+        let pending: Vec<i64> = pending_migrations(TEST_MIGRATIONS, &[1])
+            .iter()
+            .map(|m| m.version)
+            .collect();
+        assert_eq!(pending, vec![2]);
+        assert!(pending_migrations(TEST_MIGRATIONS, &[1, 2]).is_empty());
+    }
+}
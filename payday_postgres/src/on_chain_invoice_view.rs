@@ -0,0 +1,316 @@
+use async_trait::async_trait;
+use cqrs_es::persist::GenericQuery;
+use payday_btc::{
+    on_chain_aggregate::BtcOnChainInvoice,
+    on_chain_view::{OnChainInvoiceReadModel, OnChainInvoiceViewRepository},
+};
+use payday_core::{payment::invoice::InvoiceId, PaydayError, PaydayResult};
+use postgres_es::PostgresViewRepository;
+use sqlx::{postgres::PgRow, Pool, Postgres, Row};
+
+/// The table of the on-chain invoice read models.
+const ON_CHAIN_INVOICE_VIEW: &str = "on_chain_invoice_view";
+
+/// The query keeping the on-chain invoice read models up to date.
+pub type OnChainInvoiceQuery = GenericQuery<
+    PostgresViewRepository<OnChainInvoiceReadModel, BtcOnChainInvoice>,
+    OnChainInvoiceReadModel,
+    BtcOnChainInvoice,
+>;
+
+/// Creates the query that writes the on-chain invoice read models. Pass it to
+/// create_cqrs, OnChainInvoiceViews reads the written models.
+pub fn on_chain_invoice_query(db: Pool<Postgres>) -> OnChainInvoiceQuery {
+    let repository = PostgresViewRepository::new(ON_CHAIN_INVOICE_VIEW, db);
+    let mut query = GenericQuery::new(std::sync::Arc::new(repository));
+    query.use_error_handler(Box::new(|e| {
+        println!("Failed to update on-chain invoice view: {}", e)
+    }));
+    query
+}
+
+pub struct OnChainInvoiceViews {
+    db: Pool<Postgres>,
+}
+
+impl OnChainInvoiceViews {
+    pub fn new(db: Pool<Postgres>) -> Self {
+        Self { db }
+    }
+}
+
+#[async_trait]
+impl OnChainInvoiceViewRepository for OnChainInvoiceViews {
+    async fn get_invoice(
+        &self,
+        invoice_id: &InvoiceId,
+    ) -> PaydayResult<Option<OnChainInvoiceReadModel>> {
+        sqlx::query(
+            "SELECT payload FROM on_chain_invoice_view \
+             WHERE payload ->> 'invoice_id' = $1 LIMIT 1",
+        )
+        .bind(invoice_id.as_str())
+        .fetch_optional(&self.db)
+        .await
+        .map_err(|e| PaydayError::DbError(e.to_string()))?
+        .map(|r| to_read_model(&r))
+        .transpose()
+    }
+
+    async fn find_aggregate_id(&self, address: &str) -> PaydayResult<Option<String>> {
+        let row = sqlx::query(
+            "SELECT view_id FROM on_chain_invoice_view \
+             WHERE payload ->> 'address' = $1 \
+             OR (payload -> 'previous_addresses')::jsonb ? $1 LIMIT 1",
+        )
+        .bind(address)
+        .fetch_optional(&self.db)
+        .await
+        .map_err(|e| PaydayError::DbError(e.to_string()))?;
+        Ok(row.map(|r| r.get("view_id")))
+    }
+
+    async fn list_confirmed_from(&self, block_height: i32) -> PaydayResult<Vec<String>> {
+        let rows = sqlx::query(
+            "SELECT view_id FROM on_chain_invoice_view \
+             WHERE (payload ->> 'block_height')::integer >= $1",
+        )
+        .bind(block_height)
+        .fetch_all(&self.db)
+        .await
+        .map_err(|e| PaydayError::DbError(e.to_string()))?;
+        Ok(rows.iter().map(|r| r.get("view_id")).collect())
+    }
+
+    async fn list_unpaid(
+        &self,
+        limit: u32,
+        offset: u32,
+    ) -> PaydayResult<Vec<OnChainInvoiceReadModel>> {
+        sqlx::query(
+            "SELECT payload FROM on_chain_invoice_view \
+             WHERE NOT (payload ->> 'paid')::boolean AND NOT (payload ->> 'expired')::boolean \
+             AND (payload ->> 'expires_at' IS NULL OR (payload ->> 'expires_at')::timestamptz > now()) \
+             ORDER BY created_at, view_id LIMIT $1 OFFSET $2",
+        )
+        .bind(limit as i64)
+        .bind(offset as i64)
+        .fetch_all(&self.db)
+        .await
+        .map_err(|e| PaydayError::DbError(e.to_string()))?
+        .iter()
+        .map(to_read_model)
+        .collect()
+    }
+
+    async fn list_overdue(&self, limit: u32, offset: u32) -> PaydayResult<Vec<String>> {
+        let rows = sqlx::query(
+            "SELECT view_id FROM on_chain_invoice_view \
+             WHERE NOT (payload ->> 'paid')::boolean AND NOT (payload ->> 'expired')::boolean \
+             AND (payload ->> 'expires_at')::timestamptz <= now() \
+             ORDER BY created_at, view_id LIMIT $1 OFFSET $2",
+        )
+        .bind(limit as i64)
+        .bind(offset as i64)
+        .fetch_all(&self.db)
+        .await
+        .map_err(|e| PaydayError::DbError(e.to_string()))?;
+        Ok(rows.iter().map(|r| r.get("view_id")).collect())
+    }
+}
+
+fn to_read_model(row: &PgRow) -> PaydayResult<OnChainInvoiceReadModel> {
+    serde_json::from_value(row.get("payload")).map_err(|e| PaydayError::DbError(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use payday_btc::{
+        on_chain_aggregate::{OnChainCommand, OnChainInvoiceCommand},
+        on_chain_expiry::ExpirySweeper,
+        on_chain_service::OnChainService,
+        ConfirmationTiers,
+    };
+    use payday_core::{
+        date::{from_timestamp, now, DateTime},
+        payment::{amount::Amount, currency::Currency, invoice::AmountLimits},
+    };
+
+    use super::*;
+    use crate::{create_cqrs, create_event_store};
+
+    fn create_invoice(id: &str, expires_at: Option<DateTime>) -> OnChainInvoiceCommand {
+        OnChainInvoiceCommand::CreateInvoice {
+            invoice_id: InvoiceId::from(id),
+            amount: Amount::new(Currency::Btc, 100_000),
+            address: id.to_string(),
+            webhook_url: None,
+            required_confirmations: 1,
+            underpayment_tolerance: Amount::zero(Currency::Btc),
+            expires_at,
+            amount_limits: AmountLimits::default(),
+            confirmation_tiers: ConfirmationTiers::default(),
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a Postgres database, see DATABASE_URL"]
+    async fn test_view_reflects_created_and_paid() {
+        let pool = crate::test_pool().await;
+        let cqrs = create_cqrs::<BtcOnChainInvoice>(
+            pool.clone(),
+            vec![Box::new(on_chain_invoice_query(pool.clone()))],
+            (),
+        )
+        .await
+        .unwrap();
+        let views = OnChainInvoiceViews::new(pool.clone());
+        let btc = |sats: u64| Amount::new(Currency::Btc, sats);
+        let prefix = format!("view-{}", now().timestamp_nanos_opt().unwrap());
+        let id = |name: &str| format!("{}-{}", prefix, name);
+        for (name, expires_at) in [("paid", None), ("expiring", Some(from_timestamp(0)))] {
+            cqrs.execute(&id(name), create_invoice(&id(name), expires_at))
+                .await
+                .unwrap();
+        }
+        let listed = |unpaid: Vec<OnChainInvoiceReadModel>, name: &str| {
+            unpaid
+                .iter()
+                .any(|v| v.invoice_id == InvoiceId::from(id(name)))
+        };
+
+        let created = views
+            .get_invoice(&id("paid").into())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!created.paid);
+        assert!(listed(views.list_unpaid(1_000, 0).await.unwrap(), "paid"));
+        assert!(!listed(
+            views.list_unpaid(1_000, 0).await.unwrap(),
+            "expiring"
+        ));
+
+        cqrs.execute(
+            &id("paid"),
+            OnChainInvoiceCommand::SetConfirmed {
+                confirmations: 1,
+                amount: btc(100_000),
+                transaction_id: "txid".to_string(),
+                timestamp: None,
+                block_height: Some(800_000),
+            },
+        )
+        .await
+        .unwrap();
+        let paid = views
+            .get_invoice(&id("paid").into())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(paid.paid);
+        assert_eq!(paid.transaction_id, Some("txid".to_string()));
+        assert!(!listed(views.list_unpaid(1_000, 0).await.unwrap(), "paid"));
+        assert!(views
+            .list_confirmed_from(800_000)
+            .await
+            .unwrap()
+            .contains(&id("paid")));
+        assert!(!views
+            .list_confirmed_from(800_001)
+            .await
+            .unwrap()
+            .contains(&id("paid")));
+
+        let version: i64 =
+            sqlx::query("SELECT version FROM on_chain_invoice_view WHERE view_id = $1")
+                .bind(id("paid"))
+                .fetch_one(&pool)
+                .await
+                .unwrap()
+                .get("version");
+        assert_eq!(version, 2);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a Postgres database, see DATABASE_URL"]
+    async fn test_find_aggregate_id_by_rotated_address() {
+        let pool = crate::test_pool().await;
+        let cqrs = create_cqrs::<BtcOnChainInvoice>(
+            pool.clone(),
+            vec![Box::new(on_chain_invoice_query(pool.clone()))],
+            (),
+        )
+        .await
+        .unwrap();
+        let views = OnChainInvoiceViews::new(pool.clone());
+        let id = format!("rotate-{}", now().timestamp_nanos_opt().unwrap());
+        let rotated = |n: u32| format!("{}-rotated-{}", id, n);
+        cqrs.execute(&id, create_invoice(&id, None)).await.unwrap();
+        for n in 1..=2 {
+            cqrs.execute(
+                &id,
+                OnChainInvoiceCommand::RotateAddress {
+                    new_address: rotated(n),
+                },
+            )
+            .await
+            .unwrap();
+        }
+
+        for address in [id.to_owned(), rotated(1), rotated(2)] {
+            assert_eq!(
+                views.find_aggregate_id(&address).await.unwrap(),
+                Some(id.to_owned())
+            );
+        }
+        assert!(views
+            .find_aggregate_id(&rotated(3))
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a Postgres database, see DATABASE_URL"]
+    async fn test_sweep_expires_overdue_invoices() {
+        let pool = crate::test_pool().await;
+        let cqrs = create_cqrs::<BtcOnChainInvoice>(
+            pool.clone(),
+            vec![Box::new(on_chain_invoice_query(pool.clone()))],
+            (),
+        )
+        .await
+        .unwrap();
+        let service = Arc::new(OnChainService::new(cqrs, create_event_store(pool.clone())));
+        let views = Arc::new(OnChainInvoiceViews::new(pool.clone()));
+        let prefix = format!("sweep-{}", now().timestamp_nanos_opt().unwrap());
+        let overdue = format!("{}-overdue", prefix);
+        let open = format!("{}-open", prefix);
+        for (id, expires_at) in [(&overdue, Some(from_timestamp(0))), (&open, None)] {
+            service
+                .execute(OnChainCommand {
+                    id: id.to_owned(),
+                    command: create_invoice(id, expires_at),
+                })
+                .await
+                .unwrap();
+        }
+
+        let sweeper = ExpirySweeper::new(service.clone(), views.clone()).with_batch_size(1);
+        assert!(sweeper.sweep().await.unwrap() >= 1);
+        let expired = service
+            .load_on_chain_invoice(&overdue)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(expired.expired);
+        let view = views.get_invoice(&overdue.into()).await.unwrap().unwrap();
+        assert!(view.expired);
+        let open = service.load_on_chain_invoice(&open).await.unwrap().unwrap();
+        assert!(!open.expired);
+        assert!(views.list_overdue(1_000, 0).await.unwrap().is_empty());
+    }
+}
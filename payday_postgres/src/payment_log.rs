@@ -0,0 +1,100 @@
+use async_trait::async_trait;
+use payday_core::{
+    persistence::payment_log::{OutgoingPaymentStatus, PaymentLogApi, PaymentRecord},
+    PaydayError, PaydayResult,
+};
+use serde_json::Value;
+use sqlx::{postgres::PgRow, Pool, Postgres, Row};
+
+pub struct PaymentLog {
+    db: Pool<Postgres>,
+}
+
+impl PaymentLog {
+    pub fn new(db: Pool<Postgres>) -> Self {
+        Self { db }
+    }
+}
+
+#[async_trait]
+impl PaymentLogApi for PaymentLog {
+    async fn in_flight(&self) -> PaydayResult<Vec<String>> {
+        let rows = sqlx::query("SELECT payment_id FROM payment_log WHERE status = $1")
+            .bind(to_status_string(OutgoingPaymentStatus::InFlight)?)
+            .fetch_all(&self.db)
+            .await
+            .map_err(|e| PaydayError::DbError(e.to_string()))?;
+        Ok(rows.iter().map(|r| r.get("payment_id")).collect())
+    }
+
+    async fn set_status(
+        &self,
+        payment_hash: &str,
+        status: OutgoingPaymentStatus,
+    ) -> PaydayResult<()> {
+        sqlx::query("UPDATE payment_log SET status = $1 WHERE payment_id = $2")
+            .bind(to_status_string(status)?)
+            .bind(payment_hash)
+            .execute(&self.db)
+            .await
+            .map_err(|e| PaydayError::DbError(e.to_string()))?;
+        Ok(())
+    }
+
+    async fn record(&self, payment: PaymentRecord) -> PaydayResult<()> {
+        let amount = serde_json::to_value(payment.amount)
+            .map_err(|e| PaydayError::DbError(e.to_string()))?;
+        sqlx::query(
+            "INSERT INTO payment_log (payment_id, reference, amount, status) \
+             VALUES ($1, $2, $3, $4) \
+             ON CONFLICT (payment_id) DO UPDATE \
+             SET reference = $2, amount = $3, status = $4, created_at = now()",
+        )
+        .bind(payment.payment_id)
+        .bind(payment.reference)
+        .bind(amount)
+        .bind(to_status_string(payment.status)?)
+        .execute(&self.db)
+        .await
+        .map_err(|e| PaydayError::DbError(e.to_string()))?;
+        Ok(())
+    }
+
+    async fn find_payment_by_reference(
+        &self,
+        reference: &str,
+    ) -> PaydayResult<Option<PaymentRecord>> {
+        sqlx::query(
+            "SELECT payment_id, reference, amount, status FROM payment_log \
+             WHERE reference = $1 ORDER BY created_at DESC LIMIT 1",
+        )
+        .bind(reference)
+        .fetch_optional(&self.db)
+        .await
+        .map_err(|e| PaydayError::DbError(e.to_string()))?
+        .map(|r| to_payment_record(&r))
+        .transpose()
+    }
+}
+
+fn to_payment_record(row: &PgRow) -> PaydayResult<PaymentRecord> {
+    Ok(PaymentRecord {
+        payment_id: row.get("payment_id"),
+        reference: row.get("reference"),
+        amount: serde_json::from_value(row.get("amount"))
+            .map_err(|e| PaydayError::DbError(e.to_string()))?,
+        status: serde_json::from_value(Value::String(row.get("status")))
+            .map_err(|e| PaydayError::DbError(e.to_string()))?,
+    })
+}
+
+/// Statuses are stored by their variant name.
+fn to_status_string(status: OutgoingPaymentStatus) -> PaydayResult<String> {
+    match serde_json::to_value(status) {
+        Ok(Value::String(status)) => Ok(status),
+        _ => Err(PaydayError::DbError(format!(
+            "invalid payment status {:?}",
+            status
+        ))),
+    }
+}
@@ -0,0 +1,90 @@
+use std::time::Duration;
+
+use cqrs_es::Aggregate;
+use payday_btc::on_chain_aggregate::BtcOnChainInvoice;
+use payday_core::{
+    date::{from_timestamp, DateTime},
+    PaydayError, PaydayResult,
+};
+use sqlx::{Pool, Postgres, Row};
+
+/// Event types after which an on-chain invoice does not change anymore.
+pub const TERMINAL_EVENT_TYPES: &[&str] = &["OnChainPaymentConfirmed", "OnChainInvoiceExpired"];
+
+/// An on-chain invoice that did not reach a terminal state.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StuckInvoice {
+    /// The aggregate id, which is the invoice address.
+    pub aggregate_id: String,
+    pub invoice_id: Option<String>,
+    pub created_at: DateTime,
+    pub last_event_type: String,
+    pub last_event_at: DateTime,
+}
+
+/// Finds on-chain invoices created more than older_than ago that are not paid,
+/// e.g. a partial payment pending for days. Oldest invoices come first.
+pub async fn stuck_invoices(
+    db: &Pool<Postgres>,
+    older_than: Duration,
+) -> PaydayResult<Vec<StuckInvoice>> {
+    let rows = sqlx::query(
+        "SELECT aggregate_id, \
+            max(payload->'InvoiceCreated'->>'invoice_id') AS invoice_id, \
+            extract(epoch FROM min(created_at))::bigint AS created_at, \
+            extract(epoch FROM max(created_at))::bigint AS last_event_at, \
+            (array_agg(event_type ORDER BY sequence DESC))[1] AS last_event_type \
+         FROM events \
+         WHERE aggregate_type = $1 \
+         GROUP BY aggregate_id \
+         HAVING min(created_at) < now() - make_interval(secs => $2) \
+            AND bool_and(event_type <> ALL($3)) \
+         ORDER BY created_at",
+    )
+    .bind(BtcOnChainInvoice::aggregate_type())
+    .bind(older_than.as_secs_f64())
+    .bind(TERMINAL_EVENT_TYPES)
+    .fetch_all(db)
+    .await
+    .map_err(|e| PaydayError::DbError(e.to_string()))?;
+
+    Ok(rows
+        .iter()
+        .map(|r| StuckInvoice {
+            aggregate_id: r.get("aggregate_id"),
+            invoice_id: r.get("invoice_id"),
+            created_at: from_timestamp(r.get("created_at")),
+            last_event_type: r.get("last_event_type"),
+            last_event_at: from_timestamp(r.get("last_event_at")),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use cqrs_es::DomainEvent;
+    use payday_btc::on_chain_aggregate::OnChainInvoiceEvent;
+    use payday_core::payment::amount::Amount;
+
+    use super::*;
+
+    #[test]
+    fn test_terminal_event_types_match_aggregate() {
+        let confirmed = OnChainInvoiceEvent::PaymentConfirmed {
+            received_amount: Amount::default(),
+            underpayment: false,
+            overpayment: false,
+            confirmations: 1,
+            transaction_id: "txid".to_string(),
+            paid_at: None,
+            block_height: None,
+        };
+        let pending = OnChainInvoiceEvent::PaymentPending {
+            received_amount: Amount::default(),
+            underpayment: true,
+            overpayment: false,
+        };
+        assert!(TERMINAL_EVENT_TYPES.contains(&confirmed.event_type().as_str()));
+        assert!(!TERMINAL_EVENT_TYPES.contains(&pending.event_type().as_str()));
+    }
+}
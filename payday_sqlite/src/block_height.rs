@@ -0,0 +1,81 @@
+use async_trait::async_trait;
+use payday_core::{
+    persistence::block_height::{BlockHeight, BlockHeightStoreApi},
+    PaydayError, PaydayResult,
+};
+use sqlx::{Pool, Row, Sqlite};
+
+pub struct BlockHeightStore {
+    db: Pool<Sqlite>,
+}
+
+impl BlockHeightStore {
+    pub fn new(db: Pool<Sqlite>) -> Self {
+        Self { db }
+    }
+}
+
+#[async_trait]
+impl BlockHeightStoreApi for BlockHeightStore {
+    async fn get_block_height(&self, node_id: &str) -> PaydayResult<Option<BlockHeight>> {
+        let height: Option<i64> =
+            sqlx::query("SELECT block_height FROM block_height WHERE node_id = ?")
+                .bind(node_id)
+                .fetch_optional(&self.db)
+                .await
+                .map_err(|e| PaydayError::DbError(e.to_string()))?
+                .map(|r| r.get("block_height"));
+        Ok(height
+            .and_then(|h| u64::try_from(h).ok())
+            .map(|block_height| BlockHeight {
+                node_id: node_id.to_string(),
+                block_height,
+            }))
+    }
+
+    async fn set_block_height(&self, node_id: &str, block_height: u64) -> PaydayResult<()> {
+        sqlx::query(
+            "INSERT INTO block_height (node_id, block_height) VALUES (?, ?) \
+             ON CONFLICT (node_id) DO UPDATE SET block_height = excluded.block_height",
+        )
+        .bind(node_id)
+        .bind(block_height as i64)
+        .execute(&self.db)
+        .await
+        .map_err(|e| PaydayError::DbError(e.to_string()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_pool;
+
+    #[tokio::test]
+    async fn test_get_set_block_height() {
+        let store = BlockHeightStore::new(memory_pool().await);
+        store.set_block_height("node1", 800_000).await.unwrap();
+        store.set_block_height("node1", 800_001).await.unwrap();
+        store.set_block_height("node2", 10).await.unwrap();
+
+        let height = store.get_block_height("node1").await.unwrap().unwrap();
+        assert_eq!(height.node_id, "node1");
+        assert_eq!(height.block_height, 800_001);
+        assert_eq!(
+            store
+                .get_block_height("node2")
+                .await
+                .unwrap()
+                .unwrap()
+                .block_height,
+            10
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_block_height_non_existant() {
+        let store = BlockHeightStore::new(memory_pool().await);
+        assert!(store.get_block_height("node1").await.unwrap().is_none());
+    }
+}
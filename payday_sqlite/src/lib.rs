@@ -0,0 +1,48 @@
+pub mod block_height;
+
+use std::str::FromStr;
+
+use payday_core::{PaydayError, PaydayResult};
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    Executor, Pool, Sqlite,
+};
+
+/// Creates a pool for the SQLite database at connection_string, e.g.
+/// sqlite://payday.db. The database file is created if it does not exist.
+pub async fn create_sqlite_pool(connection_string: &str) -> PaydayResult<Pool<Sqlite>> {
+    let options = SqliteConnectOptions::from_str(connection_string)
+        .map_err(|e| PaydayError::DbError(e.to_string()))?
+        .create_if_missing(true);
+    let pool = SqlitePoolOptions::new()
+        .connect_with(options)
+        .await
+        .map_err(|e| PaydayError::DbError(e.to_string()))?;
+    Ok(pool)
+}
+
+/// Creates the tables of this crate if they do not exist.
+pub async fn init_tables(db: &Pool<Sqlite>) -> PaydayResult<()> {
+    db.execute(
+        "CREATE TABLE IF NOT EXISTS block_height ( \
+            node_id text NOT NULL PRIMARY KEY, \
+            block_height integer NOT NULL)",
+    )
+    .await
+    .map_err(|e| PaydayError::DbError(e.to_string()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) async fn memory_pool() -> Pool<Sqlite> {
+    // every connection to :memory: opens its own database
+    let db = SqlitePoolOptions::new()
+        .max_connections(1)
+        .idle_timeout(None)
+        .max_lifetime(None)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    init_tables(&db).await.unwrap();
+    db
+}
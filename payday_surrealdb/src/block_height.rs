@@ -17,19 +17,13 @@ impl BlockHeightStore {
 
 #[async_trait]
 impl BlockHeightStoreApi for BlockHeightStore {
-    async fn get_block_height(&self, node_id: &str) -> PaydayResult<BlockHeight> {
+    async fn get_block_height(&self, node_id: &str) -> PaydayResult<Option<BlockHeight>> {
         let height: Option<BlockHeight> = self
             .db
             .select(("block_height", node_id))
             .await
             .map_err(|e| PaydayError::DbError(e.to_string()))?;
-        match height {
-            Some(height) => Ok(height),
-            None => Ok(BlockHeight {
-                node_id: node_id.to_string(),
-                block_height: 0,
-            }),
-        }
+        Ok(height)
     }
 
     async fn set_block_height(&self, node_id: &str, block_height: u64) -> PaydayResult<()> {
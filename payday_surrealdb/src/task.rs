@@ -1,6 +1,6 @@
 use crate::serialize_chrono_as_sql_datetime;
 use crate::serialize_chrono_as_sql_datetime_option;
-use std::{sync::Arc, time::Duration};
+use std::{future::Future, sync::Arc, time::Duration};
 
 use async_trait::async_trait;
 use payday_core::events::task::TaskResult;
@@ -9,9 +9,10 @@ use payday_core::{
     events::{
         handler::{MessageProcessorApi, TaskHandler},
         publisher::{Publisher, TaskPublisher},
-        task::{RetryType, Task, TaskStatus, TaskType},
+        task::{default_priority, DeadLetterFilter, RetryType, Task, TaskStatus, TaskType},
         Message, MessageError, MessageType, Result,
     },
+    shutdown::{shutdown_signal, ShutdownSignal},
 };
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
@@ -39,6 +40,8 @@ pub struct SurrealTask {
     #[serde(serialize_with = "serialize_chrono_as_sql_datetime_option")]
     pub completed_at: Option<DateTime>,
     pub recover_after: Option<i64>,
+    #[serde(default = "default_priority")]
+    pub priority: u8,
 }
 
 impl SurrealTask {
@@ -46,6 +49,7 @@ impl SurrealTask {
         let max_retry = match retry_type {
             RetryType::Fixed(r, ..) => Some(r),
             RetryType::Exponential(r, ..) => Some(r),
+            RetryType::ExponentialJitter { max_retries, .. } => Some(max_retries),
             _ => None,
         };
 
@@ -53,6 +57,7 @@ impl SurrealTask {
             id: None,
             message_type: "task".to_string(),
             task_type: payload.task_type.to_owned(),
+            priority: payload.priority,
             retry_type,
             max_retry,
             payload,
@@ -89,7 +94,7 @@ impl SurrealTask {
             TaskResult::Retry => {
                 if updated.should_retry() {
                     updated.status = TaskStatus::Retrying;
-                    updated.next_retry = updated.retry_type.next_retry();
+                    updated.next_retry = updated.retry_type.next_retry(updated.num_retry);
                     updated.num_retry += 1;
                 } else {
                     updated.status = TaskStatus::Failed;
@@ -120,6 +125,7 @@ impl Message for SurrealTask {
 pub struct SurrealTaskQueue {
     db: Surreal<Any>,
     task_table: String,
+    max_payload_size: Option<usize>,
 }
 
 impl SurrealTaskQueue {
@@ -127,10 +133,29 @@ impl SurrealTaskQueue {
         Self {
             db,
             task_table: task_table.to_string(),
+            max_payload_size: None,
         }
     }
 
+    /// Rejects tasks whose serialized payload exceeds the given size in bytes.
+    pub fn with_max_payload_size(mut self, max_payload_size: usize) -> Self {
+        self.max_payload_size = Some(max_payload_size);
+        self
+    }
+
+    /// Backfills the priority of tasks stored before priorities existed, so
+    /// they are not ordered behind low priority tasks. Safe to run on every
+    /// start.
+    pub async fn migrate(&self) -> Result<()> {
+        self.db
+            .query(priority_backfill_query(&self.task_table))
+            .await
+            .map_err(|e| MessageError::PublishError(e.to_string()))?;
+        Ok(())
+    }
+
     async fn publish_task(&self, task: SurrealTask) -> Result<()> {
+        task.payload.check_payload_size(self.max_payload_size)?;
         let res: Vec<SurrealTask> = self
             .db
             .create(&self.task_table)
@@ -145,6 +170,21 @@ impl SurrealTaskQueue {
             Ok(())
         }
     }
+
+    /// Re-enqueues all dead-lettered tasks matching the filter, e.g. after the
+    /// bug that made them fail was fixed. The tasks keep their retry type but
+    /// start with a fresh retry count. Returns the number of replayed tasks.
+    pub async fn replay_dead_letters(&self, filter: DeadLetterFilter) -> Result<u64> {
+        let mut response = self
+            .db
+            .query(dead_letter_replay_query(&self.task_table, &filter))
+            .await
+            .map_err(|e| MessageError::PublishError(e.to_string()))?;
+        let replayed: Vec<SurrealTask> = response
+            .take(0)
+            .map_err(|e| MessageError::PublishError(e.to_string()))?;
+        Ok(replayed.len() as u64)
+    }
 }
 
 #[async_trait]
@@ -183,6 +223,7 @@ pub struct SurrealTaskProcessor {
     poll_interval: Duration,
     batch_size: usize,
     task_types: Option<Vec<String>>,
+    shutdown: ShutdownSignal,
 }
 
 impl SurrealTaskProcessor {
@@ -198,9 +239,17 @@ impl SurrealTaskProcessor {
             poll_interval: Duration::from_secs(1),
             batch_size: 5,
             task_types: None,
+            shutdown: shutdown_signal().1,
         }
     }
 
+    /// Stops polling once the signal fires. The current batch is handled and
+    /// stored first.
+    pub fn with_shutdown(mut self, shutdown: ShutdownSignal) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
     pub fn add_handler(&mut self, handler: Arc<Mutex<dyn TaskHandler>>) {
         self.handlers.push(handler);
     }
@@ -219,20 +268,20 @@ impl SurrealTaskProcessor {
             .take(0)
             .map_err(|e| MessageError::SubscribeError(e.to_string()))?)
     }
-}
 
-#[async_trait]
-impl MessageProcessorApi for SurrealTaskProcessor {
-    async fn process(&self) -> Result<JoinHandle<Result<()>>> {
+    /// Handles due tasks on the current task until shutdown, polling for new
+    /// ones every poll interval.
+    pub fn run(&self) -> impl Future<Output = Result<()>> + Send + 'static {
         let db = self.db.clone();
         let table = self.task_table.to_string();
         let batch_size = self.batch_size;
         let task_types = self.task_types.clone();
         let handlers = self.handlers.clone();
         let interval = self.poll_interval;
+        let mut shutdown = self.shutdown.clone();
 
-        let handle = tokio::spawn(async move {
-            loop {
+        async move {
+            while !shutdown.is_triggered() {
                 let _ = cleanup_batch(db.clone(), &table, task_types.clone()).await?;
                 let tasks = query_batch(db.clone(), &table, batch_size, task_types.clone()).await?;
                 for task in tasks {
@@ -241,10 +290,16 @@ impl MessageProcessorApi for SurrealTaskProcessor {
                         if h.handles(&task.task_type) {
                             let updated = match h.handle(task.payload.clone()).await {
                                 Ok(res) => task.update_status(res),
-                                Err(_) if task.should_retry() => {
-                                    task.update_status(TaskResult::Retry)
+                                Err(e) => {
+                                    println!(
+                                        "Task {} failed with context {:?}: {:?}",
+                                        task.task_type, task.payload.context, e
+                                    );
+                                    match task.should_retry() {
+                                        true => task.update_status(TaskResult::Retry),
+                                        false => task.update_status(TaskResult::Failed),
+                                    }
                                 }
-                                _ => task.update_status(TaskResult::Failed),
                             };
 
                             let _: Option<SurrealTask> = db
@@ -256,10 +311,20 @@ impl MessageProcessorApi for SurrealTaskProcessor {
                         }
                     }
                 }
-                tokio::time::sleep(interval).await;
+                tokio::select! {
+                    _ = shutdown.wait() => {}
+                    _ = tokio::time::sleep(interval) => {}
+                }
             }
-        });
-        Ok(handle)
+            Ok(())
+        }
+    }
+}
+
+#[async_trait]
+impl MessageProcessorApi for SurrealTaskProcessor {
+    async fn process(&self) -> Result<JoinHandle<Result<()>>> {
+        Ok(tokio::spawn(self.run()))
     }
 }
 
@@ -293,14 +358,18 @@ async fn cleanup_batch(
     Ok(())
 }
 
+/// Claims a batch of due tasks. Tasks are ordered by priority and then by the
+/// time they became due, which is the next retry for retried tasks and the time
+/// received otherwise, so older tasks are not starved by new ones.
 fn task_query(table: &str, limit: usize, task_types: Option<Vec<String>>) -> String {
     format!(
         "BEGIN TRANSACTION; \
-        let $batch = SELECT * FROM {} \
+        let $batch = SELECT *, (next_retry OR received_at) AS due_at FROM {} \
             WHERE processed = false \
             AND status INSIDE ['Pending', 'Retrying'] \
             AND (next_retry = NONE OR next_retry < time::now()) \
             {} \
+            ORDER BY priority DESC, due_at ASC \
             LIMIT {}; \
          UPDATE $batch SET status = 'Processing', started_at = time::now(); \
          RETURN $batch; \
@@ -333,6 +402,37 @@ fn task_cleanup_query(
     )
 }
 
+/// Resets failed tasks to pending. Tasks failed by the cleanup have no
+/// completion time, so the date range falls back to the start time.
+fn dead_letter_replay_query(table: &str, filter: &DeadLetterFilter) -> String {
+    let failed_at = |op: &str, date: Option<DateTime>| match date {
+        Some(date) => format!(
+            "AND (completed_at OR started_at) {} <datetime>\"{}\"",
+            op,
+            date.to_rfc3339()
+        ),
+        None => "".to_string(),
+    };
+    format!(
+        "UPDATE {} SET status = 'Pending', processed = false, num_retry = 0, \
+            next_retry = NONE, started_at = NONE, completed_at = NONE \
+         WHERE status = 'Failed' \
+         {} {} {};",
+        table,
+        task_type_query_fragment(filter.task_type.clone().map(|t| vec![t])),
+        failed_at(">=", filter.from),
+        failed_at("<", filter.to)
+    )
+}
+
+fn priority_backfill_query(table: &str) -> String {
+    format!(
+        "UPDATE {} SET priority = {} WHERE priority = NONE;",
+        table,
+        default_priority()
+    )
+}
+
 fn task_type_query_fragment(task_types: Option<Vec<String>>) -> String {
     match task_types {
         Some(types) if !types.is_empty() => {
@@ -342,3 +442,184 @@ fn task_type_query_fragment(task_types: Option<Vec<String>>) -> String {
         _ => "".to_string(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use payday_core::events::task::{PRIORITY_HIGH, PRIORITY_LOW, PRIORITY_NORMAL};
+
+    use super::*;
+
+    async fn mem_db() -> Surreal<Any> {
+        let db = surrealdb::engine::any::connect("mem://").await.unwrap();
+        db.use_ns("payday").use_db("payday").await.unwrap();
+        db
+    }
+
+    struct RecordingHandler(tokio::sync::mpsc::UnboundedSender<Task>);
+
+    #[async_trait]
+    impl TaskHandler for RecordingHandler {
+        fn allow_retry(&self) -> bool {
+            false
+        }
+
+        fn allow_recovery(&self) -> bool {
+            false
+        }
+
+        fn handles(&self, task_type: &str) -> bool {
+            task_type == "test"
+        }
+
+        async fn handle(&self, task: Task) -> Result<TaskResult> {
+            let _ = self.0.send(task);
+            Ok(TaskResult::Success)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_task_context_reaches_handler() {
+        let db = mem_db().await;
+        let queue = SurrealTaskQueue::new(db.clone(), "tasks");
+        queue
+            .once(
+                Task::new("test".to_string(), "payload")
+                    .with_context("invoice_id", "inv-1")
+                    .with_context("correlation_id", "corr-1"),
+            )
+            .await
+            .unwrap();
+
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let processor = SurrealTaskProcessor::new(
+            db,
+            "tasks",
+            vec![Arc::new(Mutex::new(RecordingHandler(sender)))],
+        );
+        let handle = processor.process().await.unwrap();
+        let handled = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        handle.abort();
+
+        assert_eq!(handled.context.get("invoice_id").unwrap(), "inv-1");
+        assert_eq!(handled.context.get("correlation_id").unwrap(), "corr-1");
+    }
+
+    #[tokio::test]
+    async fn test_priority_backfill() {
+        let db = mem_db().await;
+        let queue = SurrealTaskQueue::new(db.clone(), "tasks");
+        queue
+            .once(Task::new("test".to_string(), "legacy"))
+            .await
+            .unwrap();
+        // a task stored before priorities existed
+        db.query("UPDATE tasks SET priority = NONE;").await.unwrap();
+        queue
+            .once(Task::new("test".to_string(), "low").with_priority(PRIORITY_LOW))
+            .await
+            .unwrap();
+
+        queue.migrate().await.unwrap();
+
+        let tasks = query_batch(db, "tasks", 10, None).await.unwrap();
+        let payloads: Vec<Value> = tasks.iter().map(|t| t.payload.payload.clone()).collect();
+        assert_eq!(payloads, vec![Value::from("legacy"), Value::from("low")]);
+        assert_eq!(tasks[0].priority, PRIORITY_NORMAL);
+    }
+
+    /// Stores a task received the given time ago, retried if next_retry is
+    /// set.
+    async fn store_task(
+        queue: &SurrealTaskQueue,
+        name: &str,
+        received_ago: Duration,
+        next_retry: Option<DateTime>,
+    ) {
+        let mut task = SurrealTask::new(
+            Task::new("test".to_string(), name),
+            RetryType::Fixed(3, Duration::from_secs(1)),
+        );
+        task.received_at = now() - received_ago;
+        if next_retry.is_some() {
+            task.status = TaskStatus::Retrying;
+            task.num_retry = 1;
+            task.next_retry = next_retry;
+        }
+        queue.publish_task(task).await.unwrap();
+    }
+
+    /// Claims the next task like the processor does.
+    async fn claim_next(db: &Surreal<Any>) -> Option<Value> {
+        query_batch(db.clone(), "tasks", 1, None)
+            .await
+            .unwrap()
+            .first()
+            .map(|t| t.payload.payload.clone())
+    }
+
+    #[tokio::test]
+    async fn test_claims_tasks_by_due_time() {
+        let db = mem_db().await;
+        let queue = SurrealTaskQueue::new(db.clone(), "tasks");
+        let secs = Duration::from_secs;
+        store_task(&queue, "newer", secs(10), None).await;
+        store_task(&queue, "older", secs(60), None).await;
+        // retried tasks are due at their next retry, not when received
+        store_task(&queue, "retried_late", secs(300), Some(now() - secs(5))).await;
+        store_task(&queue, "retried_early", secs(300), Some(now() - secs(90))).await;
+        store_task(&queue, "retry_not_due", secs(300), Some(now() + secs(60))).await;
+
+        for expected in ["retried_early", "older", "newer", "retried_late"] {
+            assert_eq!(claim_next(&db).await, Some(Value::from(expected)));
+        }
+        assert_eq!(claim_next(&db).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_claims_high_priority_task_first() {
+        let db = mem_db().await;
+        let queue = SurrealTaskQueue::new(db.clone(), "tasks");
+        queue
+            .once(Task::new("test".to_string(), "low").with_priority(PRIORITY_LOW))
+            .await
+            .unwrap();
+        queue
+            .once(Task::new("test".to_string(), "high").with_priority(PRIORITY_HIGH))
+            .await
+            .unwrap();
+
+        assert_eq!(claim_next(&db).await, Some(Value::from("high")));
+        assert_eq!(claim_next(&db).await, Some(Value::from("low")));
+    }
+
+    #[test]
+    fn test_dead_letter_replay_query_by_task_type() {
+        let query = dead_letter_replay_query(
+            "tasks",
+            &DeadLetterFilter::default().with_task_type("payment_confirmed"),
+        );
+        assert!(query.starts_with("UPDATE tasks SET status = 'Pending'"));
+        assert!(query.contains("num_retry = 0"));
+        assert!(query.contains("WHERE status = 'Failed'"));
+        assert!(query.contains("AND task_type INSIDE [\"payment_confirmed\"]"));
+        assert!(!query.contains("<datetime>"));
+
+        let all = dead_letter_replay_query("tasks", &DeadLetterFilter::default());
+        assert!(!all.contains("task_type INSIDE"));
+
+        let range = dead_letter_replay_query(
+            "tasks",
+            &DeadLetterFilter::default().with_date_range(
+                payday_core::date::from_timestamp(1_700_000_000),
+                payday_core::date::from_timestamp(1_700_086_400),
+            ),
+        );
+        assert!(range
+            .contains("(completed_at OR started_at) >= <datetime>\"2023-11-14T22:13:20+00:00\""));
+        assert!(range
+            .contains("(completed_at OR started_at) < <datetime>\"2023-11-15T22:13:20+00:00\""));
+    }
+}
@@ -0,0 +1,75 @@
+use std::{collections::HashMap, str::FromStr};
+
+use payday_btc::on_chain_invoice::ON_CHAIN_PAYMENT_TYPE;
+use payday_core::{
+    payment::{
+        amount::Amount,
+        currency::Currency,
+        invoice::{AmountLimits, PaymentTypeLimits},
+    },
+    PaydayError, PaydayResult,
+};
+
+/// Outputs below this are not relayed by nodes, so smaller on-chain invoices
+/// can not be paid.
+const DUST_LIMIT_SATS: u64 = 546;
+
+/// Invoice settings read from the environment on start.
+pub struct InvoiceConfig {
+    /// Overrides the confirmations on-chain payments need on the node network.
+    pub required_confirmations: Option<u64>,
+    pub limits: PaymentTypeLimits,
+    /// Fiat invoice amounts are converted with these rates in minor units per
+    /// BTC.
+    pub exchange_rates: HashMap<Currency, u64>,
+}
+
+impl InvoiceConfig {
+    /// Reads PAYDAY_REQUIRED_CONFIRMATIONS, PAYDAY_ON_CHAIN_MIN_SATS,
+    /// PAYDAY_ON_CHAIN_MAX_SATS and PAYDAY_EXCHANGE_RATES, e.g.
+    /// "USD:6700000,EUR:6200000". Unset values use the defaults.
+    pub fn from_env() -> PaydayResult<Self> {
+        let on_chain_min = env_u64("PAYDAY_ON_CHAIN_MIN_SATS")?.unwrap_or(DUST_LIMIT_SATS);
+        let on_chain_max = env_u64("PAYDAY_ON_CHAIN_MAX_SATS")?;
+        Ok(Self {
+            required_confirmations: env_u64("PAYDAY_REQUIRED_CONFIRMATIONS")?,
+            limits: PaymentTypeLimits::new().with_limits(
+                ON_CHAIN_PAYMENT_TYPE,
+                AmountLimits::new(
+                    Some(Amount::new(Currency::Btc, on_chain_min)),
+                    on_chain_max.map(|max| Amount::new(Currency::Btc, max)),
+                ),
+            ),
+            exchange_rates: match std::env::var("PAYDAY_EXCHANGE_RATES") {
+                Ok(rates) => parse_rates(&rates)?,
+                Err(_) => HashMap::new(),
+            },
+        })
+    }
+}
+
+fn env_u64(name: &str) -> PaydayResult<Option<u64>> {
+    match std::env::var(name) {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| PaydayError::InvalidConfig(format!("{}: {}", name, value))),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Parses comma separated CURRENCY:RATE pairs.
+fn parse_rates(rates: &str) -> PaydayResult<HashMap<Currency, u64>> {
+    rates
+        .split(',')
+        .filter(|pair| !pair.trim().is_empty())
+        .map(|pair| {
+            let invalid = || PaydayError::InvalidConfig(format!("exchange rate {}", pair));
+            let (currency, rate) = pair.trim().split_once(':').ok_or_else(invalid)?;
+            let currency = Currency::from_str(currency).map_err(|_| invalid())?;
+            let rate = rate.parse().map_err(|_| invalid())?;
+            Ok((currency, rate))
+        })
+        .collect()
+}